@@ -0,0 +1,121 @@
+// what the admin routes take and return; the uploader-admin cli builds against this file as well
+use serde::{Deserialize, Serialize};
+
+pub const TOKEN_HEADER: &str = "x-uploader-admin";
+
+#[derive(Serialize, Deserialize, PartialEq, Debug)]
+pub struct User {
+	pub id: u64,
+	pub email: String,
+	// top level nodes the user owns
+	pub roots: Vec<u64>,
+	// every node in the owned trees
+	pub nodes: usize,
+	// blob bytes in the owned trees
+	pub bytes: u64,
+}
+
+#[derive(Serialize, Deserialize, PartialEq, Debug)]
+pub struct Node {
+	pub id: u64,
+	pub parent_id: u64,
+	// owner of the tree the node is in, if any
+	pub owner: Option<u64>,
+	// none for nodes with no blob, eg folders
+	pub size: Option<u64>,
+}
+
+#[derive(Serialize, Deserialize, PartialEq, Debug)]
+pub struct Share {
+	pub sender: u64,
+	// a user or a group
+	pub receiver: u64,
+	// viewer, editor or owner
+	pub role: String,
+	// the shared nodes
+	pub nodes: Vec<u64>,
+}
+
+#[derive(Serialize, Deserialize, PartialEq, Debug, Default)]
+pub struct NodeFilter {
+	// nodes in trees owned by this user only
+	pub user: Option<u64>,
+}
+
+#[derive(Serialize, Deserialize, PartialEq, Debug, Default)]
+pub struct Gc {
+	// list the orphans, but leave them be
+	#[serde(default)]
+	pub dry_run: bool,
+}
+
+#[derive(Serialize, Deserialize, PartialEq, Debug)]
+pub struct Orphans {
+	// blobs no node refers to, past the grace period for pending uploads
+	pub ids: Vec<u64>,
+	pub bytes: u64,
+	pub removed: bool,
+}
+
+#[derive(Serialize, Deserialize, PartialEq, Debug)]
+pub struct Stats {
+	pub users: usize,
+	pub nodes: usize,
+	pub shares: usize,
+	pub sessions: usize,
+	pub blobs: usize,
+	pub bytes: u64,
+	// none if it can't be told
+	pub disk_free: Option<u64>,
+}
+
+#[derive(Serialize, Deserialize, PartialEq, Debug)]
+pub struct Job {
+	// expire, invites, uploads or gc
+	pub name: String,
+	// seconds between runs; 0 if it only runs when triggered
+	pub interval: u64,
+	// unix time, seconds
+	pub last_run: Option<u64>,
+}
+
+#[derive(Serialize, Deserialize, PartialEq, Debug, Default)]
+pub struct FsckOptions {
+	// fix what can be fixed; only reports otherwise
+	#[serde(default)]
+	pub repair: bool,
+	// with repair, also remove nodes nobody can reach, subtrees and blobs included; as they may
+	// only have lost their owner, they are left alone unless asked for
+	#[serde(default)]
+	pub delete_unreachable: bool,
+}
+
+#[derive(Serialize, Deserialize, PartialEq, Eq, PartialOrd, Ord, Debug, Clone)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum Issue {
+	// a blob no node refers to, past the grace period for pending uploads
+	OrphanedBlob { id: u64, size: u64 },
+	// a node whose parent is gone and that isn't the top of an owned tree, so nobody can reach it
+	DanglingParent { id: u64, parent_id: u64 },
+	// a child listed more than once under the same parent
+	DuplicateBranch { parent_id: u64, id: u64 },
+	// a child listed under a parent it's not in, or that's gone
+	StrayBranch { parent_id: u64, id: u64 },
+	// a node its parent doesn't list
+	MissingBranch { parent_id: u64, id: u64 },
+	// an upload was announced for the node, but nothing's on disk, eg an abandoned one the uploads
+	// job removed
+	MissingBlob { id: u64, expected: u64 },
+	// shorter than announced and not written to for a while
+	PartialBlob { id: u64, size: u64, expected: u64 },
+	// longer than announced
+	SizeMismatch { id: u64, size: u64, expected: u64 },
+}
+
+#[derive(Serialize, Deserialize, PartialEq, Debug)]
+pub struct Fsck {
+	pub issues: Vec<Issue>,
+	// missing blobs stay as they are, there's nothing to restore them from; partial ones too, their
+	// uploads may yet be resumed
+	pub repaired: bool,
+}
@@ -0,0 +1,359 @@
+use crate::{
+	admin, audit, clear_uploads_dir, disk_free, fsck, header,
+	jobs::handlers::find_orphans,
+	nodes::{LockedNode, Nodes},
+	now, remove_account, remove_file, Admin, ClientSubject, Error, PathParam, QueryParam, State,
+};
+use axum::{
+	extract::{self, Request},
+	http::StatusCode,
+	middleware::Next,
+	response::{IntoResponse, Response},
+	Json,
+};
+use std::collections::HashMap;
+use tokio::fs::OpenOptions;
+use tracing::{info, warn};
+
+pub async fn record_admin(state: &State, admin: String, subject: u64, action: audit::Action) {
+	state.audit.lock().await.record(audit::Entry {
+		at: now(),
+		actor: None,
+		admin: Some(admin),
+		subject,
+		action,
+		allowed: true,
+	});
+}
+
+// dev mode only; not even routed otherwise
+pub async fn purge_all(
+	extract::State(mut state): extract::State<State>,
+	Admin(admin): Admin,
+) -> Result<StatusCode, Error> {
+	warn!(admin, "purging");
+
+	state.purge().await;
+
+	clear_uploads_dir(&state.config.uploads_dir).await;
+
+	// after the purge, or it'd be wiped along with everything else
+	record_admin(&state, admin, audit::NO_SUBJECT, audit::Action::Purge).await;
+
+	Ok(StatusCode::OK)
+}
+
+pub async fn purge_user(
+	extract::State(state): extract::State<State>,
+	Admin(admin): Admin,
+	PathParam(user_id): PathParam<u64>,
+) -> Result<StatusCode, Error> {
+	if state.users.lock().await.pub_for_id(user_id).is_none() {
+		return Err(Error::NotFound(user_id));
+	}
+
+	warn!(admin, user_id, "purging user");
+
+	remove_account(&state, user_id, None).await;
+	record_admin(&state, admin, user_id, audit::Action::PurgeUser).await;
+
+	Ok(StatusCode::NO_CONTENT)
+}
+
+pub async fn purge_expired(
+	extract::State(state): extract::State<State>,
+	Admin(admin): Admin,
+) -> Result<StatusCode, Error> {
+	info!(admin, "purging expired data");
+
+	state.remove_expired(now()).await;
+	record_admin(
+		&state,
+		admin,
+		audit::NO_SUBJECT,
+		audit::Action::PurgeExpired,
+	)
+	.await;
+
+	Ok(StatusCode::NO_CONTENT)
+}
+
+// blobs in the uploads dir, read off the async runtime
+pub async fn list_blobs(state: &State) -> Result<Vec<admin::Blob>, Error> {
+	let dir = state.config.uploads_dir.clone();
+
+	Ok(tokio::task::spawn_blocking(move || admin::blobs(&dir)).await??)
+}
+
+fn admin_user(
+	nodes: &Nodes,
+	sizes: &HashMap<u64, u64>,
+	id: u64,
+	email: String,
+) -> admin::api::User {
+	let roots = nodes.owned_by(id);
+	let ids: Vec<u64> = roots.iter().flat_map(|root| nodes.subtree(*root)).collect();
+
+	admin::api::User {
+		id,
+		email,
+		roots,
+		nodes: ids.len(),
+		bytes: ids.iter().filter_map(|id| sizes.get(id)).sum(),
+	}
+}
+
+fn admin_node(nodes: &Nodes, sizes: &HashMap<u64, u64>, node: &LockedNode) -> admin::api::Node {
+	admin::api::Node {
+		id: node.id,
+		parent_id: node.parent_id,
+		owner: nodes.owner_of(node.id),
+		size: sizes.get(&node.id).cloned(),
+	}
+}
+
+async fn blob_sizes(state: &State) -> Result<HashMap<u64, u64>, Error> {
+	Ok(list_blobs(state)
+		.await?
+		.into_iter()
+		.map(|blob| (blob.id, blob.size))
+		.collect())
+}
+
+pub async fn get_admin_users(
+	extract::State(state): extract::State<State>,
+	Admin(_): Admin,
+) -> Result<Json<Vec<admin::api::User>>, Error> {
+	let sizes = blob_sizes(&state).await?;
+	let nodes = state.nodes.lock().await;
+	let users = state.users.lock().await;
+	let mut list: Vec<admin::api::User> = users
+		.credentials
+		.iter()
+		.map(|(email, id)| admin_user(&nodes, &sizes, *id, email.clone()))
+		.collect();
+
+	list.sort_by(|a, b| a.email.cmp(&b.email));
+
+	Ok(Json(list))
+}
+
+pub async fn get_admin_user(
+	extract::State(state): extract::State<State>,
+	Admin(_): Admin,
+	PathParam(user_id): PathParam<u64>,
+) -> Result<Json<admin::api::User>, Error> {
+	let sizes = blob_sizes(&state).await?;
+	let nodes = state.nodes.lock().await;
+	let email = state
+		.users
+		.lock()
+		.await
+		.email_for_id(user_id)
+		.cloned()
+		.ok_or(Error::NotFound(user_id))?;
+
+	Ok(Json(admin_user(&nodes, &sizes, user_id, email)))
+}
+
+pub async fn get_admin_shares(
+	extract::State(state): extract::State<State>,
+	Admin(_): Admin,
+	PathParam(user_id): PathParam<u64>,
+) -> Result<Json<Vec<admin::api::Share>>, Error> {
+	if state.users.lock().await.pub_for_id(user_id).is_none() {
+		return Err(Error::NotFound(user_id));
+	}
+
+	let groups = state.groups_of(user_id).await;
+	let shares = state
+		.shares
+		.lock()
+		.await
+		.all_shares_for_user(user_id, &groups)
+		.into_iter()
+		.map(|share| admin::api::Share {
+			sender: share.sender.id,
+			receiver: share.export.receiver,
+			role: share.export.role.as_str().to_string(),
+			nodes: share.export.fs,
+		})
+		.collect();
+
+	Ok(Json(shares))
+}
+
+pub async fn get_admin_nodes(
+	extract::State(state): extract::State<State>,
+	Admin(_): Admin,
+	QueryParam(filter): QueryParam<admin::api::NodeFilter>,
+) -> Result<Json<Vec<admin::api::Node>>, Error> {
+	let sizes = blob_sizes(&state).await?;
+	let nodes = state.nodes.lock().await;
+	let mut list: Vec<admin::api::Node> = nodes
+		.get_all()
+		.iter()
+		.map(|node| admin_node(&nodes, &sizes, node))
+		.filter(|node| filter.user.is_none() || node.owner == filter.user)
+		.collect();
+
+	list.sort_by_key(|node| node.id);
+
+	Ok(Json(list))
+}
+
+pub async fn get_admin_node(
+	extract::State(state): extract::State<State>,
+	Admin(_): Admin,
+	PathParam(node_id): PathParam<u64>,
+) -> Result<Json<admin::api::Node>, Error> {
+	let sizes = blob_sizes(&state).await?;
+	let nodes = state.nodes.lock().await;
+	let node = nodes.get(node_id).ok_or(Error::NotFound(node_id))?;
+
+	Ok(Json(admin_node(&nodes, &sizes, node)))
+}
+
+// removes blobs no node refers to; pending uploads get ORPHAN_GRACE to get their node
+pub async fn admin_gc(
+	extract::State(state): extract::State<State>,
+	Admin(admin): Admin,
+	QueryParam(gc): QueryParam<admin::api::Gc>,
+) -> Result<Json<admin::api::Orphans>, Error> {
+	let orphans = find_orphans(&state, now()).await?;
+
+	if !gc.dry_run {
+		warn!(admin, count = orphans.len(), "removing orphaned blobs");
+
+		for blob in &orphans {
+			remove_file(&state, blob.id).await;
+		}
+
+		record_admin(&state, admin, audit::NO_SUBJECT, audit::Action::Gc).await;
+	}
+
+	Ok(Json(admin::api::Orphans {
+		ids: orphans.iter().map(|blob| blob.id).collect(),
+		bytes: orphans.iter().map(|blob| blob.size).sum(),
+		removed: !gc.dry_run,
+	}))
+}
+
+// checks the node tree against the blobs on disk; with ?repair=true, fixes what it can
+pub async fn admin_fsck(
+	extract::State(state): extract::State<State>,
+	Admin(admin): Admin,
+	QueryParam(options): QueryParam<admin::api::FsckOptions>,
+) -> Result<Json<admin::api::Fsck>, Error> {
+	use admin::api::Issue;
+
+	let blobs = list_blobs(&state).await?;
+	// held until the tree is repaired, so nothing changes in between
+	let mut nodes = state.nodes.lock().await;
+	let issues = {
+		let expected = state.blobs.lock().await;
+
+		fsck::check(&nodes, &blobs, expected.expected(), now())
+	};
+
+	info!(
+		admin,
+		issues = issues.len(),
+		repair = options.repair,
+		delete_unreachable = options.delete_unreachable,
+		"fsck"
+	);
+
+	if options.repair && !issues.is_empty() {
+		let removed = fsck::repair_tree(&mut nodes, &issues, options.delete_unreachable);
+
+		drop(nodes);
+
+		for id in removed {
+			state.links.lock().await.remove_for_node(id);
+			state.file_requests.lock().await.remove_for_node(id);
+			remove_file(&state, id).await;
+		}
+
+		for issue in &issues {
+			match issue {
+				Issue::OrphanedBlob { id, .. } => remove_file(&state, *id).await,
+				Issue::SizeMismatch { id, expected, .. } => {
+					let file = OpenOptions::new()
+						.write(true)
+						.open(state.path_for_file_id(*id))
+						.await?;
+
+					file.set_len(*expected).await?;
+				}
+				_ => {}
+			}
+		}
+
+		record_admin(&state, admin, audit::NO_SUBJECT, audit::Action::Fsck).await;
+	}
+
+	Ok(Json(admin::api::Fsck {
+		issues,
+		repaired: options.repair,
+	}))
+}
+
+pub async fn get_admin_stats(
+	extract::State(state): extract::State<State>,
+	Admin(_): Admin,
+) -> Result<Json<admin::api::Stats>, Error> {
+	let blobs = list_blobs(&state).await?;
+
+	Ok(Json(admin::api::Stats {
+		users: state.users.lock().await.credentials.len(),
+		nodes: state.nodes.lock().await.count(),
+		shares: state.shares.lock().await.shares.len(),
+		sessions: state.sessions.lock().await.tokens.len(),
+		blobs: blobs.len(),
+		bytes: blobs.iter().map(|blob| blob.size).sum(),
+		disk_free: disk_free(&state).await.ok(),
+	}))
+}
+
+pub async fn get_admin_audit(
+	extract::State(state): extract::State<State>,
+	Admin(_): Admin,
+) -> Json<Vec<audit::Entry>> {
+	Json(state.audit.lock().await.by_admins())
+}
+
+// store sizes and disk space are sampled on every scrape
+pub async fn get_metrics(
+	extract::State(state): extract::State<State>,
+	Admin(_): Admin,
+) -> Response {
+	let metrics = &state.metrics;
+
+	metrics.set_items("nodes", state.nodes.lock().await.count());
+	metrics.set_items("users", state.users.lock().await.credentials.len());
+	metrics.set_items("shares", state.shares.lock().await.shares.len());
+	metrics.set_items("sessions", state.sessions.lock().await.tokens.len());
+
+	match disk_free(&state).await {
+		Ok(free) => metrics.disk_free.set(free as i64),
+		Err(err) => warn!(%err, "can't read free disk space"),
+	}
+
+	(
+		[(header::CONTENT_TYPE, "text/plain; version=0.0.4")],
+		metrics.render(),
+	)
+		.into_response()
+}
+
+// guards admin routes once client certificates are in use
+pub async fn require_client_cert(
+	ClientSubject(subject): ClientSubject,
+	request: Request,
+	next: Next,
+) -> Response {
+	info!(subject, "admin request");
+
+	next.run(request).await
+}
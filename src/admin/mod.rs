@@ -0,0 +1,139 @@
+pub mod api;
+pub mod handlers;
+
+use crate::config;
+use sha2::{Digest, Sha256};
+use std::{fs, io, path::Path, time::UNIX_EPOCH};
+
+pub use api::TOKEN_HEADER;
+// blobs with no node are pending uploads for this long, orphans after; seconds
+pub const ORPHAN_GRACE: u64 = 24 * 60 * 60;
+// names the token in logs and audit entries; the token itself is never written anywhere
+pub const TOKEN_PRINCIPAL: &str = "token";
+
+// hex sha256, the way admin.token_sha256 is configured
+pub fn token_hash(token: &str) -> String {
+	Sha256::digest(token.as_bytes())
+		.iter()
+		.map(|b| format!("{:02x}", b))
+		.collect()
+}
+
+// who's acting as admin, if anyone: a listed client certificate subject or the configured token
+pub fn principal(
+	config: &config::Admin,
+	subject: Option<&str>,
+	token: Option<&str>,
+) -> Option<String> {
+	if let Some(subject) = subject.filter(|s| config.subjects.iter().any(|a| a == s)) {
+		return Some(subject.to_string());
+	}
+
+	match (&config.token_sha256, token) {
+		(Some(hash), Some(token)) if hash.eq_ignore_ascii_case(&token_hash(token)) => {
+			Some(TOKEN_PRINCIPAL.to_string())
+		}
+		_ => None,
+	}
+}
+
+#[derive(PartialEq, Debug, Clone)]
+pub struct Blob {
+	pub id: u64,
+	pub size: u64,
+	// unix time, seconds
+	pub modified: u64,
+}
+
+// every blob in `dir`; anything not named by a node id is skipped
+pub fn blobs(dir: &Path) -> io::Result<Vec<Blob>> {
+	let mut blobs = Vec::new();
+
+	for entry in fs::read_dir(dir)? {
+		let entry = entry?;
+		let id = entry
+			.file_name()
+			.to_str()
+			.and_then(|name| name.parse().ok());
+		let metadata = entry.metadata()?;
+
+		if let Some(id) = id.filter(|_| metadata.is_file()) {
+			blobs.push(Blob {
+				id,
+				size: metadata.len(),
+				modified: metadata
+					.modified()?
+					.duration_since(UNIX_EPOCH)
+					.map(|d| d.as_secs())
+					.unwrap_or(0),
+			});
+		}
+	}
+
+	Ok(blobs)
+}
+
+// blobs with no node that have sat there for longer than ORPHAN_GRACE
+pub fn orphans(blobs: Vec<Blob>, has_node: impl Fn(u64) -> bool, now: u64) -> Vec<Blob> {
+	blobs
+		.into_iter()
+		.filter(|blob| !has_node(blob.id) && blob.modified + ORPHAN_GRACE < now)
+		.collect()
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use crate::tmp_dir::TmpDir;
+
+	#[test]
+	fn test_orphans() {
+		let dir = TmpDir::new("admin");
+
+		fs::create_dir_all(dir.join("4")).unwrap();
+		fs::write(dir.join("1"), [0; 10]).unwrap();
+		fs::write(dir.join("2"), [0; 20]).unwrap();
+		fs::write(dir.join(".ready"), []).unwrap();
+
+		let mut found = blobs(&dir).unwrap();
+
+		found.sort_by_key(|blob| blob.id);
+
+		assert_eq!(
+			found.iter().map(|b| (b.id, b.size)).collect::<Vec<_>>(),
+			vec![(1, 10), (2, 20)]
+		);
+
+		let now = found[0].modified;
+
+		// too fresh to tell from a pending upload
+		assert!(orphans(found.clone(), |id| id == 1, now).is_empty());
+
+		let later = orphans(found, |id| id == 1, now + ORPHAN_GRACE + 1);
+
+		assert_eq!(later.iter().map(|b| b.id).collect::<Vec<_>>(), vec![2]);
+	}
+
+	#[test]
+	fn test_principal() {
+		let config = config::Admin {
+			token_sha256: Some(token_hash("secret").to_uppercase()),
+			subjects: vec!["CN=ops".to_string()],
+		};
+
+		assert_eq!(
+			principal(&config, Some("CN=ops"), None),
+			Some("CN=ops".to_string())
+		);
+		assert_eq!(
+			principal(&config, Some("CN=backup"), Some("secret")),
+			Some(TOKEN_PRINCIPAL.to_string())
+		);
+		assert_eq!(principal(&config, Some("CN=backup"), None), None);
+		assert_eq!(principal(&config, None, Some("guess")), None);
+		assert_eq!(
+			principal(&config::Admin::default(), None, Some("secret")),
+			None
+		);
+	}
+}
@@ -0,0 +1,290 @@
+use crate::{
+	nodes::{LockedNode, Nodes},
+	purge::Purge,
+	users::LockedUser,
+};
+use rand::{rngs::OsRng, RngCore};
+use serde::{Deserialize, Serialize};
+use std::{
+	collections::{HashMap, HashSet},
+	fs::File,
+	io,
+	path::Path,
+};
+
+pub const VERSION: u32 = 1;
+// always the first entry of an archive, blobs follow as `uploads/<node id>`
+pub const MANIFEST: &str = "account.json";
+const BLOBS_DIR: &str = "uploads/";
+const BLOCK_SIZE: u64 = 512;
+
+#[derive(Debug)]
+pub enum Error {
+	Malformed,
+	Io(String),
+}
+
+impl From<io::Error> for Error {
+	fn from(err: io::Error) -> Self {
+		Error::Io(format!("{}", err))
+	}
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct Manifest {
+	pub version: u32,
+	pub email: String,
+	// roots hold every node visible to the user, shared ones included
+	pub user: LockedUser,
+	// top level nodes owned by the user; only these come back on import
+	pub owned: Vec<u64>,
+}
+
+#[derive(Serialize, Deserialize, PartialEq, Debug)]
+pub struct Imported {
+	pub user_id: u64,
+	// { old id, new id } for the nodes whose ids were taken already
+	pub renamed: HashMap<u64, u64>,
+}
+
+pub fn blob_path(id: u64) -> String {
+	format!("{}{}", BLOBS_DIR, id)
+}
+
+fn blob_id(path: &Path) -> Option<u64> {
+	path.to_str()?.strip_prefix(BLOBS_DIR)?.parse().ok()
+}
+
+// a ustar header for a regular file of `size` bytes
+pub fn entry_header(path: &str, size: u64, mtime: u64) -> Result<Vec<u8>, Error> {
+	let mut header = tar::Header::new_ustar();
+
+	header.set_path(path)?;
+	header.set_size(size);
+	header.set_mode(0o600);
+	header.set_mtime(mtime);
+	header.set_entry_type(tar::EntryType::Regular);
+	header.set_cksum();
+
+	Ok(header.as_bytes().to_vec())
+}
+
+// entries are padded to whole blocks
+pub fn padding(size: u64) -> Vec<u8> {
+	vec![0; ((BLOCK_SIZE - size % BLOCK_SIZE) % BLOCK_SIZE) as usize]
+}
+
+// two empty blocks end an archive
+pub fn end() -> Vec<u8> {
+	vec![0; 2 * BLOCK_SIZE as usize]
+}
+
+pub fn read_manifest(path: &Path) -> Result<Manifest, Error> {
+	let mut archive = tar::Archive::new(File::open(path)?);
+	let entry = archive.entries()?.next().ok_or(Error::Malformed)??;
+
+	if entry.path()?.to_str() != Some(MANIFEST) {
+		return Err(Error::Malformed);
+	}
+
+	let manifest: Manifest = serde_json::from_reader(entry).map_err(|_| Error::Malformed)?;
+
+	if manifest.version != VERSION {
+		return Err(Error::Malformed);
+	}
+
+	Ok(manifest)
+}
+
+// writes the blobs of `ids` ({ id in the archive, id to store it as }) into `dir`; anything else is skipped
+pub fn unpack_blobs(path: &Path, ids: &HashMap<u64, u64>, dir: &Path) -> Result<usize, Error> {
+	let mut archive = tar::Archive::new(File::open(path)?);
+	let mut count = 0;
+
+	for entry in archive.entries()? {
+		let mut entry = entry?;
+		let target = blob_id(&entry.path()?).and_then(|id| ids.get(&id));
+
+		if let Some(id) = target {
+			io::copy(&mut entry, &mut File::create(dir.join(id.to_string()))?)?;
+			count += 1;
+		}
+	}
+
+	Ok(count)
+}
+
+// the owned trees out of everything the manifest holds
+pub fn owned_nodes(manifest: &Manifest) -> Vec<LockedNode> {
+	let mut nodes = Nodes::new();
+
+	manifest
+		.user
+		.roots
+		.iter()
+		.for_each(|node| nodes.add(node.clone()));
+
+	let ids: HashSet<u64> = manifest
+		.owned
+		.iter()
+		.flat_map(|id| nodes.subtree(*id))
+		.collect();
+
+	manifest
+		.user
+		.roots
+		.iter()
+		.filter(|node| ids.contains(&node.id))
+		.cloned()
+		.collect()
+}
+
+// keeps ids where possible and picks fresh ones for those `taken`; returns { old id, new id } for all nodes
+pub fn rename(
+	nodes: Vec<LockedNode>,
+	taken: impl Fn(u64) -> bool,
+) -> (Vec<LockedNode>, HashMap<u64, u64>) {
+	let mut ids = HashMap::new();
+
+	for node in &nodes {
+		let mut id = node.id;
+
+		while taken(id) || ids.values().any(|new| *new == id) {
+			id = OsRng.next_u64();
+		}
+
+		ids.insert(node.id, id);
+	}
+
+	let nodes = nodes
+		.into_iter()
+		.map(|mut node| {
+			node.parent_id = ids.get(&node.parent_id).cloned().unwrap_or(node.parent_id);
+			node.id = ids[&node.id];
+			node
+		})
+		.collect();
+
+	(nodes, ids)
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use crate::{encrypted::Encrypted, lock::Lock, salt::Salt, tmp_dir::TmpDir};
+	use std::io::Write;
+
+	fn encrypted() -> Encrypted {
+		Encrypted {
+			ct: vec![1, 2, 3],
+			salt: Salt {
+				bytes: [0; Salt::SIZE],
+			},
+		}
+	}
+
+	fn node(id: u64, parent_id: u64) -> LockedNode {
+		LockedNode {
+			id,
+			parent_id,
+			content: encrypted(),
+			dirty: false,
+		}
+	}
+
+	fn manifest(roots: Vec<LockedNode>, owned: Vec<u64>) -> Manifest {
+		Manifest {
+			version: VERSION,
+			email: "a@mail.com".to_string(),
+			user: LockedUser {
+				encrypted_priv: Lock {
+					ct: vec![1, 2, 3],
+					master_key: encrypted(),
+				},
+				_pub: serde_json::from_value(serde_json::json!({
+					"id": 1,
+					"x448": base64::encode([0u8; 56]),
+					"ed448": base64::encode([0u8; 57]),
+				}))
+				.unwrap(),
+				shares: Vec::new(),
+				roots,
+				groups: Vec::new(),
+			},
+			owned,
+		}
+	}
+
+	fn entry(archive: &mut Vec<u8>, path: &str, data: &[u8]) {
+		archive.extend(entry_header(path, data.len() as u64, 0).unwrap());
+		archive.extend(data);
+		archive.extend(padding(data.len() as u64));
+	}
+
+	#[test]
+	fn test_round_trip() {
+		let dir = TmpDir::new("archive");
+		let path = dir.join("export.tar");
+		let mut archive = Vec::new();
+
+		entry(
+			&mut archive,
+			MANIFEST,
+			&serde_json::to_vec(&manifest(vec![node(2, 0)], vec![2])).unwrap(),
+		);
+		entry(&mut archive, &blob_path(2), &[7; 700]);
+		entry(&mut archive, &blob_path(3), &[8; 10]);
+		archive.extend(end());
+
+		File::create(&path).unwrap().write_all(&archive).unwrap();
+
+		let read = read_manifest(&path).unwrap();
+
+		assert_eq!(read.owned, vec![2]);
+		assert_eq!(read.user.roots, vec![node(2, 0)]);
+		assert_eq!(
+			unpack_blobs(&path, &HashMap::from([(2, 5)]), &dir).unwrap(),
+			1
+		);
+		assert_eq!(std::fs::read(dir.join("5")).unwrap(), vec![7; 700]);
+		assert!(!dir.join("3").exists());
+	}
+
+	#[test]
+	fn test_manifest_comes_first() {
+		let dir = TmpDir::new("archive");
+		let path = dir.join("export.tar");
+		let mut archive = Vec::new();
+
+		entry(&mut archive, &blob_path(2), &[7; 10]);
+		archive.extend(end());
+
+		File::create(&path).unwrap().write_all(&archive).unwrap();
+
+		assert!(matches!(read_manifest(&path), Err(Error::Malformed)));
+	}
+
+	#[test]
+	fn test_owned_nodes() {
+		let manifest = manifest(
+			vec![node(2, 0), node(3, 2), node(4, 0), node(5, 4)],
+			vec![2],
+		);
+		let mut ids: Vec<u64> = owned_nodes(&manifest).iter().map(|n| n.id).collect();
+
+		ids.sort();
+
+		assert_eq!(ids, vec![2, 3]);
+	}
+
+	#[test]
+	fn test_rename_keeps_free_ids() {
+		let (nodes, ids) = rename(vec![node(2, 0), node(3, 2), node(4, 3)], |id| id == 3);
+		let new = ids[&3];
+
+		assert_eq!(ids[&2], 2);
+		assert_eq!(ids[&4], 4);
+		assert_ne!(new, 3);
+		assert_eq!(nodes, vec![node(2, 0), node(new, 2), node(4, new)]);
+	}
+}
@@ -0,0 +1,179 @@
+use crate::purge::Purge;
+use serde::{Deserialize, Serialize};
+use std::collections::VecDeque;
+
+// older entries are dropped
+pub const MAX_ENTRIES: usize = 10_000;
+// denied attempts by nobody in particular are kept apart, so flooding them can't push out the rest
+pub const MAX_ANONYMOUS: usize = 1000;
+
+#[derive(Serialize, Deserialize, PartialEq, Debug, Clone)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum Action {
+	// the password-wrapped master key was handed out, or asked for
+	MasterKey,
+	// admin only from here on
+	// every store and blob wiped, dev mode only
+	Purge,
+	// the account and everything it owned removed
+	PurgeUser,
+	// expired sessions, links, recoveries and such dropped
+	PurgeExpired,
+	// blobs no node refers to removed
+	Gc,
+	// the node tree and blobs brought back in line
+	Fsck,
+	// a background job run by hand
+	RunJob { job: String },
+	// an exported account brought in
+	Import,
+}
+
+// the subject of entries not about any account in particular
+pub const NO_SUBJECT: u64 = 0;
+
+#[derive(Serialize, Deserialize, PartialEq, Debug, Clone)]
+pub struct Entry {
+	// unix time, seconds
+	pub at: u64,
+	// who did it, if known
+	pub actor: Option<u64>,
+	// set instead of actor when done through the admin routes; a cert subject or `token`
+	#[serde(default, skip_serializing_if = "Option::is_none")]
+	pub admin: Option<String>,
+	// whose account it was done to
+	pub subject: u64,
+	pub action: Action,
+	// false if denied or rate limited
+	pub allowed: bool,
+}
+
+pub struct Audit {
+	entries: VecDeque<Entry>,
+	// denied, without an actor; see MAX_ANONYMOUS
+	anonymous: VecDeque<Entry>,
+}
+
+impl Entry {
+	fn is_anonymous(&self) -> bool {
+		self.actor.is_none() && self.admin.is_none() && !self.allowed
+	}
+}
+
+impl Audit {
+	pub fn record(&mut self, entry: Entry) {
+		tracing::info!(
+			target: "audit",
+			action = ?entry.action,
+			subject = entry.subject,
+			actor = ?entry.actor,
+			admin = ?entry.admin,
+			allowed = entry.allowed,
+		);
+
+		let (entries, max) = if entry.is_anonymous() {
+			(&mut self.anonymous, MAX_ANONYMOUS)
+		} else {
+			(&mut self.entries, MAX_ENTRIES)
+		};
+
+		entries.push_back(entry);
+
+		if entries.len() > max {
+			entries.pop_front();
+		}
+	}
+
+	pub fn by_admins(&self) -> Vec<Entry> {
+		self.entries
+			.iter()
+			.filter(|entry| entry.admin.is_some())
+			.cloned()
+			.collect()
+	}
+
+	// both kinds, oldest first
+	pub fn for_subject(&self, subject: u64) -> Vec<Entry> {
+		let mut entries: Vec<Entry> = self
+			.entries
+			.iter()
+			.chain(&self.anonymous)
+			.filter(|entry| entry.subject == subject)
+			.cloned()
+			.collect();
+
+		entries.sort_by_key(|entry| entry.at);
+
+		entries
+	}
+}
+
+impl Purge for Audit {
+	fn new() -> Self {
+		Self {
+			entries: VecDeque::new(),
+			anonymous: VecDeque::new(),
+		}
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	fn entry(subject: u64) -> Entry {
+		Entry {
+			at: 0,
+			actor: Some(subject),
+			admin: None,
+			subject,
+			action: Action::MasterKey,
+			allowed: true,
+		}
+	}
+
+	fn anonymous(subject: u64, at: u64) -> Entry {
+		Entry {
+			at,
+			actor: None,
+			allowed: false,
+			..entry(subject)
+		}
+	}
+
+	#[test]
+	fn test_for_subject() {
+		let mut audit = Audit::new();
+
+		audit.record(entry(1));
+		audit.record(entry(2));
+		audit.record(entry(1));
+
+		assert_eq!(audit.for_subject(1).len(), 2);
+		assert!(audit.for_subject(3).is_empty());
+	}
+
+	#[test]
+	fn test_bounded() {
+		let mut audit = Audit::new();
+
+		(0..MAX_ENTRIES + 1).for_each(|i| audit.record(entry(i as u64)));
+
+		assert!(audit.for_subject(0).is_empty());
+		assert_eq!(audit.for_subject(1).len(), 1);
+	}
+
+	#[test]
+	fn test_anonymous_flood_keeps_the_rest() {
+		let mut audit = Audit::new();
+
+		audit.record(entry(1));
+		(0..MAX_ENTRIES).for_each(|at| audit.record(anonymous(1, at as u64 + 1)));
+
+		let entries = audit.for_subject(1);
+
+		assert_eq!(entries.len(), MAX_ANONYMOUS + 1);
+		assert_eq!(entries[0], entry(1));
+		assert_eq!(entries[MAX_ANONYMOUS].at, MAX_ENTRIES as u64);
+	}
+}
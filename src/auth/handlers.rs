@@ -0,0 +1,261 @@
+use super::Challenges;
+use crate::{
+	auth, check_pass, check_self, devices, logging, now,
+	users::{self, LockedUser, Login, TotpCode},
+	Caller, Error, JsonBody, PathParam, State, AUTH_HEADER,
+};
+use axum::{
+	extract,
+	http::{HeaderMap, StatusCode},
+	response::{IntoResponse, Response},
+	Json,
+};
+use tracing::info;
+
+// once a user has registered devices, a login has to come from one of them, proven by its key;
+// the token is bound to it, so revoking the device logs it out
+async fn issue_token(
+	state: &State,
+	user_id: u64,
+	proof: Option<devices::Proof>,
+) -> Result<String, Error> {
+	let device_id = match proof {
+		Some(proof) => {
+			let (device_id, challenge) = state
+				.device_challenges
+				.lock()
+				.await
+				.take(&proof.challenge, now())
+				.ok_or(Error::Unauthenticated)?;
+			let devices = state.devices.lock().await;
+			let device = devices
+				.get(user_id, device_id)
+				.ok_or(Error::Unauthenticated)?;
+
+			if !device.ed448.verify(&challenge.device_message(), &proof.sig) {
+				return Err(Error::Unauthenticated);
+			}
+
+			Some(device_id)
+		}
+		None if state.devices.lock().await.has_any(user_id) => {
+			return Err(Error::Unauthenticated);
+		}
+		None => None,
+	};
+
+	Ok(state
+		.tokens
+		.lock()
+		.await
+		.issue_for_device(user_id, device_id))
+}
+
+pub async fn login(
+	extract::State(state): extract::State<State>,
+	JsonBody(login): JsonBody<Login>,
+) -> Result<Response, Error> {
+	info!(email = %logging::email(&login.email), "logging in with a password");
+
+	let user_id = state
+		.users
+		.lock()
+		.await
+		.id_for_email(&login.email)
+		.ok_or(Error::Unauthenticated)?;
+
+	check_pass(&state, user_id, &login.pass)
+		.await
+		.ok_or(Error::Unauthenticated)?;
+
+	let has_totp = state.users.lock().await.has_totp(user_id);
+
+	// nothing is handed out until the second factor is in, see login_totp
+	if has_totp {
+		let token =
+			state
+				.second_steps
+				.lock()
+				.await
+				.issue(user_id, state.config.ttls.second_step, now());
+
+		info!(user_id, "second factor due");
+
+		return Ok((StatusCode::ACCEPTED, Json(auth::SecondStep { token })).into_response());
+	}
+
+	let user = state.user_by_id(user_id).await?;
+	let token = issue_token(&state, user_id, login.device).await?;
+
+	info!(user_id, "logged in");
+
+	Ok((StatusCode::OK, [(AUTH_HEADER, token)], Json(user)).into_response())
+}
+
+pub async fn login_totp(
+	extract::State(state): extract::State<State>,
+	JsonBody(answer): JsonBody<auth::SecondStepAnswer>,
+) -> Result<(StatusCode, [(&'static str, String); 1], Json<LockedUser>), Error> {
+	let user_id = state
+		.second_steps
+		.lock()
+		.await
+		.user_for(&answer.token, now())
+		.ok_or(Error::Unauthenticated)?;
+	let ok = {
+		let mut users = state.users.lock().await;
+
+		if users.is_totp_locked(user_id, now()) {
+			return Err(Error::TooManyAttempts);
+		}
+
+		users.check_second_factor(user_id, &answer.code, now())
+	};
+
+	if !ok {
+		state.second_steps.lock().await.fail(&answer.token);
+
+		return Err(Error::Unauthenticated);
+	}
+
+	state.second_steps.lock().await.remove(&answer.token);
+
+	let user = state.user_by_id(user_id).await?;
+	let token = issue_token(&state, user_id, answer.device).await?;
+
+	info!(user_id, "logged in with a second factor");
+
+	Ok((StatusCode::OK, [(AUTH_HEADER, token)], Json(user)))
+}
+
+// starts over any pending enrollment; enforced once confirmed with a first code
+pub async fn enroll_totp(
+	extract::State(state): extract::State<State>,
+	PathParam(user_id): PathParam<u64>,
+	headers: HeaderMap,
+) -> Result<(StatusCode, Json<users::TotpEnrollment>), Error> {
+	check_self(&state, &headers, user_id).await?;
+
+	let enrollment = state
+		.users
+		.lock()
+		.await
+		.enroll_totp(user_id)
+		.ok_or(Error::Conflict)?;
+
+	Ok((StatusCode::CREATED, Json(enrollment)))
+}
+
+pub async fn confirm_totp(
+	extract::State(state): extract::State<State>,
+	PathParam(user_id): PathParam<u64>,
+	headers: HeaderMap,
+	JsonBody(code): JsonBody<TotpCode>,
+) -> Result<StatusCode, Error> {
+	check_self(&state, &headers, user_id).await?;
+
+	if state
+		.users
+		.lock()
+		.await
+		.confirm_totp(user_id, &code.code, now())
+	{
+		info!(user_id, "totp enabled");
+
+		Ok(StatusCode::NO_CONTENT)
+	} else {
+		Err(Error::Unauthorised)
+	}
+}
+
+pub async fn disable_totp(
+	extract::State(state): extract::State<State>,
+	PathParam(user_id): PathParam<u64>,
+	headers: HeaderMap,
+	JsonBody(code): JsonBody<TotpCode>,
+) -> Result<StatusCode, Error> {
+	check_self(&state, &headers, user_id).await?;
+
+	if state
+		.users
+		.lock()
+		.await
+		.disable_totp(user_id, &code.code, now())
+	{
+		info!(user_id, "totp disabled");
+
+		Ok(StatusCode::NO_CONTENT)
+	} else {
+		Err(Error::Unauthorised)
+	}
+}
+
+// unknown users get a challenge too, it just can't be answered
+pub async fn login_challenge(
+	extract::State(state): extract::State<State>,
+	Caller(caller): Caller,
+	JsonBody(req): JsonBody<auth::ChallengeRequest>,
+) -> Result<(StatusCode, Json<auth::Challenge>), Error> {
+	info!(user_id = req.user_id, "login challenge");
+
+	let known = state.users.lock().await.pub_for_id(req.user_id).is_some();
+	let mut challenges = state.challenges.lock().await;
+	let challenge = if known {
+		challenges
+			.issue(req.user_id, caller, state.config.ttls.challenge, now())
+			.ok_or(Error::TooManyAttempts)?
+	} else {
+		Challenges::unanswerable(state.config.ttls.challenge, now())
+	};
+
+	Ok((StatusCode::CREATED, Json(challenge)))
+}
+
+// for a devices::Proof; like login challenges, unknown devices get one which can't be answered
+pub async fn device_challenge(
+	extract::State(state): extract::State<State>,
+	Caller(caller): Caller,
+	JsonBody(req): JsonBody<auth::DeviceChallengeRequest>,
+) -> Result<(StatusCode, Json<auth::Challenge>), Error> {
+	let known = state.devices.lock().await.exists(req.device_id);
+	let mut challenges = state.device_challenges.lock().await;
+	let challenge = if known {
+		challenges
+			.issue(req.device_id, caller, state.config.ttls.challenge, now())
+			.ok_or(Error::TooManyAttempts)?
+	} else {
+		Challenges::unanswerable(state.config.ttls.challenge, now())
+	};
+
+	Ok((StatusCode::CREATED, Json(challenge)))
+}
+
+pub async fn login_verify(
+	extract::State(state): extract::State<State>,
+	JsonBody(answer): JsonBody<auth::Answer>,
+) -> Result<(StatusCode, [(&'static str, String); 1], Json<LockedUser>), Error> {
+	let (user_id, challenge) = state
+		.challenges
+		.lock()
+		.await
+		.take(&answer.id, now())
+		.ok_or(Error::Unauthenticated)?;
+	let ed448 = state
+		.users
+		.lock()
+		.await
+		.pub_for_id(user_id)
+		.map(|_pub| _pub.ed448.clone())
+		.ok_or(Error::Unauthenticated)?;
+
+	if !ed448.verify(&challenge.message(), &answer.sig) {
+		return Err(Error::Unauthenticated);
+	}
+
+	let user = state.user_by_id(user_id).await?;
+	let token = issue_token(&state, user_id, answer.device).await?;
+
+	info!(user_id, "logged in by signature");
+
+	Ok((StatusCode::OK, [(AUTH_HEADER, token)], Json(user)))
+}
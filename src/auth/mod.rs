@@ -0,0 +1,391 @@
+pub mod handlers;
+
+use crate::{devices, ed448, purge::Purge, token};
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+
+// how long a login challenge may be answered, seconds
+pub const CHALLENGE_TTL: u64 = 60;
+// prepended to the nonce before signing, so a login signature can't be lifted from anywhere else
+const CHALLENGE_CONTEXT: &[u8] = b"uploader login:";
+// same for the device key, see devices::Proof
+const DEVICE_CHALLENGE_CONTEXT: &[u8] = b"uploader device login:";
+// unanswered challenges kept at once, for one requester (see Caller) and for everyone
+pub const MAX_CHALLENGES_PER_REQUESTER: usize = 20;
+pub const MAX_CHALLENGES: usize = 10_000;
+
+struct Grant {
+	user_id: u64,
+	// set once the token is bound to a registered device
+	device_id: Option<u64>,
+}
+
+// access tokens handed out on signup/login and sent back via the x-uploader-auth header
+pub struct Tokens {
+	// { token, grant }
+	tokens: HashMap<String, Grant>,
+}
+
+impl Tokens {
+	pub fn issue(&mut self, user_id: u64) -> String {
+		self.issue_for_device(user_id, None)
+	}
+
+	pub fn issue_for_device(&mut self, user_id: u64, device_id: Option<u64>) -> String {
+		let token = token::generate();
+
+		self.tokens
+			.insert(token.clone(), Grant { user_id, device_id });
+
+		token
+	}
+
+	pub fn user_for(&self, token: &str) -> Option<u64> {
+		self.tokens.get(token).map(|grant| grant.user_id)
+	}
+
+	#[cfg(test)]
+	pub fn device_for(&self, token: &str) -> Option<u64> {
+		self.tokens.get(token).and_then(|grant| grant.device_id)
+	}
+
+	// once a user has devices, only tokens bound to one are handed out; `keep` is the caller's
+	pub fn revoke_unbound(&mut self, user_id: u64, keep: &str) {
+		self.tokens.retain(|token, grant| {
+			grant.user_id != user_id || grant.device_id.is_some() || token == keep
+		});
+	}
+
+	pub fn revoke_all_for(&mut self, user_id: u64) {
+		self.tokens.retain(|_, grant| grant.user_id != user_id);
+	}
+
+	// everyone but the one holding `keep`
+	pub fn revoke_others(&mut self, user_id: u64, keep: &str) {
+		self.tokens
+			.retain(|token, grant| grant.user_id != user_id || token == keep);
+	}
+
+	pub fn revoke_device(&mut self, device_id: u64) {
+		self.tokens
+			.retain(|_, grant| grant.device_id != Some(device_id));
+	}
+}
+
+impl Purge for Tokens {
+	fn new() -> Self {
+		Self {
+			tokens: HashMap::new(),
+		}
+	}
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct ChallengeRequest {
+	pub user_id: u64,
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct DeviceChallengeRequest {
+	pub device_id: u64,
+}
+
+#[derive(Serialize, Deserialize, PartialEq, Debug, Clone)]
+pub struct Challenge {
+	pub id: String,
+	// to be signed with the identity key, prefixed with the login context
+	pub nonce: String,
+	pub expires_at: u64,
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct Answer {
+	pub id: String,
+	pub sig: ed448::Signature,
+	// required once the user has registered devices
+	#[serde(default)]
+	pub device: Option<devices::Proof>,
+}
+
+impl Challenge {
+	pub fn message(&self) -> Vec<u8> {
+		[CHALLENGE_CONTEXT, self.nonce.as_bytes()].concat()
+	}
+
+	pub fn device_message(&self) -> Vec<u8> {
+		[DEVICE_CHALLENGE_CONTEXT, self.nonce.as_bytes()].concat()
+	}
+}
+
+// a login for devices already holding the decrypted identity: no password, just a signed nonce.
+// also used for device challenges, by device id
+pub struct Challenges {
+	// { challenge_id, (user or device id, requester, challenge) }
+	challenges: HashMap<String, (u64, u64, Challenge)>,
+}
+
+impl Challenges {
+	// None if too many are pending already, counted by whoever asks rather than whom it's for,
+	// so nobody can use up someone else's; `ttl` in seconds
+	pub fn issue(&mut self, user_id: u64, requester: u64, ttl: u64, now: u64) -> Option<Challenge> {
+		self.remove_expired(now);
+
+		let pending = self
+			.challenges
+			.values()
+			.filter(|(_, by, _)| *by == requester)
+			.count();
+
+		if pending >= MAX_CHALLENGES_PER_REQUESTER || self.challenges.len() >= MAX_CHALLENGES {
+			return None;
+		}
+
+		let challenge = Self::unanswerable(ttl, now);
+
+		self.challenges.insert(
+			challenge.id.clone(),
+			(user_id, requester, challenge.clone()),
+		);
+
+		Some(challenge)
+	}
+
+	// looks like any other, but is never stored: for unknown users, so as not to tell them apart
+	pub fn unanswerable(ttl: u64, now: u64) -> Challenge {
+		Challenge {
+			id: token::generate(),
+			nonce: token::generate(),
+			expires_at: now + ttl,
+		}
+	}
+
+	// a challenge can be answered once, right or wrong
+	pub fn take(&mut self, id: &str, now: u64) -> Option<(u64, Challenge)> {
+		self.challenges
+			.remove(id)
+			.filter(|(_, _, challenge)| now < challenge.expires_at)
+			.map(|(id, _, challenge)| (id, challenge))
+	}
+
+	pub fn remove_expired(&mut self, now: u64) {
+		self.challenges
+			.retain(|_, (_, _, challenge)| now < challenge.expires_at);
+	}
+}
+
+impl Purge for Challenges {
+	fn new() -> Self {
+		Self {
+			challenges: HashMap::new(),
+		}
+	}
+}
+
+// how long the second step of a login may take, seconds
+pub const SECOND_STEP_TTL: u64 = 5 * 60;
+// wrong codes after which the password has to be entered again
+pub const SECOND_STEP_ATTEMPTS: u32 = 5;
+
+// returned by a password login when a second factor is due
+#[derive(Serialize, Deserialize, PartialEq, Debug)]
+pub struct SecondStep {
+	pub token: String,
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct SecondStepAnswer {
+	pub token: String,
+	pub code: String,
+	// required once the user has registered devices
+	#[serde(default)]
+	pub device: Option<devices::Proof>,
+}
+
+struct Pending {
+	user_id: u64,
+	expires_at: u64,
+	attempts: u32,
+}
+
+// logins which got the password right and still owe a second factor
+pub struct SecondSteps {
+	// { token, pending login }
+	pending: HashMap<String, Pending>,
+}
+
+impl SecondSteps {
+	pub fn issue(&mut self, user_id: u64, ttl: u64, now: u64) -> String {
+		let token = token::generate();
+
+		self.pending.insert(
+			token.clone(),
+			Pending {
+				user_id,
+				expires_at: now + ttl,
+				attempts: 0,
+			},
+		);
+
+		token
+	}
+
+	pub fn user_for(&self, token: &str, now: u64) -> Option<u64> {
+		self.pending
+			.get(token)
+			.filter(|pending| now < pending.expires_at)
+			.map(|pending| pending.user_id)
+	}
+
+	pub fn fail(&mut self, token: &str) {
+		if let Some(pending) = self.pending.get_mut(token) {
+			pending.attempts += 1;
+
+			if pending.attempts >= SECOND_STEP_ATTEMPTS {
+				self.pending.remove(token);
+			}
+		}
+	}
+
+	pub fn remove(&mut self, token: &str) {
+		self.pending.remove(token);
+	}
+
+	pub fn remove_expired(&mut self, now: u64) {
+		self.pending.retain(|_, pending| now < pending.expires_at);
+	}
+}
+
+impl Purge for SecondSteps {
+	fn new() -> Self {
+		Self {
+			pending: HashMap::new(),
+		}
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	#[test]
+	fn test_challenge_is_taken_once() {
+		let mut challenges = Challenges::new();
+		let challenge = challenges.issue(1, 7, CHALLENGE_TTL, 0).unwrap();
+
+		assert_eq!(
+			challenges.take(&challenge.id, 1),
+			Some((1, challenge.clone()))
+		);
+		assert_eq!(challenges.take(&challenge.id, 1), None);
+	}
+
+	#[test]
+	fn test_expired_challenge() {
+		let mut challenges = Challenges::new();
+		let challenge = challenges.issue(1, 7, CHALLENGE_TTL, 0).unwrap();
+
+		assert_eq!(challenges.take(&challenge.id, CHALLENGE_TTL), None);
+	}
+
+	#[test]
+	fn test_challenges_per_requester_are_capped() {
+		let mut challenges = Challenges::new();
+
+		(0..MAX_CHALLENGES_PER_REQUESTER)
+			.for_each(|_| assert!(challenges.issue(1, 7, CHALLENGE_TTL, 0).is_some()));
+
+		assert_eq!(challenges.issue(1, 7, CHALLENGE_TTL, 0), None);
+		assert_eq!(challenges.issue(2, 7, CHALLENGE_TTL, 0), None);
+		// someone else can still ask for the same user
+		assert!(challenges.issue(1, 8, CHALLENGE_TTL, 0).is_some());
+		// expired ones make room
+		assert!(challenges
+			.issue(1, 7, CHALLENGE_TTL, CHALLENGE_TTL)
+			.is_some());
+	}
+
+	#[test]
+	fn test_challenges_are_capped() {
+		let mut challenges = Challenges::new();
+
+		(0..MAX_CHALLENGES as u64)
+			.for_each(|id| assert!(challenges.issue(1, id, CHALLENGE_TTL, 0).is_some()));
+
+		assert_eq!(challenges.issue(1, u64::MAX, CHALLENGE_TTL, 0), None);
+	}
+
+	#[test]
+	fn test_revoke_device() {
+		let mut tokens = Tokens::new();
+		let a = tokens.issue_for_device(1, Some(10));
+		let b = tokens.issue_for_device(1, Some(11));
+		let c = tokens.issue(1);
+
+		tokens.revoke_device(10);
+
+		assert_eq!(tokens.user_for(&a), None);
+		assert_eq!(tokens.device_for(&b), Some(11));
+		assert_eq!(tokens.user_for(&c), Some(1));
+	}
+
+	#[test]
+	fn test_revoke_others() {
+		let mut tokens = Tokens::new();
+		let a = tokens.issue(1);
+		let b = tokens.issue(1);
+		let c = tokens.issue(2);
+
+		tokens.revoke_others(1, &a);
+
+		assert_eq!(tokens.user_for(&a), Some(1));
+		assert_eq!(tokens.user_for(&b), None);
+		assert_eq!(tokens.user_for(&c), Some(2));
+	}
+
+	#[test]
+	fn test_revoke_unbound() {
+		let mut tokens = Tokens::new();
+		let a = tokens.issue(1);
+		let b = tokens.issue(1);
+		let c = tokens.issue_for_device(1, Some(10));
+		let d = tokens.issue(2);
+
+		tokens.revoke_unbound(1, &a);
+
+		assert_eq!(tokens.user_for(&a), Some(1));
+		assert_eq!(tokens.user_for(&b), None);
+		assert_eq!(tokens.user_for(&c), Some(1));
+		assert_eq!(tokens.user_for(&d), Some(2));
+	}
+
+	#[test]
+	fn test_second_step_attempts() {
+		let mut steps = SecondSteps::new();
+		let token = steps.issue(1, SECOND_STEP_TTL, 0);
+
+		(1..SECOND_STEP_ATTEMPTS).for_each(|_| steps.fail(&token));
+
+		assert_eq!(steps.user_for(&token, 1), Some(1));
+
+		steps.fail(&token);
+
+		assert_eq!(steps.user_for(&token, 1), None);
+	}
+
+	#[test]
+	fn test_second_step_expires() {
+		let mut steps = SecondSteps::new();
+		let token = steps.issue(1, SECOND_STEP_TTL, 0);
+
+		assert_eq!(steps.user_for(&token, SECOND_STEP_TTL), None);
+	}
+
+	#[test]
+	fn test_configured_ttl() {
+		let mut challenges = Challenges::new();
+		let challenge = challenges.issue(1, 7, 10, 0).unwrap();
+
+		assert_eq!(challenge.expires_at, 10);
+		assert!(challenges.take(&challenge.id, 10).is_none());
+	}
+}
@@ -4,6 +4,7 @@ use serde::de::{Error, Unexpected};
 use serde::{Deserializer, Serializer};
 
 // A wrapper for blob serialization as a base64 encoded string.
+#[allow(dead_code)]
 pub struct Base64BlobRef<'a>(&'a [u8]);
 impl<'a> From<&'a [u8]> for Base64BlobRef<'a> {
 	fn from(value: &'a [u8]) -> Self {
@@ -12,6 +13,7 @@ impl<'a> From<&'a [u8]> for Base64BlobRef<'a> {
 }
 
 struct Base64Visitor;
+#[allow(dead_code)]
 struct OptionalBase64Visitor;
 
 impl<'de> Visitor<'de> for Base64Visitor {
@@ -68,6 +70,7 @@ impl serde::Serialize for Base64BlobRef<'_> {
 	}
 }
 
+#[allow(dead_code)]
 pub fn serialize_vec_optional_base64<S: Serializer>(
 	blob: &Option<Vec<u8>>,
 	serializer: S,
@@ -80,6 +83,7 @@ pub fn serialize_vec_optional_base64<S: Serializer>(
 	}
 }
 
+#[allow(dead_code)]
 pub fn deserialize_vec_optional_base64<'de, D: Deserializer<'de>>(
 	deserializer: D,
 ) -> Result<Option<Vec<u8>>, D::Error> {
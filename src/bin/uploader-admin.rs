@@ -0,0 +1,401 @@
+// talks to the admin routes of a running server; every store lives in its memory, so there's no other way in
+#[path = "../admin/api.rs"]
+mod api;
+
+use clap::{Parser, Subcommand};
+use reqwest::{Certificate, Client, Identity, Method, RequestBuilder};
+use serde::{de::DeserializeOwned, Deserialize, Serialize};
+use std::{fs, path::PathBuf, process};
+
+#[derive(Parser)]
+#[command(version, about = "inspects and repairs an uploader server")]
+struct Args {
+	/// eg https://uploader.example.com
+	#[arg(
+		long,
+		env = "UPLOADER_ADMIN_URL",
+		default_value = "http://localhost:3000"
+	)]
+	url: String,
+	/// the token admin.token_sha256 is the hash of
+	#[arg(long, env = "UPLOADER_ADMIN_TOKEN")]
+	token: Option<String>,
+	/// ca to trust for the server's cert, if not a public one
+	#[arg(long, env = "UPLOADER_ADMIN_CA")]
+	ca: Option<PathBuf>,
+	/// client cert, for servers with tls.client_ca set
+	#[arg(long, env = "UPLOADER_ADMIN_CERT", requires = "key")]
+	cert: Option<PathBuf>,
+	#[arg(long, env = "UPLOADER_ADMIN_KEY", requires = "cert")]
+	key: Option<PathBuf>,
+	/// print responses as json
+	#[arg(long)]
+	json: bool,
+	#[command(subcommand)]
+	command: Command,
+}
+
+#[derive(Subcommand)]
+enum Command {
+	/// every user with what they own
+	Users,
+	/// one user with what they own
+	User { id: u64 },
+	/// shares sent or received by a user
+	Shares { user_id: u64 },
+	/// every node, or those owned by --user
+	Nodes {
+		#[arg(long)]
+		user: Option<u64>,
+	},
+	/// one node and who owns it
+	Node { id: u64 },
+	/// removes a user and everything they own
+	DeleteUser { id: u64 },
+	/// removes blobs no node refers to
+	Gc {
+		/// list them, but leave them be
+		#[arg(long)]
+		dry_run: bool,
+	},
+	/// checks nodes against the blobs on disk
+	Fsck {
+		/// fix what can be fixed
+		#[arg(long)]
+		repair: bool,
+		/// with --repair, also remove nodes nobody can reach, along with their blobs
+		#[arg(long, requires = "repair")]
+		delete_unreachable: bool,
+	},
+	/// drops expired sessions, links, recoveries and such
+	Expire,
+	/// background jobs and when they last ran
+	Jobs,
+	/// runs a background job now: expire, invites, uploads or gc
+	RunJob { job: String },
+	/// store sizes and disk usage
+	Stats,
+	/// what admins have done so far
+	Audit,
+}
+
+// mirrors the server's error bodies
+#[derive(Deserialize)]
+struct ErrorBody {
+	code: String,
+	message: String,
+}
+
+struct Api {
+	client: Client,
+	url: String,
+	token: Option<String>,
+}
+
+impl Api {
+	fn new(args: &Args) -> Result<Self, String> {
+		let mut builder = Client::builder().use_rustls_tls();
+
+		if let Some(ca) = &args.ca {
+			let pem = fs::read(ca).map_err(|err| format!("{}: {}", ca.display(), err))?;
+
+			builder = builder
+				.add_root_certificate(Certificate::from_pem(&pem).map_err(|err| err.to_string())?);
+		}
+
+		if let (Some(cert), Some(key)) = (&args.cert, &args.key) {
+			let mut pem = fs::read(cert).map_err(|err| format!("{}: {}", cert.display(), err))?;
+
+			pem.extend(fs::read(key).map_err(|err| format!("{}: {}", key.display(), err))?);
+			builder = builder.identity(Identity::from_pem(&pem).map_err(|err| err.to_string())?);
+		}
+
+		Ok(Self {
+			client: builder.build().map_err(|err| err.to_string())?,
+			url: args.url.trim_end_matches('/').to_string(),
+			token: args.token.clone(),
+		})
+	}
+
+	fn request(&self, method: Method, path: &str) -> RequestBuilder {
+		let request = self.client.request(method, format!("{}{}", self.url, path));
+
+		match &self.token {
+			Some(token) => request.header(api::TOKEN_HEADER, token),
+			None => request,
+		}
+	}
+
+	async fn send(&self, request: RequestBuilder) -> Result<reqwest::Response, String> {
+		let response = request.send().await.map_err(|err| err.to_string())?;
+
+		if response.status().is_success() {
+			return Ok(response);
+		}
+
+		let status = response.status();
+
+		match response.json::<ErrorBody>().await {
+			Ok(body) => Err(format!("{}: {}", body.code, body.message)),
+			Err(_) => Err(status.to_string()),
+		}
+	}
+
+	async fn json<T: DeserializeOwned>(&self, request: RequestBuilder) -> Result<T, String> {
+		self.send(request)
+			.await?
+			.json()
+			.await
+			.map_err(|err| err.to_string())
+	}
+
+	async fn get<T: DeserializeOwned>(&self, path: &str) -> Result<T, String> {
+		self.json(self.request(Method::GET, path)).await
+	}
+
+	// for routes that answer with no body
+	async fn call(&self, method: Method, path: &str) -> Result<(), String> {
+		self.send(self.request(method, path)).await.map(|_| ())
+	}
+}
+
+// 1.5 GiB and such
+fn size(bytes: u64) -> String {
+	let units = ["B", "KiB", "MiB", "GiB", "TiB"];
+	let mut value = bytes as f64;
+	let mut unit = 0;
+
+	while value >= 1024.0 && unit < units.len() - 1 {
+		value /= 1024.0;
+		unit += 1;
+	}
+
+	if unit == 0 {
+		format!("{} B", bytes)
+	} else {
+		format!("{:.1} {}", value, units[unit])
+	}
+}
+
+fn print_users(users: &[api::User]) {
+	println!("{:<20} {:<32} {:>8} {:>12}", "id", "email", "nodes", "size");
+
+	for user in users {
+		println!(
+			"{:<20} {:<32} {:>8} {:>12}",
+			user.id,
+			user.email,
+			user.nodes,
+			size(user.bytes)
+		);
+	}
+}
+
+fn print_nodes(nodes: &[api::Node]) {
+	println!(
+		"{:<20} {:<20} {:<20} {:>12}",
+		"id", "parent", "owner", "size"
+	);
+
+	for node in nodes {
+		println!(
+			"{:<20} {:<20} {:<20} {:>12}",
+			node.id,
+			node.parent_id,
+			node.owner.map_or("-".to_string(), |id| id.to_string()),
+			node.size.map_or("-".to_string(), size)
+		);
+	}
+}
+
+fn print_shares(shares: &[api::Share]) {
+	println!("{:<20} {:<20} {:<8} nodes", "sender", "receiver", "role");
+
+	for share in shares {
+		let nodes: Vec<String> = share.nodes.iter().map(|id| id.to_string()).collect();
+
+		println!(
+			"{:<20} {:<20} {:<8} {}",
+			share.sender,
+			share.receiver,
+			share.role,
+			nodes.join(",")
+		);
+	}
+}
+
+fn print_orphans(orphans: &api::Orphans) {
+	for id in &orphans.ids {
+		println!("{}", id);
+	}
+
+	println!(
+		"{} orphaned blobs, {}{}",
+		orphans.ids.len(),
+		size(orphans.bytes),
+		if orphans.removed { ", removed" } else { "" }
+	);
+}
+
+fn print_fsck(fsck: &api::Fsck) {
+	for issue in &fsck.issues {
+		println!("{}", serde_json::to_string(issue).unwrap());
+	}
+
+	println!(
+		"{} issues{}",
+		fsck.issues.len(),
+		if fsck.repaired && !fsck.issues.is_empty() {
+			", repaired"
+		} else {
+			""
+		}
+	);
+}
+
+fn print_jobs(jobs: &[api::Job]) {
+	println!("{:<10} {:>10} {:>12}", "job", "every", "last run");
+
+	for job in jobs {
+		println!(
+			"{:<10} {:>10} {:>12}",
+			job.name,
+			if job.interval == 0 {
+				"-".to_string()
+			} else {
+				format!("{}s", job.interval)
+			},
+			job.last_run
+				.map_or("never".to_string(), |at| at.to_string())
+		);
+	}
+}
+
+fn print_stats(stats: &api::Stats) {
+	println!("users     {}", stats.users);
+	println!("nodes     {}", stats.nodes);
+	println!("shares    {}", stats.shares);
+	println!("sessions  {}", stats.sessions);
+	println!("blobs     {} ({})", stats.blobs, size(stats.bytes));
+	println!(
+		"disk free {}",
+		stats.disk_free.map_or("unknown".to_string(), size)
+	);
+}
+
+fn print<T: Serialize>(value: &T, json: bool, text: impl FnOnce(&T)) {
+	if json {
+		println!("{}", serde_json::to_string_pretty(value).unwrap());
+	} else {
+		text(value);
+	}
+}
+
+async fn run(args: Args) -> Result<(), String> {
+	let api = Api::new(&args)?;
+	let json = args.json;
+
+	match args.command {
+		Command::Users => print(
+			&api.get::<Vec<api::User>>("/admin/users").await?,
+			json,
+			|users| print_users(users),
+		),
+		Command::User { id } => print(
+			&api.get::<api::User>(&format!("/admin/users/{}", id))
+				.await?,
+			json,
+			|user| {
+				print_users(std::slice::from_ref(user));
+				println!("roots: {:?}", user.roots);
+			},
+		),
+		Command::Shares { user_id } => print(
+			&api.get::<Vec<api::Share>>(&format!("/admin/users/{}/shares", user_id))
+				.await?,
+			json,
+			|shares| print_shares(shares),
+		),
+		Command::Nodes { user } => {
+			let request = api
+				.request(Method::GET, "/admin/nodes")
+				.query(&api::NodeFilter { user });
+
+			print(&api.json::<Vec<api::Node>>(request).await?, json, |nodes| {
+				print_nodes(nodes)
+			})
+		}
+		Command::Node { id } => print(
+			&api.get::<api::Node>(&format!("/admin/nodes/{}", id))
+				.await?,
+			json,
+			|node| print_nodes(std::slice::from_ref(node)),
+		),
+		Command::DeleteUser { id } => {
+			api.call(Method::DELETE, &format!("/admin/users/{}", id))
+				.await?;
+			println!("deleted {}", id);
+		}
+		Command::Gc { dry_run } => {
+			let request = api
+				.request(Method::POST, "/admin/gc")
+				.query(&api::Gc { dry_run });
+
+			print(
+				&api.json::<api::Orphans>(request).await?,
+				json,
+				print_orphans,
+			)
+		}
+		Command::Fsck {
+			repair,
+			delete_unreachable,
+		} => {
+			let request = api
+				.request(Method::POST, "/admin/fsck")
+				.query(&api::FsckOptions {
+					repair,
+					delete_unreachable,
+				});
+
+			print(&api.json::<api::Fsck>(request).await?, json, print_fsck)
+		}
+		Command::Jobs => print(
+			&api.get::<Vec<api::Job>>("/admin/jobs").await?,
+			json,
+			|jobs| print_jobs(jobs),
+		),
+		Command::RunJob { job } => {
+			api.call(Method::POST, &format!("/admin/jobs/{}", job))
+				.await?;
+			println!("ran {}", job);
+		}
+		Command::Expire => {
+			api.call(Method::POST, "/admin/expired").await?;
+			println!("expired data dropped");
+		}
+		Command::Stats => print(
+			&api.get::<api::Stats>("/admin/stats").await?,
+			json,
+			print_stats,
+		),
+		// entries are printed as they come, whatever the server's version of them
+		Command::Audit => {
+			let entries = api.get::<Vec<serde_json::Value>>("/admin/audit").await?;
+
+			for entry in entries {
+				println!("{}", entry);
+			}
+		}
+	}
+
+	Ok(())
+}
+
+#[tokio::main]
+async fn main() {
+	if let Err(err) = run(Args::parse()).await {
+		eprintln!("error: {}", err);
+		process::exit(1);
+	}
+}
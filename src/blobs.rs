@@ -0,0 +1,44 @@
+use crate::purge::Purge;
+use std::collections::HashMap;
+
+// what uploads announce the full size of a blob to be, so short or overlong ones can be told apart,
+// and who started the ones that have no node yet
+pub struct Blobs {
+	// { node id, length from Content-Range }
+	expected: HashMap<u64, u64>,
+	// { node id, user id }
+	uploaders: HashMap<u64, u64>,
+}
+
+impl Blobs {
+	pub fn expect(&mut self, id: u64, length: u64) {
+		self.expected.insert(id, length);
+	}
+
+	pub fn expected(&self) -> &HashMap<u64, u64> {
+		&self.expected
+	}
+
+	// false if someone else started uploading `id` first
+	pub fn claim(&mut self, id: u64, user_id: u64) -> bool {
+		*self.uploaders.entry(id).or_insert(user_id) == user_id
+	}
+
+	pub fn uploader(&self, id: u64) -> Option<u64> {
+		self.uploaders.get(&id).copied()
+	}
+
+	pub fn remove(&mut self, id: u64) {
+		self.expected.remove(&id);
+		self.uploaders.remove(&id);
+	}
+}
+
+impl Purge for Blobs {
+	fn new() -> Self {
+		Self {
+			expected: HashMap::new(),
+			uploaders: HashMap::new(),
+		}
+	}
+}
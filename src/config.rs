@@ -0,0 +1,701 @@
+use crate::{logging, mailer};
+use axum::http::HeaderValue;
+use clap::{Parser, ValueEnum};
+use serde::Deserialize;
+use std::{
+	collections::BTreeMap,
+	fmt, fs,
+	net::SocketAddr,
+	path::{Path, PathBuf},
+};
+
+#[derive(Debug)]
+pub enum Error {
+	Read(PathBuf, String),
+	Parse(PathBuf, String),
+	Invalid(String),
+}
+
+impl fmt::Display for Error {
+	fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+		match self {
+			Error::Read(path, err) => write!(f, "can't read {}: {}", path.display(), err),
+			Error::Parse(path, err) => write!(f, "can't parse {}: {}", path.display(), err),
+			Error::Invalid(err) => write!(f, "{}", err),
+		}
+	}
+}
+
+// defaults, overridden by the config file, overridden in turn by env vars and flags
+#[derive(Parser, Debug, Default)]
+#[command(version, about = "end-to-end encrypted file storage")]
+pub struct Args {
+	/// toml config file
+	#[arg(short, long, env = "UPLOADER_CONFIG")]
+	pub config: Option<PathBuf>,
+	/// address to listen on, eg 0.0.0.0:3000
+	#[arg(long, env = "UPLOADER_LISTEN")]
+	pub listen: Option<SocketAddr>,
+	/// where blobs are stored
+	#[arg(long, env = "UPLOADER_UPLOADS_DIR")]
+	pub uploads_dir: Option<PathBuf>,
+	/// serve over https
+	#[arg(long, env = "USE_TLS", value_parser = parse_toggle)]
+	pub tls: Option<bool>,
+	#[arg(long, env = "UPLOADER_TLS_CERT")]
+	pub tls_cert: Option<PathBuf>,
+	#[arg(long, env = "UPLOADER_TLS_KEY")]
+	pub tls_key: Option<PathBuf>,
+	/// ca bundle client certificates are checked against; enables mutual tls
+	#[arg(long, env = "UPLOADER_TLS_CLIENT_CA")]
+	pub tls_client_ca: Option<PathBuf>,
+	/// origins allowed to call the api; any if none
+	#[arg(
+		long = "cors-origin",
+		env = "UPLOADER_CORS_ORIGINS",
+		value_delimiter = ','
+	)]
+	pub cors_origins: Option<Vec<String>>,
+	/// enables POST /purge, which wipes every account and blob
+	#[arg(long, env = "UPLOADER_DEV_MODE", value_parser = parse_toggle)]
+	pub dev_mode: Option<bool>,
+	/// hex sha256 of the admin token
+	#[arg(long, env = "UPLOADER_ADMIN_TOKEN_SHA256")]
+	pub admin_token_sha256: Option<String>,
+	/// the static x-uploader-auth value clients sent before logins, eg aabb1122; only while they move over
+	#[arg(long, env = "UPLOADER_LEGACY_TOKEN")]
+	pub legacy_token: Option<String>,
+	/// the web client invite links point to, eg https://app.example.com
+	#[arg(long, env = "INVITE_URL")]
+	pub invite_url: Option<String>,
+	/// bytes a single blob may take
+	#[arg(long, env = "UPLOADER_MAX_BLOB_SIZE")]
+	pub max_blob_size: Option<u64>,
+	/// eg info, or a full filter like warn,uploader::nodes=debug
+	#[arg(long, env = "UPLOADER_LOG_LEVEL")]
+	pub log_level: Option<String>,
+	#[arg(long, env = "UPLOADER_LOG_FORMAT")]
+	pub log_format: Option<LogFormat>,
+	/// eg uploader <noreply@example.com>
+	#[arg(long, env = "MAIL_FROM")]
+	pub mail_from: Option<String>,
+	/// where mail is written to when there's no smtp host
+	#[arg(long, env = "MAIL_DIR")]
+	pub mail_dir: Option<PathBuf>,
+	/// sends mail over smtp instead of writing it to the mail dir
+	#[arg(long, env = "SMTP_HOST")]
+	pub smtp_host: Option<String>,
+	#[arg(long, env = "SMTP_PORT", value_parser = parse_port)]
+	pub smtp_port: Option<u16>,
+	/// starttls, which credentials need
+	#[arg(long, env = "SMTP_TLS", value_parser = parse_toggle)]
+	pub smtp_tls: Option<bool>,
+	#[arg(long, env = "SMTP_USER")]
+	pub smtp_user: Option<String>,
+	#[arg(long, env = "SMTP_PASS", hide_env_values = true)]
+	pub smtp_pass: Option<String>,
+}
+
+// anything but true is off, the way USE_TLS has always been read
+fn parse_toggle(value: &str) -> Result<bool, String> {
+	Ok(value == "true")
+}
+
+// 0 for empty, which is then left unset
+fn parse_port(value: &str) -> Result<u16, String> {
+	if value.is_empty() {
+		Ok(0)
+	} else {
+		value
+			.parse()
+			.map_err(|err: std::num::ParseIntError| err.to_string())
+	}
+}
+
+#[derive(Deserialize, Debug, Clone, PartialEq)]
+#[serde(default, deny_unknown_fields)]
+pub struct Config {
+	pub listen: SocketAddr,
+	pub uploads_dir: PathBuf,
+	pub invite_url: String,
+	// seconds readiness fails after SIGTERM before new connections are refused, for load
+	// balancers to stop sending any
+	pub shutdown_delay: u64,
+	// seconds in-flight requests get to finish after that
+	pub shutdown_timeout: u64,
+	// never in production: routes POST /purge
+	pub dev_mode: bool,
+	// the one x-uploader-auth value every client used to send; while set, it still lets them
+	// up- and download any blob the way it always did, roles aside. off unless set
+	pub legacy_token: Option<String>,
+	pub admin: Admin,
+	pub tls: Tls,
+	pub cors: Cors,
+	pub limits: Limits,
+	pub ttls: Ttls,
+	pub features: Features,
+	pub logging: Logging,
+	pub jobs: Jobs,
+	pub mail: Mail,
+}
+
+#[derive(Deserialize, Debug, Clone, PartialEq)]
+#[serde(default, deny_unknown_fields)]
+pub struct Tls {
+	pub enabled: bool,
+	// both are read again on SIGHUP
+	pub cert: PathBuf,
+	pub key: PathBuf,
+	// client certificates are only asked for when set
+	pub client_ca: Option<PathBuf>,
+	pub client_auth: ClientAuth,
+}
+
+#[derive(Deserialize, Debug, Clone, Copy, PartialEq, Default)]
+#[serde(rename_all = "lowercase")]
+pub enum ClientAuth {
+	// anyone may connect, but admin routes need a client certificate
+	#[default]
+	Optional,
+	// no connection without one, eg for server-to-server traffic only
+	Required,
+}
+
+// who may use the admin routes; nobody if neither is set
+#[derive(Deserialize, Debug, Clone, PartialEq, Default)]
+#[serde(default, deny_unknown_fields)]
+pub struct Admin {
+	// hex sha256 of the token sent in x-uploader-admin
+	pub token_sha256: Option<String>,
+	// client certificate subjects, eg CN=ops; needs tls.client_ca
+	pub subjects: Vec<String>,
+}
+
+#[derive(Deserialize, Debug, Clone, PartialEq, Default)]
+#[serde(default, deny_unknown_fields)]
+pub struct Cors {
+	// eg https://app.example.com; any origin is allowed if empty
+	pub origins: Vec<String>,
+}
+
+// bytes
+#[derive(Deserialize, Debug, Clone, PartialEq)]
+#[serde(default, deny_unknown_fields)]
+pub struct Limits {
+	// any json payload
+	pub max_json_size: usize,
+	pub max_blob_size: u64,
+	// a whole account archive, blobs included
+	pub max_import_size: u64,
+}
+
+// seconds
+#[derive(Deserialize, Debug, Clone, PartialEq)]
+#[serde(default, deny_unknown_fields)]
+pub struct Ttls {
+	// lock sessions and pairing channels
+	pub session: u64,
+	pub recovery: u64,
+	// before a contact recovery may be completed
+	pub recovery_delay: u64,
+	pub challenge: u64,
+	pub second_step: u64,
+	// invites nobody signed up with
+	pub invite: u64,
+}
+
+// seconds between runs of each background job; 0 turns it off, it can still be run through the admin api
+#[derive(Deserialize, Debug, Clone, PartialEq)]
+#[serde(default, deny_unknown_fields)]
+pub struct Jobs {
+	pub expire: u64,
+	pub invites: u64,
+	pub uploads: u64,
+	pub gc: u64,
+}
+
+#[derive(Deserialize, Debug, Clone, Copy, PartialEq, Default, ValueEnum)]
+#[serde(rename_all = "lowercase")]
+pub enum LogFormat {
+	#[default]
+	Text,
+	Json,
+}
+
+#[derive(Deserialize, Debug, Clone, PartialEq)]
+#[serde(default, deny_unknown_fields)]
+pub struct Logging {
+	pub level: String,
+	pub format: LogFormat,
+	// { module, level }, eg nodes = "debug"; uploads and shares work the same way
+	pub modules: BTreeMap<String, String>,
+}
+
+// sent over smtp if smtp.host is set, otherwise written to dir
+#[derive(Deserialize, Debug, Clone, PartialEq)]
+#[serde(default, deny_unknown_fields)]
+pub struct Mail {
+	pub from: String,
+	pub dir: PathBuf,
+	pub smtp: Smtp,
+}
+
+#[derive(Deserialize, Debug, Clone, PartialEq)]
+#[serde(default, deny_unknown_fields)]
+pub struct Smtp {
+	pub host: Option<String>,
+	pub port: u16,
+	// starttls; without it, nothing but a local relay or a test sink should be used
+	pub tls: bool,
+	// both or neither, and only over tls
+	pub user: Option<String>,
+	pub pass: Option<String>,
+}
+
+// disabled features are not routed at all
+#[derive(Deserialize, Debug, Clone, PartialEq)]
+#[serde(default, deny_unknown_fields)]
+pub struct Features {
+	pub signup: bool,
+	pub pairing: bool,
+	pub links: bool,
+	pub file_requests: bool,
+	pub recovery: bool,
+	pub export: bool,
+	pub import: bool,
+	// prometheus scrapes at /metrics, as an admin like the other admin routes
+	pub metrics: bool,
+}
+
+impl Default for Config {
+	fn default() -> Self {
+		Self {
+			listen: SocketAddr::from(([0, 0, 0, 0], 3000)),
+			uploads_dir: PathBuf::from("uploads"),
+			invite_url: "http://localhost:3000".into(),
+			shutdown_delay: 5,
+			shutdown_timeout: 30,
+			dev_mode: false,
+			legacy_token: None,
+			admin: Admin::default(),
+			tls: Tls::default(),
+			cors: Cors::default(),
+			limits: Limits::default(),
+			ttls: Ttls::default(),
+			features: Features::default(),
+			logging: Logging::default(),
+			jobs: Jobs::default(),
+			mail: Mail::default(),
+		}
+	}
+}
+
+impl Default for Tls {
+	fn default() -> Self {
+		Self {
+			enabled: false,
+			cert: PathBuf::from("certs").join("cert.pem"),
+			key: PathBuf::from("certs").join("key.pem"),
+			client_ca: None,
+			client_auth: ClientAuth::default(),
+		}
+	}
+}
+
+impl Default for Limits {
+	fn default() -> Self {
+		Self {
+			max_json_size: 2 * 1024 * 1024,
+			max_blob_size: 10 * 1024 * 1024 * 1024,
+			max_import_size: 10 * 1024 * 1024 * 1024,
+		}
+	}
+}
+
+impl Default for Ttls {
+	fn default() -> Self {
+		Self {
+			session: crate::sessions::TTL,
+			recovery: crate::recovery::TTL,
+			recovery_delay: crate::recovery::DELAY,
+			challenge: crate::auth::CHALLENGE_TTL,
+			second_step: crate::auth::SECOND_STEP_TTL,
+			invite: 30 * 24 * 60 * 60,
+		}
+	}
+}
+
+impl Default for Jobs {
+	fn default() -> Self {
+		Self {
+			expire: 60,
+			invites: 60 * 60,
+			uploads: 60 * 60,
+			gc: 24 * 60 * 60,
+		}
+	}
+}
+
+impl Default for Logging {
+	fn default() -> Self {
+		Self {
+			level: "info".into(),
+			format: LogFormat::Text,
+			modules: BTreeMap::new(),
+		}
+	}
+}
+
+impl Default for Mail {
+	fn default() -> Self {
+		Self {
+			from: crate::mailer::DEFAULT_FROM.into(),
+			dir: PathBuf::from("mail"),
+			smtp: Smtp::default(),
+		}
+	}
+}
+
+impl Default for Smtp {
+	fn default() -> Self {
+		Self {
+			host: None,
+			port: 25,
+			tls: false,
+			user: None,
+			pass: None,
+		}
+	}
+}
+
+impl Default for Features {
+	fn default() -> Self {
+		Self {
+			signup: true,
+			pairing: true,
+			links: true,
+			file_requests: true,
+			recovery: true,
+			export: true,
+			import: true,
+			metrics: false,
+		}
+	}
+}
+
+impl Config {
+	pub fn from_toml(path: &Path) -> Result<Self, Error> {
+		let text = fs::read_to_string(path)
+			.map_err(|err| Error::Read(path.to_path_buf(), err.to_string()))?;
+
+		toml::from_str(&text).map_err(|err| Error::Parse(path.to_path_buf(), err.to_string()))
+	}
+
+	fn apply(&mut self, args: Args) {
+		if let Some(listen) = args.listen {
+			self.listen = listen;
+		}
+
+		if let Some(dir) = args.uploads_dir {
+			self.uploads_dir = dir;
+		}
+
+		if let Some(enabled) = args.tls {
+			self.tls.enabled = enabled;
+		}
+
+		if let Some(cert) = args.tls_cert {
+			self.tls.cert = cert;
+		}
+
+		if let Some(key) = args.tls_key {
+			self.tls.key = key;
+		}
+
+		if let Some(ca) = args.tls_client_ca {
+			self.tls.client_ca = Some(ca);
+		}
+
+		if let Some(origins) = args.cors_origins {
+			self.cors.origins = origins.into_iter().filter(|o| !o.is_empty()).collect();
+		}
+
+		if let Some(dev_mode) = args.dev_mode {
+			self.dev_mode = dev_mode;
+		}
+
+		if let Some(hash) = args.admin_token_sha256.filter(|hash| !hash.is_empty()) {
+			self.admin.token_sha256 = Some(hash);
+		}
+
+		if let Some(token) = args.legacy_token.filter(|token| !token.is_empty()) {
+			self.legacy_token = Some(token);
+		}
+
+		if let Some(url) = args.invite_url.filter(|url| !url.is_empty()) {
+			self.invite_url = url;
+		}
+
+		if let Some(size) = args.max_blob_size {
+			self.limits.max_blob_size = size;
+		}
+
+		if let Some(level) = args.log_level {
+			self.logging.level = level;
+		}
+
+		if let Some(format) = args.log_format {
+			self.logging.format = format;
+		}
+
+		if let Some(from) = args.mail_from.filter(|from| !from.is_empty()) {
+			self.mail.from = from;
+		}
+
+		if let Some(dir) = args.mail_dir.filter(|dir| !dir.as_os_str().is_empty()) {
+			self.mail.dir = dir;
+		}
+
+		if let Some(host) = args.smtp_host.filter(|host| !host.is_empty()) {
+			self.mail.smtp.host = Some(host);
+		}
+
+		if let Some(port) = args.smtp_port.filter(|port| *port != 0) {
+			self.mail.smtp.port = port;
+		}
+
+		if let Some(tls) = args.smtp_tls {
+			self.mail.smtp.tls = tls;
+		}
+
+		if let Some(user) = args.smtp_user.filter(|user| !user.is_empty()) {
+			self.mail.smtp.user = Some(user);
+		}
+
+		if let Some(pass) = args.smtp_pass.filter(|pass| !pass.is_empty()) {
+			self.mail.smtp.pass = Some(pass);
+		}
+	}
+
+	// everything that's wrong, not just the first thing
+	pub fn validate(&self) -> Result<(), Error> {
+		let mut errors = Vec::new();
+
+		if self.tls.enabled {
+			let paths = [
+				("cert", Some(&self.tls.cert)),
+				("key", Some(&self.tls.key)),
+				("client_ca", self.tls.client_ca.as_ref()),
+			];
+
+			for (name, path) in paths
+				.into_iter()
+				.filter_map(|(name, path)| Some((name, path?)))
+			{
+				if !path.is_file() {
+					errors.push(format!("tls.{}: {} is not a file", name, path.display()));
+				}
+			}
+		}
+
+		if self.uploads_dir.as_os_str().is_empty() {
+			errors.push("uploads_dir: must not be empty".to_string());
+		}
+
+		if !self.invite_url.starts_with("http://") && !self.invite_url.starts_with("https://") {
+			errors.push(format!(
+				"invite_url: {} is not an http(s) url",
+				self.invite_url
+			));
+		}
+
+		if let Some(hash) = &self.admin.token_sha256 {
+			if hash.len() != 64 || !hash.chars().all(|c| c.is_ascii_hexdigit()) {
+				errors.push("admin.token_sha256: must be 64 hex digits".to_string());
+			}
+		}
+
+		if self.legacy_token.as_deref() == Some("") {
+			errors.push("legacy_token: must not be empty".to_string());
+		}
+
+		if !self.admin.subjects.is_empty() && self.tls.client_ca.is_none() {
+			errors.push("admin.subjects: need tls.client_ca to be set".to_string());
+		}
+
+		for origin in &self.cors.origins {
+			if HeaderValue::from_str(origin).is_err() || !origin.contains("://") {
+				errors.push(format!("cors.origins: {} is not an origin", origin));
+			}
+		}
+
+		for (name, value) in [
+			("limits.max_json_size", self.limits.max_json_size as u64),
+			("limits.max_blob_size", self.limits.max_blob_size),
+			("limits.max_import_size", self.limits.max_import_size),
+			("ttls.session", self.ttls.session),
+			("ttls.recovery", self.ttls.recovery),
+			("ttls.recovery_delay", self.ttls.recovery_delay),
+			("ttls.challenge", self.ttls.challenge),
+			("ttls.second_step", self.ttls.second_step),
+			("ttls.invite", self.ttls.invite),
+		] {
+			if value == 0 {
+				errors.push(format!("{}: must be positive", name));
+			}
+		}
+
+		if let Err(err) = logging::filter(&self.logging) {
+			errors.push(format!("logging: {}", err));
+		}
+
+		errors.extend(self.mail.errors());
+
+		if errors.is_empty() {
+			Ok(())
+		} else {
+			Err(Error::Invalid(errors.join("\n")))
+		}
+	}
+}
+
+impl Mail {
+	fn errors(&self) -> Vec<String> {
+		let mut errors = Vec::new();
+		let smtp = &self.smtp;
+
+		if mailer::mailbox(&self.from).is_err() {
+			errors.push(format!("mail.from: {} is not an address", self.from));
+		}
+
+		match &smtp.host {
+			Some(host) if host.is_empty() => {
+				errors.push("mail.smtp.host: must not be empty".to_string())
+			}
+			Some(_) if smtp.port == 0 => {
+				errors.push("mail.smtp.port: must be positive".to_string())
+			}
+			Some(_) => {}
+			None if self.dir.as_os_str().is_empty() => {
+				errors.push("mail.dir: must not be empty without mail.smtp.host".to_string())
+			}
+			None => {}
+		}
+
+		if smtp.user.is_some() != smtp.pass.is_some() {
+			errors.push("mail.smtp: user and pass go together".to_string());
+		} else if smtp.user.is_some() && !smtp.tls {
+			errors.push("mail.smtp: credentials need tls".to_string());
+		}
+
+		errors
+	}
+}
+
+pub fn load(args: Args) -> Result<Config, Error> {
+	let mut config = match &args.config {
+		Some(path) => Config::from_toml(path)?,
+		None => Config::default(),
+	};
+
+	config.apply(args);
+	config.validate()?;
+
+	Ok(config)
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	#[test]
+	fn test_defaults_are_valid() {
+		assert_eq!(load(Args::default()).unwrap(), Config::default());
+	}
+
+	#[test]
+	fn test_file_then_flags() {
+		let mut config: Config = toml::from_str(
+			r#"
+			listen = "127.0.0.1:8080"
+
+			[limits]
+			max_blob_size = 1024
+
+			[features]
+			pairing = false
+			"#,
+		)
+		.unwrap();
+
+		config.apply(Args {
+			listen: Some(SocketAddr::from(([127, 0, 0, 1], 9090))),
+			..Default::default()
+		});
+
+		assert_eq!(config.listen, SocketAddr::from(([127, 0, 0, 1], 9090)));
+		assert_eq!(config.limits.max_blob_size, 1024);
+		assert_eq!(config.limits.max_json_size, Limits::default().max_json_size);
+		assert!(!config.features.pairing);
+		assert!(config.features.signup);
+	}
+
+	#[test]
+	fn test_unknown_keys_are_rejected() {
+		assert!(toml::from_str::<Config>("lisen = \"127.0.0.1:8080\"").is_err());
+	}
+
+	#[test]
+	fn test_invalid_values_are_all_reported() {
+		let mut config = Config::default();
+
+		config.tls.enabled = true;
+		config.tls.cert = PathBuf::from("nope.pem");
+		config.ttls.session = 0;
+		config.cors.origins = vec!["not an origin".into()];
+		config.admin.token_sha256 = Some("secret".into());
+
+		let err = config.validate().unwrap_err().to_string();
+
+		assert!(err.contains("tls.cert"));
+		assert!(err.contains("ttls.session"));
+		assert!(err.contains("cors.origins"));
+		assert!(err.contains("admin.token_sha256"));
+	}
+
+	#[test]
+	fn test_mail() {
+		let mut config: Config = toml::from_str(
+			r#"
+			[mail]
+			from = "uploader <noreply@example.com>"
+
+			[mail.smtp]
+			host = "smtp.example.com"
+			port = 587
+			tls = true
+			"#,
+		)
+		.unwrap();
+
+		config.apply(Args {
+			smtp_user: Some("uploader".into()),
+			smtp_pass: Some("secret".into()),
+			// as compose passes an unset one
+			smtp_port: Some(parse_port("").unwrap()),
+			..Default::default()
+		});
+
+		assert_eq!(config.mail.smtp.port, 587);
+		assert!(config.validate().is_ok());
+
+		config.mail.from = "nobody".into();
+		config.mail.smtp.tls = false;
+		config.mail.smtp.pass = None;
+
+		let err = config.validate().unwrap_err().to_string();
+
+		assert!(err.contains("mail.from"));
+		assert!(err.contains("user and pass"));
+	}
+}
@@ -0,0 +1,194 @@
+use crate::{
+	ed448::{PublicKeyEd448, Signature},
+	purge::Purge,
+	x448::PublicKeyX448,
+};
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+
+const DEVICE_CONTEXT: &[u8] = b"uploader device:";
+
+#[derive(PartialEq, Debug)]
+pub enum Error {
+	NotFound(u64),
+	// registered to someone else already
+	Taken,
+	// not signed by the device key
+	BadSig,
+}
+
+// may be registered by the device itself or, after pairing, by another device of the same user
+#[derive(Serialize, Deserialize)]
+pub struct NewDevice {
+	// eg "alice's laptop"
+	pub name: String,
+	// keys of the device itself, not to be confused with the identity of its user
+	pub x448: PublicKeyX448,
+	pub ed448: PublicKeyEd448,
+	// over message(), by ed448: whoever registers a key has to hold it
+	pub sig: Signature,
+}
+
+impl NewDevice {
+	pub fn message(user_id: u64, x448: &PublicKeyX448) -> Vec<u8> {
+		[DEVICE_CONTEXT, &user_id.to_be_bytes(), x448.as_bytes()].concat()
+	}
+}
+
+// sent along with a login by a registered device: a device challenge signed with its key
+#[derive(Serialize, Deserialize)]
+pub struct Proof {
+	pub challenge: String,
+	pub sig: Signature,
+}
+
+#[derive(Serialize, Deserialize, PartialEq, Debug, Clone)]
+pub struct Device {
+	pub id: u64,
+	pub user_id: u64,
+	pub name: String,
+	pub x448: PublicKeyX448,
+	pub ed448: PublicKeyEd448,
+	// unix time, seconds
+	pub added_at: u64,
+}
+
+pub struct Devices {
+	// { device_id, device }
+	devices: HashMap<u64, Device>,
+}
+
+impl Devices {
+	// the id is derived from the signing key, so registering the same device twice updates it instead
+	pub fn add(&mut self, user_id: u64, new: NewDevice, now: u64) -> Result<u64, Error> {
+		if !new
+			.ed448
+			.verify(&NewDevice::message(user_id, &new.x448), &new.sig)
+		{
+			return Err(Error::BadSig);
+		}
+
+		let id = new.ed448.id();
+
+		if self
+			.devices
+			.get(&id)
+			.is_some_and(|device| device.user_id != user_id)
+		{
+			return Err(Error::Taken);
+		}
+
+		self.devices.insert(
+			id,
+			Device {
+				id,
+				user_id,
+				name: new.name,
+				x448: new.x448,
+				ed448: new.ed448,
+				added_at: now,
+			},
+		);
+
+		Ok(id)
+	}
+
+	// someone else's devices are not found either
+	pub fn get(&self, user_id: u64, id: u64) -> Option<&Device> {
+		self.devices
+			.get(&id)
+			.filter(|device| device.user_id == user_id)
+	}
+
+	pub fn exists(&self, id: u64) -> bool {
+		self.devices.contains_key(&id)
+	}
+
+	pub fn has_any(&self, user_id: u64) -> bool {
+		self.devices
+			.values()
+			.any(|device| device.user_id == user_id)
+	}
+
+	pub fn all_for_user(&self, user_id: u64) -> Vec<Device> {
+		let mut devices: Vec<Device> = self
+			.devices
+			.values()
+			.filter(|device| device.user_id == user_id)
+			.cloned()
+			.collect();
+
+		devices.sort_by_key(|device| device.added_at);
+
+		devices
+	}
+
+	pub fn remove(&mut self, user_id: u64, id: u64) -> Result<Device, Error> {
+		self.get(user_id, id).ok_or(Error::NotFound(id))?;
+		self.devices.remove(&id).ok_or(Error::NotFound(id))
+	}
+
+	pub fn remove_all_for(&mut self, user_id: u64) {
+		self.devices.retain(|_, device| device.user_id != user_id);
+	}
+}
+
+impl Purge for Devices {
+	fn new() -> Self {
+		Self {
+			devices: HashMap::new(),
+		}
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	pub fn new_device(user_id: u64, b: u8) -> NewDevice {
+		let x448 = PublicKeyX448::new([b; 56]);
+		let (ed448, sig) = crate::ed448::sign(&[b; 57], &NewDevice::message(user_id, &x448));
+
+		NewDevice {
+			name: format!("device {}", b),
+			x448,
+			ed448,
+			sig,
+		}
+	}
+
+	#[test]
+	fn test_add_and_list() {
+		let mut devices = Devices::new();
+		let a = devices.add(1, new_device(1, 1), 10).unwrap();
+		let b = devices.add(1, new_device(1, 2), 5).unwrap();
+
+		devices.add(2, new_device(2, 3), 0).unwrap();
+
+		let ids: Vec<u64> = devices.all_for_user(1).iter().map(|d| d.id).collect();
+
+		assert_eq!(ids, vec![b, a]);
+	}
+
+	#[test]
+	fn test_device_of_another_user() {
+		let mut devices = Devices::new();
+		let id = devices.add(1, new_device(1, 1), 0).unwrap();
+
+		assert_eq!(devices.add(2, new_device(2, 1), 0), Err(Error::Taken));
+		assert!(devices.get(2, id).is_none());
+		assert_eq!(devices.remove(2, id).err(), Some(Error::NotFound(id)));
+		assert!(devices.remove(1, id).is_ok());
+		assert!(devices.all_for_user(1).is_empty());
+		assert!(!devices.has_any(1));
+	}
+
+	#[test]
+	fn test_key_must_be_held() {
+		let mut devices = Devices::new();
+
+		// signed for someone else
+		assert_eq!(devices.add(2, new_device(1, 1), 0), Err(Error::BadSig));
+		assert!(!devices.has_any(2));
+	}
+}
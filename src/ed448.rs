@@ -2,6 +2,7 @@ use crate::{
 	base64_blobs::{deserialize_array_base64, serialize_array_base64},
 	public_key::PublicKey,
 };
+use ed448_goldilocks_plus::{self as goldilocks, VerifyingKey};
 use serde::{Deserialize, Serialize};
 
 const SIG_SIZE: usize = 114;
@@ -24,3 +25,93 @@ pub struct KeyTypeEd448;
 
 const PUB_KEY_SIZE: usize = 57;
 pub type PublicKeyEd448 = PublicKey<KeyTypeEd448, { PUB_KEY_SIZE }>;
+
+impl PublicKeyEd448 {
+	// pure ed448 with an empty context, rfc 8032, 5.2.7
+	pub fn verify(&self, msg: &[u8], sig: &Signature) -> bool {
+		let (Ok(key), Ok(sig)) = (
+			VerifyingKey::from_bytes(self.as_bytes()),
+			goldilocks::Signature::from_bytes(&sig.bytes),
+		) else {
+			return false;
+		};
+
+		key.verify_raw(&sig, msg).is_ok()
+	}
+}
+
+// only the clients hold secret keys; tests play one
+#[cfg(test)]
+pub fn sign(secret: &[u8; PUB_KEY_SIZE], msg: &[u8]) -> (PublicKeyEd448, Signature) {
+	let key = goldilocks::SigningKey::try_from(secret.as_slice()).unwrap();
+	let public = PublicKeyEd448::new(key.verifying_key().to_bytes());
+
+	(
+		public,
+		Signature {
+			bytes: key.sign_raw(msg).to_bytes(),
+		},
+	)
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	fn public(hex: &str) -> PublicKeyEd448 {
+		PublicKeyEd448::new(hex_bytes(hex).try_into().unwrap())
+	}
+
+	fn sig(hex: &str) -> Signature {
+		Signature {
+			bytes: hex_bytes(hex).try_into().unwrap(),
+		}
+	}
+
+	fn hex_bytes(hex: &str) -> Vec<u8> {
+		(0..hex.len())
+			.step_by(2)
+			.map(|i| u8::from_str_radix(&hex[i..i + 2], 16).unwrap())
+			.collect()
+	}
+
+	// rfc 8032, 7.4, "blank"
+	const BLANK_PUB: &str = "5fd7449b59b461fd2ce787ec616ad46a1da1342485a70e1f8a0ea75d80e96778edf124769b46c7061bd6783df1e50f6cd1fa1abeafe8256180";
+	const BLANK_SIG: &str = "533a37f6bbe457251f023c0d88f976ae2dfb504a843e34d2074fd823d41a591f2b233f034f628281f2fd7a22ddd47d7828c59bd0a21bfd3980ff0d2028d4b18a9df63e006c5d1c2d345b925d8dc00b4104852db99ac5c7cdda8530a113a0f4dbb61149f05a7363268c71d95808ff2e652600";
+	// "1 octet"
+	const PUB: &str = "43ba28f430cdff456ae531545f7ecd0ac834a55d9358c0372bfa0c6c6798c0866aea01eb00742802b8438ea4cb82169c235160627b4c3a9480";
+	const SIG: &str = "26b8f91727bd62897af15e41eb43c377efb9c610d48f2335cb0bd0087810f4352541b143c4b981b7e18f62de8ccdf633fc1bf037ab7cd779805e0dbcc0aae1cbcee1afb2e027df36bc04dcecbf154336c19f0af7e0a6472905e799f1953d2a0ff3348ab21aa4adafd1d234441cf807c03a00";
+
+	#[test]
+	fn test_verify_rfc_vectors() {
+		assert!(public(BLANK_PUB).verify(b"", &sig(BLANK_SIG)));
+		assert!(public(PUB).verify(&[0x03], &sig(SIG)));
+	}
+
+	#[test]
+	fn test_reject_wrong_message() {
+		assert!(!public(PUB).verify(&[0x04], &sig(SIG)));
+	}
+
+	#[test]
+	fn test_reject_tampered_sig() {
+		let mut bad = sig(SIG);
+
+		bad.bytes[0] ^= 1;
+
+		assert!(!public(PUB).verify(&[0x03], &bad));
+	}
+
+	#[test]
+	fn test_sign() {
+		let (public, sig) = sign(&[7; PUB_KEY_SIZE], b"msg");
+
+		assert!(public.verify(b"msg", &sig));
+		assert!(!public.verify(b"other", &sig));
+	}
+
+	#[test]
+	fn test_reject_bad_key() {
+		assert!(!PublicKeyEd448::new([0xff; PUB_KEY_SIZE]).verify(&[0x03], &sig(SIG)));
+	}
+}
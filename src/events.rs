@@ -0,0 +1,159 @@
+use crate::purge::Purge;
+use serde::{Deserialize, Serialize};
+use std::collections::{HashMap, VecDeque};
+use tokio::sync::watch;
+
+// kept per user; older events are dropped, so a device that has been offline for long should resync
+pub const MAX_EVENTS: usize = 100;
+
+#[derive(Serialize, Deserialize, PartialEq, Debug, Clone)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum Event {
+	DeviceRemoved { device_id: u64 },
+	// the group key is to be rotated, see groups::Rotation
+	GroupMemberLeft { group_id: u64, user_id: u64 },
+	// to be cancelled unless it was the user, see recovery::DELAY
+	RecoveryStarted { request_id: String, contact_id: u64 },
+}
+
+#[derive(Serialize, Deserialize, PartialEq, Debug, Clone)]
+pub struct Entry {
+	// grows by one with every event of a user
+	pub seq: u64,
+	// unix time, seconds
+	pub at: u64,
+	pub event: Event,
+}
+
+#[derive(Deserialize)]
+pub struct Since {
+	// the last seq seen; everything newer is returned
+	pub after: Option<u64>,
+	// seconds to wait for anything new, if there's nothing yet
+	pub wait: Option<u64>,
+}
+
+struct Feed {
+	entries: VecDeque<Entry>,
+	// carries the last seq
+	seq: watch::Sender<u64>,
+}
+
+pub struct Events {
+	// { user_id, feed }
+	feeds: HashMap<u64, Feed>,
+}
+
+impl Events {
+	fn feed(&mut self, user_id: u64) -> &mut Feed {
+		self.feeds.entry(user_id).or_insert_with(|| Feed {
+			entries: VecDeque::new(),
+			seq: watch::channel(0).0,
+		})
+	}
+
+	pub fn push(&mut self, user_id: u64, event: Event, now: u64) -> u64 {
+		let feed = self.feed(user_id);
+		let seq = *feed.seq.borrow() + 1;
+
+		feed.entries.push_back(Entry {
+			seq,
+			at: now,
+			event,
+		});
+
+		if feed.entries.len() > MAX_EVENTS {
+			feed.entries.pop_front();
+		}
+
+		feed.seq.send_replace(seq);
+
+		seq
+	}
+
+	pub fn since(&self, user_id: u64, after: u64) -> Vec<Entry> {
+		self.feeds
+			.get(&user_id)
+			.map(|feed| {
+				feed.entries
+					.iter()
+					.filter(|entry| entry.seq > after)
+					.cloned()
+					.collect()
+			})
+			.unwrap_or_default()
+	}
+
+	// changes once anything is pushed for the user after this call
+	pub fn subscribe(&mut self, user_id: u64) -> watch::Receiver<u64> {
+		self.feed(user_id).seq.subscribe()
+	}
+
+	pub fn remove_all_for(&mut self, user_id: u64) {
+		self.feeds.remove(&user_id);
+	}
+}
+
+impl Purge for Events {
+	fn new() -> Self {
+		Self {
+			feeds: HashMap::new(),
+		}
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	fn removed(device_id: u64) -> Event {
+		Event::DeviceRemoved { device_id }
+	}
+
+	#[test]
+	fn test_since() {
+		let mut events = Events::new();
+
+		assert_eq!(events.push(1, removed(10), 0), 1);
+		assert_eq!(events.push(1, removed(11), 0), 2);
+		events.push(2, removed(12), 0);
+
+		let seqs: Vec<u64> = events.since(1, 1).iter().map(|e| e.seq).collect();
+
+		assert_eq!(seqs, vec![2]);
+		assert!(events.since(3, 0).is_empty());
+	}
+
+	#[test]
+	fn test_feed_is_bounded() {
+		let mut events = Events::new();
+
+		(0..MAX_EVENTS + 5).for_each(|i| {
+			events.push(1, removed(i as u64), 0);
+		});
+
+		let entries = events.since(1, 0);
+
+		assert_eq!(entries.len(), MAX_EVENTS);
+		assert_eq!(entries[0].seq, 6);
+	}
+
+	#[tokio::test]
+	async fn test_subscriber_sees_push() {
+		let mut events = Events::new();
+		let mut rx = events.subscribe(1);
+
+		events.push(1, removed(10), 0);
+
+		assert!(rx.changed().await.is_ok());
+		assert_eq!(*rx.borrow(), 1);
+	}
+
+	#[test]
+	fn test_serialized_as_tagged() {
+		assert_eq!(
+			serde_json::to_value(removed(7)).unwrap(),
+			serde_json::json!({ "type": "device_removed", "device_id": 7 })
+		);
+	}
+}
@@ -0,0 +1,86 @@
+use crate::{
+	check_auth, check_role, file_requests, now, shares::Role, Error, JsonBody, PathParam, State,
+};
+use axum::{
+	extract,
+	http::{HeaderMap, StatusCode},
+	Json,
+};
+use tracing::info;
+
+pub async fn create_file_request(
+	extract::State(state): extract::State<State>,
+	headers: HeaderMap,
+	JsonBody(new_request): JsonBody<file_requests::NewFileRequest>,
+) -> Result<(StatusCode, Json<file_requests::Created>), Error> {
+	let user_id = check_auth(&state, &headers).await?;
+	let parent_id = new_request.parent_id;
+
+	if state.nodes.lock().await.get(parent_id).is_none() {
+		return Err(Error::NotFound(parent_id));
+	}
+
+	check_role(&state, user_id, parent_id, Role::Editor).await?;
+
+	if new_request.expires_at <= now() {
+		return Err(Error::Gone);
+	}
+
+	let token = state.file_requests.lock().await.add(new_request, user_id);
+
+	info!(target: "shares", parent_id, "file request created");
+
+	Ok((StatusCode::CREATED, Json(file_requests::Created { token })))
+}
+
+pub async fn get_file_request(
+	extract::State(state): extract::State<State>,
+	PathParam(token): PathParam<String>,
+) -> Result<(StatusCode, Json<file_requests::Dropbox>), Error> {
+	let users = state.users.lock().await;
+	let requests = state.file_requests.lock().await;
+	let request = requests.get(&token, now())?;
+	let owner = users
+		.pub_for_id(request.owner_id)
+		.ok_or(Error::NotFound(request.owner_id))?;
+
+	info!(target: "shares", parent_id = request.parent_id, "opening file request");
+
+	Ok((
+		StatusCode::OK,
+		Json(file_requests::Dropbox {
+			owner: owner.clone(),
+			parent_id: request.parent_id,
+			expires_at: request.expires_at,
+			max_bytes: request.max_bytes,
+			received: request.received(),
+		}),
+	))
+}
+
+pub async fn delete_file_request(
+	extract::State(state): extract::State<State>,
+	PathParam(token): PathParam<String>,
+	headers: HeaderMap,
+) -> Result<StatusCode, Error> {
+	let user_id = check_auth(&state, &headers).await?;
+	let (owner_id, parent_id) = state
+		.file_requests
+		.lock()
+		.await
+		.find(&token)
+		.map(|request| (request.owner_id, request.parent_id))
+		.ok_or(Error::NoFileRequest)?;
+
+	if owner_id != user_id {
+		check_role(&state, user_id, parent_id, Role::Owner).await?;
+	}
+
+	state
+		.file_requests
+		.lock()
+		.await
+		.remove(&token)
+		.map(|_| StatusCode::NO_CONTENT)
+		.ok_or(Error::NoFileRequest)
+}
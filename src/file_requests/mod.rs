@@ -0,0 +1,310 @@
+pub mod handlers;
+
+use crate::{identity, nodes::LockedNode, purge::Purge, token};
+use serde::{Deserialize, Serialize};
+use std::collections::{HashMap, HashSet};
+
+#[derive(PartialEq, Debug)]
+pub enum Error {
+	NotFound,
+	Expired,
+	NotAllowed,
+	TooLarge,
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct NewFileRequest {
+	// the folder uploads end up in
+	pub parent_id: u64,
+	// unix time, seconds
+	pub expires_at: u64,
+	// total bytes all uploads together may take
+	pub max_bytes: u64,
+}
+
+#[derive(Serialize, Deserialize, PartialEq, Debug)]
+pub struct Created {
+	pub token: String,
+}
+
+// what an anonymous uploader gets to see: enough to encrypt to the owner, nothing of the folder
+#[derive(Serialize, Deserialize)]
+pub struct Dropbox {
+	pub owner: identity::Public,
+	pub parent_id: u64,
+	pub expires_at: u64,
+	pub max_bytes: u64,
+	pub received: u64,
+}
+
+pub struct FileRequest {
+	pub parent_id: u64,
+	pub owner_id: u64,
+	pub expires_at: u64,
+	pub max_bytes: u64,
+	// { file_id, bytes } for everything uploaded through this request
+	sizes: HashMap<u64, u64>,
+	// nodes and blobs introduced through this request; nothing else can be touched with its token
+	ids: HashSet<u64>,
+}
+
+impl FileRequest {
+	pub fn received(&self) -> u64 {
+		self.sizes.values().sum()
+	}
+}
+
+pub struct FileRequests {
+	// { token, request }
+	requests: HashMap<String, FileRequest>,
+}
+
+impl FileRequests {
+	pub fn add(&mut self, new: NewFileRequest, owner_id: u64) -> String {
+		let token = token::generate();
+
+		self.requests.insert(
+			token.clone(),
+			FileRequest {
+				parent_id: new.parent_id,
+				owner_id,
+				expires_at: new.expires_at,
+				max_bytes: new.max_bytes,
+				sizes: HashMap::new(),
+				ids: HashSet::new(),
+			},
+		);
+
+		token
+	}
+
+	// regardless of expiry
+	pub fn find(&self, token: &str) -> Option<&FileRequest> {
+		self.requests.get(token)
+	}
+
+	pub fn get(&self, token: &str, now: u64) -> Result<&FileRequest, Error> {
+		let request = self.requests.get(token).ok_or(Error::NotFound)?;
+
+		if now >= request.expires_at {
+			Err(Error::Expired)
+		} else {
+			Ok(request)
+		}
+	}
+
+	fn get_mut(&mut self, token: &str, now: u64) -> Result<&mut FileRequest, Error> {
+		self.get(token, now)?;
+
+		self.requests.get_mut(token).ok_or(Error::NotFound)
+	}
+
+	// `exists` tells whether a node with this id is already known to the server
+	pub fn claim_node(
+		&mut self,
+		token: &str,
+		node: &LockedNode,
+		exists: bool,
+		now: u64,
+	) -> Result<(), Error> {
+		let request = self.get_mut(token, now)?;
+		let parent_ok =
+			node.parent_id == request.parent_id || request.ids.contains(&node.parent_id);
+		let id_ok = request.ids.contains(&node.id) || !exists;
+
+		if parent_ok && id_ok {
+			request.ids.insert(node.id);
+
+			Ok(())
+		} else {
+			Err(Error::NotAllowed)
+		}
+	}
+
+	// `end` is exclusive; `exists` tells whether a node or a blob with this id is already there
+	pub fn claim_upload(
+		&mut self,
+		token: &str,
+		file_id: u64,
+		end: u64,
+		exists: bool,
+		now: u64,
+	) -> Result<(), Error> {
+		let request = self.get_mut(token, now)?;
+
+		if !request.ids.contains(&file_id) && exists {
+			return Err(Error::NotAllowed);
+		}
+
+		let current = request.sizes.get(&file_id).cloned().unwrap_or(0);
+		let size = current.max(end);
+
+		if request.received() - current + size > request.max_bytes {
+			return Err(Error::TooLarge);
+		}
+
+		request.ids.insert(file_id);
+		request.sizes.insert(file_id, size);
+
+		Ok(())
+	}
+
+	// once an upload is over, the blob counts for what actually made it to disk rather than what was claimed
+	pub fn settle(&mut self, token: &str, file_id: u64, size: u64) {
+		if let Some(request) = self.requests.get_mut(token) {
+			if request.sizes.contains_key(&file_id) {
+				request.sizes.insert(file_id, size);
+			}
+		}
+	}
+
+	pub fn remove(&mut self, token: &str) -> Option<FileRequest> {
+		self.requests.remove(token)
+	}
+
+	pub fn remove_for_node(&mut self, node_id: u64) {
+		self.requests
+			.retain(|_, request| request.parent_id != node_id);
+	}
+
+	pub fn remove_for_owner(&mut self, owner_id: u64) {
+		self.requests
+			.retain(|_, request| request.owner_id != owner_id);
+	}
+
+	pub fn remove_expired(&mut self, now: u64) {
+		self.requests.retain(|_, request| now < request.expires_at);
+	}
+}
+
+impl Purge for FileRequests {
+	fn new() -> Self {
+		Self {
+			requests: HashMap::new(),
+		}
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use crate::{encrypted::Encrypted, salt::Salt};
+
+	fn new_request() -> NewFileRequest {
+		NewFileRequest {
+			parent_id: 1,
+			expires_at: 100,
+			max_bytes: 10,
+		}
+	}
+
+	fn node(id: u64, parent_id: u64) -> LockedNode {
+		LockedNode {
+			id,
+			parent_id,
+			content: Encrypted {
+				ct: vec![],
+				salt: Salt::generate(),
+			},
+			dirty: false,
+		}
+	}
+
+	#[test]
+	fn test_expired_request() {
+		let mut requests = FileRequests::new();
+		let token = requests.add(new_request(), 0);
+
+		assert_eq!(requests.get(&token, 100).err(), Some(Error::Expired));
+		assert_eq!(
+			requests.claim_node(&token, &node(2, 1), false, 100),
+			Err(Error::Expired)
+		);
+	}
+
+	#[test]
+	fn test_claim_node_under_parent() {
+		let mut requests = FileRequests::new();
+		let token = requests.add(new_request(), 0);
+
+		assert_eq!(requests.claim_node(&token, &node(2, 1), false, 10), Ok(()));
+		// a sub folder created through the same request
+		assert_eq!(requests.claim_node(&token, &node(3, 2), false, 10), Ok(()));
+	}
+
+	#[test]
+	fn test_claim_node_elsewhere() {
+		let mut requests = FileRequests::new();
+		let token = requests.add(new_request(), 0);
+
+		assert_eq!(
+			requests.claim_node(&token, &node(2, 5), false, 10),
+			Err(Error::NotAllowed)
+		);
+	}
+
+	#[test]
+	fn test_claim_existing_node() {
+		let mut requests = FileRequests::new();
+		let token = requests.add(new_request(), 0);
+
+		assert_eq!(
+			requests.claim_node(&token, &node(2, 1), true, 10),
+			Err(Error::NotAllowed)
+		);
+	}
+
+	#[test]
+	fn test_claim_upload_of_foreign_blob() {
+		let mut requests = FileRequests::new();
+		let token = requests.add(new_request(), 0);
+
+		assert_eq!(
+			requests.claim_upload(&token, 9, 4, true, 10),
+			Err(Error::NotAllowed)
+		);
+	}
+
+	#[test]
+	fn test_claim_upload_of_own_node() {
+		let mut requests = FileRequests::new();
+		let token = requests.add(new_request(), 0);
+
+		requests.claim_node(&token, &node(2, 1), false, 10).unwrap();
+
+		assert_eq!(requests.claim_upload(&token, 2, 4, true, 10), Ok(()));
+	}
+
+	#[test]
+	fn test_claim_upload_respects_cap() {
+		let mut requests = FileRequests::new();
+		let token = requests.add(new_request(), 0);
+
+		assert_eq!(requests.claim_upload(&token, 2, 6, false, 10), Ok(()));
+		// retrying the same chunk doesn't count twice
+		assert_eq!(requests.claim_upload(&token, 2, 6, true, 10), Ok(()));
+		assert_eq!(requests.claim_upload(&token, 3, 4, false, 10), Ok(()));
+		assert_eq!(
+			requests.claim_upload(&token, 3, 5, true, 10),
+			Err(Error::TooLarge)
+		);
+		assert_eq!(requests.get(&token, 10).unwrap().received(), 10);
+	}
+
+	#[test]
+	fn test_failed_upload_frees_its_claim() {
+		let mut requests = FileRequests::new();
+		let token = requests.add(new_request(), 0);
+
+		requests.claim_upload(&token, 2, 8, false, 10).unwrap();
+		// only 3 bytes got through
+		requests.settle(&token, 2, 3);
+
+		assert_eq!(requests.get(&token, 10).unwrap().received(), 3);
+		assert_eq!(requests.claim_upload(&token, 3, 7, false, 10), Ok(()));
+		// nothing to settle for blobs never claimed
+		requests.settle(&token, 9, 5);
+
+		assert_eq!(requests.get(&token, 10).unwrap().received(), 10);
+	}
+}
@@ -0,0 +1,218 @@
+use crate::{
+	admin::{self, api::Issue, Blob},
+	nodes::Nodes,
+};
+use std::collections::{HashMap, HashSet};
+
+// everything that doesn't add up between the node tree, the blobs on disk and the sizes uploads announced
+pub fn check(nodes: &Nodes, blobs: &[Blob], expected: &HashMap<u64, u64>, now: u64) -> Vec<Issue> {
+	let mut issues = Vec::new();
+	let all = nodes.get_all();
+
+	for node in &all {
+		let listed = nodes
+			.branches()
+			.get(&node.parent_id)
+			.is_some_and(|children| children.contains(&node.id));
+
+		if !listed {
+			issues.push(Issue::MissingBranch {
+				parent_id: node.parent_id,
+				id: node.id,
+			});
+		}
+
+		if nodes.get(node.parent_id).is_none() && !nodes.is_root(node.id) {
+			issues.push(Issue::DanglingParent {
+				id: node.id,
+				parent_id: node.parent_id,
+			});
+		}
+	}
+
+	for (parent_id, children) in nodes.branches() {
+		let mut seen = HashSet::new();
+
+		for id in children {
+			if !seen.insert(*id) {
+				issues.push(Issue::DuplicateBranch {
+					parent_id: *parent_id,
+					id: *id,
+				});
+			} else if nodes
+				.get(*id)
+				.is_none_or(|node| node.parent_id != *parent_id)
+			{
+				issues.push(Issue::StrayBranch {
+					parent_id: *parent_id,
+					id: *id,
+				});
+			}
+		}
+	}
+
+	let orphans: HashSet<u64> = admin::orphans(blobs.to_vec(), |id| nodes.get(id).is_some(), now)
+		.into_iter()
+		.map(|blob| {
+			issues.push(Issue::OrphanedBlob {
+				id: blob.id,
+				size: blob.size,
+			});
+
+			blob.id
+		})
+		.collect();
+
+	for blob in blobs.iter().filter(|blob| !orphans.contains(&blob.id)) {
+		match expected.get(&blob.id) {
+			Some(&expected) if blob.size > expected => issues.push(Issue::SizeMismatch {
+				id: blob.id,
+				size: blob.size,
+				expected,
+			}),
+			// uploads still going are left alone
+			Some(&expected)
+				if blob.size < expected && blob.modified + admin::ORPHAN_GRACE < now =>
+			{
+				issues.push(Issue::PartialBlob {
+					id: blob.id,
+					size: blob.size,
+					expected,
+				})
+			}
+			_ => {}
+		}
+	}
+
+	let on_disk: HashSet<u64> = blobs.iter().map(|blob| blob.id).collect();
+
+	for (id, expected) in expected {
+		if nodes.get(*id).is_some() && !on_disk.contains(id) {
+			issues.push(Issue::MissingBlob {
+				id: *id,
+				expected: *expected,
+			});
+		}
+	}
+
+	issues.sort();
+	issues
+}
+
+// fixes the tree; unreachable nodes only go if `delete_unreachable`. returns the ids of the nodes
+// removed on the way, whose blobs are to go as well
+pub fn repair_tree(nodes: &mut Nodes, issues: &[Issue], delete_unreachable: bool) -> Vec<u64> {
+	let mut removed = Vec::new();
+
+	for issue in issues.iter().filter(|_| delete_unreachable) {
+		if let Issue::DanglingParent { id, .. } = issue {
+			removed.extend(nodes.subtree(*id));
+			nodes.remove(*id);
+		}
+	}
+
+	nodes.rebuild_branches();
+
+	removed
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use crate::{encrypted::Encrypted, nodes::LockedNode, purge::Purge, salt::Salt};
+
+	fn node(id: u64, parent_id: u64) -> LockedNode {
+		LockedNode {
+			id,
+			parent_id,
+			content: Encrypted {
+				ct: vec![],
+				salt: Salt::generate(),
+			},
+			dirty: false,
+		}
+	}
+
+	fn blob(id: u64, size: u64) -> Blob {
+		Blob {
+			id,
+			size,
+			modified: 0,
+		}
+	}
+
+	#[test]
+	fn test_consistent() {
+		let mut nodes = Nodes::new();
+
+		nodes.add(node(1, 100));
+		nodes.add(node(2, 1));
+		nodes.set_owner(1, 7);
+
+		let expected = HashMap::from([(2, 10)]);
+
+		assert!(check(&nodes, &[blob(2, 10)], &expected, admin::ORPHAN_GRACE * 2).is_empty());
+	}
+
+	#[test]
+	fn test_issues() {
+		let now = admin::ORPHAN_GRACE * 2;
+		let mut nodes = Nodes::new();
+
+		nodes.add(node(1, 100));
+		nodes.set_owner(1, 7);
+		nodes.add(node(2, 1));
+		nodes.add(node(3, 1));
+		nodes.add(node(4, 1));
+		nodes.add(node(5, 1));
+		// its parent was removed without it
+		nodes.add(node(6, 50));
+		// re-added under the same parent, and under another one
+		nodes.add(node(2, 1));
+		nodes.add(node(5, 2));
+
+		let blobs = [blob(3, 5), blob(4, 20), blob(9, 1)];
+		let expected = HashMap::from([(2, 10), (3, 10), (4, 10)]);
+		let issues = check(&nodes, &blobs, &expected, now);
+
+		assert_eq!(
+			issues,
+			vec![
+				Issue::OrphanedBlob { id: 9, size: 1 },
+				Issue::DanglingParent {
+					id: 6,
+					parent_id: 50
+				},
+				Issue::DuplicateBranch {
+					parent_id: 1,
+					id: 2
+				},
+				Issue::StrayBranch {
+					parent_id: 1,
+					id: 5
+				},
+				Issue::MissingBlob {
+					id: 2,
+					expected: 10
+				},
+				Issue::PartialBlob {
+					id: 3,
+					size: 5,
+					expected: 10
+				},
+				Issue::SizeMismatch {
+					id: 4,
+					size: 20,
+					expected: 10
+				},
+			]
+		);
+
+		assert!(repair_tree(&mut nodes, &issues, false).is_empty());
+		assert!(nodes.get(6).is_some());
+		assert_eq!(repair_tree(&mut nodes, &issues, true), vec![6]);
+		assert!(nodes.get(6).is_none());
+		assert_eq!(nodes.branches()[&1], vec![2, 3, 4]);
+		assert_eq!(nodes.branches()[&2], vec![5]);
+	}
+}
@@ -0,0 +1,330 @@
+use crate::{identity, purge::Purge};
+use serde::{Deserialize, Serialize};
+use std::collections::{HashMap, HashSet};
+
+#[derive(PartialEq, Debug)]
+pub enum Error {
+	NotFound(u64),
+	NotAllowed,
+	// a rotation not matching the group: another id, or not for exactly the current members
+	Mismatch,
+}
+
+// a principal shares can be exported to; its identity::Private is wrapped for each member separately,
+// so joining or leaving re-wraps a single key instead of re-sharing everything exported to the group
+#[derive(Serialize, Deserialize, PartialEq, Debug, Clone)]
+pub struct LockedGroup {
+	#[serde(rename = "pub")]
+	pub _pub: identity::Public,
+	// whoever created the group; the only one to manage members
+	pub owner: u64,
+	// { user_id, identity::Private of the group encrypted to the member's identity::Public }
+	pub members: HashMap<u64, identity::Encrypted>,
+	// keys replaced so far, latest last, for the shares sealed to them
+	#[serde(default)]
+	pub previous: Vec<PreviousKey>,
+	// a member has left holding the key; nothing can be shared with the group until it's rotated
+	#[serde(default)]
+	pub stale: bool,
+}
+
+#[derive(Serialize, Deserialize, PartialEq, Debug, Clone)]
+pub struct PreviousKey {
+	#[serde(rename = "pub")]
+	pub _pub: identity::Public,
+	// its identity::Private encrypted to the key that replaced it
+	pub key: identity::Encrypted,
+}
+
+// a fresh group key, so whoever left can't read what's shared from then on
+#[derive(Serialize, Deserialize)]
+pub struct Rotation {
+	// with the id of the group
+	#[serde(rename = "pub")]
+	pub _pub: identity::Public,
+	// { user_id, the new identity::Private encrypted to the member }, for every current member
+	pub members: HashMap<u64, identity::Encrypted>,
+	// the current identity::Private encrypted to the new key
+	pub previous: identity::Encrypted,
+}
+
+impl LockedGroup {
+	pub fn id(&self) -> u64 {
+		self._pub.id()
+	}
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct NewGroup {
+	#[serde(rename = "pub")]
+	pub _pub: identity::Public,
+	// the group key wrapped for its creator
+	pub key: identity::Encrypted,
+}
+
+pub struct Groups {
+	// { group_id, group }
+	groups: HashMap<u64, LockedGroup>,
+}
+
+impl Groups {
+	pub fn add(&mut self, new: NewGroup, owner: u64) -> u64 {
+		let id = new._pub.id();
+
+		self.groups.insert(
+			id,
+			LockedGroup {
+				_pub: new._pub,
+				owner,
+				members: HashMap::from([(owner, new.key)]),
+				previous: Vec::new(),
+				stale: false,
+			},
+		);
+
+		id
+	}
+
+	pub fn get(&self, id: u64) -> Option<&LockedGroup> {
+		self.groups.get(&id)
+	}
+
+	pub fn remove(&mut self, id: u64, by: u64) -> Result<LockedGroup, Error> {
+		let group = self.groups.get(&id).ok_or(Error::NotFound(id))?;
+
+		if group.owner != by {
+			return Err(Error::NotAllowed);
+		}
+
+		self.groups.remove(&id).ok_or(Error::NotFound(id))
+	}
+
+	// adds a member or re-wraps the key of an existing one
+	pub fn add_member(
+		&mut self,
+		id: u64,
+		user_id: u64,
+		key: identity::Encrypted,
+		by: u64,
+	) -> Result<(), Error> {
+		let group = self.groups.get_mut(&id).ok_or(Error::NotFound(id))?;
+
+		if group.owner != by {
+			return Err(Error::NotAllowed);
+		}
+
+		group.members.insert(user_id, key);
+
+		Ok(())
+	}
+
+	// owners manage everyone but themselves; anyone else may only leave
+	pub fn remove_member(&mut self, id: u64, user_id: u64, by: u64) -> Result<(), Error> {
+		let group = self.groups.get_mut(&id).ok_or(Error::NotFound(id))?;
+
+		if user_id == group.owner || (by != group.owner && by != user_id) {
+			return Err(Error::NotAllowed);
+		}
+
+		group
+			.members
+			.remove(&user_id)
+			.ok_or(Error::NotFound(user_id))?;
+		group.stale = true;
+
+		Ok(())
+	}
+
+	pub fn rotate(&mut self, id: u64, rotation: Rotation, by: u64) -> Result<(), Error> {
+		let group = self.groups.get_mut(&id).ok_or(Error::NotFound(id))?;
+
+		if group.owner != by {
+			return Err(Error::NotAllowed);
+		}
+
+		let members: HashSet<&u64> = group.members.keys().collect();
+
+		if rotation._pub.id() != id || rotation.members.keys().collect::<HashSet<_>>() != members {
+			return Err(Error::Mismatch);
+		}
+
+		let previous = std::mem::replace(&mut group._pub, rotation._pub);
+
+		group.previous.push(PreviousKey {
+			_pub: previous,
+			key: rotation.previous,
+		});
+		group.members = rotation.members;
+		group.stale = false;
+
+		Ok(())
+	}
+
+	// drops the user from every group, deleting the ones they own; returns the ids of those
+	pub fn remove_all_for(&mut self, user_id: u64) -> Vec<u64> {
+		let owned: Vec<u64> = self
+			.groups
+			.values()
+			.filter(|group| group.owner == user_id)
+			.map(|group| group.id())
+			.collect();
+
+		self.groups.retain(|_, group| group.owner != user_id);
+		self.groups.values_mut().for_each(|group| {
+			if group.members.remove(&user_id).is_some() {
+				group.stale = true;
+			}
+		});
+
+		owned
+	}
+
+	pub fn ids_for_member(&self, user_id: u64) -> Vec<u64> {
+		self.groups
+			.values()
+			.filter(|group| group.members.contains_key(&user_id))
+			.map(|group| group.id())
+			.collect()
+	}
+
+	pub fn all_groups_for_member(&self, user_id: u64) -> Vec<LockedGroup> {
+		self.groups
+			.values()
+			.filter(|group| group.members.contains_key(&user_id))
+			.cloned()
+			.collect()
+	}
+}
+
+impl Purge for Groups {
+	fn new() -> Self {
+		Self {
+			groups: HashMap::new(),
+		}
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	fn public(id: u64) -> identity::Public {
+		serde_json::from_value(serde_json::json!({
+			"id": id,
+			"x448": base64::encode([0u8; 56]),
+			"ed448": base64::encode([0u8; 57]),
+		}))
+		.unwrap()
+	}
+
+	fn key() -> identity::Encrypted {
+		serde_json::from_value(serde_json::json!({
+			"ct": "AQID",
+			"eph_x448": base64::encode([0u8; 56]),
+		}))
+		.unwrap()
+	}
+
+	fn group(groups: &mut Groups) -> u64 {
+		groups.add(
+			NewGroup {
+				_pub: public(100),
+				key: key(),
+			},
+			1,
+		)
+	}
+
+	#[test]
+	fn test_creator_is_a_member() {
+		let mut groups = Groups::new();
+		let id = group(&mut groups);
+
+		assert_eq!(id, 100);
+		assert_eq!(groups.ids_for_member(1), vec![100]);
+		assert!(groups.ids_for_member(2).is_empty());
+	}
+
+	#[test]
+	fn test_only_owner_adds_members() {
+		let mut groups = Groups::new();
+		let id = group(&mut groups);
+
+		assert_eq!(groups.add_member(id, 2, key(), 3), Err(Error::NotAllowed));
+		assert_eq!(groups.add_member(id, 2, key(), 1), Ok(()));
+		assert_eq!(groups.add_member(id, 3, key(), 2), Err(Error::NotAllowed));
+		assert_eq!(groups.ids_for_member(2), vec![100]);
+	}
+
+	#[test]
+	fn test_remove_member() {
+		let mut groups = Groups::new();
+		let id = group(&mut groups);
+
+		groups.add_member(id, 2, key(), 1).unwrap();
+		groups.add_member(id, 3, key(), 1).unwrap();
+
+		// members can't kick each other, but can leave
+		assert_eq!(groups.remove_member(id, 3, 2), Err(Error::NotAllowed));
+		assert_eq!(groups.remove_member(id, 2, 2), Ok(()));
+		assert_eq!(groups.remove_member(id, 3, 1), Ok(()));
+		assert_eq!(groups.remove_member(id, 3, 1), Err(Error::NotFound(3)));
+		// the owner can't leave their own group
+		assert_eq!(groups.remove_member(id, 1, 1), Err(Error::NotAllowed));
+	}
+
+	#[test]
+	fn test_removal_asks_for_rotation() {
+		let mut groups = Groups::new();
+		let id = group(&mut groups);
+		let rotation = |members: &[u64]| Rotation {
+			_pub: public(100),
+			members: members.iter().map(|id| (*id, key())).collect(),
+			previous: key(),
+		};
+
+		groups.add_member(id, 2, key(), 1).unwrap();
+		groups.add_member(id, 3, key(), 1).unwrap();
+		groups.remove_member(id, 3, 1).unwrap();
+
+		assert!(groups.get(id).unwrap().stale);
+		assert_eq!(
+			groups.rotate(id, rotation(&[1, 2]), 2),
+			Err(Error::NotAllowed)
+		);
+		// the one who left must not get the new key
+		assert_eq!(
+			groups.rotate(id, rotation(&[1, 2, 3]), 1),
+			Err(Error::Mismatch)
+		);
+		assert_eq!(groups.rotate(id, rotation(&[1, 2]), 1), Ok(()));
+
+		let group = groups.get(id).unwrap();
+
+		assert!(!group.stale);
+		assert_eq!(group.previous.len(), 1);
+	}
+
+	#[test]
+	fn test_remove_all_for() {
+		let mut groups = Groups::new();
+		let id = group(&mut groups);
+
+		groups.add_member(id, 2, key(), 1).unwrap();
+
+		assert!(groups.remove_all_for(2).is_empty());
+		assert!(groups.ids_for_member(2).is_empty());
+		assert_eq!(groups.remove_all_for(1), vec![id]);
+		assert!(groups.get(id).is_none());
+	}
+
+	#[test]
+	fn test_remove_group() {
+		let mut groups = Groups::new();
+		let id = group(&mut groups);
+
+		assert_eq!(groups.remove(id, 2).err(), Some(Error::NotAllowed));
+		assert!(groups.remove(id, 1).is_ok());
+		assert!(groups.get(id).is_none());
+	}
+}
@@ -0,0 +1,137 @@
+use super::Job;
+use crate::{
+	admin::{
+		self,
+		handlers::{list_blobs, record_admin},
+	},
+	audit, fsck, jobs, remove_file, Admin, Error, PathParam, State,
+};
+use axum::{extract, http::StatusCode, Json};
+use std::time::{Duration, Instant};
+use tracing::{error, info};
+
+pub async fn find_orphans(state: &State, now: u64) -> Result<Vec<admin::Blob>, Error> {
+	let blobs = list_blobs(state).await?;
+	let nodes = state.nodes.lock().await;
+
+	Ok(admin::orphans(blobs, |id| nodes.get(id).is_some(), now))
+}
+
+// runs `job` right away, whether it's due or not; returns how many items it removed
+pub async fn run_job(state: &State, job: Job) -> Result<usize, Error> {
+	let now = state.jobs.lock().await.now();
+	let start = Instant::now();
+	let removed = match job {
+		Job::Expire => {
+			state.remove_expired(now).await;
+
+			Ok(0)
+		}
+		Job::Invites => Ok(state
+			.shares
+			.lock()
+			.await
+			.remove_stale_invites(state.config.ttls.invite, now)),
+		Job::Uploads => remove_abandoned_uploads(state, now).await,
+		Job::Gc => {
+			let orphans = find_orphans(state, now).await?;
+
+			for blob in &orphans {
+				remove_file(state, blob.id).await;
+			}
+
+			Ok(orphans.len())
+		}
+	};
+
+	state.metrics.observe_job(
+		job.name(),
+		removed.is_ok(),
+		start.elapsed().as_secs_f64(),
+		now,
+	);
+	state.jobs.lock().await.ran(job, now);
+
+	match &removed {
+		Ok(count) => info!(job = job.name(), removed = count, "job done"),
+		Err(err) => error!(job = job.name(), ?err, "job failed"),
+	}
+
+	removed
+}
+
+// partial blobs nobody has written to for ORPHAN_GRACE, as fsck would report them; their nodes stay,
+// and so does the size announced, so fsck reports them as missing blobs from then on
+async fn remove_abandoned_uploads(state: &State, now: u64) -> Result<usize, Error> {
+	let blobs = list_blobs(state).await?;
+	let issues = {
+		let nodes = state.nodes.lock().await;
+		let expected = state.blobs.lock().await;
+
+		fsck::check(&nodes, &blobs, expected.expected(), now)
+	};
+	let mut count = 0;
+
+	for issue in issues {
+		if let admin::api::Issue::PartialBlob { id, .. } = issue {
+			_ = tokio::fs::remove_file(state.path_for_file_id(id)).await;
+			count += 1;
+		}
+	}
+
+	Ok(count)
+}
+
+// checks for due jobs every TICK seconds, for as long as the server runs
+pub async fn run_jobs(state: State) {
+	let mut tick = tokio::time::interval(Duration::from_secs(jobs::TICK));
+
+	loop {
+		tick.tick().await;
+
+		let due = state.jobs.lock().await.due();
+
+		for job in due {
+			_ = run_job(&state, job).await;
+		}
+	}
+}
+
+pub async fn get_admin_jobs(
+	extract::State(state): extract::State<State>,
+	Admin(_): Admin,
+) -> Json<Vec<admin::api::Job>> {
+	let schedule = state.jobs.lock().await;
+
+	Json(
+		Job::ALL
+			.into_iter()
+			.map(|job| admin::api::Job {
+				name: job.name().to_string(),
+				interval: schedule.interval(job),
+				last_run: schedule.last_run(job),
+			})
+			.collect(),
+	)
+}
+
+pub async fn trigger_job(
+	extract::State(state): extract::State<State>,
+	Admin(admin): Admin,
+	PathParam(job): PathParam<Job>,
+) -> Result<StatusCode, Error> {
+	info!(admin, job = job.name(), "running job");
+
+	run_job(&state, job).await?;
+	record_admin(
+		&state,
+		admin,
+		audit::NO_SUBJECT,
+		audit::Action::RunJob {
+			job: job.name().to_string(),
+		},
+	)
+	.await;
+
+	Ok(StatusCode::NO_CONTENT)
+}
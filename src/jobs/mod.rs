@@ -0,0 +1,164 @@
+pub mod handlers;
+
+use crate::config;
+use serde::{Deserialize, Serialize};
+use std::{collections::HashMap, sync::Arc};
+
+// how often the scheduler looks for due jobs, seconds
+pub const TICK: u64 = 1;
+
+pub trait Clock: Send + Sync {
+	// unix time, seconds
+	fn now(&self) -> u64;
+}
+
+pub struct SystemClock;
+
+impl Clock for SystemClock {
+	fn now(&self) -> u64 {
+		crate::now()
+	}
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq, Hash, PartialOrd, Ord)]
+#[serde(rename_all = "snake_case")]
+pub enum Job {
+	// expired sessions, links, recoveries, challenges and such
+	Expire,
+	// invites nobody signed up with in time
+	Invites,
+	// partial uploads nobody has written to for a while; fsck then reports their nodes' blobs missing
+	Uploads,
+	// blobs no node refers to
+	Gc,
+}
+
+impl Job {
+	pub const ALL: [Job; 4] = [Job::Expire, Job::Invites, Job::Uploads, Job::Gc];
+
+	// same as serialized
+	pub fn name(&self) -> &'static str {
+		match self {
+			Job::Expire => "expire",
+			Job::Invites => "invites",
+			Job::Uploads => "uploads",
+			Job::Gc => "gc",
+		}
+	}
+}
+
+// decides which jobs are due; running them is up to the caller
+pub struct Schedule {
+	clock: Arc<dyn Clock>,
+	// { job, seconds between runs }; 0 never runs on its own
+	intervals: HashMap<Job, u64>,
+	// { job, unix time of the last run }
+	last_runs: HashMap<Job, u64>,
+	started_at: u64,
+}
+
+impl Schedule {
+	pub fn new(clock: Arc<dyn Clock>, config: &config::Jobs) -> Self {
+		let started_at = clock.now();
+
+		Self {
+			clock,
+			intervals: HashMap::from([
+				(Job::Expire, config.expire),
+				(Job::Invites, config.invites),
+				(Job::Uploads, config.uploads),
+				(Job::Gc, config.gc),
+			]),
+			last_runs: HashMap::new(),
+			started_at,
+		}
+	}
+
+	pub fn now(&self) -> u64 {
+		self.clock.now()
+	}
+
+	pub fn interval(&self, job: Job) -> u64 {
+		self.intervals.get(&job).cloned().unwrap_or(0)
+	}
+
+	pub fn last_run(&self, job: Job) -> Option<u64> {
+		self.last_runs.get(&job).cloned()
+	}
+
+	// one interval after the last run, or after start for jobs that haven't run yet
+	pub fn due(&self) -> Vec<Job> {
+		let now = self.clock.now();
+
+		Job::ALL
+			.into_iter()
+			.filter(|job| {
+				let interval = self.interval(*job);
+				let since = self.last_run(*job).unwrap_or(self.started_at);
+
+				interval > 0 && now >= since + interval
+			})
+			.collect()
+	}
+
+	// triggered runs count as well, so a job run by hand isn't repeated right after
+	pub fn ran(&mut self, job: Job, at: u64) {
+		self.last_runs.insert(job, at);
+	}
+}
+
+#[cfg(test)]
+pub mod tests {
+	use super::*;
+	use std::sync::atomic::{AtomicU64, Ordering};
+
+	// only moves when told to
+	#[derive(Default)]
+	pub struct ManualClock(AtomicU64);
+
+	impl ManualClock {
+		pub fn advance(&self, seconds: u64) {
+			self.0.fetch_add(seconds, Ordering::SeqCst);
+		}
+	}
+
+	impl Clock for ManualClock {
+		fn now(&self) -> u64 {
+			self.0.load(Ordering::SeqCst)
+		}
+	}
+
+	#[test]
+	fn test_due() {
+		let clock = Arc::new(ManualClock::default());
+		let mut schedule = Schedule::new(
+			clock.clone(),
+			&config::Jobs {
+				expire: 10,
+				invites: 0,
+				uploads: 30,
+				gc: 30,
+			},
+		);
+
+		assert!(schedule.due().is_empty());
+
+		clock.advance(10);
+
+		assert_eq!(schedule.due(), vec![Job::Expire]);
+
+		schedule.ran(Job::Expire, clock.now());
+		// run by hand
+		schedule.ran(Job::Gc, clock.now());
+		clock.advance(20);
+
+		assert_eq!(schedule.due(), vec![Job::Expire, Job::Uploads]);
+
+		schedule.ran(Job::Expire, clock.now());
+		schedule.ran(Job::Uploads, clock.now());
+		clock.advance(10);
+
+		assert_eq!(schedule.due(), vec![Job::Expire, Job::Gc]);
+		assert_eq!(schedule.last_run(Job::Invites), None);
+	}
+}
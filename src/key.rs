@@ -15,6 +15,10 @@ macro_rules! key {
 					_marker: std::marker::PhantomData,
 				}
 			}
+
+			pub fn as_bytes(&self) -> &[u8; SIZE] {
+				&self.bytes
+			}
 		}
 
 		impl<T, const SIZE: usize> Clone for $type<T, SIZE> {
@@ -0,0 +1,101 @@
+use crate::{
+	check_auth, check_role, header, links, nodes::LockedNode, now, password, shares::Role, Error,
+	JsonBody, PathParam, State,
+};
+use axum::{
+	extract,
+	http::{HeaderMap, StatusCode},
+	Json,
+};
+use tracing::info;
+
+// argon2 runs once per download at most: chunks after the first find the password verified already
+pub async fn verify_link_pass(state: &State, token: &str, pass: Option<&str>) {
+	let Some(pass) = pass else {
+		return;
+	};
+	let hash = state.links.lock().await.unverified(token, pass);
+
+	if let Some(hash) = hash {
+		if password::verify_async(hash, pass.to_string()).await {
+			state.links.lock().await.set_verified(token, pass);
+		}
+	}
+}
+
+pub async fn create_link(
+	extract::State(state): extract::State<State>,
+	headers: HeaderMap,
+	JsonBody(new_link): JsonBody<links::NewLink>,
+) -> Result<(StatusCode, Json<links::Created>), Error> {
+	let user_id = check_auth(&state, &headers).await?;
+	let node_id = new_link.node_id;
+
+	if state.nodes.lock().await.get(node_id).is_none() {
+		return Err(Error::NotFound(node_id));
+	}
+
+	check_role(&state, user_id, node_id, Role::Viewer).await?;
+
+	if new_link.expires_at <= now() {
+		return Err(Error::Gone);
+	}
+
+	let hash = match &new_link.pass {
+		Some(pass) => Some(password::hash_async(pass.clone()).await),
+		None => None,
+	};
+	let token = state.links.lock().await.add(new_link, hash);
+
+	info!(target: "shares", node_id, "link created");
+
+	Ok((StatusCode::CREATED, Json(links::Created { token })))
+}
+
+pub async fn get_link(
+	extract::State(state): extract::State<State>,
+	PathParam(token): PathParam<String>,
+	headers: HeaderMap,
+) -> Result<(StatusCode, Json<LockedNode>), Error> {
+	let pass = header(&headers, "x-uploader-link-pass");
+
+	verify_link_pass(&state, &token, pass).await;
+
+	let node_id = state.links.lock().await.check(&token, pass, now())?.node_id;
+
+	info!(target: "shares", node_id, "opening link");
+
+	state
+		.nodes
+		.lock()
+		.await
+		.get(node_id)
+		.cloned()
+		.map(|node| (StatusCode::OK, Json(node)))
+		.ok_or(Error::NotFound(node_id))
+}
+
+pub async fn delete_link(
+	extract::State(state): extract::State<State>,
+	PathParam(token): PathParam<String>,
+	headers: HeaderMap,
+) -> Result<StatusCode, Error> {
+	let user_id = check_auth(&state, &headers).await?;
+	let node_id = state
+		.links
+		.lock()
+		.await
+		.get(&token)
+		.map(|link| link.node_id)
+		.ok_or(Error::NoLink)?;
+
+	check_role(&state, user_id, node_id, Role::Editor).await?;
+
+	state
+		.links
+		.lock()
+		.await
+		.remove(&token)
+		.map(|_| StatusCode::NO_CONTENT)
+		.ok_or(Error::NoLink)
+}
@@ -0,0 +1,347 @@
+pub mod handlers;
+
+use crate::{purge::Purge, token};
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use std::collections::HashMap;
+
+// seconds a counted download has to fetch the rest of its chunks
+pub const DOWNLOAD_TTL: u64 = 60 * 60;
+
+#[derive(PartialEq, Debug)]
+pub enum Error {
+	NotFound,
+	Expired,
+	Exhausted,
+	WrongPass,
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct NewLink {
+	pub node_id: u64,
+	// unix time, seconds
+	pub expires_at: u64,
+	pub max_downloads: Option<u32>,
+	// an optional access password; the decryption key itself stays in the url fragment and never gets here
+	pub pass: Option<String>,
+}
+
+#[derive(Serialize, Deserialize, PartialEq, Debug)]
+pub struct Created {
+	pub token: String,
+}
+
+pub struct Link {
+	pub node_id: u64,
+	pub expires_at: u64,
+	pub max_downloads: Option<u32>,
+	pub downloads: u32,
+	// password::hash of the access password, if any
+	pass: Option<String>,
+	// once argon2 has accepted the password, see verified(); so chunks don't each pay for it
+	verified: Option<[u8; 32]>,
+	// { download id, expires_at } of counted downloads whose chunks may follow; limited links only
+	in_flight: HashMap<String, u64>,
+}
+
+// keyed with the argon2 hash, whose salt is random per link
+fn verified(hash: &str, pass: &str) -> [u8; 32] {
+	Sha256::new()
+		.chain_update(hash.as_bytes())
+		.chain_update(pass.as_bytes())
+		.finalize()
+		.into()
+}
+
+pub struct Links {
+	// { token, link }
+	links: HashMap<String, Link>,
+}
+
+impl Links {
+	// `pass` is the password::hash of new.pass
+	pub fn add(&mut self, new: NewLink, pass: Option<String>) -> String {
+		let token = token::generate();
+
+		self.links.insert(
+			token.clone(),
+			Link {
+				node_id: new.node_id,
+				expires_at: new.expires_at,
+				max_downloads: new.max_downloads,
+				downloads: 0,
+				pass,
+				verified: None,
+				in_flight: HashMap::new(),
+			},
+		);
+
+		token
+	}
+
+	// validates the link for a download yet to be counted
+	pub fn check(&self, token: &str, pass: Option<&str>, now: u64) -> Result<&Link, Error> {
+		let link = self.validate(token, pass, now)?;
+
+		if link.max_downloads.is_some_and(|max| link.downloads >= max) {
+			return Err(Error::Exhausted);
+		}
+
+		Ok(link)
+	}
+
+	// chunks after the first come with the id consume handed out, so the last allowed download can
+	// finish; one that was never counted, or has taken too long, is turned away
+	pub fn resume(
+		&self,
+		token: &str,
+		download_id: &str,
+		pass: Option<&str>,
+		now: u64,
+	) -> Result<&Link, Error> {
+		let link = self.validate(token, pass, now)?;
+		let counted = link.max_downloads.is_none()
+			|| link
+				.in_flight
+				.get(download_id)
+				.is_some_and(|expires_at| now < *expires_at);
+
+		if counted {
+			Ok(link)
+		} else {
+			Err(Error::Exhausted)
+		}
+	}
+
+	fn validate(&self, token: &str, pass: Option<&str>, now: u64) -> Result<&Link, Error> {
+		let link = self.links.get(token).ok_or(Error::NotFound)?;
+
+		if now >= link.expires_at {
+			return Err(Error::Expired);
+		}
+
+		match (&link.pass, pass) {
+			(None, _) => Ok(link),
+			(Some(hash), Some(pass)) if link.verified == Some(verified(hash, pass)) => Ok(link),
+			_ => Err(Error::WrongPass),
+		}
+	}
+
+	// the argon2 hash `pass` has yet to be verified against, if any; once it has, see set_verified
+	pub fn unverified(&self, token: &str, pass: &str) -> Option<String> {
+		self.links
+			.get(token)
+			.and_then(|link| link.pass.as_ref().map(|hash| (link, hash)))
+			.filter(|(link, hash)| link.verified != Some(verified(hash, pass)))
+			.map(|(_, hash)| hash.clone())
+	}
+
+	pub fn set_verified(&mut self, token: &str, pass: &str) {
+		if let Some(link) = self.links.get_mut(token) {
+			link.verified = link.pass.as_deref().map(|hash| verified(hash, pass));
+		}
+	}
+
+	// validates the link and counts a download against it; returns the id for resume
+	pub fn consume(&mut self, token: &str, pass: Option<&str>, now: u64) -> Result<String, Error> {
+		self.check(token, pass, now)?;
+
+		let download_id = token::generate();
+
+		if let Some(link) = self.links.get_mut(token) {
+			link.downloads += 1;
+
+			// at most max_downloads of them, so there's no need to keep any for unlimited links
+			if link.max_downloads.is_some() {
+				link.in_flight.retain(|_, expires_at| now < *expires_at);
+				link.in_flight
+					.insert(download_id.clone(), now + DOWNLOAD_TTL);
+			}
+		}
+
+		Ok(download_id)
+	}
+
+	pub fn get(&self, token: &str) -> Option<&Link> {
+		self.links.get(token)
+	}
+
+	pub fn remove(&mut self, token: &str) -> Option<Link> {
+		self.links.remove(token)
+	}
+
+	pub fn remove_for_node(&mut self, node_id: u64) {
+		self.links.retain(|_, link| link.node_id != node_id);
+	}
+
+	pub fn remove_expired(&mut self, now: u64) {
+		self.links.retain(|_, link| now < link.expires_at);
+	}
+}
+
+impl Purge for Links {
+	fn new() -> Self {
+		Self {
+			links: HashMap::new(),
+		}
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use crate::password;
+
+	fn new_link(max_downloads: Option<u32>, pass: Option<&str>) -> NewLink {
+		NewLink {
+			node_id: 7,
+			expires_at: 100,
+			max_downloads,
+			pass: pass.map(|p| p.to_string()),
+		}
+	}
+
+	fn add(links: &mut Links, new: NewLink) -> String {
+		let hash = new.pass.as_deref().map(password::hash);
+
+		links.add(new, hash)
+	}
+
+	// what the handlers do, off the runtime
+	fn unlock(links: &mut Links, token: &str, pass: &str) {
+		if links
+			.unverified(token, pass)
+			.is_some_and(|hash| password::verify(&hash, pass))
+		{
+			links.set_verified(token, pass);
+		}
+	}
+
+	#[test]
+	fn test_check_valid_link() {
+		let mut links = Links::new();
+		let token = add(&mut links, new_link(None, None));
+
+		assert_eq!(links.check(&token, None, 10).unwrap().node_id, 7);
+	}
+
+	#[test]
+	fn test_check_unknown_link() {
+		let links = Links::new();
+
+		assert_eq!(links.check("nope", None, 10).err(), Some(Error::NotFound));
+	}
+
+	#[test]
+	fn test_check_expired_link() {
+		let mut links = Links::new();
+		let token = add(&mut links, new_link(None, None));
+
+		assert_eq!(links.check(&token, None, 100).err(), Some(Error::Expired));
+	}
+
+	#[test]
+	fn test_consume_until_exhausted() {
+		let mut links = Links::new();
+		let token = add(&mut links, new_link(Some(2), None));
+
+		assert!(links.consume(&token, None, 10).is_ok());
+		assert!(links.consume(&token, None, 10).is_ok());
+		assert_eq!(links.consume(&token, None, 10), Err(Error::Exhausted));
+		assert_eq!(links.check(&token, None, 10).err(), Some(Error::Exhausted));
+	}
+
+	#[test]
+	fn test_last_download_finishes() {
+		let mut links = Links::new();
+		let token = add(
+			&mut links,
+			NewLink {
+				expires_at: 2 * DOWNLOAD_TTL,
+				..new_link(Some(1), None)
+			},
+		);
+
+		// first chunk, then the rest of the same download
+		let id = links.consume(&token, None, 10).unwrap();
+
+		assert!(links.resume(&token, &id, None, 10).is_ok());
+		assert!(links.resume(&token, &id, None, 10).is_ok());
+		assert_eq!(links.consume(&token, None, 10), Err(Error::Exhausted));
+		// only for so long
+		assert_eq!(
+			links.resume(&token, &id, None, 10 + DOWNLOAD_TTL).err(),
+			Some(Error::Exhausted)
+		);
+	}
+
+	#[test]
+	fn test_resume_needs_a_counted_download() {
+		let mut links = Links::new();
+		let token = add(&mut links, new_link(Some(1), None));
+
+		// never consumed
+		assert_eq!(
+			links.resume(&token, "made up", None, 10).err(),
+			Some(Error::Exhausted)
+		);
+
+		let id = links.consume(&token, None, 10).unwrap();
+
+		// exhausted, and not the id that was handed out
+		assert_eq!(
+			links.resume(&token, "made up", None, 10).err(),
+			Some(Error::Exhausted)
+		);
+		assert!(links.resume(&token, &id, None, 10).is_ok());
+	}
+
+	#[test]
+	fn test_protected_link() {
+		let mut links = Links::new();
+		let token = add(&mut links, new_link(None, Some("secret")));
+
+		// not until argon2 said so
+		assert_eq!(
+			links.check(&token, Some("secret"), 10).err(),
+			Some(Error::WrongPass)
+		);
+
+		unlock(&mut links, &token, "wrong");
+		unlock(&mut links, &token, "secret");
+
+		assert_eq!(links.check(&token, None, 10).err(), Some(Error::WrongPass));
+		assert_eq!(
+			links.check(&token, Some("wrong"), 10).err(),
+			Some(Error::WrongPass)
+		);
+		assert!(links.check(&token, Some("secret"), 10).is_ok());
+		assert_eq!(links.unverified(&token, "secret"), None);
+		assert!(links.unverified(&token, "wrong").is_some());
+	}
+
+	#[test]
+	fn test_wrong_pass_does_not_count_download() {
+		let mut links = Links::new();
+		let token = add(&mut links, new_link(Some(1), Some("secret")));
+
+		unlock(&mut links, &token, "secret");
+
+		assert_eq!(
+			links.consume(&token, Some("wrong"), 10),
+			Err(Error::WrongPass)
+		);
+		assert!(links.consume(&token, Some("secret"), 10).is_ok());
+		assert_eq!(links.get(&token).unwrap().downloads, 1);
+	}
+
+	#[test]
+	fn test_remove_for_node() {
+		let mut links = Links::new();
+		let token = add(&mut links, new_link(None, None));
+
+		links.remove_for_node(7);
+
+		assert_eq!(links.check(&token, None, 10).err(), Some(Error::NotFound));
+	}
+}
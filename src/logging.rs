@@ -0,0 +1,133 @@
+use crate::config::{LogFormat, Logging};
+use axum::{
+	extract::{MatchedPath, Request},
+	http::HeaderValue,
+};
+use rand::{rngs::OsRng, RngCore};
+use tower_http::request_id::{MakeRequestId, RequestId};
+use tracing::Span;
+use tracing_subscriber::EnvFilter;
+
+pub const REQUEST_ID_HEADER: &str = "x-request-id";
+// characters of a token kept in logs, enough to tell them apart
+const TOKEN_PREFIX: usize = 4;
+
+// the global level plus one per module; `nodes` covers both the nodes module and node handlers
+pub fn filter(config: &Logging) -> Result<EnvFilter, String> {
+	let directives: Vec<String> = std::iter::once(config.level.clone())
+		.chain(config.modules.iter().flat_map(|(module, level)| {
+			[
+				format!("{}={}", module, level),
+				format!("uploader::{}={}", module, level),
+			]
+		}))
+		.collect();
+
+	EnvFilter::try_new(directives.join(",")).map_err(|err| err.to_string())
+}
+
+// RUST_LOG, if set, takes precedence over the config
+pub fn init(config: &Logging) -> Result<(), String> {
+	let filter = match EnvFilter::try_from_default_env() {
+		Ok(filter) => filter,
+		Err(_) => filter(config)?,
+	};
+	let builder = tracing_subscriber::fmt().with_env_filter(filter);
+
+	match config.format {
+		LogFormat::Json => builder
+			.json()
+			.with_current_span(true)
+			.with_span_list(false)
+			.try_init(),
+		LogFormat::Text => builder.try_init(),
+	}
+	.map_err(|err| err.to_string())
+}
+
+// a***@example.com
+pub fn email(email: &str) -> String {
+	match email.split_once('@') {
+		Some((name, domain)) => format!("{}***@{}", name.chars().next().unwrap_or('*'), domain),
+		None => "***".to_string(),
+	}
+}
+
+// abcd…
+pub fn token(token: &str) -> String {
+	format!("{}…", token.chars().take(TOKEN_PREFIX).collect::<String>())
+}
+
+// the route is logged as its template, never the actual uri, which may hold emails and tokens
+pub fn request_span(request: &Request) -> Span {
+	let request_id = request
+		.headers()
+		.get(REQUEST_ID_HEADER)
+		.and_then(|id| id.to_str().ok())
+		.unwrap_or_default();
+	let route = request
+		.extensions()
+		.get::<MatchedPath>()
+		.map(|path| path.as_str())
+		.unwrap_or_default();
+
+	tracing::info_span!(
+		"request",
+		request_id,
+		method = %request.method(),
+		route,
+		user_id = tracing::field::Empty,
+	)
+}
+
+// fills in the user of the current request, once known
+pub fn record_user(user_id: u64) {
+	Span::current().record("user_id", user_id);
+}
+
+#[derive(Clone, Default)]
+pub struct RequestIds;
+
+impl MakeRequestId for RequestIds {
+	fn make_request_id<B>(&mut self, _: &axum::http::Request<B>) -> Option<RequestId> {
+		HeaderValue::from_str(&format!("{:016x}", OsRng.next_u64()))
+			.ok()
+			.map(RequestId::new)
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use std::collections::BTreeMap;
+
+	#[test]
+	fn test_email_is_redacted() {
+		assert_eq!(email("alice@mail.com"), "a***@mail.com");
+		assert_eq!(email("@mail.com"), "****@mail.com");
+		assert_eq!(email("nope"), "***");
+	}
+
+	#[test]
+	fn test_token_is_redacted() {
+		assert_eq!(token("abcdefgh"), "abcd…");
+		assert_eq!(token("ab"), "ab…");
+	}
+
+	#[test]
+	fn test_module_levels() {
+		let mut config = Logging {
+			level: "info".into(),
+			format: LogFormat::Text,
+			modules: BTreeMap::from([("nodes".into(), "debug".into())]),
+		};
+		let filter = filter(&config).unwrap().to_string();
+
+		assert!(filter.contains("nodes=debug"));
+		assert!(filter.contains("uploader::nodes=debug"));
+
+		config.modules.insert("uploads".into(), "loud".into());
+
+		assert!(super::filter(&config).is_err());
+	}
+}
@@ -0,0 +1,303 @@
+use crate::config;
+use async_trait::async_trait;
+use lettre::{
+	message::Mailbox, transport::smtp::authentication::Credentials, AsyncFileTransport,
+	AsyncSmtpTransport, AsyncTransport, Message, Tokio1Executor,
+};
+use std::{path::PathBuf, sync::Arc};
+
+pub const DEFAULT_FROM: &str = "uploader <noreply@localhost>";
+
+#[derive(Debug, PartialEq)]
+pub enum Error {
+	BadAddress(String),
+	Transport(String),
+}
+
+#[derive(Debug, PartialEq, Clone)]
+pub struct Mail {
+	pub to: String,
+	pub subject: String,
+	pub body: String,
+}
+
+#[async_trait]
+pub trait Mailer: Send + Sync {
+	async fn send(&self, mail: Mail) -> Result<(), Error>;
+}
+
+fn message(from: &Mailbox, mail: Mail) -> Result<Message, Error> {
+	let to: Mailbox = mail
+		.to
+		.parse()
+		.map_err(|_| Error::BadAddress(mail.to.clone()))?;
+
+	Message::builder()
+		.from(from.clone())
+		.to(to)
+		.subject(mail.subject)
+		.body(mail.body)
+		.map_err(|e| Error::Transport(e.to_string()))
+}
+
+pub fn mailbox(addr: &str) -> Result<Mailbox, Error> {
+	addr.parse()
+		.map_err(|_| Error::BadAddress(addr.to_string()))
+}
+
+pub struct SmtpMailer {
+	from: Mailbox,
+	transport: AsyncSmtpTransport<Tokio1Executor>,
+}
+
+impl SmtpMailer {
+	// no tls at all; meant for a local relay or a test sink
+	pub fn plain(host: &str, port: u16, from: &str) -> Result<Self, Error> {
+		let transport = AsyncSmtpTransport::<Tokio1Executor>::builder_dangerous(host)
+			.port(port)
+			.build();
+
+		Ok(Self {
+			from: mailbox(from)?,
+			transport,
+		})
+	}
+
+	pub fn starttls(
+		host: &str,
+		port: u16,
+		from: &str,
+		credentials: Option<(String, String)>,
+	) -> Result<Self, Error> {
+		let mut builder = AsyncSmtpTransport::<Tokio1Executor>::starttls_relay(host)
+			.map_err(|e| Error::Transport(e.to_string()))?
+			.port(port);
+
+		if let Some((user, pass)) = credentials {
+			builder = builder.credentials(Credentials::new(user, pass));
+		}
+
+		Ok(Self {
+			from: mailbox(from)?,
+			transport: builder.build(),
+		})
+	}
+}
+
+#[async_trait]
+impl Mailer for SmtpMailer {
+	async fn send(&self, mail: Mail) -> Result<(), Error> {
+		let msg = message(&self.from, mail)?;
+
+		self.transport
+			.send(msg)
+			.await
+			.map(|_| ())
+			.map_err(|e| Error::Transport(e.to_string()))
+	}
+}
+
+// writes every message as an .eml file to `dir` instead of sending it; handy for tests and local runs
+pub struct FileMailer {
+	from: Mailbox,
+	dir: PathBuf,
+	transport: AsyncFileTransport<Tokio1Executor>,
+}
+
+impl FileMailer {
+	pub fn new(dir: PathBuf, from: &str) -> Result<Self, Error> {
+		std::fs::create_dir_all(&dir).map_err(|e| Error::Transport(e.to_string()))?;
+
+		Ok(Self {
+			from: mailbox(from)?,
+			transport: AsyncFileTransport::new(&dir),
+			dir,
+		})
+	}
+}
+
+#[async_trait]
+impl Mailer for FileMailer {
+	async fn send(&self, mail: Mail) -> Result<(), Error> {
+		let msg = message(&self.from, mail)?;
+		let id = self
+			.transport
+			.send(msg)
+			.await
+			.map_err(|e| Error::Transport(e.to_string()))?;
+
+		tracing::info!(path = %self.dir.join(id).display(), "mail stored");
+
+		Ok(())
+	}
+}
+
+// smtp if a host is configured (starttls if asked for), otherwise the mail dir; see Config::validate
+pub fn from_config(config: &config::Mail) -> Result<Arc<dyn Mailer>, Error> {
+	let smtp = &config.smtp;
+
+	if let Some(host) = &smtp.host {
+		if smtp.tls {
+			let credentials = smtp.user.clone().zip(smtp.pass.clone());
+
+			Ok(Arc::new(SmtpMailer::starttls(
+				host,
+				smtp.port,
+				&config.from,
+				credentials,
+			)?))
+		} else {
+			Ok(Arc::new(SmtpMailer::plain(host, smtp.port, &config.from)?))
+		}
+	} else {
+		Ok(Arc::new(FileMailer::new(config.dir.clone(), &config.from)?))
+	}
+}
+
+// the signup page of the web client, which then fetches the invite itself from GET /invite/:email
+pub fn invite_link(base_url: &str, email: &str) -> String {
+	format!(
+		"{}/signup?email={}",
+		base_url.trim_end_matches('/'),
+		urlencoding::encode(email)
+	)
+}
+
+pub fn invite(email: &str, link: &str) -> Mail {
+	Mail {
+		to: email.to_string(),
+		subject: "You have been invited".to_string(),
+		body: format!(
+			"Hi,\n\n\
+			someone has shared an encrypted vault with you.\n\
+			Follow the link below to accept the invite and set up your account:\n\n\
+			{}\n\n\
+			If you were not expecting this, just ignore this message.\n",
+			link
+		),
+	}
+}
+
+// the recovery page of the web client; only whoever reads the account's mail gets to go on
+pub fn recovery_link(base_url: &str, token: &str) -> String {
+	format!(
+		"{}/recover?token={}",
+		base_url.trim_end_matches('/'),
+		urlencoding::encode(token)
+	)
+}
+
+pub fn recovery(email: &str, link: &str) -> Mail {
+	Mail {
+		to: email.to_string(),
+		subject: "Recovering your account".to_string(),
+		body: format!(
+			"Hi,\n\n\
+			a recovery of your account through one of your trusted contacts has been started.\n\
+			Follow the link below to go on with it:\n\n\
+			{}\n\n\
+			If this wasn't you, cancel it from any of your devices before it completes.\n",
+			link
+		),
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use crate::tmp_dir::TmpDir;
+	use tokio::{
+		io::{AsyncBufReadExt, AsyncWriteExt, BufReader},
+		net::TcpListener,
+	};
+
+	// a minimal smtp sink: accepts a single session and returns the DATA it received
+	async fn smtp_sink(listener: TcpListener) -> String {
+		let (stream, _) = listener.accept().await.unwrap();
+		let (read, mut write) = stream.into_split();
+		let mut lines = BufReader::new(read).lines();
+		let mut data = String::new();
+		let mut in_data = false;
+
+		write.write_all(b"220 sink ESMTP\r\n").await.unwrap();
+
+		while let Some(line) = lines.next_line().await.unwrap() {
+			if in_data {
+				if line == "." {
+					in_data = false;
+					write.write_all(b"250 queued\r\n").await.unwrap();
+				} else {
+					data.push_str(&line);
+					data.push('\n');
+				}
+				continue;
+			}
+
+			let cmd = line.to_uppercase();
+
+			if cmd.starts_with("EHLO") || cmd.starts_with("HELO") {
+				write.write_all(b"250 sink\r\n").await.unwrap();
+			} else if cmd.starts_with("DATA") {
+				in_data = true;
+				write.write_all(b"354 go ahead\r\n").await.unwrap();
+			} else if cmd.starts_with("QUIT") {
+				write.write_all(b"221 bye\r\n").await.unwrap();
+				break;
+			} else {
+				write.write_all(b"250 ok\r\n").await.unwrap();
+			}
+		}
+
+		data
+	}
+
+	#[test]
+	fn test_invite_link_is_encoded() {
+		assert_eq!(
+			invite_link("https://example.com/", "a+b@mail.com"),
+			"https://example.com/signup?email=a%2Bb%40mail.com"
+		);
+	}
+
+	#[tokio::test]
+	async fn test_file_mailer_writes_message() {
+		let dir = TmpDir::new("mail");
+		let mailer = FileMailer::new(dir.to_path_buf(), DEFAULT_FROM).unwrap();
+		let link = invite_link("https://example.com", "alice@mail.com");
+
+		mailer.send(invite("alice@mail.com", &link)).await.unwrap();
+
+		let mut entries = std::fs::read_dir(&dir).unwrap();
+		let path = entries.next().unwrap().unwrap().path();
+		let eml = std::fs::read_to_string(path).unwrap();
+
+		assert!(eml.contains("To: alice@mail.com"));
+		assert!(eml.contains(&link));
+	}
+
+	#[tokio::test]
+	async fn test_file_mailer_rejects_bad_address() {
+		let dir = TmpDir::new("mail");
+		let mailer = FileMailer::new(dir.to_path_buf(), DEFAULT_FROM).unwrap();
+		let res = mailer.send(invite("not an address", "link")).await;
+
+		assert_eq!(res, Err(Error::BadAddress("not an address".to_string())));
+	}
+
+	#[tokio::test]
+	async fn test_smtp_mailer_delivers_to_sink() {
+		let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+		let port = listener.local_addr().unwrap().port();
+		let sink = tokio::spawn(smtp_sink(listener));
+		let mailer = SmtpMailer::plain("127.0.0.1", port, DEFAULT_FROM).unwrap();
+		let link = invite_link("https://example.com", "bob@mail.com");
+
+		mailer.send(invite("bob@mail.com", &link)).await.unwrap();
+
+		let data = sink.await.unwrap();
+
+		assert!(data.contains("To: bob@mail.com"));
+		assert!(data.contains("Subject: You have been invited"));
+		assert!(data.contains(&link));
+	}
+}
@@ -1,51 +1,148 @@
+mod admin;
+// shared with the client, which does the encrypting
+#[allow(dead_code)]
 mod aes_gcm;
+mod archive;
+mod audit;
+mod auth;
+#[allow(clippy::redundant_closure)]
 mod base64_blobs;
+mod blobs;
+mod config;
 mod content_range;
+mod devices;
 mod ed448;
 mod encrypted;
+mod events;
+mod file_requests;
+mod fsck;
+mod groups;
 mod id;
 mod identity;
+mod jobs;
 mod key;
+mod links;
 mod lock;
+mod logging;
+mod mailer;
+mod metrics;
+#[allow(clippy::bool_assert_comparison)]
 mod nodes;
+mod pairing;
+mod password;
 mod public_key;
 mod purge;
+mod rate_limit;
+mod recovery;
 mod salt;
 mod sessions;
 mod shares;
+// the http api, end to end
+#[cfg(test)]
+mod tests;
+mod tls;
+#[cfg(test)]
+mod tmp_dir;
+mod token;
+mod totp;
 mod users;
 mod x448;
 
 use crate::purge::Purge;
+use admin::handlers::{
+	admin_fsck, admin_gc, get_admin_audit, get_admin_node, get_admin_nodes, get_admin_shares,
+	get_admin_stats, get_admin_user, get_admin_users, get_metrics, purge_all, purge_expired,
+	purge_user, record_admin, require_client_cert,
+};
+use archive::{Imported, Manifest};
+use audit::Audit;
+use auth::handlers::{
+	confirm_totp, device_challenge, disable_totp, enroll_totp, login, login_challenge, login_totp,
+	login_verify,
+};
+use auth::{Challenges, SecondSteps, Tokens};
 use axum::{
 	body::{Body, BodyDataStream},
-	extract::{self, Path, Request},
-	http::{HeaderMap, HeaderValue, Response as HttpResponse, StatusCode},
+	extract::{self, DefaultBodyLimit, MatchedPath, Path, Query, Request},
+	http::{header, HeaderMap, HeaderValue, Response as HttpResponse, StatusCode},
+	middleware::{self, Next},
 	response::{IntoResponse, Response},
-	routing::{delete, get, head, post},
+	routing::{delete, get, head, post, put},
 	Json, Router,
 };
-use axum_server::{tls_rustls::RustlsConfig, Server};
+use axum_server::{tls_rustls::RustlsConfig, Handle, Server};
+use blobs::Blobs;
+use clap::Parser;
+use config::Config;
 use content_range::{ContentRange, Range};
-use futures_util::StreamExt;
+use devices::Devices;
+use events::{Event, Events};
+use file_requests::handlers::{create_file_request, delete_file_request, get_file_request};
+use file_requests::FileRequests;
+use futures_util::{stream, Stream, StreamExt};
+use groups::Groups;
+use jobs::handlers::{get_admin_jobs, run_jobs, trigger_job};
+use jobs::Schedule;
+use links::handlers::{create_link, delete_link, get_link, verify_link_pass};
+use links::Links;
+use mailer::Mailer;
+use metrics::Metrics;
 use nodes::LockedNode;
 use nodes::Nodes;
+use pairing::Side;
+use rate_limit::RateLimit;
+use recovery::Recoveries;
+use serde::{de::DeserializeOwned, Deserialize, Serialize};
 use sessions::Sessions;
-use shares::{Invite, Shares, Welcome};
-use std::{env, net::SocketAddr, path::PathBuf, str::FromStr, sync::Arc};
+use shares::{Invite, LockedShare, Role, Shares, Welcome};
+use std::{
+	collections::{HashMap, HashSet},
+	env,
+	net::{IpAddr, SocketAddr},
+	path::PathBuf,
+	str::FromStr,
+	sync::{
+		atomic::{AtomicBool, Ordering},
+		Arc,
+	},
+	time::{Duration, Instant, SystemTime, UNIX_EPOCH},
+};
 use tokio::io::{AsyncReadExt, AsyncSeekExt, AsyncWriteExt};
 use tokio::{fs::OpenOptions, sync::Mutex};
-use tower_http::cors::CorsLayer;
-use users::{LockedUser, Login, Signup, Users};
+use tower_http::{
+	cors::{self, CorsLayer},
+	request_id::{PropagateRequestIdLayer, SetRequestIdLayer},
+	trace::{DefaultOnResponse, TraceLayer},
+};
+use tracing::{debug, error, info, trace, warn, Level};
+use users::{Deletion, LockChange, LockedUser, Reset, Signup, Users};
 
 // Define a custom error type that can convert into an HTTP response
 #[derive(Debug)]
 enum Error {
 	Io(String),
+	// no valid access token or wrong credentials
+	Unauthenticated,
+	// authenticated, but not allowed to
 	Unauthorised,
-	InvalidRange,
+	// the length of the blob, if known
+	InvalidRange(Option<u64>),
 	NotFound(u64),
-	NoInvite(String),
+	NoBlob(u64),
+	BadJson(String),
+	// a path segment or query string that doesn't parse
+	BadRequest(String),
+	NoInvite,
+	TooManyAttempts,
+	Conflict,
+	NoLink,
+	NoFileRequest,
+	NoSession,
+	Full,
+	Gone,
+	TooLarge,
+	Mail(String),
+	Malformed,
 }
 
 impl From<std::io::Error> for Error {
@@ -66,16 +163,360 @@ impl From<axum::Error> for Error {
 	}
 }
 
-impl IntoResponse for Error {
-	fn into_response(self) -> Response {
+impl From<tokio::task::JoinError> for Error {
+	fn from(err: tokio::task::JoinError) -> Self {
+		Error::Io(format!("{}", err))
+	}
+}
+
+impl From<archive::Error> for Error {
+	fn from(err: archive::Error) -> Self {
+		match err {
+			archive::Error::Malformed => Error::Malformed,
+			archive::Error::Io(err) => Error::Io(err),
+		}
+	}
+}
+
+impl From<mailer::Error> for Error {
+	fn from(err: mailer::Error) -> Self {
+		Error::Mail(format!("{:?}", err))
+	}
+}
+
+impl From<nodes::Error> for Error {
+	fn from(err: nodes::Error) -> Self {
+		match err {
+			nodes::Error::NotFound(id) => Error::NotFound(id),
+			nodes::Error::NotAllowed => Error::Conflict,
+		}
+	}
+}
+
+impl From<devices::Error> for Error {
+	fn from(err: devices::Error) -> Self {
+		match err {
+			devices::Error::NotFound(id) => Error::NotFound(id),
+			devices::Error::Taken => Error::Conflict,
+			devices::Error::BadSig => Error::Unauthorised,
+		}
+	}
+}
+
+impl From<recovery::Error> for Error {
+	fn from(err: recovery::Error) -> Self {
+		match err {
+			recovery::Error::NotFound => Error::Unauthorised,
+			recovery::Error::NotAllowed => Error::Unauthorised,
+			recovery::Error::Blocked => Error::TooManyAttempts,
+			recovery::Error::Expired => Error::Gone,
+			recovery::Error::Pending => Error::Conflict,
+		}
+	}
+}
+
+impl From<groups::Error> for Error {
+	fn from(err: groups::Error) -> Self {
+		match err {
+			groups::Error::NotFound(id) => Error::NotFound(id),
+			groups::Error::NotAllowed => Error::Unauthorised,
+			groups::Error::Mismatch => Error::Conflict,
+		}
+	}
+}
+
+impl From<sessions::Error> for Error {
+	fn from(err: sessions::Error) -> Self {
+		match err {
+			sessions::Error::Blocked => Error::TooManyAttempts,
+			sessions::Error::Taken => Error::Conflict,
+			sessions::Error::Pairing(err) => err.into(),
+		}
+	}
+}
+
+impl From<pairing::Error> for Error {
+	fn from(err: pairing::Error) -> Self {
+		match err {
+			pairing::Error::NotFound => Error::NoSession,
+			pairing::Error::AlreadyJoined => Error::Conflict,
+			pairing::Error::OutOfOrder => Error::Conflict,
+			pairing::Error::Mismatch => Error::Unauthorised,
+			pairing::Error::NotConfirmed => Error::Conflict,
+			pairing::Error::Full => Error::Full,
+			pairing::Error::TooLarge => Error::TooLarge,
+		}
+	}
+}
+
+impl From<links::Error> for Error {
+	fn from(err: links::Error) -> Self {
+		match err {
+			links::Error::NotFound => Error::NoLink,
+			links::Error::Expired | links::Error::Exhausted => Error::Gone,
+			links::Error::WrongPass => Error::Unauthorised,
+		}
+	}
+}
+
+impl From<file_requests::Error> for Error {
+	fn from(err: file_requests::Error) -> Self {
+		match err {
+			file_requests::Error::NotFound => Error::NoFileRequest,
+			file_requests::Error::Expired => Error::Gone,
+			file_requests::Error::NotAllowed => Error::Unauthorised,
+			file_requests::Error::TooLarge => Error::TooLarge,
+		}
+	}
+}
+
+// what every failed request responds with
+#[derive(Serialize, Deserialize, PartialEq, Debug)]
+struct ErrorBody {
+	// stable, for clients to match on
+	code: String,
+	// human readable, may change at any time
+	message: String,
+	#[serde(default, skip_serializing_if = "Option::is_none")]
+	details: Option<ErrorDetails>,
+}
+
+#[derive(Serialize, Deserialize, PartialEq, Debug)]
+#[serde(untagged)]
+enum ErrorDetails {
+	// the node, user, group or device the request referred to
+	Id { id: u64 },
+	// valid ranges are within 0..length
+	Range { length: u64 },
+}
+
+impl Error {
+	fn status(&self) -> StatusCode {
 		match self {
-			Error::Io(_) => StatusCode::SERVICE_UNAVAILABLE,
+			Error::Io(_) => StatusCode::INTERNAL_SERVER_ERROR,
+			Error::Unauthenticated => StatusCode::UNAUTHORIZED,
 			Error::Unauthorised => StatusCode::FORBIDDEN,
-			Error::InvalidRange => StatusCode::RANGE_NOT_SATISFIABLE,
+			Error::InvalidRange(_) => StatusCode::RANGE_NOT_SATISFIABLE,
 			Error::NotFound(_) => StatusCode::NOT_FOUND,
-			Error::NoInvite(_) => StatusCode::NOT_FOUND,
+			Error::NoBlob(_) => StatusCode::NOT_FOUND,
+			Error::BadJson(_) => StatusCode::BAD_REQUEST,
+			Error::BadRequest(_) => StatusCode::BAD_REQUEST,
+			Error::NoInvite => StatusCode::NOT_FOUND,
+			Error::TooManyAttempts => StatusCode::TOO_MANY_REQUESTS,
+			Error::Conflict => StatusCode::CONFLICT,
+			Error::NoLink => StatusCode::NOT_FOUND,
+			Error::NoFileRequest => StatusCode::NOT_FOUND,
+			Error::NoSession => StatusCode::NOT_FOUND,
+			Error::Full => StatusCode::TOO_MANY_REQUESTS,
+			Error::Gone => StatusCode::GONE,
+			Error::TooLarge => StatusCode::PAYLOAD_TOO_LARGE,
+			Error::Mail(_) => StatusCode::BAD_GATEWAY,
+			Error::Malformed => StatusCode::BAD_REQUEST,
+		}
+	}
+
+	fn body(&self) -> ErrorBody {
+		let (code, message, details) = match self {
+			// not to leak paths and such; it's logged instead
+			Error::Io(_) => ("io", "storage or connection failure".into(), None),
+			Error::Unauthenticated => (
+				"unauthenticated",
+				"missing or invalid credentials".into(),
+				None,
+			),
+			Error::Unauthorised => ("forbidden", "not allowed".into(), None),
+			Error::InvalidRange(length) => (
+				"invalid_range",
+				"missing or unsatisfiable range".into(),
+				length.map(|length| ErrorDetails::Range { length }),
+			),
+			Error::NotFound(id) => (
+				"not_found",
+				"no such item".into(),
+				Some(ErrorDetails::Id { id: *id }),
+			),
+			Error::NoBlob(id) => (
+				"no_blob",
+				"nothing uploaded for this id".into(),
+				Some(ErrorDetails::Id { id: *id }),
+			),
+			Error::BadJson(err) => ("bad_json", err.clone(), None),
+			Error::BadRequest(err) => ("bad_request", err.clone(), None),
+			Error::NoInvite => ("no_invite", "no pending invite".into(), None),
+			Error::TooManyAttempts => ("too_many_attempts", "try again later".into(), None),
+			Error::Conflict => ("conflict", "conflicts with the current state".into(), None),
+			Error::NoLink => ("no_link", "no such link".into(), None),
+			Error::NoFileRequest => ("no_file_request", "no such file request".into(), None),
+			Error::NoSession => ("no_session", "no such session".into(), None),
+			Error::Full => ("full", "too many pending messages".into(), None),
+			Error::Gone => ("gone", "expired or used up".into(), None),
+			Error::TooLarge => ("too_large", "payload too large".into(), None),
+			Error::Mail(_) => ("mail", "failed to send mail".into(), None),
+			Error::Malformed => (
+				"malformed_archive",
+				"not a valid account archive".into(),
+				None,
+			),
+		};
+
+		ErrorBody {
+			code: code.into(),
+			message,
+			details,
+		}
+	}
+}
+
+impl IntoResponse for Error {
+	fn into_response(self) -> Response {
+		if let Error::Io(err) | Error::Mail(err) = &self {
+			error!(%err, "request failed");
+		}
+
+		(self.status(), Json(self.body())).into_response()
+	}
+}
+
+// a json payload that rejects with an Error, so bad ones get a proper body as well
+struct JsonBody<T>(T);
+
+#[async_trait::async_trait]
+impl<T, S> extract::FromRequest<S> for JsonBody<T>
+where
+	T: DeserializeOwned,
+	S: Send + Sync,
+{
+	type Rejection = Error;
+
+	async fn from_request(request: Request, state: &S) -> Result<Self, Self::Rejection> {
+		match Json::<T>::from_request(request, state).await {
+			Ok(Json(value)) => Ok(Self(value)),
+			Err(rejection) => Err(Error::BadJson(rejection.body_text())),
+		}
+	}
+}
+
+// path parameters that reject with an Error, like JsonBody
+struct PathParam<T>(T);
+
+#[async_trait::async_trait]
+impl<T, S> extract::FromRequestParts<S> for PathParam<T>
+where
+	T: DeserializeOwned + Send,
+	S: Send + Sync,
+{
+	type Rejection = Error;
+
+	async fn from_request_parts(
+		parts: &mut axum::http::request::Parts,
+		state: &S,
+	) -> Result<Self, Self::Rejection> {
+		match Path::<T>::from_request_parts(parts, state).await {
+			Ok(Path(value)) => Ok(Self(value)),
+			Err(rejection) => Err(Error::BadRequest(rejection.body_text())),
+		}
+	}
+}
+
+// a query string that rejects with an Error, like JsonBody
+struct QueryParam<T>(T);
+
+#[async_trait::async_trait]
+impl<T, S> extract::FromRequestParts<S> for QueryParam<T>
+where
+	T: DeserializeOwned,
+	S: Send + Sync,
+{
+	type Rejection = Error;
+
+	async fn from_request_parts(
+		parts: &mut axum::http::request::Parts,
+		state: &S,
+	) -> Result<Self, Self::Rejection> {
+		match Query::<T>::from_request_parts(parts, state).await {
+			Ok(Query(value)) => Ok(Self(value)),
+			Err(rejection) => Err(Error::BadRequest(rejection.body_text())),
+		}
+	}
+}
+
+// whose budget a request counts against where there's no account to go by: its ipv4 address, or
+// the /64 of an ipv6 one, as hosts tend to get whole ones. 0 when served without connect info
+struct Caller(u64);
+
+#[async_trait::async_trait]
+impl<S> extract::FromRequestParts<S> for Caller
+where
+	S: Send + Sync,
+{
+	type Rejection = Error;
+
+	async fn from_request_parts(
+		parts: &mut axum::http::request::Parts,
+		_: &S,
+	) -> Result<Self, Self::Rejection> {
+		let key = parts
+			.extensions
+			.get::<extract::ConnectInfo<SocketAddr>>()
+			.map(|info| match info.0.ip().to_canonical() {
+				IpAddr::V4(ip) => u32::from(ip) as u64,
+				IpAddr::V6(ip) => (u128::from(ip) >> 64) as u64,
+			})
+			.unwrap_or(0);
+
+		Ok(Self(key))
+	}
+}
+
+// the subject of a verified client certificate; only ever there over mutual tls
+struct ClientSubject(String);
+
+#[async_trait::async_trait]
+impl<S> extract::FromRequestParts<S> for ClientSubject
+where
+	S: Send + Sync,
+{
+	type Rejection = Error;
+
+	async fn from_request_parts(
+		parts: &mut axum::http::request::Parts,
+		_: &S,
+	) -> Result<Self, Self::Rejection> {
+		parts
+			.extensions
+			.get::<tls::ClientCert>()
+			.and_then(|cert| cert.subject.clone())
+			.map(Self)
+			.ok_or(Error::Unauthenticated)
+	}
+}
+
+// someone allowed to use the admin routes, see admin::principal
+struct Admin(String);
+
+#[async_trait::async_trait]
+impl extract::FromRequestParts<State> for Admin {
+	type Rejection = Error;
+
+	async fn from_request_parts(
+		parts: &mut axum::http::request::Parts,
+		state: &State,
+	) -> Result<Self, Self::Rejection> {
+		let subject = parts
+			.extensions
+			.get::<tls::ClientCert>()
+			.and_then(|cert| cert.subject.as_deref());
+		let token = header(&parts.headers, admin::TOKEN_HEADER);
+		let principal = admin::principal(&state.config.admin, subject, token);
+
+		match principal {
+			Some(principal) => Ok(Self(principal)),
+			None => {
+				warn!(subject, "admin access denied");
+
+				Err(Error::Unauthenticated)
+			}
 		}
-		.into_response()
 	}
 }
 
@@ -85,18 +526,85 @@ struct State {
 	shares: Arc<Mutex<Shares>>,
 	users: Arc<Mutex<Users>>,
 	sessions: Arc<Mutex<Sessions>>,
+	groups: Arc<Mutex<Groups>>,
+	links: Arc<Mutex<Links>>,
+	file_requests: Arc<Mutex<FileRequests>>,
+	devices: Arc<Mutex<Devices>>,
+	recoveries: Arc<Mutex<Recoveries>>,
+	tokens: Arc<Mutex<Tokens>>,
+	challenges: Arc<Mutex<Challenges>>,
+	// by device id, see devices::Proof
+	device_challenges: Arc<Mutex<Challenges>>,
+	second_steps: Arc<Mutex<SecondSteps>>,
+	events: Arc<Mutex<Events>>,
+	// reads of wrapped master keys, per account
+	mk_reads: Arc<Mutex<RateLimit>>,
+	// invites sent, per sender
+	invites_sent: Arc<Mutex<RateLimit>>,
+	audit: Arc<Mutex<Audit>>,
+	blobs: Arc<Mutex<Blobs>>,
+	jobs: Arc<Mutex<Schedule>>,
+	mailer: Arc<dyn Mailer>,
+	config: Arc<Config>,
+	metrics: Arc<Metrics>,
+	// set once SIGTERM arrives, so load balancers stop sending traffic while uploads drain
+	shutting_down: Arc<AtomicBool>,
 }
 
 impl State {
-	fn new() -> Self {
+	fn new(mailer: Arc<dyn Mailer>, config: Config) -> Self {
+		Self::with_clock(mailer, config, Arc::new(jobs::SystemClock))
+	}
+
+	// jobs go by `clock`, so tests can move time along
+	fn with_clock(mailer: Arc<dyn Mailer>, config: Config, clock: Arc<dyn jobs::Clock>) -> Self {
 		Self {
 			nodes: Arc::new(Mutex::new(Nodes::new())),
 			shares: Arc::new(Mutex::new(Shares::new())),
 			users: Arc::new(Mutex::new(Users::new())),
 			sessions: Arc::new(Mutex::new(Sessions::new())),
+			groups: Arc::new(Mutex::new(Groups::new())),
+			links: Arc::new(Mutex::new(Links::new())),
+			file_requests: Arc::new(Mutex::new(FileRequests::new())),
+			devices: Arc::new(Mutex::new(Devices::new())),
+			recoveries: Arc::new(Mutex::new(Recoveries::new())),
+			tokens: Arc::new(Mutex::new(Tokens::new())),
+			challenges: Arc::new(Mutex::new(Challenges::new())),
+			device_challenges: Arc::new(Mutex::new(Challenges::new())),
+			second_steps: Arc::new(Mutex::new(SecondSteps::new())),
+			events: Arc::new(Mutex::new(Events::new())),
+			mk_reads: Arc::new(Mutex::new(RateLimit::new())),
+			invites_sent: Arc::new(Mutex::new(RateLimit::new())),
+			audit: Arc::new(Mutex::new(Audit::new())),
+			blobs: Arc::new(Mutex::new(Blobs::new())),
+			jobs: Arc::new(Mutex::new(Schedule::new(clock, &config.jobs))),
+			mailer,
+			config: Arc::new(config),
+			metrics: Arc::new(Metrics::new()),
+			shutting_down: Arc::new(AtomicBool::new(false)),
+		}
+	}
+
+	// everything but the blobs lives in memory; their contents are synced as uploads finish,
+	// which leaves the uploads dir, so new ones are still there after a crash
+	async fn flush(&self) {
+		let dir = self.config.uploads_dir.clone();
+		let synced = tokio::task::spawn_blocking(move || -> std::io::Result<()> {
+			std::fs::File::open(dir)?.sync_all()
+		})
+		.await;
+
+		match synced {
+			Ok(Ok(())) => info!("uploads flushed"),
+			Ok(Err(err)) => error!(%err, "failed to flush uploads"),
+			Err(err) => error!(%err, "failed to flush uploads"),
 		}
 	}
 
+	fn path_for_file_id(&self, id: u64) -> PathBuf {
+		self.config.uploads_dir.join(id.to_string())
+	}
+
 	async fn purge(&mut self) {
 		{
 			self.nodes.lock().await.purge();
@@ -110,44 +618,105 @@ impl State {
 		{
 			self.sessions.lock().await.purge();
 		}
+		{
+			self.groups.lock().await.purge();
+		}
+		{
+			self.links.lock().await.purge();
+		}
+		{
+			self.file_requests.lock().await.purge();
+		}
+		{
+			self.devices.lock().await.purge();
+		}
+		{
+			self.recoveries.lock().await.purge();
+		}
+		{
+			self.tokens.lock().await.purge();
+		}
+		{
+			self.challenges.lock().await.purge();
+		}
+		{
+			self.device_challenges.lock().await.purge();
+		}
+		{
+			self.second_steps.lock().await.purge();
+		}
+		{
+			self.events.lock().await.purge();
+		}
+		{
+			self.mk_reads.lock().await.purge();
+		}
+		{
+			self.invites_sent.lock().await.purge();
+		}
+		{
+			self.audit.lock().await.purge();
+		}
+		{
+			self.blobs.lock().await.purge();
+		}
 	}
 
-	async fn user_by_email(&self, email: &str) -> Result<LockedUser, Error> {
-		println!("getting user with email: {}", email);
-
-		let id = self
-			.users
+	// drops whatever has expired by `now` from every store that expires things
+	async fn remove_expired(&self, now: u64) {
+		self.sessions.lock().await.remove_expired(now);
+		self.recoveries.lock().await.remove_expired(now);
+		self.challenges.lock().await.remove_expired(now);
+		self.device_challenges.lock().await.remove_expired(now);
+		self.second_steps.lock().await.remove_expired(now);
+		self.links.lock().await.remove_expired(now);
+		self.file_requests.lock().await.remove_expired(now);
+		self.mk_reads.lock().await.remove_expired(MK_WINDOW, now);
+		self.invites_sent
 			.lock()
 			.await
-			.id_for_email(&email)
-			.ok_or(Error::Unauthorised)?;
+			.remove_expired(INVITE_WINDOW, now);
+	}
 
-		self.user_by_id(id).await
+	// the groups shares exported to count for the user as well
+	async fn groups_of(&self, user_id: u64) -> Vec<u64> {
+		self.groups.lock().await.ids_for_member(user_id)
 	}
 
+	// only the nodes the user has a role on, same as GET /nodes
 	async fn user_by_id(&self, id: u64) -> Result<LockedUser, Error> {
+		let (member_of, groups) = {
+			let groups = self.groups.lock().await;
+
+			(groups.ids_for_member(id), groups.all_groups_for_member(id))
+		};
 		let nodes = self.nodes.lock().await;
 		let shares = self.shares.lock().await;
 		let users = self.users.lock().await;
 
-		println!("getting user by id: {}", id);
+		debug!(user_id = id, "getting user");
 
 		let _priv = users.priv_for_id(id).ok_or(Error::Unauthorised)?;
 		let _pub = users.pub_for_id(id).ok_or(Error::Unauthorised)?;
-		let shares = shares.all_shares_for_user(id);
-		// FIXME: return nodes based on exports and pending uploads (if uploader == users.id_for_email(email))
-		let roots = nodes.get_all();
+		let roots = nodes
+			.get_all()
+			.into_iter()
+			.filter(|node| shares.role(id, &member_of, &nodes, node.id).is_some())
+			.collect();
+		let shares = shares.all_shares_for_user(id, &member_of);
 
 		Ok(LockedUser {
 			encrypted_priv: _priv.clone(),
 			_pub: _pub.clone(),
 			shares,
 			roots,
+			groups,
 		})
 	}
 }
 
 async fn open_file_at_offset(
+	state: &State,
 	file_id: u64,
 	create: bool,
 	append: bool,
@@ -155,9 +724,9 @@ async fn open_file_at_offset(
 	write: bool,
 	offset: u64,
 ) -> Result<tokio::fs::File, Error> {
-	let path = path_for_file_id(file_id);
+	let path = state.path_for_file_id(file_id);
 
-	println!("path to process: {}", path);
+	debug!(target: "uploads", path = %path.display(), "opening blob");
 
 	let mut file = OpenOptions::new()
 		.create(create)
@@ -166,79 +735,291 @@ async fn open_file_at_offset(
 		.write(write)
 		.open(path)
 		.await
-		.map_err(|e| Error::from(e))?;
+		.map_err(|err| match err.kind() {
+			std::io::ErrorKind::NotFound => Error::NoBlob(file_id),
+			_ => Error::from(err),
+		})?;
 
 	file.seek(tokio::io::SeekFrom::Start(offset)).await?;
 
 	Ok(file)
 }
 
-async fn check_auth(headers: &HeaderMap) -> Result<(), Error> {
-	if headers
-		.get("x-uploader-auth")
-		.eq(&Some(&HeaderValue::from_bytes(b"aabb1122").unwrap()))
-	{
+const AUTH_HEADER: &str = "x-uploader-auth";
+
+fn header<'a>(headers: &'a HeaderMap, name: &str) -> Option<&'a str> {
+	headers.get(name).and_then(|value| value.to_str().ok())
+}
+
+// the static token old clients all send, see Config::legacy_token
+fn is_legacy(state: &State, headers: &HeaderMap) -> bool {
+	let legacy = state
+		.config
+		.legacy_token
+		.as_deref()
+		.is_some_and(|token| header(headers, AUTH_HEADER) == Some(token));
+
+	if legacy {
+		warn!("static access token used; the client is to log in instead");
+	}
+
+	legacy
+}
+
+// returns the id of the user the supplied access token belongs to
+async fn check_auth(state: &State, headers: &HeaderMap) -> Result<u64, Error> {
+	let token = header(headers, AUTH_HEADER).ok_or(Error::Unauthenticated)?;
+
+	let user_id = state
+		.tokens
+		.lock()
+		.await
+		.user_for(token)
+		.ok_or(Error::Unauthenticated)?;
+
+	logging::record_user(user_id);
+
+	Ok(user_id)
+}
+
+// fails unless `user_id` has at least `role` for the node
+async fn check_role(state: &State, user_id: u64, node_id: u64, role: Role) -> Result<(), Error> {
+	let groups = state.groups_of(user_id).await;
+	let nodes = state.nodes.lock().await;
+	let shares = state.shares.lock().await;
+
+	if shares.role(user_id, &groups, &nodes, node_id) >= Some(role) {
+		Ok(())
+	} else {
+		Err(Error::Unauthorised)
+	}
+}
+
+// same as check_role, but blobs with no node yet are pending uploads, only for whoever started them
+async fn check_file_role(
+	state: &State,
+	user_id: u64,
+	file_id: u64,
+	role: Role,
+) -> Result<(), Error> {
+	if state.nodes.lock().await.get(file_id).is_some() {
+		return check_role(state, user_id, file_id, role).await;
+	}
+
+	let mut blobs = state.blobs.lock().await;
+	let allowed = match blobs.uploader(file_id) {
+		Some(uploader) => uploader == user_id,
+		// a blob nobody is known to have started, eg from before a restart, is nobody's to take
+		None if role >= Role::Editor => {
+			!tokio::fs::try_exists(state.path_for_file_id(file_id))
+				.await
+				.unwrap_or(true)
+				&& blobs.claim(file_id, user_id)
+		}
+		None => false,
+	};
+
+	if allowed {
 		Ok(())
 	} else {
 		Err(Error::Unauthorised)
 	}
 }
 
+// taking a node away from its parent requires either owning it or editing the parent,
+// so nobody can drop the very root that was shared with them
+fn can_detach(nodes: &Nodes, shares: &Shares, user_id: u64, groups: &[u64], node_id: u64) -> bool {
+	shares.role(user_id, groups, nodes, node_id) == Some(Role::Owner)
+		|| nodes.get(node_id).is_some_and(|node| {
+			shares.role(user_id, groups, nodes, node.parent_id) >= Some(Role::Editor)
+		})
+}
+
+// either a regular user or a public link bound to this very file; for the latter, returns the id
+// of a newly counted download, for the chunks that follow to send back
+async fn check_download_auth(
+	state: &State,
+	file_id: u64,
+	headers: &HeaderMap,
+) -> Result<Option<String>, Error> {
+	if let Some(token) = header(headers, "x-uploader-link") {
+		let pass = header(headers, "x-uploader-link-pass");
+
+		verify_link_pass(state, token, pass).await;
+
+		let mut links = state.links.lock().await;
+
+		// before anything is counted against it
+		if links.get(token).ok_or(Error::NoLink)?.node_id != file_id {
+			return Err(Error::Unauthorised);
+		}
+
+		// a download is counted once, by the chunk which comes without an id
+		match header(headers, "x-uploader-link-download") {
+			Some(download_id) => {
+				links.resume(token, download_id, pass, now())?;
+
+				Ok(None)
+			}
+			None => Ok(Some(links.consume(token, pass, now())?)),
+		}
+	} else if is_legacy(state, headers) {
+		Ok(None)
+	} else {
+		let user_id = check_auth(state, headers).await?;
+
+		check_file_role(state, user_id, file_id, Role::Viewer).await?;
+
+		Ok(None)
+	}
+}
+
+// either a regular user or an anonymous uploader holding a file request token;
+// for the latter, returns how many bytes this very request may carry
+async fn check_upload_auth(
+	state: &State,
+	file_id: u64,
+	headers: &HeaderMap,
+	range: &ContentRange,
+) -> Result<Option<u64>, Error> {
+	if let Some(token) = header(headers, "x-uploader-drop") {
+		let exists = state.nodes.lock().await.get(file_id).is_some()
+			|| tokio::fs::try_exists(state.path_for_file_id(file_id))
+				.await
+				.unwrap_or(true);
+
+		state.file_requests.lock().await.claim_upload(
+			token,
+			file_id,
+			range.end + 1,
+			exists,
+			now(),
+		)?;
+
+		Ok(Some(range.end - range.start + 1))
+	} else if is_legacy(state, headers) {
+		Ok(None)
+	} else {
+		let user_id = check_auth(state, headers).await?;
+
+		check_file_role(state, user_id, file_id, Role::Editor).await?;
+
+		Ok(None)
+	}
+}
+
 async fn process_data_stream(
+	metrics: &Metrics,
 	file_id: u64,
 	mut file: tokio::fs::File,
 	mut stream: BodyDataStream,
+	limit: Option<u64>,
 ) -> Result<StatusCode, Error> {
-	println!("auth passed, working...");
+	debug!(target: "uploads", file_id, "receiving");
+
+	let mut written = 0u64;
 
 	while let Some(chunk) = stream.next().await {
 		let data = chunk?;
 
+		written += data.len() as u64;
+
+		if limit.is_some_and(|limit| written > limit) {
+			return Err(Error::TooLarge);
+		}
+
 		file.write_all(&data).await?;
-		println!("{}: chunk size - {}", file_id, data.len());
+		metrics.bytes_received.inc_by(data.len() as u64);
+		trace!(target: "uploads", file_id, size = data.len(), "chunk");
 	}
 
+	// tokio writes in the background; make sure it's all on disk before saying so
+	file.sync_data().await?;
+
 	Ok(StatusCode::OK)
 }
 
 async fn handle_upload(
+	state: &State,
 	file_id: u64,
 	request: Request<Body>,
 	append: bool,
 ) -> Result<StatusCode, Error> {
-	check_auth(&request.headers()).await?;
-
 	let range = request
 		.headers()
 		.get("Content-Range")
 		.and_then(|header| header.to_str().ok())
 		.and_then(|header_str| ContentRange::from_str(header_str).ok())
-		.ok_or(Error::InvalidRange)?;
+		.filter(|range| range.start <= range.end)
+		.ok_or(Error::InvalidRange(None))?;
+
+	let max = state.config.limits.max_blob_size;
+
+	if range.end >= max {
+		return Err(Error::TooLarge);
+	}
+
+	// whatever the range says, the blob can't grow beyond the limit
+	let limit = check_upload_auth(state, file_id, request.headers(), &range)
+		.await?
+		.map_or(max - range.start, |limit| limit.min(max - range.start));
 
-	println!("received: {}", range.to_string());
+	info!(target: "uploads", file_id, range = %range, "uploading");
+
+	if let Some(length) = range.length {
+		state.blobs.lock().await.expect(file_id, length);
+	}
 
-	let file = open_file_at_offset(file_id, true, append, false, true, range.start).await?;
+	let drop_token = header(request.headers(), "x-uploader-drop").map(str::to_string);
+	let file = open_file_at_offset(state, file_id, true, append, false, true, range.start).await?;
 	let stream = request.into_body().into_data_stream();
+	let res = process_data_stream(&state.metrics, file_id, file, stream, Some(limit)).await;
+
+	// bytes claimed up front for a drop box are given back for whatever didn't arrive
+	if let Some(token) = drop_token {
+		let size = tokio::fs::metadata(state.path_for_file_id(file_id))
+			.await
+			.map_or(0, |metadata| metadata.len());
+
+		state
+			.file_requests
+			.lock()
+			.await
+			.settle(&token, file_id, size);
+	}
 
-	process_data_stream(file_id, file, stream).await
+	res
 }
 
 async fn upload_stream(
-	Path(file_id): Path<u64>,
+	extract::State(state): extract::State<State>,
+	PathParam(file_id): PathParam<u64>,
 	request: Request<Body>,
 ) -> Result<StatusCode, Error> {
-	handle_upload(file_id, request, false).await
+	handle_upload(&state, file_id, request, false).await
 }
 
 async fn upload_ranged(
-	Path(file_id): Path<u64>,
+	extract::State(state): extract::State<State>,
+	PathParam(file_id): PathParam<u64>,
 	request: Request<Body>,
 ) -> Result<StatusCode, Error> {
-	handle_upload(file_id, request, false).await
+	handle_upload(&state, file_id, request, false).await
 }
 
-async fn read_file_chunk(file_id: u64, start: u64, end: u64) -> Result<Vec<u8>, Error> {
-	let mut file = open_file_at_offset(file_id, false, false, true, false, start).await?;
+async fn read_file_chunk(
+	state: &State,
+	file_id: u64,
+	start: u64,
+	end: u64,
+) -> Result<Vec<u8>, Error> {
+	let mut file = open_file_at_offset(state, file_id, false, false, true, false, start).await?;
+	let length = file.metadata().await?.len();
+
+	if end >= length {
+		return Err(Error::InvalidRange(Some(length)));
+	}
+
 	let mut buffer = vec![0; (end - start + 1) as usize];
 
 	file.read_exact(&mut buffer).await?;
@@ -247,21 +1028,29 @@ async fn read_file_chunk(file_id: u64, start: u64, end: u64) -> Result<Vec<u8>,
 }
 
 async fn download_ranged(
-	Path(file_id): Path<u64>,
+	extract::State(state): extract::State<State>,
+	PathParam(file_id): PathParam<u64>,
 	request: Request<Body>,
 ) -> Result<Response<Body>, Error> {
-	check_auth(&request.headers()).await?;
-
 	let range = request
 		.headers()
 		.get("Range")
 		.and_then(|header| header.to_str().ok())
 		.and_then(|header_str| Range::from_str(header_str).ok())
-		.ok_or(Error::InvalidRange)?;
+		.ok_or(Error::InvalidRange(None))?;
+
+	let download_id = check_download_auth(&state, file_id, request.headers()).await?;
+	let chunk = read_file_chunk(&state, file_id, range.start, range.end).await?;
+
+	state.metrics.bytes_served.inc_by(chunk.len() as u64);
+
+	let mut response = Response::builder();
 
-	let chunk = read_file_chunk(file_id, range.start, range.end).await?;
+	if let Some(download_id) = download_id {
+		response = response.header("x-uploader-link-download", download_id);
+	}
 
-	let response = Response::builder()
+	let response = response
 		.status(StatusCode::PARTIAL_CONTENT)
 		.header(
 			"Content-Range",
@@ -279,7 +1068,7 @@ async fn download_ranged(
 	Ok(response)
 }
 
-async fn file_length(file_path: String) -> Option<usize> {
+async fn file_length(file_path: PathBuf) -> Option<usize> {
 	// FIXME: check auth token
 	if let Ok(file) = OpenOptions::new().read(true).open(file_path).await {
 		if let Ok(metadata) = file.metadata().await {
@@ -289,264 +1078,2012 @@ async fn file_length(file_path: String) -> Option<usize> {
 	None
 }
 
-async fn check_file_length(Path(file_id): Path<String>) -> Result<HttpResponse<Body>, Error> {
-	let file_path = format!("uploads/{}", file_id);
-
-	match file_length(file_path).await {
+async fn check_file_length(
+	extract::State(state): extract::State<State>,
+	PathParam(file_id): PathParam<u64>,
+) -> Result<HttpResponse<Body>, Error> {
+	match file_length(state.path_for_file_id(file_id)).await {
 		Some(length) => Ok(Response::builder()
 			.status(StatusCode::OK)
 			.header("Content-Length", length.to_string())
 			.body(Body::empty())
 			.unwrap()),
-		None => Err(Error::Io("File not found".to_string())),
+		None => Err(Error::NoBlob(file_id)),
 	}
 }
 
 async fn add_nodes(
 	extract::State(state): extract::State<State>,
-	extract::Json(new_nodes): extract::Json<Vec<LockedNode>>,
+	headers: HeaderMap,
+	JsonBody(new_nodes): JsonBody<Vec<LockedNode>>,
 ) -> Result<StatusCode, Error> {
+	// resolved before locking nodes
+	let user_id = if header(&headers, "x-uploader-drop").is_some() {
+		None
+	} else {
+		Some(check_auth(&state, &headers).await?)
+	};
+	let groups = match user_id {
+		Some(user_id) => state.groups_of(user_id).await,
+		None => Vec::new(),
+	};
 	let mut nodes = state.nodes.lock().await;
+	let mut roots = Vec::new();
+
+	if let Some(user_id) = user_id {
+		let shares = state.shares.lock().await;
+
+		roots = check_new_nodes(&nodes, &shares, user_id, &groups, &new_nodes)?;
+	} else if let Some(token) = header(&headers, "x-uploader-drop") {
+		// anonymous uploaders may only add nodes to the folder they were invited to
+		let mut requests = state.file_requests.lock().await;
+
+		for node in &new_nodes {
+			requests.claim_node(token, node, nodes.get(node.id).is_some(), now())?;
+		}
+	}
 
 	new_nodes.into_iter().for_each(|n| {
-		println!("inserting {}", n.id);
+		debug!(target: "nodes", node_id = n.id, "inserting");
 
 		nodes.add(n);
 	});
 
+	if let Some(user_id) = user_id {
+		roots
+			.into_iter()
+			.for_each(|id| nodes.set_owner(id, user_id));
+	}
+
 	Ok(StatusCode::CREATED)
 }
 
+// a user may change nodes it can edit and add new ones to folders it can edit; nodes that go into
+// no known folder start a new tree, the roots of which are returned for the user to own
+fn check_new_nodes(
+	nodes: &Nodes,
+	shares: &Shares,
+	user_id: u64,
+	groups: &[u64],
+	new_nodes: &[LockedNode],
+) -> Result<Vec<u64>, Error> {
+	let batch: HashSet<u64> = new_nodes.iter().map(|n| n.id).collect();
+	let mut roots = Vec::new();
+
+	for node in new_nodes {
+		let exists = nodes.get(node.id).is_some();
+
+		if exists && shares.role(user_id, groups, nodes, node.id) < Some(Role::Editor) {
+			return Err(Error::Unauthorised);
+		}
+
+		if nodes.get(node.parent_id).is_some() {
+			if shares.role(user_id, groups, nodes, node.parent_id) < Some(Role::Editor) {
+				return Err(Error::Unauthorised);
+			}
+		} else if !exists && !batch.contains(&node.parent_id) {
+			roots.push(node.id);
+		}
+	}
+
+	Ok(roots)
+}
+
+// a share from someone else is taken only if signed by its registered sender,
+// who must hold at least the exported role on every exported node
+fn check_share(
+	users: &Users,
+	shares: &Shares,
+	nodes: &Nodes,
+	sender_groups: &[u64],
+	share: &LockedShare,
+) -> Result<(), Error> {
+	let sender = share.sender.id();
+	let signed = users.pub_for_id(sender) == Some(&share.sender) && share.verify();
+	let granted = share.export.fs.iter().all(|id| {
+		nodes.get(*id).is_some()
+			&& shares.role(sender, sender_groups, nodes, *id) >= Some(share.export.role)
+	});
+
+	if signed && granted {
+		Ok(())
+	} else {
+		Err(Error::Unauthorised)
+	}
+}
+
 async fn signup(
 	extract::State(state): extract::State<State>,
-	extract::Json(signup): extract::Json<Signup>,
-) -> Result<StatusCode, Error> {
+	JsonBody(signup): JsonBody<Signup>,
+) -> Result<(StatusCode, [(&'static str, String); 1]), Error> {
+	let user = signup.user;
+	let user_id = user._pub.id();
+	let hash = password::hash_async(signup.pass).await;
+	let (own, others): (Vec<LockedShare>, Vec<LockedShare>) = user
+		.shares
+		.into_iter()
+		.partition(|share| share.sender.id() == user_id);
+	let (taken, sender_groups) = {
+		let groups = state.groups.lock().await;
+		let sender_groups: HashMap<u64, Vec<u64>> = others
+			.iter()
+			.map(|share| share.sender.id())
+			.map(|id| (id, groups.ids_for_member(id)))
+			.collect();
+
+		(groups.get(user_id).is_some(), sender_groups)
+	};
 	let mut nodes = state.nodes.lock().await;
 	let mut shares = state.shares.lock().await;
 	let mut users = state.users.lock().await;
-	let user = signup.user;
-	let user_id = user._pub.id();
 
-	user.roots.iter().for_each(|node| {
-		nodes.add(node.clone());
-	});
+	if taken
+		|| users.id_for_email(&signup.email).is_some()
+		|| users.pub_for_id(user_id).is_some()
+		|| users.is_tombstoned(user_id)
+	{
+		return Err(Error::Conflict);
+	}
 
-	user.shares.iter().for_each(|share| {
-		shares.add_share(share.clone());
-	});
-	shares.delete_invite(&signup.email);
+	// only what was shared with the new user, which then decides what it may do to existing nodes
+	for share in &others {
+		if share.export.receiver != user_id {
+			return Err(Error::Unauthorised);
+		}
 
-	users.add_priv(user_id, user.encrypted_priv);
-	users.add_pub(user_id, user._pub);
-	// password should be hashed and stored as well, but no need for now
-	users.add_credentials(&signup.email, user_id);
+		check_share(
+			&users,
+			&shares,
+			&nodes,
+			&sender_groups[&share.sender.id()],
+			share,
+		)?;
+	}
 
-	println!("signed up {}", signup.email);
+	let count = shares.count();
 
-	// you'd generate an access token here for subsequent requests
+	others.into_iter().for_each(|share| shares.add_share(share));
 
-	Ok(StatusCode::CREATED)
-}
+	// the user's own shares may only export the new trees it brings along
+	let roots = check_new_nodes(&nodes, &shares, user_id, &[], &user.roots).and_then(|roots| {
+		let signed = own.iter().all(|share| {
+			share.sender == user._pub
+				&& share.export.receiver == user_id
+				&& share.export.fs.iter().all(|id| roots.contains(id))
+				&& share.verify()
+		});
 
-async fn login(
+		if signed {
+			Ok(roots)
+		} else {
+			Err(Error::Unauthorised)
+		}
+	});
+
+	let roots = match roots {
+		Ok(roots) => roots,
+		Err(err) => {
+			shares.truncate(count);
+
+			return Err(err);
+		}
+	};
+
+	user.roots.into_iter().for_each(|node| nodes.add(node));
+	// whatever new tree the user brings along is theirs
+	roots
+		.into_iter()
+		.for_each(|id| nodes.set_owner(id, user_id));
+	own.into_iter().for_each(|share| shares.add_share(share));
+	shares.delete_invite(&signup.email);
+
+	users.add_priv(user_id, user.encrypted_priv);
+	users.add_pub(user_id, user._pub);
+	users.add_pass_hash(user_id, &hash);
+	users.add_credentials(&signup.email, user_id);
+
+	info!(user_id, email = %logging::email(&signup.email), "signed up");
+
+	let token = state.tokens.lock().await.issue(user_id);
+
+	Ok((StatusCode::CREATED, [(AUTH_HEADER, token)]))
+}
+
+async fn get_invite(
+	extract::State(state): extract::State<State>,
+	PathParam(email): PathParam<String>,
+) -> Result<(StatusCode, Json<Welcome>), Error> {
+	let shares = state.shares.lock().await;
+	let nodes = state.nodes.lock().await;
+
+	info!(target: "shares", email = %logging::email(&email), "getting invite");
+
+	if let Some(invite) = shares.invie_for_mail(&email) {
+		let welcome = Welcome {
+			user_id: invite.user_id,
+			sender: invite.sender.clone(),
+			imports: invite.payload.clone(),
+			sig: invite.sig.clone(),
+			// only what was exported to the invitee
+			nodes: nodes_under(&nodes, &invite.export.fs),
+		};
+
+		// TODO: do I need thi sstatus code?
+		Ok((StatusCode::OK, Json(welcome)))
+	} else {
+		Err(Error::NoInvite)
+	}
+}
+
+// wrapped master keys an account may read per window
+const MK_MAX_READS: usize = 10;
+// seconds
+const MK_WINDOW: u64 = 60 * 60;
+
+// a password-wrapped master key is only ever handed to its owner, a few times per window,
+// so it can't be harvested for offline guessing; every attempt is audited either way
+async fn check_mk_access(state: &State, headers: &HeaderMap, user_id: u64) -> Result<(), Error> {
+	let actor = optional_auth(state, headers).await;
+	let res = if actor != Some(user_id) {
+		Err(Error::Unauthorised)
+	} else if !state
+		.mk_reads
+		.lock()
+		.await
+		.check(user_id, MK_MAX_READS, MK_WINDOW, now())
+	{
+		Err(Error::TooManyAttempts)
+	} else {
+		Ok(())
+	};
+
+	state.audit.lock().await.record(audit::Entry {
+		at: now(),
+		actor,
+		admin: None,
+		subject: user_id,
+		action: audit::Action::MasterKey,
+		allowed: res.is_ok(),
+	});
+
+	res
+}
+
+async fn get_master_key(
+	extract::State(state): extract::State<State>,
+	PathParam(user_id): PathParam<u64>,
+	headers: HeaderMap,
+) -> Result<(StatusCode, Json<encrypted::Encrypted>), Error> {
+	check_mk_access(&state, &headers, user_id).await?;
+
+	let users = state.users.lock().await;
+
+	info!(user_id, "getting mk");
+
+	if let Some(mk) = users.mk_for_id(user_id) {
+		Ok((StatusCode::OK, Json(mk.clone())))
+	} else {
+		Err(Error::NotFound(user_id))
+	}
+}
+
+// swaps in a master key wrapped with a new password and logs out every other session
+async fn change_lock(
+	extract::State(state): extract::State<State>,
+	PathParam(user_id): PathParam<u64>,
+	headers: HeaderMap,
+	JsonBody(change): JsonBody<LockChange>,
+) -> Result<StatusCode, Error> {
+	check_self(&state, &headers, user_id).await?;
+
+	let stored = state.users.lock().await.pass_hash_for_id(user_id).is_some();
+	// an imported account has no password until its first change
+	let checked = if stored {
+		Some(
+			check_pass(&state, user_id, &change.old_pass)
+				.await
+				.ok_or(Error::Unauthorised)?,
+		)
+	} else {
+		None
+	};
+	let hash = password::hash_async(change.new_pass).await;
+
+	if !state
+		.users
+		.lock()
+		.await
+		.change_lock(user_id, checked.as_deref(), &hash, change.lock)
+	{
+		return Err(Error::Unauthorised);
+	}
+
+	if let Some(token) = header(&headers, AUTH_HEADER) {
+		state.tokens.lock().await.revoke_others(user_id, token);
+	}
+
+	info!(user_id, "lock changed");
+
+	Ok(StatusCode::NO_CONTENT)
+}
+
+// the hash `pass` matched, if it did; argon2 runs on the blocking pool with the users unlocked
+async fn check_pass(state: &State, user_id: u64, pass: &str) -> Option<String> {
+	let hash = state
+		.users
+		.lock()
+		.await
+		.pass_hash_for_id(user_id)
+		.cloned()?;
+
+	password::verify_async(hash.clone(), pass.to_string())
+		.await
+		.then_some(hash)
+}
+
+// fails unless the access token belongs to `user_id` itself
+async fn check_self(state: &State, headers: &HeaderMap, user_id: u64) -> Result<(), Error> {
+	if check_auth(state, headers).await? == user_id {
+		Ok(())
+	} else {
+		Err(Error::Unauthorised)
+	}
+}
+
+async fn set_recovery_code(
+	extract::State(state): extract::State<State>,
+	PathParam(user_id): PathParam<u64>,
+	headers: HeaderMap,
+	JsonBody(code): JsonBody<recovery::NewCode>,
+) -> Result<StatusCode, Error> {
+	check_self(&state, &headers, user_id).await?;
+
+	let proof = password::hash_async(code.proof).await;
+
+	state
+		.recoveries
+		.lock()
+		.await
+		.set_code(user_id, proof, code.master_key);
+
+	info!(user_id, "recovery code set");
+
+	Ok(StatusCode::NO_CONTENT)
+}
+
+async fn delete_recovery_code(
+	extract::State(state): extract::State<State>,
+	PathParam(user_id): PathParam<u64>,
+	headers: HeaderMap,
+) -> Result<StatusCode, Error> {
+	check_self(&state, &headers, user_id).await?;
+
+	if state.recoveries.lock().await.remove_code(user_id) {
+		Ok(StatusCode::NO_CONTENT)
+	} else {
+		Err(Error::NotFound(user_id))
+	}
+}
+
+// the master key wrapped to the contact's identity::Public
+async fn add_recovery_contact(
+	extract::State(state): extract::State<State>,
+	PathParam((user_id, contact_id)): PathParam<(u64, u64)>,
+	headers: HeaderMap,
+	JsonBody(master_key): JsonBody<identity::Encrypted>,
+) -> Result<StatusCode, Error> {
+	check_self(&state, &headers, user_id).await?;
+
+	if state.users.lock().await.pub_for_id(contact_id).is_none() {
+		return Err(Error::NotFound(contact_id));
+	}
+
+	state
+		.recoveries
+		.lock()
+		.await
+		.add_contact(user_id, contact_id, master_key);
+
+	info!(user_id, contact_id, "recovery contact added");
+
+	Ok(StatusCode::NO_CONTENT)
+}
+
+async fn delete_recovery_contact(
+	extract::State(state): extract::State<State>,
+	PathParam((user_id, contact_id)): PathParam<(u64, u64)>,
+	headers: HeaderMap,
+) -> Result<StatusCode, Error> {
+	check_self(&state, &headers, user_id).await?;
+
+	if state
+		.recoveries
+		.lock()
+		.await
+		.remove_contact(user_id, contact_id)
+	{
+		Ok(StatusCode::NO_CONTENT)
+	} else {
+		Err(Error::NotFound(contact_id))
+	}
+}
+
+async fn id_for_email(state: &State, email: &str) -> Result<u64, Error> {
+	state
+		.users
+		.lock()
+		.await
+		.id_for_email(email)
+		.ok_or(Error::Unauthorised)
+}
+
+async fn recover_with_code(
+	extract::State(state): extract::State<State>,
+	JsonBody(req): JsonBody<recovery::CodeRecovery>,
+) -> Result<(StatusCode, Json<recovery::Started>), Error> {
+	let user_id = id_for_email(&state, &req.email).await?;
+
+	info!(user_id, "recovering with a code");
+
+	let proof =
+		state
+			.recoveries
+			.lock()
+			.await
+			.code_proof(user_id, state.config.ttls.recovery, now())?;
+
+	if !password::verify_async(proof.clone(), req.proof).await {
+		return Err(recovery::Error::NotAllowed.into());
+	}
+
+	let token = state.recoveries.lock().await.recover_with_code(
+		user_id,
+		&proof,
+		state.config.ttls.recovery,
+		now(),
+	)?;
+
+	Ok((StatusCode::CREATED, Json(recovery::Started { token })))
+}
+
+// the token goes to the account's mail rather than back to the caller, so a contact can't start
+// one on their own; the owner is told on their devices too and has recovery::DELAY to cancel it
+async fn recover_with_contact(
+	extract::State(state): extract::State<State>,
+	JsonBody(req): JsonBody<recovery::ContactRecovery>,
+) -> Result<StatusCode, Error> {
+	let user_id = id_for_email(&state, &req.email).await?;
+
+	info!(
+		user_id,
+		contact_id = req.contact_id,
+		"recovering via a contact"
+	);
+
+	let (token, request_id) = state.recoveries.lock().await.recover_with_contact(
+		user_id,
+		req.contact_id,
+		req.eph_x448,
+		state.config.ttls.recovery,
+		state.config.ttls.recovery_delay,
+		now(),
+	)?;
+	let link = mailer::recovery_link(&state.config.invite_url, &token);
+
+	if let Err(err) = state.mailer.send(mailer::recovery(&req.email, &link)).await {
+		state.recoveries.lock().await.cancel(user_id, &request_id);
+
+		return Err(err.into());
+	}
+
+	state.events.lock().await.push(
+		user_id,
+		Event::RecoveryStarted {
+			request_id,
+			contact_id: req.contact_id,
+		},
+		now(),
+	);
+
+	Ok(StatusCode::ACCEPTED)
+}
+
+// by the owner, for a recovery they didn't start
+async fn cancel_recovery(
+	extract::State(state): extract::State<State>,
+	PathParam((user_id, request_id)): PathParam<(u64, String)>,
+	headers: HeaderMap,
+) -> Result<StatusCode, Error> {
+	check_self(&state, &headers, user_id).await?;
+
+	if !state.recoveries.lock().await.cancel(user_id, &request_id) {
+		return Err(Error::NotFound(user_id));
+	}
+
+	info!(user_id, "recovery cancelled");
+
+	Ok(StatusCode::NO_CONTENT)
+}
+
+// recoveries the caller was asked to help with
+async fn get_recovery_requests(
+	extract::State(state): extract::State<State>,
+	headers: HeaderMap,
+) -> Result<Json<Vec<recovery::ContactRequest>>, Error> {
+	let user_id = check_auth(&state, &headers).await?;
+
+	Ok(Json(
+		state
+			.recoveries
+			.lock()
+			.await
+			.requests_for_contact(user_id, now()),
+	))
+}
+
+// the master key re-wrapped by the contact to the ephemeral key of the recovery
+async fn approve_recovery_request(
+	extract::State(state): extract::State<State>,
+	PathParam(request_id): PathParam<String>,
+	headers: HeaderMap,
+	JsonBody(master_key): JsonBody<identity::Encrypted>,
+) -> Result<StatusCode, Error> {
+	let user_id = check_auth(&state, &headers).await?;
+
+	state
+		.recoveries
+		.lock()
+		.await
+		.approve(user_id, &request_id, master_key, now())?;
+
+	info!(user_id, "recovery request approved");
+
+	Ok(StatusCode::NO_CONTENT)
+}
+
+async fn get_recovery_wrapping(
+	extract::State(state): extract::State<State>,
+	PathParam(token): PathParam<String>,
+) -> Result<Json<recovery::Wrapping>, Error> {
+	let recoveries = state.recoveries.lock().await;
+
+	Ok(Json(recoveries.wrapping(&token, now())?.clone()))
+}
+
+// sets the new password and lock, logs out every session and signs in afresh
+async fn complete_recovery(
+	extract::State(state): extract::State<State>,
+	PathParam(token): PathParam<String>,
+	JsonBody(reset): JsonBody<Reset>,
+) -> Result<(StatusCode, [(&'static str, String); 1]), Error> {
+	let user_id = state.recoveries.lock().await.complete(&token, now())?;
+	let hash = password::hash_async(reset.new_pass).await;
+
+	if !state
+		.users
+		.lock()
+		.await
+		.reset_lock(user_id, &hash, reset.lock)
+	{
+		return Err(Error::NotFound(user_id));
+	}
+
+	// whatever devices there were are presumed lost, so the new token needn't be bound to any
+	state.devices.lock().await.remove_all_for(user_id);
+
+	let token = {
+		let mut tokens = state.tokens.lock().await;
+
+		tokens.revoke_all_for(user_id);
+		tokens.issue(user_id)
+	};
+
+	info!(user_id, "recovered");
+
+	Ok((StatusCode::OK, [(AUTH_HEADER, token)]))
+}
+
+// the user comes with its Lock, so it's guarded the same way as the master key
+async fn get_user(
+	extract::State(state): extract::State<State>,
+	PathParam(user_id): PathParam<u64>,
+	headers: HeaderMap,
+) -> Result<(StatusCode, Json<LockedUser>), Error> {
+	check_mk_access(&state, &headers, user_id).await?;
+
+	let user = state.user_by_id(user_id).await?;
+
+	Ok((StatusCode::OK, Json(user)))
+}
+
+// bytes per read when streaming blobs into an export
+const EXPORT_CHUNK_SIZE: u64 = 64 * 1024;
+
+// exactly `size` bytes of a blob; one that shrank in the meantime fails the stream
+fn read_blob(path: PathBuf, size: u64) -> impl Stream<Item = Result<Vec<u8>, std::io::Error>> {
+	stream::try_unfold((None, size), move |(file, left)| {
+		let path = path.clone();
+
+		async move {
+			if left == 0 {
+				return Ok(None);
+			}
+
+			let mut file = match file {
+				Some(file) => file,
+				None => tokio::fs::File::open(path).await?,
+			};
+			let mut chunk = vec![0; left.min(EXPORT_CHUNK_SIZE) as usize];
+			let read = file.read(&mut chunk).await?;
+
+			if read == 0 {
+				return Err(std::io::ErrorKind::UnexpectedEof.into());
+			}
+
+			chunk.truncate(read);
+
+			Ok(Some((chunk, (Some(file), left - read as u64))))
+		}
+	})
+}
+
+// a tar of the user, every node visible to it and the blobs uploaded for those, streamed from disk;
+// it holds the Lock, so it's guarded the same way as the master key
+async fn export_user(
+	extract::State(state): extract::State<State>,
+	PathParam(user_id): PathParam<u64>,
+	headers: HeaderMap,
+) -> Result<Response<Body>, Error> {
+	check_mk_access(&state, &headers, user_id).await?;
+
+	let mut user = state.user_by_id(user_id).await?;
+	let groups = state.groups_of(user_id).await;
+	let owned = {
+		let nodes = state.nodes.lock().await;
+		let shares = state.shares.lock().await;
+
+		user.roots
+			.retain(|node| shares.role(user_id, &groups, &nodes, node.id).is_some());
+
+		nodes.owned_by(user_id)
+	};
+	let email = state
+		.users
+		.lock()
+		.await
+		.email_for_id(user_id)
+		.cloned()
+		.ok_or(Error::NotFound(user_id))?;
+	let at = now();
+	let mut blobs = Vec::new();
+
+	for node in &user.roots {
+		let path = state.path_for_file_id(node.id);
+
+		if let Ok(metadata) = tokio::fs::metadata(&path).await {
+			let size = metadata.len();
+
+			blobs.push((
+				archive::entry_header(&archive::blob_path(node.id), size, at)?,
+				path,
+				size,
+			));
+		}
+	}
+
+	let manifest = serde_json::to_vec(&Manifest {
+		version: archive::VERSION,
+		email,
+		user,
+		owned,
+	})
+	.map_err(|err| Error::Io(format!("{}", err)))?;
+	let size = manifest.len() as u64;
+	let head = [
+		archive::entry_header(archive::MANIFEST, size, at)?,
+		manifest,
+		archive::padding(size),
+	]
+	.concat();
+
+	info!(user_id, blobs = blobs.len(), "exporting");
+
+	let body = stream::once(async { Ok(head) })
+		.chain(stream::iter(blobs).flat_map(|(header, path, size)| {
+			stream::once(async { Ok(header) })
+				.chain(read_blob(path, size))
+				.chain(stream::once(async move { Ok(archive::padding(size)) }))
+		}))
+		.chain(stream::once(async { Ok(archive::end()) }));
+
+	Ok(Response::builder()
+		.status(StatusCode::OK)
+		.header("Content-Type", "application/x-tar")
+		.header(
+			"Content-Disposition",
+			format!("attachment; filename=\"{}.tar\"", user_id),
+		)
+		.body(Body::from_stream(body))
+		.unwrap())
+}
+
+// recreates an exported account along with its own trees; shared ones stay with their owners.
+// admin only, and no password comes along: the owner signs in with their key and sets a new one
+async fn import_user(
+	extract::State(state): extract::State<State>,
+	Admin(admin): Admin,
+	request: Request<Body>,
+) -> Result<(StatusCode, Json<Imported>), Error> {
+	let token = token::generate();
+	let path = env::temp_dir().join(format!("uploader-import-{}.tar", token));
+	// inside the uploads dir so blobs can be renamed into place; not a number, so not a blob
+	let staging = state.config.uploads_dir.join(format!(".import-{}", token));
+	let res = import_archive(
+		&state,
+		&path,
+		&staging,
+		request.into_body().into_data_stream(),
+	)
+	.await;
+
+	_ = tokio::fs::remove_file(&path).await;
+	_ = tokio::fs::remove_dir_all(&staging).await;
+
+	if let Ok((_, Json(imported))) = &res {
+		record_admin(&state, admin, imported.user_id, audit::Action::Import).await;
+	}
+
+	res
+}
+
+async fn import_archive(
+	state: &State,
+	path: &std::path::Path,
+	staging: &std::path::Path,
+	mut stream: BodyDataStream,
+) -> Result<(StatusCode, Json<Imported>), Error> {
+	let mut file = tokio::fs::File::create(path).await?;
+	let mut written = 0u64;
+
+	while let Some(chunk) = stream.next().await {
+		let data = chunk?;
+
+		written += data.len() as u64;
+
+		if written > state.config.limits.max_import_size {
+			return Err(Error::TooLarge);
+		}
+
+		file.write_all(&data).await?;
+	}
+
+	file.flush().await?;
+
+	let manifest = {
+		let path = path.to_path_buf();
+
+		tokio::task::spawn_blocking(move || archive::read_manifest(&path)).await??
+	};
+	let owned = archive::owned_nodes(&manifest);
+
+	// blobs first, under their archive ids and with nothing locked; moved in place once the ids are final
+	{
+		let path = path.to_path_buf();
+		let ids: HashMap<u64, u64> = owned.iter().map(|node| (node.id, node.id)).collect();
+		let staging = staging.to_path_buf();
+
+		tokio::fs::create_dir_all(&staging).await?;
+		tokio::task::spawn_blocking(move || archive::unpack_blobs(&path, &ids, &staging)).await??;
+	}
+
+	let Manifest {
+		email,
+		user,
+		owned: top,
+		..
+	} = manifest;
+	let user_id = user._pub.id();
+	let count = user.shares.len();
+	let (own, others): (Vec<LockedShare>, Vec<LockedShare>) = user
+		.shares
+		.into_iter()
+		.partition(|share| share.sender.id() == user_id);
+	let (taken, sender_groups) = {
+		let groups = state.groups.lock().await;
+		let sender_groups: HashMap<u64, Vec<u64>> = others
+			.iter()
+			.map(|share| share.sender.id())
+			.map(|id| (id, groups.ids_for_member(id)))
+			.collect();
+
+		(groups.get(user_id).is_some(), sender_groups)
+	};
+	let mut nodes = state.nodes.lock().await;
+	let mut shares = state.shares.lock().await;
+	let mut users = state.users.lock().await;
+
+	if taken
+		|| users.id_for_email(&email).is_some()
+		|| users.pub_for_id(user_id).is_some()
+		|| users.is_tombstoned(user_id)
+	{
+		return Err(Error::Conflict);
+	}
+
+	let (imported, ids) = archive::rename(owned, |id| nodes.get(id).is_some());
+
+	let mut moved = Vec::new();
+
+	for (old, new) in &ids {
+		let to = state.path_for_file_id(*new);
+
+		match tokio::fs::rename(staging.join(old.to_string()), &to).await {
+			Ok(()) => moved.push(to),
+			Err(err) if err.kind() == std::io::ErrorKind::NotFound => {}
+			Err(err) => {
+				// nothing is inserted yet, so the ones moved already would belong to no node
+				for path in moved {
+					_ = tokio::fs::remove_file(path).await;
+				}
+
+				return Err(err.into());
+			}
+		}
+	}
+
+	// shares only hold as far as their senders' rights on this server go, same as on signup;
+	// the user's own may only export imported trees that kept their ids, or the signature breaks
+	let kept: HashSet<u64> = ids
+		.iter()
+		.filter(|(old, new)| old == new)
+		.map(|(id, _)| *id)
+		.collect();
+	let valid: Vec<LockedShare> = others
+		.into_iter()
+		.filter(|share| {
+			share.export.receiver == user_id
+				&& check_share(
+					&users,
+					&shares,
+					&nodes,
+					&sender_groups[&share.sender.id()],
+					share,
+				)
+				.is_ok()
+		})
+		.chain(own.into_iter().filter(|share| {
+			share.sender == user._pub
+				&& share.export.receiver == user_id
+				&& share.export.fs.iter().all(|id| kept.contains(id))
+				&& share.verify()
+		}))
+		.collect();
+
+	if valid.len() < count {
+		warn!(
+			user_id,
+			dropped = count - valid.len(),
+			"dropped shares that do not hold"
+		);
+	}
+
+	imported.into_iter().for_each(|node| nodes.add(node));
+	top.iter()
+		.filter_map(|id| ids.get(id))
+		.for_each(|id| nodes.set_owner(*id, user_id));
+	valid.into_iter().for_each(|share| shares.add_share(share));
+	users.add_priv(user_id, user.encrypted_priv);
+	users.add_pub(user_id, user._pub);
+	users.add_credentials(&email, user_id);
+
+	info!(user_id, email = %logging::email(&email), "imported");
+
+	let renamed = ids.into_iter().filter(|(old, new)| old != new).collect();
+
+	Ok((StatusCode::CREATED, Json(Imported { user_id, renamed })))
+}
+
+// wipes the account: keys, credentials, shares either way, groups, devices and every owned tree
+// not handed over to `transfer_to`, blobs included; only a tombstone remains
+async fn delete_user(
+	extract::State(state): extract::State<State>,
+	PathParam(user_id): PathParam<u64>,
+	headers: HeaderMap,
+	JsonBody(deletion): JsonBody<Deletion>,
+) -> Result<StatusCode, Error> {
+	check_self(&state, &headers, user_id).await?;
+	check_pass(&state, user_id, &deletion.pass)
+		.await
+		.ok_or(Error::Unauthorised)?;
+
+	{
+		let mut users = state.users.lock().await;
+
+		if users.has_totp(user_id)
+			&& !deletion
+				.code
+				.as_deref()
+				.is_some_and(|code| users.check_second_factor(user_id, code, now()))
+		{
+			return Err(Error::Unauthorised);
+		}
+
+		if deletion
+			.transfer_to
+			.is_some_and(|id| id == user_id || users.pub_for_id(id).is_none())
+		{
+			return Err(Error::Conflict);
+		}
+	}
+
+	remove_account(&state, user_id, deletion.transfer_to).await;
+
+	info!(user_id, "deleted user");
+
+	Ok(StatusCode::NO_CONTENT)
+}
+
+// everything the user owned goes, save trees `heir` has been shared, which become theirs
+async fn remove_account(state: &State, user_id: u64, heir: Option<u64>) {
+	let heir_groups = match heir {
+		Some(heir) => state.groups_of(heir).await,
+		None => Vec::new(),
+	};
+	let removed = {
+		let mut nodes = state.nodes.lock().await;
+		let mut shares = state.shares.lock().await;
+		let mut removed = Vec::new();
+
+		for root in nodes.owned_by(user_id) {
+			match heir {
+				// only whoever has been shared the tree can read it
+				Some(heir) if shares.role(heir, &heir_groups, &nodes, root).is_some() => {
+					nodes.set_owner(root, heir);
+				}
+				_ => {
+					removed.extend(nodes.subtree(root));
+					nodes.remove(root);
+				}
+			}
+		}
+
+		shares.remove_for_user(user_id);
+		shares.remove_for_nodes(&removed.iter().copied().collect());
+
+		removed
+	};
+	let groups = state.groups.lock().await.remove_all_for(user_id);
+
+	{
+		let mut shares = state.shares.lock().await;
+
+		groups
+			.iter()
+			.for_each(|group_id| shares.remove_for_receiver(*group_id));
+	}
+
+	state.users.lock().await.remove(user_id, now());
+	state.devices.lock().await.remove_all_for(user_id);
+	state.recoveries.lock().await.remove_all_for(user_id);
+	state.file_requests.lock().await.remove_for_owner(user_id);
+	state.tokens.lock().await.revoke_all_for(user_id);
+	state.events.lock().await.remove_all_for(user_id);
+
+	for id in removed {
+		state.links.lock().await.remove_for_node(id);
+		state.file_requests.lock().await.remove_for_node(id);
+		remove_file(state, id).await;
+	}
+}
+
+async fn get_audit(
+	extract::State(state): extract::State<State>,
+	PathParam(user_id): PathParam<u64>,
+	headers: HeaderMap,
+) -> Result<Json<Vec<audit::Entry>>, Error> {
+	check_self(&state, &headers, user_id).await?;
+
+	Ok(Json(state.audit.lock().await.for_subject(user_id)))
+}
+
+// invites an account may send per window
+const INVITE_MAX: usize = 20;
+// seconds
+const INVITE_WINDOW: u64 = 60 * 60;
+
+async fn invite(
+	extract::State(state): extract::State<State>,
+	headers: HeaderMap,
+	JsonBody(invite): JsonBody<Invite>,
+) -> Result<StatusCode, Error> {
+	let user_id = check_auth(&state, &headers).await?;
+	let email = invite.email.clone();
+
+	info!(target: "shares", email = %logging::email(&email), "inviting");
+
+	// only on one's own behalf, or the mail would go out in someone else's name
+	if invite.sender.id() != user_id {
+		return Err(Error::Unauthorised);
+	}
+
+	// someone else's pending invite stays as it is
+	if !state.shares.lock().await.can_invite(&email, user_id) {
+		return Err(Error::Conflict);
+	}
+
+	if !state
+		.invites_sent
+		.lock()
+		.await
+		.check(user_id, INVITE_MAX, INVITE_WINDOW, now())
+	{
+		return Err(Error::TooManyAttempts);
+	}
+
+	let link = mailer::invite_link(&state.config.invite_url, &email);
+
+	// stored once sent, so a failed mail leaves nothing behind
+	state.mailer.send(mailer::invite(&email, &link)).await?;
+
+	if !state.shares.lock().await.add_invite(invite, &email, now()) {
+		return Err(Error::Conflict);
+	}
+
+	info!(target: "shares", email = %logging::email(&email), "invite sent");
+
+	Ok(StatusCode::CREATED)
+}
+
+async fn lock_session(
+	extract::State(state): extract::State<State>,
+	PathParam(token_id): PathParam<String>,
+	JsonBody(token): JsonBody<shares::Seed>,
+) -> Result<StatusCode, Error> {
+	let mut sessions = state.sessions.lock().await;
+
+	info!(session = %logging::token(&token_id), "locking session");
+
+	sessions.add_token(&token_id, token, state.config.ttls.session, now());
+
+	Ok(StatusCode::CREATED)
+}
+
+// with ?wait=N, waits up to N seconds for the other device to lock the session instead of failing right away
+async fn unlock_session(
+	extract::State(state): extract::State<State>,
+	PathParam(token_id): PathParam<String>,
+	QueryParam(wait): QueryParam<sessions::Wait>,
+	Caller(caller): Caller,
+) -> Result<(StatusCode, Json<shares::Seed>), Error> {
+	let deadline = deadline(&wait);
+	let mut missed = false;
+
+	// session ids are expected to be unguessable; not finding one counts against the caller's
+	// budget, once per request, whether it goes on to wait or not
+	info!(session = %logging::token(&token_id), "unlocking session");
+
+	loop {
+		let waiter = {
+			let mut sessions = state.sessions.lock().await;
+
+			if let Some(token) = sessions.consume_token_by_id(&token_id, now()) {
+				return Ok((StatusCode::OK, Json(token)));
+			}
+
+			if !missed {
+				sessions.miss(caller, now())?;
+				missed = true;
+			}
+
+			if tokio::time::Instant::now() >= deadline {
+				// TODO: a different error code or return a random token?
+				return Err(Error::Unauthorised);
+			}
+
+			sessions.waiter(&token_id)?
+		};
+
+		// either locked or timed out, both are checked above
+		_ = tokio::time::timeout_at(deadline, waiter.notified()).await;
+	}
+}
+
+// when a long-poll gives up
+fn deadline(wait: &sessions::Wait) -> tokio::time::Instant {
+	let wait = wait.wait.unwrap_or(0).min(sessions::MAX_WAIT);
+
+	tokio::time::Instant::now() + Duration::from_secs(wait)
+}
+
+const PAIRING_HEADER: &str = "x-uploader-pairing";
+
+// the initiator is the owner of the channel, signed in as usual; the joiner presents the token it got on join
+fn pairing_side(
+	channel: &pairing::Channel,
+	user_id: Option<u64>,
+	headers: &HeaderMap,
+) -> Result<Side, Error> {
+	if header(headers, PAIRING_HEADER).is_some_and(|token| channel.is_joiner(token)) {
+		Ok(Side::Joiner)
+	} else if user_id == Some(channel.owner_id) {
+		Ok(Side::Initiator)
+	} else {
+		Err(Error::Unauthorised)
+	}
+}
+
+// a new device can't sign in yet, so its requests go without an access token
+async fn optional_auth(state: &State, headers: &HeaderMap) -> Option<u64> {
+	check_auth(state, headers).await.ok()
+}
+
+// opened by a signed in device; the id is then passed to the new device, eg via a qr code.
+// no keys yet: the initiator's goes in only once the joiner has committed to its own
+async fn open_pairing(
+	extract::State(state): extract::State<State>,
+	PathParam(channel_id): PathParam<String>,
+	headers: HeaderMap,
+) -> Result<StatusCode, Error> {
+	let user_id = check_auth(&state, &headers).await?;
+
+	info!(channel = %logging::token(&channel_id), "opening pairing channel");
+
+	state.sessions.lock().await.open_channel(
+		&channel_id,
+		user_id,
+		state.config.ttls.session,
+		now(),
+	)?;
+
+	Ok(StatusCode::CREATED)
+}
+
+async fn join_pairing(
+	extract::State(state): extract::State<State>,
+	PathParam(channel_id): PathParam<String>,
+	Caller(caller): Caller,
+	JsonBody(commitment): JsonBody<pairing::Commitment>,
+) -> Result<Json<pairing::Joined>, Error> {
+	info!(channel = %logging::token(&channel_id), "joining pairing channel");
+
+	let joined =
+		state
+			.sessions
+			.lock()
+			.await
+			.join_channel(&channel_id, commitment, caller, now())?;
+
+	Ok(Json(joined))
+}
+
+// with ?wait=N, waits up to N seconds for the new device to join
+async fn get_pairing_commitment(
+	extract::State(state): extract::State<State>,
+	PathParam(channel_id): PathParam<String>,
+	headers: HeaderMap,
+	QueryParam(wait): QueryParam<sessions::Wait>,
+) -> Result<Json<pairing::Commitment>, Error> {
+	let user_id = check_auth(&state, &headers).await?;
+	let deadline = deadline(&wait);
+
+	loop {
+		let waiter = {
+			let mut sessions = state.sessions.lock().await;
+			let channel = sessions
+				.channel(&channel_id, now())
+				.ok_or(Error::NoSession)?;
+
+			if channel.owner_id != user_id {
+				return Err(Error::Unauthorised);
+			}
+
+			if let Some(commitment) = channel.commitment() {
+				return Ok(Json(commitment.clone()));
+			}
+
+			if tokio::time::Instant::now() >= deadline {
+				return Err(Error::NoSession);
+			}
+
+			channel.waiter(Side::Initiator)
+		};
+
+		_ = tokio::time::timeout_at(deadline, waiter.notified()).await;
+	}
+}
+
+// the initiator's key once the joiner has committed, then the joiner's once it has seen the initiator's;
+// a joiner's key not matching its commitment ends the channel
+async fn post_pairing_key(
+	extract::State(state): extract::State<State>,
+	PathParam(channel_id): PathParam<String>,
+	headers: HeaderMap,
+	JsonBody(hello): JsonBody<pairing::Hello>,
+) -> Result<StatusCode, Error> {
+	let user_id = optional_auth(&state, &headers).await;
+	let mut sessions = state.sessions.lock().await;
+	let channel = sessions
+		.channel(&channel_id, now())
+		.ok_or(Error::NoSession)?;
+	let side = pairing_side(channel, user_id, &headers)?;
+
+	if let Err(err) = channel.reveal(side, hello) {
+		if err == pairing::Error::Mismatch {
+			sessions.close_channel(&channel_id);
+		}
+
+		return Err(err.into());
+	}
+
+	Ok(StatusCode::CREATED)
+}
+
+// the other side's key; with ?wait=N, waits up to N seconds for it to be revealed
+async fn get_pairing_peer(
 	extract::State(state): extract::State<State>,
-	extract::Json(login): extract::Json<Login>,
-) -> Result<(StatusCode, Json<LockedUser>), Error> {
-	println!("loggin in via email/pass: {}", login.email);
+	PathParam(channel_id): PathParam<String>,
+	headers: HeaderMap,
+	QueryParam(wait): QueryParam<sessions::Wait>,
+) -> Result<Json<pairing::Hello>, Error> {
+	let user_id = optional_auth(&state, &headers).await;
+	let deadline = deadline(&wait);
+
+	loop {
+		let waiter = {
+			let mut sessions = state.sessions.lock().await;
+			let channel = sessions
+				.channel(&channel_id, now())
+				.ok_or(Error::NoSession)?;
+			let side = pairing_side(channel, user_id, &headers)?;
+
+			if let Some(peer) = channel.peer(side) {
+				return Ok(Json(peer.clone()));
+			}
+
+			if tokio::time::Instant::now() >= deadline {
+				return Err(Error::NoSession);
+			}
+
+			channel.waiter(side)
+		};
 
-	let user = state.user_by_email(&login.email).await?;
+		_ = tokio::time::timeout_at(deadline, waiter.notified()).await;
+	}
+}
 
-	println!("logged in {}", login.email);
+// called by the initiator once the user has seen the same sas on both devices; a different one ends the channel
+async fn confirm_pairing(
+	extract::State(state): extract::State<State>,
+	PathParam(channel_id): PathParam<String>,
+	headers: HeaderMap,
+	JsonBody(confirmation): JsonBody<pairing::Confirmation>,
+) -> Result<StatusCode, Error> {
+	let user_id = check_auth(&state, &headers).await?;
+	let mut sessions = state.sessions.lock().await;
+	let channel = sessions
+		.channel(&channel_id, now())
+		.ok_or(Error::NoSession)?;
 
-	// you'd generate an access token here for subsequent requests
+	if channel.owner_id != user_id {
+		return Err(Error::Unauthorised);
+	}
 
-	Ok((StatusCode::OK, Json(user)))
+	if let Err(err) = channel.confirm(&confirmation) {
+		if err == pairing::Error::Mismatch {
+			sessions.close_channel(&channel_id);
+		}
+
+		return Err(err.into());
+	}
+
+	info!(channel = %logging::token(&channel_id), "pairing channel confirmed");
+
+	Ok(StatusCode::NO_CONTENT)
 }
 
-async fn get_invite(
+// either side may walk away, eg when the sas does not match
+async fn close_pairing(
 	extract::State(state): extract::State<State>,
-	Path(email): Path<String>,
-) -> Result<(StatusCode, Json<Welcome>), Error> {
-	let shares = state.shares.lock().await;
-	let nodes = state.nodes.lock().await;
+	PathParam(channel_id): PathParam<String>,
+	headers: HeaderMap,
+) -> Result<StatusCode, Error> {
+	let user_id = optional_auth(&state, &headers).await;
+	let mut sessions = state.sessions.lock().await;
+	let channel = sessions
+		.channel(&channel_id, now())
+		.ok_or(Error::NoSession)?;
 
-	println!("getting invite: {}", email);
+	pairing_side(channel, user_id, &headers)?;
+	sessions.close_channel(&channel_id);
 
-	if let Some(invite) = shares.invie_for_mail(&email) {
-		let welcome = Welcome {
-			user_id: invite.user_id,
-			sender: invite.sender.clone(),
-			imports: invite.payload.clone(),
-			sig: invite.sig.clone(),
-			// FIXME: return nodes based on exports and pending uploads (if uploader == users.id_for_email(email))
-			nodes: nodes.get_all(),
+	info!(channel = %logging::token(&channel_id), "pairing channel closed");
+
+	Ok(StatusCode::NO_CONTENT)
+}
+
+async fn post_pairing_message(
+	extract::State(state): extract::State<State>,
+	PathParam(channel_id): PathParam<String>,
+	headers: HeaderMap,
+	JsonBody(msg): JsonBody<pairing::Message>,
+) -> Result<StatusCode, Error> {
+	let user_id = optional_auth(&state, &headers).await;
+	let mut sessions = state.sessions.lock().await;
+	let channel = sessions
+		.channel(&channel_id, now())
+		.ok_or(Error::NoSession)?;
+	let side = pairing_side(channel, user_id, &headers)?;
+
+	channel.post(side, msg)?;
+
+	Ok(StatusCode::CREATED)
+}
+
+// drains whatever the other side sent; with ?wait=N, waits up to N seconds for anything to arrive
+async fn get_pairing_messages(
+	extract::State(state): extract::State<State>,
+	PathParam(channel_id): PathParam<String>,
+	headers: HeaderMap,
+	QueryParam(wait): QueryParam<sessions::Wait>,
+) -> Result<Json<Vec<pairing::Message>>, Error> {
+	let user_id = optional_auth(&state, &headers).await;
+	let deadline = deadline(&wait);
+
+	loop {
+		let waiter = {
+			let mut sessions = state.sessions.lock().await;
+			let channel = sessions
+				.channel(&channel_id, now())
+				.ok_or(Error::NoSession)?;
+			let side = pairing_side(channel, user_id, &headers)?;
+			let messages = channel.take(side);
+
+			if !messages.is_empty() || tokio::time::Instant::now() >= deadline {
+				return Ok(Json(messages));
+			}
+
+			channel.waiter(side)
 		};
 
-		// TODO: do I need thi sstatus code?
-		Ok((StatusCode::OK, Json(welcome)))
-	} else {
-		Err(Error::NoInvite(email))
+		_ = tokio::time::timeout_at(deadline, waiter.notified()).await;
 	}
 }
 
-async fn get_master_key(
+async fn delete_node(
 	extract::State(state): extract::State<State>,
-	Path(user_id): Path<u64>,
-) -> Result<(StatusCode, Json<encrypted::Encrypted>), Error> {
-	let users = state.users.lock().await;
+	PathParam(file_id): PathParam<u64>,
+	headers: HeaderMap,
+) -> Result<StatusCode, Error> {
+	let user_id = check_auth(&state, &headers).await?;
+	let groups = state.groups_of(user_id).await;
+	let removed = {
+		let mut nodes = state.nodes.lock().await;
+		let mut shares = state.shares.lock().await;
+
+		if nodes.get(file_id).is_some() && !can_detach(&nodes, &shares, user_id, &groups, file_id) {
+			return Err(Error::Unauthorised);
+		}
 
-	println!("getting mk: {}", user_id);
+		let subtree = nodes.subtree(file_id);
 
-	if let Some(mk) = users.mk_for_id(user_id) {
-		Ok((StatusCode::OK, Json(mk.clone())))
-	} else {
-		Err(Error::NotFound(user_id))
+		nodes.remove(file_id);
+		shares.remove_for_nodes(&subtree.iter().copied().collect());
+
+		subtree
+	};
+
+	if removed.is_empty() {
+		warn!(target: "nodes", node_id = file_id, "can not delete; not found");
+
+		return Err(Error::NotFound(file_id));
+	}
+
+	// the whole subtree went, blobs and links of every node in it included
+	for id in removed {
+		state.links.lock().await.remove_for_node(id);
+		state.file_requests.lock().await.remove_for_node(id);
+		remove_file(&state, id).await;
 	}
+
+	info!(target: "nodes", node_id = file_id, "deleted");
+
+	Ok(StatusCode::NO_CONTENT)
 }
 
-async fn get_user(
+async fn move_node(
 	extract::State(state): extract::State<State>,
-	Path(user_id): Path<u64>,
-) -> Result<(StatusCode, Json<LockedUser>), Error> {
-	let user = state.user_by_id(user_id).await?;
+	PathParam((file_id, parent_id)): PathParam<(u64, u64)>,
+	headers: HeaderMap,
+) -> Result<StatusCode, Error> {
+	let user_id = check_auth(&state, &headers).await?;
+	let groups = state.groups_of(user_id).await;
+	let mut nodes = state.nodes.lock().await;
+	let shares = state.shares.lock().await;
+
+	if nodes.get(file_id).is_none() {
+		return Err(Error::NotFound(file_id));
+	}
+
+	if !can_detach(&nodes, &shares, user_id, &groups, file_id)
+		|| shares.role(user_id, &groups, &nodes, parent_id) < Some(Role::Editor)
+	{
+		return Err(Error::Unauthorised);
+	}
 
-	println!("logged in {}", user_id);
+	nodes.move_to(file_id, parent_id)?;
 
-	// you'd generate an access token here for subsequent requests
+	info!(target: "nodes", node_id = file_id, parent_id, "moved");
 
-	Ok((StatusCode::OK, Json(user)))
+	Ok(StatusCode::NO_CONTENT)
 }
 
-async fn invite(
+// every node under `roots`, each once
+fn nodes_under(nodes: &Nodes, roots: &[u64]) -> Vec<LockedNode> {
+	let mut seen = HashSet::new();
+
+	roots
+		.iter()
+		.flat_map(|root| nodes.subtree(*root))
+		.filter(|id| seen.insert(*id))
+		.filter_map(|id| nodes.get(id).cloned())
+		.collect()
+}
+
+// what the caller has a role on; anonymous callers, drop box uploaders included, see nothing
+async fn get_all(
+	extract::State(state): extract::State<State>,
+	headers: HeaderMap,
+) -> Result<(StatusCode, Json<Vec<LockedNode>>), Error> {
+	let nodes = match optional_auth(&state, &headers).await {
+		Some(user_id) => {
+			let groups = state.groups_of(user_id).await;
+			let nodes = state.nodes.lock().await;
+			let shares = state.shares.lock().await;
+
+			nodes
+				.get_all()
+				.into_iter()
+				.filter(|node| shares.role(user_id, &groups, &nodes, node.id).is_some())
+				.collect()
+		}
+		None => Vec::new(),
+	};
+
+	debug!(target: "nodes", count = nodes.len(), "returning nodes");
+
+	Ok((StatusCode::OK, Json(nodes)))
+}
+
+async fn set_role(
 	extract::State(state): extract::State<State>,
-	extract::Json(invite): extract::Json<Invite>,
+	PathParam((receiver, node_id)): PathParam<(u64, u64)>,
+	headers: HeaderMap,
+	JsonBody(change): JsonBody<shares::RoleChange>,
 ) -> Result<StatusCode, Error> {
+	let user_id = check_auth(&state, &headers).await?;
+	let groups = state.groups_of(user_id).await;
+	let nodes = state.nodes.lock().await;
 	let mut shares = state.shares.lock().await;
-	let email = invite.email.clone();
 
-	println!("inviting: {}", email);
+	if shares.role(user_id, &groups, &nodes, node_id) != Some(Role::Owner) {
+		return Err(Error::Unauthorised);
+	}
 
-	shares.add_invite(invite, &email);
+	if shares.set_role(receiver, node_id, change.role) {
+		info!(target: "shares", receiver, role = ?change.role, node_id, "role changed");
 
-	Ok(StatusCode::CREATED)
+		Ok(StatusCode::NO_CONTENT)
+	} else {
+		Err(Error::NotFound(node_id))
+	}
 }
 
-async fn lock_session(
+// registers a device of the caller's, itself or one it paired with; the caller's token is left as
+// is, any other token not bound to a device is revoked, as none would be handed out from now on
+async fn add_device(
+	extract::State(state): extract::State<State>,
+	headers: HeaderMap,
+	JsonBody(new_device): JsonBody<devices::NewDevice>,
+) -> Result<(StatusCode, Json<devices::Device>), Error> {
+	let user_id = check_auth(&state, &headers).await?;
+	let device = {
+		let mut devices = state.devices.lock().await;
+		let id = devices.add(user_id, new_device, now())?;
+
+		devices
+			.get(user_id, id)
+			.cloned()
+			.ok_or(Error::NotFound(id))?
+	};
+
+	if let Some(token) = header(&headers, AUTH_HEADER) {
+		state.tokens.lock().await.revoke_unbound(user_id, token);
+	}
+
+	info!(device_id = device.id, user_id, "device registered");
+
+	Ok((StatusCode::CREATED, Json(device)))
+}
+
+async fn get_devices(
+	extract::State(state): extract::State<State>,
+	headers: HeaderMap,
+) -> Result<Json<Vec<devices::Device>>, Error> {
+	let user_id = check_auth(&state, &headers).await?;
+
+	Ok(Json(state.devices.lock().await.all_for_user(user_id)))
+}
+
+// logs the device out everywhere and lets the user's other devices know
+async fn delete_device(
 	extract::State(state): extract::State<State>,
-	Path(token_id): Path<String>,
-	extract::Json(token): extract::Json<shares::Seed>,
+	PathParam(device_id): PathParam<u64>,
+	headers: HeaderMap,
 ) -> Result<StatusCode, Error> {
-	let mut sessions = state.sessions.lock().await;
+	let user_id = check_auth(&state, &headers).await?;
+
+	state.devices.lock().await.remove(user_id, device_id)?;
+	state.tokens.lock().await.revoke_device(device_id);
+	state
+		.events
+		.lock()
+		.await
+		.push(user_id, Event::DeviceRemoved { device_id }, now());
 
-	println!("locking session: {}", token_id);
+	info!(device_id, user_id, "device removed");
 
-	sessions.add_token(&token_id, token);
+	Ok(StatusCode::NO_CONTENT)
+}
 
-	Ok(StatusCode::CREATED)
+// with ?wait=N, waits up to N seconds for anything newer than ?after=seq
+async fn get_events(
+	extract::State(state): extract::State<State>,
+	headers: HeaderMap,
+	QueryParam(since): QueryParam<events::Since>,
+) -> Result<Json<Vec<events::Entry>>, Error> {
+	let user_id = check_auth(&state, &headers).await?;
+	let after = since.after.unwrap_or(0);
+	let deadline = deadline(&sessions::Wait { wait: since.wait });
+	let mut rx = {
+		let mut events = state.events.lock().await;
+		let entries = events.since(user_id, after);
+
+		if !entries.is_empty() || tokio::time::Instant::now() >= deadline {
+			return Ok(Json(entries));
+		}
+
+		events.subscribe(user_id)
+	};
+
+	_ = tokio::time::timeout_at(deadline, rx.changed()).await;
+
+	Ok(Json(state.events.lock().await.since(user_id, after)))
 }
 
-async fn unlock_session(
+async fn create_group(
 	extract::State(state): extract::State<State>,
-	Path(token_id): Path<String>,
-) -> Result<(StatusCode, Json<shares::Seed>), Error> {
-	let mut sessions = state.sessions.lock().await;
+	headers: HeaderMap,
+	JsonBody(new_group): JsonBody<groups::NewGroup>,
+) -> Result<StatusCode, Error> {
+	let user_id = check_auth(&state, &headers).await?;
+	let group_id = new_group._pub.id();
+
+	// users and groups are both share receivers, so their ids may not clash
+	if state.users.lock().await.pub_for_id(group_id).is_some() {
+		return Err(Error::Conflict);
+	}
+
+	let mut groups = state.groups.lock().await;
+
+	if groups.get(group_id).is_some() {
+		return Err(Error::Conflict);
+	}
+
+	groups.add(new_group, user_id);
 
-	// should be authenticated probably; on another hand,
-	// session id is already supplied which should be enough, should it not?
-	println!("unlocking session: {}", token_id);
+	info!(group_id, user_id, "group created");
+
+	Ok(StatusCode::CREATED)
+}
 
-	if let Some(token) = sessions.consume_token_by_id(&token_id) {
-		Ok((StatusCode::OK, Json(token)))
+async fn get_group(
+	extract::State(state): extract::State<State>,
+	PathParam(group_id): PathParam<u64>,
+	headers: HeaderMap,
+) -> Result<(StatusCode, Json<groups::LockedGroup>), Error> {
+	let user_id = check_auth(&state, &headers).await?;
+	let groups = state.groups.lock().await;
+	let group = groups.get(group_id).ok_or(Error::NotFound(group_id))?;
+
+	if group.members.contains_key(&user_id) {
+		Ok((StatusCode::OK, Json(group.clone())))
 	} else {
-		// TODO: a different error code or return a random token?
 		Err(Error::Unauthorised)
 	}
 }
 
-async fn delete_node(
+async fn delete_group(
 	extract::State(state): extract::State<State>,
-	Path(file_id): Path<u64>,
+	PathParam(group_id): PathParam<u64>,
+	headers: HeaderMap,
 ) -> Result<StatusCode, Error> {
-	if let Some(_) = state.nodes.lock().await.remove(file_id) {
-		remove_file(file_id).await;
+	let user_id = check_auth(&state, &headers).await?;
 
-		println!("deleted {}", file_id);
+	state.groups.lock().await.remove(group_id, user_id)?;
+	state.shares.lock().await.remove_for_receiver(group_id);
 
-		Ok(StatusCode::NO_CONTENT)
-	} else {
-		println!("can not delete {}; not found", file_id);
+	info!(group_id, "group deleted");
+
+	Ok(StatusCode::NO_CONTENT)
+}
+
+async fn add_group_member(
+	extract::State(state): extract::State<State>,
+	PathParam((group_id, member_id)): PathParam<(u64, u64)>,
+	headers: HeaderMap,
+	JsonBody(key): JsonBody<identity::Encrypted>,
+) -> Result<StatusCode, Error> {
+	let user_id = check_auth(&state, &headers).await?;
 
-		Err(Error::NotFound(file_id))
+	if state.users.lock().await.pub_for_id(member_id).is_none() {
+		return Err(Error::NotFound(member_id));
 	}
+
+	state
+		.groups
+		.lock()
+		.await
+		.add_member(group_id, member_id, key, user_id)?;
+
+	info!(member_id, group_id, "added to group");
+
+	Ok(StatusCode::NO_CONTENT)
 }
 
-async fn get_all(
+async fn remove_group_member(
 	extract::State(state): extract::State<State>,
-) -> Result<(StatusCode, Json<Vec<LockedNode>>), Error> {
-	let nodes = state.nodes.lock().await.get_all();
+	PathParam((group_id, member_id)): PathParam<(u64, u64)>,
+	headers: HeaderMap,
+) -> Result<StatusCode, Error> {
+	let user_id = check_auth(&state, &headers).await?;
+
+	let owner = {
+		let mut groups = state.groups.lock().await;
+
+		groups.remove_member(group_id, member_id, user_id)?;
+		groups.get(group_id).map(|group| group.owner)
+	};
+
+	// the key is to be rotated; the owner is told if it wasn't them
+	if let Some(owner) = owner.filter(|owner| *owner != user_id) {
+		state.events.lock().await.push(
+			owner,
+			Event::GroupMemberLeft {
+				group_id,
+				user_id: member_id,
+			},
+			now(),
+		);
+	}
 
-	println!("returnin {} nodes", nodes.len());
+	info!(member_id, group_id, "removed from group");
 
-	Ok((StatusCode::OK, Json(nodes)))
+	Ok(StatusCode::NO_CONTENT)
 }
 
-async fn purge(extract::State(mut state): extract::State<State>) -> Result<StatusCode, Error> {
-	println!("purgin...");
+// replaces the group key once somebody left; see groups::Rotation
+async fn rotate_group_key(
+	extract::State(state): extract::State<State>,
+	PathParam(group_id): PathParam<u64>,
+	headers: HeaderMap,
+	JsonBody(rotation): JsonBody<groups::Rotation>,
+) -> Result<StatusCode, Error> {
+	let user_id = check_auth(&state, &headers).await?;
+
+	state
+		.groups
+		.lock()
+		.await
+		.rotate(group_id, rotation, user_id)?;
 
-	state.purge().await;
+	info!(group_id, "group key rotated");
 
-	clear_uploads_dir().await;
+	Ok(StatusCode::NO_CONTENT)
+}
 
-	Ok(StatusCode::OK)
+// exports to a group the same way signup takes shares, sent by the caller itself
+async fn share_with_group(
+	extract::State(state): extract::State<State>,
+	PathParam(group_id): PathParam<u64>,
+	headers: HeaderMap,
+	JsonBody(share): JsonBody<LockedShare>,
+) -> Result<StatusCode, Error> {
+	let user_id = check_auth(&state, &headers).await?;
+	let groups = state.groups_of(user_id).await;
+
+	match state.groups.lock().await.get(group_id) {
+		None => return Err(Error::NotFound(group_id)),
+		// whoever left still holds the current key
+		Some(group) if group.stale => return Err(Error::Conflict),
+		Some(_) => {}
+	}
+
+	if share.sender.id() != user_id || share.export.receiver != group_id {
+		return Err(Error::Unauthorised);
+	}
+
+	let nodes = state.nodes.lock().await;
+	let mut shares = state.shares.lock().await;
+	let users = state.users.lock().await;
+
+	check_share(&users, &shares, &nodes, &groups, &share)?;
+	shares.add_share(share);
+
+	info!(target: "shares", group_id, "shared with group");
+
+	Ok(StatusCode::CREATED)
+}
+
+// how long a readiness check may wait on a store
+const READY_TIMEOUT: Duration = Duration::from_secs(1);
+// written and removed again to see whether the uploads dir takes writes
+const READY_PROBE: &str = ".ready";
+
+#[derive(Serialize, Deserialize, PartialEq, Debug)]
+struct Readiness {
+	ready: bool,
+	// names of the failed checks
+	failed: Vec<String>,
+}
+
+// the process is up; says nothing about whether it can serve
+async fn healthz() -> StatusCode {
+	StatusCode::OK
+}
+
+async fn readyz(extract::State(state): extract::State<State>) -> (StatusCode, Json<Readiness>) {
+	let readiness = check_readiness(&state).await;
+	let status = if readiness.ready {
+		StatusCode::OK
+	} else {
+		StatusCode::SERVICE_UNAVAILABLE
+	};
+
+	(status, Json(readiness))
+}
+
+async fn check_readiness(state: &State) -> Readiness {
+	let mut failed = Vec::new();
+
+	if state.shutting_down.load(Ordering::SeqCst) {
+		failed.push("shutting_down".to_string());
+	}
+
+	// a store held for this long means requests are piling up behind it
+	let stores = tokio::time::timeout(READY_TIMEOUT, async {
+		drop(state.nodes.lock().await);
+		drop(state.users.lock().await);
+		drop(state.shares.lock().await);
+		drop(state.sessions.lock().await);
+	})
+	.await;
+
+	if stores.is_err() {
+		failed.push("stores".to_string());
+	}
+
+	let probe = state.config.uploads_dir.join(READY_PROBE);
+	let writable = tokio::fs::write(&probe, b"").await.is_ok();
+
+	_ = tokio::fs::remove_file(&probe).await;
+
+	if !writable {
+		failed.push("uploads_dir".to_string());
+	}
+
+	Readiness {
+		ready: failed.is_empty(),
+		failed,
+	}
+}
+
+// resolves on SIGTERM or ctrl-c
+async fn shutdown_signal() {
+	let terminate = async {
+		match tokio::signal::unix::signal(tokio::signal::unix::SignalKind::terminate()) {
+			Ok(mut signal) => {
+				signal.recv().await;
+			}
+			Err(err) => {
+				error!(%err, "can't listen for SIGTERM");
+				std::future::pending::<()>().await;
+			}
+		}
+	};
+
+	tokio::select! {
+		_ = terminate => {},
+		_ = tokio::signal::ctrl_c() => {},
+	}
+}
+
+// fails readiness right away and only stops accepting connections after the delay, so load
+// balancers have moved on by then; in-flight requests, uploads mostly, get until the deadline
+async fn shutdown_on_signal(state: State, handle: Handle) {
+	shutdown_signal().await;
+	state.shutting_down.store(true, Ordering::SeqCst);
+
+	let delay = state.config.shutdown_delay;
+	let grace = state.config.shutdown_timeout;
+
+	warn!(delay, grace, "shutting down");
+
+	tokio::time::sleep(Duration::from_secs(delay)).await;
+	handle.graceful_shutdown(Some(Duration::from_secs(grace)));
+}
+
+// counted by route template, so ids in paths don't make new series
+async fn track_metrics(
+	extract::State(state): extract::State<State>,
+	request: Request,
+	next: Next,
+) -> Response {
+	let route = request
+		.extensions()
+		.get::<MatchedPath>()
+		.map(|path| path.as_str().to_string())
+		.unwrap_or_else(|| "unmatched".to_string());
+	let method = request.method().to_string();
+	let start = Instant::now();
+
+	let in_flight = state.metrics.in_flight();
+	let response = next.run(request).await;
+
+	drop(in_flight);
+	state.metrics.observe(
+		&route,
+		&method,
+		response.status().as_u16(),
+		start.elapsed().as_secs_f64(),
+	);
+
+	response
+}
+
+// statvfs may block for a while on network filesystems
+async fn disk_free(state: &State) -> std::io::Result<u64> {
+	let dir = state.config.uploads_dir.clone();
+
+	tokio::task::spawn_blocking(move || fs2::available_space(dir)).await?
 }
 
-async fn clear_uploads_dir() {
-	_ = tokio::fs::remove_dir_all("uploads").await;
-	_ = tokio::fs::create_dir("uploads").await;
+async fn clear_uploads_dir(dir: &std::path::Path) {
+	_ = tokio::fs::remove_dir_all(dir).await;
+	_ = tokio::fs::create_dir_all(dir).await;
 }
 
-async fn remove_file(id: u64) {
-	let path = path_for_file_id(id);
+async fn remove_file(state: &State, id: u64) {
+	let path = state.path_for_file_id(id);
 
 	_ = tokio::fs::remove_file(path).await;
+	state.blobs.lock().await.remove(id);
 }
 
-fn path_for_file_id(id: u64) -> String {
-	format!("./uploads/{}", id)
+// unix time, seconds
+fn now() -> u64 {
+	SystemTime::now()
+		.duration_since(UNIX_EPOCH)
+		.map(|d| d.as_secs())
+		.unwrap_or(0)
 }
 
 #[tokio::main]
 async fn main() {
-	clear_uploads_dir().await;
+	let config = match config::load(config::Args::parse()) {
+		Ok(config) => config,
+		Err(err) => {
+			eprintln!("invalid config:\n{}", err);
+			std::process::exit(2);
+		}
+	};
 
-	let addr = SocketAddr::from(([0, 0, 0, 0], 3000));
-	let state = State::new();
-	let use_tls = env::var("USE_TLS").unwrap_or_else(|_| "false".into()) == "true";
-	let router = router(state);
+	if let Err(err) = logging::init(&config.logging) {
+		eprintln!("failed to set up logging: {}", err);
+		std::process::exit(2);
+	}
 
-	if use_tls {
-		let config = RustlsConfig::from_pem_file(
-			PathBuf::from("certs").join("cert.pem"),
-			PathBuf::from("certs").join("key.pem"),
-		)
-		.await
-		.unwrap();
+	// blobs are left alone for gc to judge; only dev mode starts from an empty dir
+	if config.dev_mode {
+		warn!("dev mode, wiping the uploads dir");
+
+		clear_uploads_dir(&config.uploads_dir).await;
+	} else if let Err(err) = tokio::fs::create_dir_all(&config.uploads_dir).await {
+		error!(%err, "failed to create the uploads dir");
+		std::process::exit(2);
+	}
 
-		axum_server::bind_rustls(addr, config)
-			.serve(router.into_make_service())
+	let addr = config.listen;
+	let tls = config.tls.clone();
+	let mailer = match mailer::from_config(&config.mail) {
+		Ok(mailer) => mailer,
+		Err(err) => {
+			error!(?err, "failed to set up a mailer");
+			std::process::exit(2);
+		}
+	};
+	let state = State::new(mailer, config);
+	let router = router(state.clone());
+	let handle = Handle::new();
+
+	tokio::spawn(shutdown_on_signal(state.clone(), handle.clone()));
+	tokio::spawn(run_jobs(state.clone()));
+
+	info!(%addr, "listening");
+
+	let served = if tls.enabled {
+		let config = match tls::server_config(&tls) {
+			Ok(config) => RustlsConfig::from_config(Arc::new(config)),
+			Err(err) => {
+				error!(%err, "failed to load tls cert");
+				std::process::exit(1);
+			}
+		};
+
+		tokio::spawn(tls::reload_on_sighup(config.clone(), tls));
+
+		Server::bind(addr)
+			.acceptor(tls::Acceptor::new(config))
+			.handle(handle.clone())
+			.serve(router.into_make_service_with_connect_info::<SocketAddr>())
 			.await
-			.unwrap();
 	} else {
 		Server::bind(addr)
-			.serve(router.into_make_service())
+			.handle(handle.clone())
+			.serve(router.into_make_service_with_connect_info::<SocketAddr>())
 			.await
-			.unwrap();
+	};
+
+	if let Err(err) = served {
+		error!(%err, "server failed");
+		std::process::exit(1);
+	}
+
+	let dropped = handle.connection_count();
+
+	if dropped > 0 {
+		warn!(dropped, "connections cut off at the deadline");
+	}
+
+	state.flush().await;
+
+	info!("stopped");
+}
+
+// any origin, unless some are configured
+fn cors_layer(config: &config::Cors) -> CorsLayer {
+	if config.origins.is_empty() {
+		return CorsLayer::permissive();
 	}
+
+	let origins: Vec<HeaderValue> = config
+		.origins
+		.iter()
+		.filter_map(|origin| origin.parse().ok())
+		.collect();
+
+	CorsLayer::new()
+		.allow_origin(origins)
+		.allow_methods(cors::Any)
+		.allow_headers(cors::Any)
+		.expose_headers(cors::Any)
 }
 
 #[allow(dead_code)]
 fn router(state: State) -> Router {
-	Router::new()
+	let features = state.config.features.clone();
+	let mut router = Router::new()
+		.route("/healthz", get(healthz))
+		.route("/readyz", get(readyz))
 		.route("/uploads/stream/:file_id", post(upload_stream))
 		.route("/uploads/chunk/:file_id", post(upload_ranged))
 		.route("/uploads/chunk/:file_id", get(download_ranged))
@@ -554,16 +3091,159 @@ fn router(state: State) -> Router {
 		.route("/uploads/:file_id", head(check_file_length))
 		.route("/nodes", post(add_nodes))
 		.route("/nodes/:file_id", delete(delete_node))
+		.route("/nodes/:file_id/move/:parent_id", post(move_node))
 		.route("/nodes", get(get_all))
-		.route("/purge", post(purge))
-		.route("/signup", post(signup))
 		.route("/sessions/lock/:token_id", post(lock_session))
 		.route("/sessions/unlock/:token_id", post(unlock_session))
+		.route("/devices", post(add_device))
+		.route("/devices", get(get_devices))
+		.route("/devices/:device_id", delete(delete_device))
+		.route("/events", get(get_events))
 		.route("/users/:user_id/mk", get(get_master_key))
 		.route("/users/:user_id", get(get_user))
+		.route("/users/:user_id", delete(delete_user))
+		.route("/users/:user_id/audit", get(get_audit))
+		.route("/users/:user_id/lock", put(change_lock))
+		.route("/users/:user_id/totp", post(enroll_totp))
+		.route("/users/:user_id/totp", put(confirm_totp))
+		.route("/users/:user_id/totp", delete(disable_totp))
 		.route("/login", post(login))
+		.route("/login/totp", post(login_totp))
+		.route("/login/challenge", post(login_challenge))
+		.route("/login/verify", post(login_verify))
+		.route("/login/device", post(device_challenge))
 		.route("/invite/:email", get(get_invite))
 		.route("/invite", post(invite))
-		.layer(CorsLayer::permissive())
+		.route("/shares/:receiver/:node_id/role", put(set_role))
+		.route("/groups", post(create_group))
+		.route("/groups/:group_id", get(get_group))
+		.route("/groups/:group_id", delete(delete_group))
+		.route("/groups/:group_id/members/:user_id", put(add_group_member))
+		.route(
+			"/groups/:group_id/members/:user_id",
+			delete(remove_group_member),
+		)
+		.route("/groups/:group_id/key", put(rotate_group_key))
+		.route("/groups/:group_id/shares", post(share_with_group));
+
+	if features.signup {
+		router = router.route("/signup", post(signup));
+	}
+
+	if features.pairing {
+		router = router
+			.route("/pairing/:channel_id", post(open_pairing))
+			.route("/pairing/:channel_id", delete(close_pairing))
+			.route("/pairing/:channel_id/join", post(join_pairing))
+			.route(
+				"/pairing/:channel_id/commitment",
+				get(get_pairing_commitment),
+			)
+			.route("/pairing/:channel_id/key", post(post_pairing_key))
+			.route("/pairing/:channel_id/peer", get(get_pairing_peer))
+			.route("/pairing/:channel_id/confirm", post(confirm_pairing))
+			.route("/pairing/:channel_id/messages", post(post_pairing_message))
+			.route("/pairing/:channel_id/messages", get(get_pairing_messages));
+	}
+
+	if features.recovery {
+		router = router
+			.route("/users/:user_id/recovery/code", put(set_recovery_code))
+			.route(
+				"/users/:user_id/recovery/code",
+				delete(delete_recovery_code),
+			)
+			.route(
+				"/users/:user_id/recovery/contacts/:contact_id",
+				put(add_recovery_contact),
+			)
+			.route(
+				"/users/:user_id/recovery/contacts/:contact_id",
+				delete(delete_recovery_contact),
+			)
+			.route(
+				"/users/:user_id/recovery/requests/:request_id",
+				delete(cancel_recovery),
+			)
+			.route("/recovery/code", post(recover_with_code))
+			.route("/recovery/contact", post(recover_with_contact))
+			.route("/recovery/requests", get(get_recovery_requests))
+			.route(
+				"/recovery/requests/:request_id",
+				put(approve_recovery_request),
+			)
+			.route("/recovery/:token", get(get_recovery_wrapping))
+			.route("/recovery/:token", put(complete_recovery));
+	}
+
+	if features.links {
+		router = router
+			.route("/links", post(create_link))
+			.route("/links/:token", get(get_link))
+			.route("/links/:token", delete(delete_link));
+	}
+
+	if features.file_requests {
+		router = router
+			.route("/file-requests", post(create_file_request))
+			.route("/file-requests/:token", get(get_file_request))
+			.route("/file-requests/:token", delete(delete_file_request));
+	}
+
+	if features.export {
+		router = router.route("/users/:user_id/export", get(export_user));
+	}
+
+	let mut admin = Router::new()
+		.route("/admin/users", get(get_admin_users))
+		.route("/admin/users/:user_id", get(get_admin_user))
+		.route("/admin/users/:user_id", delete(purge_user))
+		.route("/admin/users/:user_id/shares", get(get_admin_shares))
+		.route("/admin/nodes", get(get_admin_nodes))
+		.route("/admin/nodes/:node_id", get(get_admin_node))
+		.route("/admin/gc", post(admin_gc))
+		.route("/admin/fsck", post(admin_fsck))
+		.route("/admin/jobs", get(get_admin_jobs))
+		.route("/admin/jobs/:job", post(trigger_job))
+		.route("/admin/stats", get(get_admin_stats))
+		.route("/admin/expired", post(purge_expired))
+		.route("/admin/audit", get(get_admin_audit));
+
+	if features.import {
+		admin = admin.route("/admin/import", post(import_user));
+	}
+
+	if features.metrics {
+		admin = admin.route("/metrics", get(get_metrics));
+	}
+
+	if state.config.dev_mode {
+		warn!("dev mode, POST /purge wipes everything");
+
+		admin = admin.route("/purge", post(purge_all));
+	}
+
+	if state.config.tls.client_ca.is_some() {
+		admin = admin.route_layer(middleware::from_fn(require_client_cert));
+	}
+
+	router = router.merge(admin);
+
+	router
+		.layer(middleware::from_fn_with_state(state.clone(), track_metrics))
+		.layer(PropagateRequestIdLayer::new(
+			logging::REQUEST_ID_HEADER.parse().unwrap(),
+		))
+		.layer(
+			TraceLayer::new_for_http()
+				.make_span_with(logging::request_span)
+				.on_response(DefaultOnResponse::new().level(Level::INFO)),
+		)
+		.layer(SetRequestIdLayer::new(
+			logging::REQUEST_ID_HEADER.parse().unwrap(),
+			logging::RequestIds,
+		))
+		.layer(DefaultBodyLimit::max(state.config.limits.max_json_size))
+		.layer(cors_layer(&state.config.cors))
 		.with_state(state)
 }
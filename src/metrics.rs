@@ -0,0 +1,186 @@
+use prometheus::{
+	Encoder, HistogramOpts, HistogramVec, IntCounter, IntCounterVec, IntGauge, IntGaugeVec, Opts,
+	Registry, TextEncoder,
+};
+
+pub struct Metrics {
+	registry: Registry,
+	// by route, method and status
+	pub requests: IntCounterVec,
+	// seconds, by route and method
+	pub latency: HistogramVec,
+	in_flight: IntGauge,
+	// blob bytes written by uploads
+	pub bytes_received: IntCounter,
+	// blob bytes read by downloads
+	pub bytes_served: IntCounter,
+	// nodes, users, shares and sessions; refreshed on every scrape
+	pub items: IntGaugeVec,
+	// bytes left on the volume holding the uploads directory
+	pub disk_free: IntGauge,
+	// by job and outcome
+	pub job_runs: IntCounterVec,
+	// seconds, by job
+	pub job_duration: HistogramVec,
+	// unix time of the last run, by job
+	pub job_last_run: IntGaugeVec,
+}
+
+impl Metrics {
+	pub fn new() -> Self {
+		let requests = IntCounterVec::new(
+			Opts::new("uploader_requests_total", "requests handled"),
+			&["route", "method", "status"],
+		)
+		.unwrap();
+		let latency = HistogramVec::new(
+			HistogramOpts::new("uploader_request_duration_seconds", "time to respond"),
+			&["route", "method"],
+		)
+		.unwrap();
+		let in_flight =
+			IntGauge::new("uploader_requests_in_flight", "requests being handled").unwrap();
+		let bytes_received =
+			IntCounter::new("uploader_received_bytes_total", "blob bytes uploaded").unwrap();
+		let bytes_served =
+			IntCounter::new("uploader_served_bytes_total", "blob bytes downloaded").unwrap();
+		let items = IntGaugeVec::new(
+			Opts::new("uploader_items", "items currently stored"),
+			&["kind"],
+		)
+		.unwrap();
+		let disk_free = IntGauge::new(
+			"uploader_disk_free_bytes",
+			"space left for the uploads directory",
+		)
+		.unwrap();
+		let job_runs = IntCounterVec::new(
+			Opts::new("uploader_job_runs_total", "background job runs"),
+			&["job", "outcome"],
+		)
+		.unwrap();
+		let job_duration = HistogramVec::new(
+			HistogramOpts::new("uploader_job_duration_seconds", "time a job run took"),
+			&["job"],
+		)
+		.unwrap();
+		let job_last_run = IntGaugeVec::new(
+			Opts::new("uploader_job_last_run_seconds", "unix time of the last run"),
+			&["job"],
+		)
+		.unwrap();
+		let registry = Registry::new();
+
+		registry.register(Box::new(requests.clone())).unwrap();
+		registry.register(Box::new(latency.clone())).unwrap();
+		registry.register(Box::new(in_flight.clone())).unwrap();
+		registry.register(Box::new(bytes_received.clone())).unwrap();
+		registry.register(Box::new(bytes_served.clone())).unwrap();
+		registry.register(Box::new(items.clone())).unwrap();
+		registry.register(Box::new(disk_free.clone())).unwrap();
+		registry.register(Box::new(job_runs.clone())).unwrap();
+		registry.register(Box::new(job_duration.clone())).unwrap();
+		registry.register(Box::new(job_last_run.clone())).unwrap();
+
+		Self {
+			registry,
+			requests,
+			latency,
+			in_flight,
+			bytes_received,
+			bytes_served,
+			items,
+			disk_free,
+			job_runs,
+			job_duration,
+			job_last_run,
+		}
+	}
+
+	pub fn observe_job(&self, job: &str, ok: bool, seconds: f64, at: u64) {
+		self.job_runs
+			.with_label_values(&[job, if ok { "ok" } else { "failed" }])
+			.inc();
+		self.job_duration.with_label_values(&[job]).observe(seconds);
+		self.job_last_run.with_label_values(&[job]).set(at as i64);
+	}
+
+	pub fn observe(&self, route: &str, method: &str, status: u16, seconds: f64) {
+		self.requests
+			.with_label_values(&[route, method, &status.to_string()])
+			.inc();
+		self.latency
+			.with_label_values(&[route, method])
+			.observe(seconds);
+	}
+
+	// counts a request in flight until dropped, so one cancelled halfway is let go of as well
+	pub fn in_flight(&self) -> InFlight {
+		self.in_flight.inc();
+
+		InFlight(self.in_flight.clone())
+	}
+
+	pub fn set_items(&self, kind: &str, count: usize) {
+		self.items.with_label_values(&[kind]).set(count as i64);
+	}
+
+	// the text exposition format
+	pub fn render(&self) -> String {
+		let mut buffer = Vec::new();
+
+		TextEncoder::new()
+			.encode(&self.registry.gather(), &mut buffer)
+			.unwrap();
+
+		String::from_utf8(buffer).unwrap()
+	}
+}
+
+pub struct InFlight(IntGauge);
+
+impl Drop for InFlight {
+	fn drop(&mut self) {
+		self.0.dec();
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	#[test]
+	fn test_render() {
+		let metrics = Metrics::new();
+
+		metrics.observe("/nodes", "GET", 200, 0.01);
+		metrics.observe("/nodes", "GET", 200, 0.02);
+		metrics.bytes_received.inc_by(10);
+		metrics.set_items("nodes", 3);
+
+		let text = metrics.render();
+
+		assert!(text
+			.contains("uploader_requests_total{method=\"GET\",route=\"/nodes\",status=\"200\"} 2"));
+		assert!(text.contains("uploader_received_bytes_total 10"));
+		assert!(text.contains("uploader_items{kind=\"nodes\"} 3"));
+	}
+
+	#[test]
+	fn test_in_flight() {
+		let metrics = Metrics::new();
+		let first = metrics.in_flight();
+
+		{
+			let _second = metrics.in_flight();
+
+			assert_eq!(metrics.in_flight.get(), 2);
+		}
+
+		assert_eq!(metrics.in_flight.get(), 1);
+
+		drop(first);
+
+		assert_eq!(metrics.in_flight.get(), 0);
+	}
+}
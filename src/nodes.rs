@@ -1,8 +1,9 @@
 use crate::{encrypted::Encrypted, purge::Purge};
 use serde::{Deserialize, Serialize};
-use std::collections::HashMap;
+use std::collections::{HashMap, HashSet};
 
 const NO_PARENT_ID: u64 = u64::MAX;
+#[allow(dead_code)]
 const ROOT_ID: u64 = 0;
 
 #[derive(PartialEq, Debug)]
@@ -26,6 +27,8 @@ pub struct Nodes {
 	branches: HashMap<u64, Vec<u64>>,
 	// { id, node }
 	nodes: HashMap<u64, LockedNode>,
+	// { id, user_id }; set for top level nodes only, everything beneath belongs to the same user
+	owners: HashMap<u64, u64>,
 }
 
 impl Nodes {
@@ -37,8 +40,38 @@ impl Nodes {
 		self.branches.entry(parent).or_default().push(id);
 	}
 
+	pub fn set_owner(&mut self, id: u64, user_id: u64) {
+		self.owners.insert(id, user_id);
+	}
+
+	pub fn owner_of(&self, id: u64) -> Option<u64> {
+		self.ancestors(id)
+			.iter()
+			.find_map(|id| self.owners.get(id).cloned())
+	}
+
+	// the node itself followed by its parents up to the top most known one
+	pub fn ancestors(&self, id: u64) -> Vec<u64> {
+		let mut chain = Vec::new();
+		let mut current = id;
+
+		// bounded by the node count in case the hierarchy is broken somewhere
+		while let Some(node) = self.nodes.get(&current) {
+			if chain.len() > self.nodes.len() {
+				break;
+			}
+
+			chain.push(current);
+			current = node.parent_id;
+		}
+
+		chain
+	}
+
 	pub fn remove(&mut self, id: u64) -> Option<u64> {
 		if let Some(node) = self.nodes.remove(&id) {
+			self.owners.remove(&id);
+
 			if let Some(parent) = self.branches.get_mut(&node.parent_id) {
 				parent.retain(|eid| *eid != id);
 			}
@@ -55,10 +88,70 @@ impl Nodes {
 		}
 	}
 
+	// top level nodes owned by `user_id`
+	pub fn owned_by(&self, user_id: u64) -> Vec<u64> {
+		self.owners
+			.iter()
+			.filter(|(_, owner)| **owner == user_id)
+			.map(|(id, _)| *id)
+			.collect()
+	}
+
+	// the node itself and everything beneath it
+	pub fn subtree(&self, id: u64) -> Vec<u64> {
+		let mut ids = Vec::new();
+		let mut seen = HashSet::new();
+		let mut pending = vec![id];
+
+		while let Some(current) = pending.pop() {
+			if !self.nodes.contains_key(&current) || !seen.insert(current) {
+				continue;
+			}
+
+			ids.push(current);
+
+			if let Some(children) = self.branches.get(&current) {
+				pending.extend(children);
+			}
+		}
+
+		ids
+	}
+
+	pub fn get(&self, id: u64) -> Option<&LockedNode> {
+		self.nodes.get(&id)
+	}
+
+	pub fn count(&self) -> usize {
+		self.nodes.len()
+	}
+
 	pub fn get_all(&self) -> Vec<LockedNode> {
 		self.nodes.values().cloned().collect()
 	}
 
+	// { parent_id, children_ids } as stored, for consistency checks
+	pub fn branches(&self) -> &HashMap<u64, Vec<u64>> {
+		&self.branches
+	}
+
+	// the top of a tree someone owns
+	pub fn is_root(&self, id: u64) -> bool {
+		self.owners.contains_key(&id)
+	}
+
+	// from the parent ids of the nodes, which drops duplicate and stray children and adds missing ones
+	pub fn rebuild_branches(&mut self) {
+		let mut branches: HashMap<u64, Vec<u64>> = HashMap::new();
+
+		for node in self.nodes.values() {
+			branches.entry(node.parent_id).or_default().push(node.id);
+		}
+
+		branches.values_mut().for_each(|children| children.sort());
+		self.branches = branches;
+	}
+
 	pub fn move_to(&mut self, id: u64, new_parent: u64) -> Result<(), Error> {
 		// only one root is allowed
 		if new_parent == NO_PARENT_ID {
@@ -108,6 +201,7 @@ impl Purge for Nodes {
 		Self {
 			branches: HashMap::new(),
 			nodes: HashMap::new(),
+			owners: HashMap::new(),
 		}
 	}
 }
@@ -124,6 +218,30 @@ mod tests {
 		}
 	}
 
+	#[test]
+	fn test_rebuild_branches() {
+		let mut storage = Nodes::new();
+
+		for (id, parent_id) in [(1, 0), (2, 1), (3, 1)] {
+			storage.add(LockedNode {
+				id,
+				parent_id,
+				content: stub_encrypted(),
+				dirty: false,
+			});
+		}
+
+		// a duplicate, a child that's gone and one missing altogether
+		storage.branches.get_mut(&1).unwrap().push(2);
+		storage.branches.get_mut(&1).unwrap().push(9);
+		storage.branches.get_mut(&1).unwrap().retain(|id| *id != 3);
+
+		storage.rebuild_branches();
+
+		assert_eq!(storage.branches()[&1], vec![2, 3]);
+		assert_eq!(storage.branches()[&0], vec![1]);
+	}
+
 	#[test]
 	fn test_move_node_to_itself() {
 		let mut storage = Nodes::new();
@@ -412,6 +530,74 @@ mod tests {
 		assert_eq!(storage.nodes.contains_key(&1), false);
 	}
 
+	#[test]
+	fn test_owner_is_inherited() {
+		let mut storage = Nodes::new();
+
+		storage.add(LockedNode {
+			id: 0,
+			parent_id: NO_PARENT_ID,
+			content: stub_encrypted(),
+			dirty: false,
+		});
+		storage.add(LockedNode {
+			id: 1,
+			parent_id: 0,
+			content: stub_encrypted(),
+			dirty: false,
+		});
+		storage.add(LockedNode {
+			id: 2,
+			parent_id: 1,
+			content: stub_encrypted(),
+			dirty: false,
+		});
+		storage.set_owner(0, 7);
+
+		assert_eq!(storage.ancestors(2), vec![2, 1, 0]);
+		assert_eq!(storage.owner_of(2), Some(7));
+		assert_eq!(storage.owner_of(999), None);
+
+		storage.set_owner(1, 8);
+
+		assert_eq!(storage.owner_of(2), Some(8));
+		assert_eq!(storage.owner_of(0), Some(7));
+	}
+
+	#[test]
+	fn test_owned_subtree() {
+		let mut storage = Nodes::new();
+
+		storage.add(LockedNode {
+			id: 0,
+			parent_id: NO_PARENT_ID,
+			content: stub_encrypted(),
+			dirty: false,
+		});
+		storage.add(LockedNode {
+			id: 1,
+			parent_id: 0,
+			content: stub_encrypted(),
+			dirty: false,
+		});
+		storage.add(LockedNode {
+			id: 2,
+			parent_id: 1,
+			content: stub_encrypted(),
+			dirty: false,
+		});
+		storage.set_owner(1, 7);
+
+		let mut subtree = storage.subtree(1);
+
+		subtree.sort();
+
+		assert_eq!(storage.owned_by(7), vec![1]);
+		assert!(storage.owned_by(8).is_empty());
+		assert_eq!(subtree, vec![1, 2]);
+		assert!(storage.subtree(999).is_empty());
+	}
+
 	#[test]
 	fn test_remove_leaf_node() {
 		let mut storage = Nodes::new();
@@ -0,0 +1,418 @@
+use crate::{
+	base64_blobs::{
+		deserialize_array_base64, deserialize_vec_base64, serialize_array_base64,
+		serialize_vec_base64,
+	},
+	token,
+	x448::PublicKeyX448,
+};
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use std::{collections::VecDeque, sync::Arc};
+use tokio::sync::Notify;
+
+// messages waiting to be read, per direction
+pub const MAX_MESSAGES: usize = 8;
+// ct bytes per message; enough for a Lock
+pub const MAX_MESSAGE_SIZE: usize = 64 * 1024;
+
+const COMMITMENT_CONTEXT: &[u8] = b"uploader pairing commitment";
+const COMMITMENT_SIZE: usize = 32;
+const SAS_CONTEXT: &[u8] = b"uploader pairing sas";
+const SAS_DIGITS: u32 = 6;
+
+#[derive(PartialEq, Debug)]
+pub enum Error {
+	NotFound,
+	AlreadyJoined,
+	// a key sent before the other side's turn, or sent twice
+	OutOfOrder,
+	// the joiner's key doesn't match what it committed to, or the sas doesn't match the keys
+	Mismatch,
+	NotConfirmed,
+	Full,
+	TooLarge,
+}
+
+// an ephemeral key, thrown away as soon as pairing is over
+#[derive(Serialize, Deserialize, PartialEq, Debug, Clone)]
+pub struct Hello {
+	pub eph_x448: PublicKeyX448,
+}
+
+// the joiner's key, hashed; sent before the initiator's key is known and revealed only after,
+// so whoever relays can't pick keys to make both screens show the same sas
+#[derive(Serialize, Deserialize, PartialEq, Debug, Clone)]
+pub struct Commitment {
+	#[serde(
+		serialize_with = "serialize_array_base64::<_, COMMITMENT_SIZE>",
+		deserialize_with = "deserialize_array_base64::<_, COMMITMENT_SIZE>"
+	)]
+	pub hash: [u8; COMMITMENT_SIZE],
+}
+
+impl Commitment {
+	pub fn new(joiner: &Hello) -> Self {
+		Self {
+			hash: Sha256::new()
+				.chain_update(COMMITMENT_CONTEXT)
+				.chain_update(joiner.eph_x448.as_bytes())
+				.finalize()
+				.into(),
+		}
+	}
+}
+
+// what the joining device gets back: a token to use the channel with
+#[derive(Serialize, Deserialize, PartialEq, Debug)]
+pub struct Joined {
+	pub token: String,
+}
+
+// the sas the initiator's user saw on both screens
+#[derive(Serialize, Deserialize, PartialEq, Debug)]
+pub struct Confirmation {
+	pub sas: String,
+}
+
+// encrypted with a key derived from both ephemeral keys; opaque to the server
+#[derive(Serialize, Deserialize, PartialEq, Debug, Clone)]
+pub struct Message {
+	#[serde(
+		serialize_with = "serialize_vec_base64",
+		deserialize_with = "deserialize_vec_base64"
+	)]
+	pub ct: Vec<u8>,
+}
+
+#[derive(PartialEq, Debug, Clone, Copy)]
+pub enum Side {
+	// the device already logged in, which opens the channel
+	Initiator,
+	// the new device, which joins by the id it got from the initiator, eg via a qr code
+	Joiner,
+}
+
+struct Mailbox {
+	messages: VecDeque<Message>,
+	// woken whenever there's something new to read
+	notify: Arc<Notify>,
+}
+
+impl Mailbox {
+	fn new() -> Self {
+		Self {
+			messages: VecDeque::new(),
+			notify: Arc::new(Notify::new()),
+		}
+	}
+}
+
+// in order: opened, joined with a commitment, initiator's key, joiner's key, confirmed
+pub struct Channel {
+	pub owner_id: u64,
+	pub expires_at: u64,
+	commitment: Option<Commitment>,
+	initiator: Option<Hello>,
+	joiner: Option<Hello>,
+	joiner_token: Option<String>,
+	// set by the initiator once both screens showed the same sas
+	confirmed: bool,
+	to_initiator: Mailbox,
+	to_joiner: Mailbox,
+}
+
+impl Channel {
+	pub fn new(owner_id: u64, expires_at: u64) -> Self {
+		Self {
+			owner_id,
+			expires_at,
+			commitment: None,
+			initiator: None,
+			joiner: None,
+			joiner_token: None,
+			confirmed: false,
+			to_initiator: Mailbox::new(),
+			to_joiner: Mailbox::new(),
+		}
+	}
+
+	// a channel can be joined exactly once
+	pub fn join(&mut self, commitment: Commitment) -> Result<Joined, Error> {
+		if self.commitment.is_some() {
+			return Err(Error::AlreadyJoined);
+		}
+
+		let token = token::generate();
+
+		self.commitment = Some(commitment);
+		self.joiner_token = Some(token.clone());
+		self.to_initiator.notify.notify_one();
+
+		Ok(Joined { token })
+	}
+
+	pub fn commitment(&self) -> Option<&Commitment> {
+		self.commitment.as_ref()
+	}
+
+	pub fn is_joiner(&self, token: &str) -> bool {
+		self.joiner_token.as_deref() == Some(token)
+	}
+
+	// the initiator goes once the joiner has committed, the joiner once it has seen the initiator's key
+	pub fn reveal(&mut self, side: Side, hello: Hello) -> Result<(), Error> {
+		match side {
+			Side::Initiator => {
+				if self.commitment.is_none() || self.initiator.is_some() {
+					return Err(Error::OutOfOrder);
+				}
+
+				self.initiator = Some(hello);
+				self.to_joiner.notify.notify_one();
+			}
+			Side::Joiner => {
+				if self.initiator.is_none() || self.joiner.is_some() {
+					return Err(Error::OutOfOrder);
+				}
+
+				if self.commitment.as_ref() != Some(&Commitment::new(&hello)) {
+					return Err(Error::Mismatch);
+				}
+
+				self.joiner = Some(hello);
+				self.to_initiator.notify.notify_one();
+			}
+		}
+
+		Ok(())
+	}
+
+	// the key the other side revealed, if it has
+	pub fn peer(&self, side: Side) -> Option<&Hello> {
+		match side {
+			Side::Initiator => self.joiner.as_ref(),
+			Side::Joiner => self.initiator.as_ref(),
+		}
+	}
+
+	// the initiator passes on what its user saw, so a confirmation only ever goes with these two keys
+	pub fn confirm(&mut self, confirmation: &Confirmation) -> Result<(), Error> {
+		let (Some(initiator), Some(joiner)) = (&self.initiator, &self.joiner) else {
+			return Err(Error::OutOfOrder);
+		};
+
+		if sas(&initiator.eph_x448, &joiner.eph_x448) != confirmation.sas {
+			return Err(Error::Mismatch);
+		}
+
+		self.confirmed = true;
+
+		Ok(())
+	}
+
+	fn inbox(&mut self, side: Side) -> &mut Mailbox {
+		match side {
+			Side::Initiator => &mut self.to_initiator,
+			Side::Joiner => &mut self.to_joiner,
+		}
+	}
+
+	// nothing flows either way until the sas has been confirmed
+	pub fn post(&mut self, from: Side, msg: Message) -> Result<(), Error> {
+		if !self.confirmed {
+			return Err(Error::NotConfirmed);
+		}
+
+		if msg.ct.len() > MAX_MESSAGE_SIZE {
+			return Err(Error::TooLarge);
+		}
+
+		let inbox = self.inbox(match from {
+			Side::Initiator => Side::Joiner,
+			Side::Joiner => Side::Initiator,
+		});
+
+		if inbox.messages.len() >= MAX_MESSAGES {
+			return Err(Error::Full);
+		}
+
+		inbox.messages.push_back(msg);
+		inbox.notify.notify_one();
+
+		Ok(())
+	}
+
+	pub fn take(&mut self, side: Side) -> Vec<Message> {
+		self.inbox(side).messages.drain(..).collect()
+	}
+
+	// to be awaited (with a timeout) when there's nothing for `side` yet
+	pub fn waiter(&mut self, side: Side) -> Arc<Notify> {
+		self.inbox(side).notify.clone()
+	}
+}
+
+// a short authentication string both devices show; if they differ, someone is in the middle
+pub fn sas(initiator: &PublicKeyX448, joiner: &PublicKeyX448) -> String {
+	let digest = Sha256::new()
+		.chain_update(SAS_CONTEXT)
+		.chain_update(initiator.as_bytes())
+		.chain_update(joiner.as_bytes())
+		.finalize();
+	let num = u32::from_be_bytes(digest[..4].try_into().unwrap()) % 10u32.pow(SAS_DIGITS);
+
+	format!("{:0width$}", num, width = SAS_DIGITS as usize)
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	fn hello(b: u8) -> Hello {
+		Hello {
+			eph_x448: PublicKeyX448::new([b; 56]),
+		}
+	}
+
+	fn msg(size: usize) -> Message {
+		Message { ct: vec![1; size] }
+	}
+
+	fn confirmation() -> Confirmation {
+		Confirmation {
+			sas: sas(&hello(1).eph_x448, &hello(2).eph_x448),
+		}
+	}
+
+	// up to both keys revealed
+	fn exchanged() -> Channel {
+		let mut channel = Channel::new(1, 100);
+
+		channel.join(Commitment::new(&hello(2))).unwrap();
+		channel.reveal(Side::Initiator, hello(1)).unwrap();
+		channel.reveal(Side::Joiner, hello(2)).unwrap();
+
+		channel
+	}
+
+	#[test]
+	fn test_join_once() {
+		let mut channel = Channel::new(1, 100);
+		let joined = channel.join(Commitment::new(&hello(2))).unwrap();
+
+		assert!(channel.is_joiner(&joined.token));
+		assert!(!channel.is_joiner("nope"));
+		assert_eq!(channel.commitment(), Some(&Commitment::new(&hello(2))));
+		assert_eq!(
+			channel.join(Commitment::new(&hello(3))).err(),
+			Some(Error::AlreadyJoined)
+		);
+	}
+
+	#[test]
+	fn test_keys_are_revealed_in_order() {
+		let mut channel = Channel::new(1, 100);
+
+		// nothing to reveal to before the joiner has committed
+		assert_eq!(
+			channel.reveal(Side::Initiator, hello(1)),
+			Err(Error::OutOfOrder)
+		);
+
+		channel.join(Commitment::new(&hello(2))).unwrap();
+
+		// the joiner goes last
+		assert_eq!(
+			channel.reveal(Side::Joiner, hello(2)),
+			Err(Error::OutOfOrder)
+		);
+		assert_eq!(channel.peer(Side::Joiner), None);
+
+		channel.reveal(Side::Initiator, hello(1)).unwrap();
+
+		assert_eq!(channel.peer(Side::Joiner), Some(&hello(1)));
+		assert_eq!(
+			channel.reveal(Side::Initiator, hello(3)),
+			Err(Error::OutOfOrder)
+		);
+		// and may not change its mind now it knows the initiator's key
+		assert_eq!(channel.reveal(Side::Joiner, hello(3)), Err(Error::Mismatch));
+		assert_eq!(channel.peer(Side::Initiator), None);
+
+		channel.reveal(Side::Joiner, hello(2)).unwrap();
+
+		assert_eq!(channel.peer(Side::Initiator), Some(&hello(2)));
+	}
+
+	#[test]
+	fn test_confirm_checks_sas() {
+		let mut channel = Channel::new(1, 100);
+
+		assert_eq!(channel.confirm(&confirmation()), Err(Error::OutOfOrder));
+
+		let mut channel = exchanged();
+		let wrong = Confirmation {
+			sas: sas(&hello(2).eph_x448, &hello(1).eph_x448),
+		};
+
+		assert_eq!(channel.confirm(&wrong), Err(Error::Mismatch));
+		assert_eq!(channel.post(Side::Joiner, msg(1)), Err(Error::NotConfirmed));
+		assert_eq!(channel.confirm(&confirmation()), Ok(()));
+	}
+
+	#[test]
+	fn test_no_messages_before_confirmation() {
+		let mut channel = exchanged();
+
+		assert_eq!(channel.post(Side::Joiner, msg(1)), Err(Error::NotConfirmed));
+
+		channel.confirm(&confirmation()).unwrap();
+
+		assert_eq!(channel.post(Side::Joiner, msg(1)), Ok(()));
+	}
+
+	#[test]
+	fn test_messages_cross_over() {
+		let mut channel = exchanged();
+
+		channel.confirm(&confirmation()).unwrap();
+		channel.post(Side::Joiner, msg(1)).unwrap();
+		channel.post(Side::Initiator, msg(2)).unwrap();
+
+		assert_eq!(channel.take(Side::Initiator), vec![msg(1)]);
+		assert_eq!(channel.take(Side::Joiner), vec![msg(2)]);
+		assert!(channel.take(Side::Joiner).is_empty());
+	}
+
+	#[test]
+	fn test_mailbox_is_bounded() {
+		let mut channel = exchanged();
+
+		channel.confirm(&confirmation()).unwrap();
+
+		assert_eq!(
+			channel.post(Side::Initiator, msg(MAX_MESSAGE_SIZE + 1)),
+			Err(Error::TooLarge)
+		);
+
+		(0..MAX_MESSAGES).for_each(|_| channel.post(Side::Initiator, msg(1)).unwrap());
+
+		assert_eq!(channel.post(Side::Initiator, msg(1)), Err(Error::Full));
+
+		channel.take(Side::Joiner);
+
+		assert_eq!(channel.post(Side::Initiator, msg(1)), Ok(()));
+	}
+
+	#[test]
+	fn test_sas() {
+		let a = hello(1).eph_x448;
+		let b = hello(2).eph_x448;
+
+		assert_eq!(sas(&a, &b).len(), 6);
+		assert_eq!(sas(&a, &b), sas(&a, &b));
+		// order matters, so a relay can't swap the keys unnoticed
+		assert_ne!(sas(&a, &b), sas(&b, &a));
+	}
+}
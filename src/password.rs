@@ -0,0 +1,63 @@
+use argon2::{
+	password_hash::{rand_core::OsRng, PasswordHash, PasswordHasher, PasswordVerifier, SaltString},
+	Argon2,
+};
+
+// an argon2 hash of a password in the PHC string format
+pub fn hash(pass: &str) -> String {
+	let salt = SaltString::generate(&mut OsRng);
+
+	Argon2::default()
+		.hash_password(pass.as_bytes(), &salt)
+		.expect("argon2 with default params can't fail")
+		.to_string()
+}
+
+pub fn verify(hash: &str, pass: &str) -> bool {
+	PasswordHash::new(hash)
+		.map(|parsed| {
+			Argon2::default()
+				.verify_password(pass.as_bytes(), &parsed)
+				.is_ok()
+		})
+		.unwrap_or(false)
+}
+
+// argon2 is slow on purpose, so handlers run it on the blocking pool, with no store locked
+pub async fn hash_async(pass: String) -> String {
+	tokio::task::spawn_blocking(move || hash(&pass))
+		.await
+		.expect("argon2 with default params can't fail")
+}
+
+pub async fn verify_async(hash: String, pass: String) -> bool {
+	tokio::task::spawn_blocking(move || verify(&hash, &pass))
+		.await
+		.unwrap_or(false)
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	#[test]
+	fn test_verify() {
+		let hashed = hash("secret");
+
+		assert!(verify(&hashed, "secret"));
+		assert!(!verify(&hashed, "wrong"));
+	}
+
+	#[tokio::test]
+	async fn test_verify_async() {
+		let hashed = hash_async("secret".into()).await;
+
+		assert!(verify_async(hashed.clone(), "secret".into()).await);
+		assert!(!verify_async(hashed, "wrong".into()).await);
+	}
+
+	#[test]
+	fn test_verify_garbage_hash() {
+		assert!(!verify("not a hash", "secret"));
+	}
+}
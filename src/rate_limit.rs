@@ -0,0 +1,71 @@
+use crate::purge::Purge;
+use std::collections::{HashMap, VecDeque};
+
+// a sliding window of hits per key, eg per account
+pub struct RateLimit {
+	// { key, unix times of the hits within the window }
+	hits: HashMap<u64, VecDeque<u64>>,
+}
+
+impl RateLimit {
+	// records a hit unless `max` hits within `window` seconds have been made already
+	pub fn check(&mut self, key: u64, max: usize, window: u64, now: u64) -> bool {
+		let hits = self.hits.entry(key).or_default();
+
+		while hits.front().is_some_and(|at| at + window <= now) {
+			hits.pop_front();
+		}
+
+		if hits.len() >= max {
+			false
+		} else {
+			hits.push_back(now);
+
+			true
+		}
+	}
+
+	pub fn remove_expired(&mut self, window: u64, now: u64) {
+		self.hits
+			.retain(|_, hits| hits.back().is_some_and(|at| at + window > now));
+	}
+}
+
+impl Purge for RateLimit {
+	fn new() -> Self {
+		Self {
+			hits: HashMap::new(),
+		}
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	#[test]
+	fn test_window_slides() {
+		let mut limit = RateLimit::new();
+
+		assert!(limit.check(1, 2, 10, 0));
+		assert!(limit.check(1, 2, 10, 5));
+		assert!(!limit.check(1, 2, 10, 9));
+		// other keys are counted separately
+		assert!(limit.check(2, 2, 10, 9));
+		// the first hit is out of the window by now
+		assert!(limit.check(1, 2, 10, 10));
+		assert!(!limit.check(1, 2, 10, 14));
+	}
+
+	#[test]
+	fn test_remove_expired() {
+		let mut limit = RateLimit::new();
+
+		limit.check(1, 2, 10, 0);
+		limit.check(2, 2, 10, 5);
+		limit.remove_expired(10, 10);
+
+		assert!(!limit.hits.contains_key(&1));
+		assert!(limit.hits.contains_key(&2));
+	}
+}
@@ -0,0 +1,565 @@
+use crate::{encrypted, identity, purge::Purge, rate_limit::RateLimit, token, x448::PublicKeyX448};
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+
+// how long a started recovery stays valid, seconds
+pub const TTL: u64 = 15 * 60;
+// how long a contact recovery waits before it can be completed, so the owner gets to cancel it
+// should it not be theirs: the contacts can approve their own requests, after all; seconds
+pub const DELAY: u64 = 24 * 60 * 60;
+// wrong recovery codes after which an account can't be recovered by code until the ttl runs out
+pub const MAX_ATTEMPTS: u32 = 5;
+// contact recoveries started per account, each of which mails the owner
+pub const MAX_STARTS: usize = 3;
+// seconds
+pub const START_WINDOW: u64 = 60 * 60;
+
+#[derive(PartialEq, Debug)]
+pub enum Error {
+	NotFound,
+	NotAllowed,
+	Blocked,
+	Expired,
+	// a trusted contact has not answered yet, or the delay has not passed
+	Pending,
+}
+
+// the master key wrapped with a key derived from a recovery code, client side
+#[derive(Serialize, Deserialize)]
+pub struct NewCode {
+	// derived from the code separately from the wrapping key, so the server can't unwrap anything
+	pub proof: String,
+	pub master_key: encrypted::Encrypted,
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct CodeRecovery {
+	pub email: String,
+	pub proof: String,
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct ContactRecovery {
+	pub email: String,
+	pub contact_id: u64,
+	// the contact re-wraps the master key to this one
+	pub eph_x448: PublicKeyX448,
+}
+
+#[derive(Serialize, Deserialize, PartialEq, Debug)]
+pub struct Started {
+	pub token: String,
+}
+
+// what a trusted contact gets to see of a recovery addressed to them
+#[derive(Serialize, Deserialize, PartialEq, Debug, Clone)]
+pub struct ContactRequest {
+	pub id: String,
+	pub user_id: u64,
+	// the master key wrapped to the contact's identity::Public
+	pub master_key: identity::Encrypted,
+	pub eph_x448: PublicKeyX448,
+	pub expires_at: u64,
+}
+
+#[derive(Serialize, Deserialize, PartialEq, Debug, Clone)]
+#[serde(tag = "kind", rename_all = "snake_case")]
+pub enum Wrapping {
+	Code { master_key: encrypted::Encrypted },
+	// re-wrapped by a trusted contact to the ephemeral key of the recovery
+	Contact { master_key: identity::Encrypted },
+}
+
+struct Code {
+	// password::hash of the proof, done by the caller
+	proof: String,
+	master_key: encrypted::Encrypted,
+}
+
+struct Attempts {
+	count: u32,
+	expires_at: u64,
+}
+
+struct Recovery {
+	user_id: u64,
+	// not to be completed before; right away for codes
+	ready_at: u64,
+	expires_at: u64,
+	// set right away for codes, once approved for contacts
+	wrapping: Option<Wrapping>,
+	request: Option<ContactRequest>,
+}
+
+pub struct Recoveries {
+	// { user_id, code }
+	codes: HashMap<u64, Code>,
+	// { user_id, { contact_id, master key wrapped to the contact } }
+	contacts: HashMap<u64, HashMap<u64, identity::Encrypted>>,
+	// { user_id, code attempts }
+	attempts: HashMap<u64, Attempts>,
+	// contact recoveries started, by user_id
+	starts: RateLimit,
+	// { token, recovery in progress }
+	recoveries: HashMap<String, Recovery>,
+}
+
+impl Recoveries {
+	// `proof` is hashed already
+	pub fn set_code(&mut self, user_id: u64, proof: String, master_key: encrypted::Encrypted) {
+		self.codes.insert(user_id, Code { proof, master_key });
+	}
+
+	pub fn remove_code(&mut self, user_id: u64) -> bool {
+		self.codes.remove(&user_id).is_some()
+	}
+
+	pub fn add_contact(&mut self, user_id: u64, contact_id: u64, master_key: identity::Encrypted) {
+		self.contacts
+			.entry(user_id)
+			.or_default()
+			.insert(contact_id, master_key);
+	}
+
+	pub fn remove_contact(&mut self, user_id: u64, contact_id: u64) -> bool {
+		self.contacts
+			.get_mut(&user_id)
+			.is_some_and(|contacts| contacts.remove(&contact_id).is_some())
+	}
+
+	fn is_blocked(&self, user_id: u64, now: u64) -> bool {
+		self.attempts
+			.get(&user_id)
+			.is_some_and(|a| now < a.expires_at && a.count >= MAX_ATTEMPTS)
+	}
+
+	fn count_attempt(&mut self, user_id: u64, ttl: u64, now: u64) {
+		let attempts = self.attempts.entry(user_id).or_insert(Attempts {
+			count: 0,
+			expires_at: now + ttl,
+		});
+
+		if now >= attempts.expires_at {
+			attempts.count = 0;
+			attempts.expires_at = now + ttl;
+		}
+
+		attempts.count += 1;
+	}
+
+	fn start(&mut self, recovery: Recovery) -> String {
+		let token = token::generate();
+
+		self.recoveries.insert(token.clone(), recovery);
+
+		token
+	}
+
+	// the hash to check a code against, off the lock; counted as an attempt until it matches, so
+	// guesses running side by side are capped all the same. `ttl` in seconds, here and below
+	pub fn code_proof(&mut self, user_id: u64, ttl: u64, now: u64) -> Result<String, Error> {
+		if self.is_blocked(user_id, now) {
+			return Err(Error::Blocked);
+		}
+
+		let proof = self
+			.codes
+			.get(&user_id)
+			.map(|code| code.proof.clone())
+			.ok_or(Error::NotFound)?;
+
+		self.count_attempt(user_id, ttl, now);
+
+		Ok(proof)
+	}
+
+	// `checked` is the hash from code_proof the code matched
+	pub fn recover_with_code(
+		&mut self,
+		user_id: u64,
+		checked: &str,
+		ttl: u64,
+		now: u64,
+	) -> Result<String, Error> {
+		let master_key = self
+			.codes
+			.get(&user_id)
+			.filter(|code| code.proof == checked)
+			.map(|code| code.master_key.clone())
+			.ok_or(Error::NotFound)?;
+
+		self.attempts.remove(&user_id);
+
+		Ok(self.start(Recovery {
+			user_id,
+			ready_at: now,
+			expires_at: now + ttl,
+			wrapping: Some(Wrapping::Code { master_key }),
+			request: None,
+		}))
+	}
+
+	// returns the token and the id of the request, which the owner may cancel it by; it can't be
+	// completed for `delay` seconds
+	pub fn recover_with_contact(
+		&mut self,
+		user_id: u64,
+		contact_id: u64,
+		eph_x448: PublicKeyX448,
+		ttl: u64,
+		delay: u64,
+		now: u64,
+	) -> Result<(String, String), Error> {
+		let master_key = self
+			.contacts
+			.get(&user_id)
+			.and_then(|contacts| contacts.get(&contact_id))
+			.cloned()
+			.ok_or(Error::NotFound)?;
+
+		if !self.starts.check(user_id, MAX_STARTS, START_WINDOW, now) {
+			return Err(Error::Blocked);
+		}
+
+		let ready_at = now + delay;
+		let expires_at = ready_at + ttl;
+		let id = token::generate();
+		let token = self.start(Recovery {
+			user_id,
+			ready_at,
+			expires_at,
+			wrapping: None,
+			request: Some(ContactRequest {
+				id: id.clone(),
+				user_id,
+				master_key,
+				eph_x448,
+				expires_at,
+			}),
+		});
+
+		Ok((token, id))
+	}
+
+	// by the owner, who may well still have a device
+	pub fn cancel(&mut self, user_id: u64, request_id: &str) -> bool {
+		let before = self.recoveries.len();
+
+		self.recoveries.retain(|_, recovery| {
+			recovery.user_id != user_id
+				|| recovery
+					.request
+					.as_ref()
+					.is_none_or(|req| req.id != request_id)
+		});
+
+		self.recoveries.len() < before
+	}
+
+	fn contact_of(&self, recovery: &Recovery, contact_id: u64) -> bool {
+		self.contacts
+			.get(&recovery.user_id)
+			.is_some_and(|contacts| contacts.contains_key(&contact_id))
+	}
+
+	pub fn requests_for_contact(&self, contact_id: u64, now: u64) -> Vec<ContactRequest> {
+		self.recoveries
+			.values()
+			.filter(|r| now < r.expires_at && r.wrapping.is_none())
+			.filter(|r| self.contact_of(r, contact_id))
+			.filter_map(|r| r.request.clone())
+			.collect()
+	}
+
+	pub fn approve(
+		&mut self,
+		contact_id: u64,
+		request_id: &str,
+		master_key: identity::Encrypted,
+		now: u64,
+	) -> Result<(), Error> {
+		let token = self
+			.recoveries
+			.iter()
+			.find(|(_, r)| r.request.as_ref().is_some_and(|req| req.id == request_id))
+			.map(|(token, _)| token.clone())
+			.ok_or(Error::NotFound)?;
+		let recovery = self.recoveries.get(&token).ok_or(Error::NotFound)?;
+
+		if !self.contact_of(recovery, contact_id) {
+			return Err(Error::NotFound);
+		}
+
+		if now >= recovery.expires_at {
+			return Err(Error::Expired);
+		}
+
+		if let Some(recovery) = self.recoveries.get_mut(&token) {
+			recovery.wrapping = Some(Wrapping::Contact { master_key });
+		}
+
+		Ok(())
+	}
+
+	pub fn wrapping(&self, token: &str, now: u64) -> Result<&Wrapping, Error> {
+		let recovery = self.recoveries.get(token).ok_or(Error::NotFound)?;
+
+		if now >= recovery.expires_at {
+			return Err(Error::Expired);
+		}
+
+		if now < recovery.ready_at {
+			return Err(Error::Pending);
+		}
+
+		recovery.wrapping.as_ref().ok_or(Error::Pending)
+	}
+
+	// ends the recovery; returns whose password is to be reset
+	pub fn complete(&mut self, token: &str, now: u64) -> Result<u64, Error> {
+		self.wrapping(token, now)?;
+
+		self.recoveries
+			.remove(token)
+			.map(|recovery| recovery.user_id)
+			.ok_or(Error::NotFound)
+	}
+
+	pub fn remove_all_for(&mut self, user_id: u64) {
+		self.codes.remove(&user_id);
+		self.contacts.remove(&user_id);
+		self.attempts.remove(&user_id);
+		self.recoveries
+			.retain(|_, recovery| recovery.user_id != user_id);
+	}
+
+	pub fn remove_expired(&mut self, now: u64) {
+		self.attempts.retain(|_, a| now < a.expires_at);
+		self.starts.remove_expired(START_WINDOW, now);
+		self.recoveries.retain(|_, r| now < r.expires_at);
+	}
+}
+
+impl Purge for Recoveries {
+	fn new() -> Self {
+		Self {
+			codes: HashMap::new(),
+			contacts: HashMap::new(),
+			attempts: HashMap::new(),
+			starts: RateLimit::new(),
+			recoveries: HashMap::new(),
+		}
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use crate::{password, salt::Salt};
+
+	fn mk() -> encrypted::Encrypted {
+		encrypted::Encrypted {
+			ct: vec![1, 2, 3],
+			salt: Salt::generate(),
+		}
+	}
+
+	fn wrapped() -> identity::Encrypted {
+		serde_json::from_value(serde_json::json!({
+			"ct": "AQID",
+			"eph_x448": base64::encode([0u8; 56]),
+		}))
+		.unwrap()
+	}
+
+	fn eph() -> PublicKeyX448 {
+		PublicKeyX448::new([2u8; 56])
+	}
+
+	fn with_code(recoveries: &mut Recoveries) -> encrypted::Encrypted {
+		let master_key = mk();
+
+		recoveries.set_code(1, password::hash("proof"), master_key.clone());
+
+		master_key
+	}
+
+	// what the handler does, with argon2 inline
+	fn recover_with_code(
+		recoveries: &mut Recoveries,
+		proof: &str,
+		now: u64,
+	) -> Result<String, Error> {
+		let hash = recoveries.code_proof(1, TTL, now)?;
+
+		if !password::verify(&hash, proof) {
+			return Err(Error::NotAllowed);
+		}
+
+		recoveries.recover_with_code(1, &hash, TTL, now)
+	}
+
+	#[test]
+	fn test_recover_with_code() {
+		let mut recoveries = Recoveries::new();
+		let master_key = with_code(&mut recoveries);
+		let token = recover_with_code(&mut recoveries, "proof", 0).unwrap();
+
+		assert_eq!(
+			recoveries.wrapping(&token, 1),
+			Ok(&Wrapping::Code { master_key })
+		);
+		assert_eq!(recoveries.complete(&token, 1), Ok(1));
+		assert_eq!(recoveries.complete(&token, 1), Err(Error::NotFound));
+	}
+
+	#[test]
+	fn test_wrong_codes_block() {
+		let mut recoveries = Recoveries::new();
+
+		with_code(&mut recoveries);
+
+		(0..MAX_ATTEMPTS).for_each(|_| {
+			assert_eq!(
+				recover_with_code(&mut recoveries, "nope", 0).err(),
+				Some(Error::NotAllowed)
+			)
+		});
+
+		assert_eq!(
+			recover_with_code(&mut recoveries, "proof", 1).err(),
+			Some(Error::Blocked)
+		);
+		assert!(recover_with_code(&mut recoveries, "proof", TTL).is_ok());
+	}
+
+	#[test]
+	fn test_code_checked_against_stale_hash() {
+		let mut recoveries = Recoveries::new();
+
+		with_code(&mut recoveries);
+
+		let hash = recoveries.code_proof(1, TTL, 0).unwrap();
+
+		// replaced while the old one was being checked
+		with_code(&mut recoveries);
+
+		assert_eq!(
+			recoveries.recover_with_code(1, &hash, TTL, 0).err(),
+			Some(Error::NotFound)
+		);
+	}
+
+	#[test]
+	fn test_recovery_expires() {
+		let mut recoveries = Recoveries::new();
+
+		with_code(&mut recoveries);
+
+		let token = recover_with_code(&mut recoveries, "proof", 0).unwrap();
+
+		assert_eq!(recoveries.complete(&token, TTL), Err(Error::Expired));
+	}
+
+	#[test]
+	fn test_recover_with_contact() {
+		let mut recoveries = Recoveries::new();
+
+		recoveries.add_contact(1, 2, wrapped());
+
+		assert_eq!(
+			recoveries
+				.recover_with_contact(1, 3, eph(), TTL, DELAY, 0)
+				.err(),
+			Some(Error::NotFound)
+		);
+
+		let (token, id) = recoveries
+			.recover_with_contact(1, 2, eph(), TTL, DELAY, 0)
+			.unwrap();
+
+		assert_eq!(recoveries.wrapping(&token, 1), Err(Error::Pending));
+		assert!(recoveries.requests_for_contact(3, 1).is_empty());
+
+		let requests = recoveries.requests_for_contact(2, 1);
+
+		assert_eq!(requests.len(), 1);
+		assert_eq!(requests[0].id, id);
+		assert_eq!(
+			recoveries.approve(3, &id, wrapped(), 1),
+			Err(Error::NotFound)
+		);
+		assert_eq!(recoveries.approve(2, &id, wrapped(), 1), Ok(()));
+		// approved, but the owner still gets the whole delay to object
+		assert_eq!(recoveries.wrapping(&token, DELAY - 1), Err(Error::Pending));
+		assert_eq!(recoveries.complete(&token, DELAY - 1), Err(Error::Pending));
+		assert_eq!(
+			recoveries.wrapping(&token, DELAY),
+			Ok(&Wrapping::Contact {
+				master_key: wrapped()
+			})
+		);
+		assert!(recoveries.requests_for_contact(2, 1).is_empty());
+		assert_eq!(recoveries.complete(&token, DELAY), Ok(1));
+	}
+
+	#[test]
+	fn test_owner_cancels_contact_recovery() {
+		let mut recoveries = Recoveries::new();
+
+		recoveries.add_contact(1, 2, wrapped());
+
+		let (token, id) = recoveries
+			.recover_with_contact(1, 2, eph(), TTL, DELAY, 0)
+			.unwrap();
+
+		// only the owner's own
+		assert!(!recoveries.cancel(2, &id));
+		assert!(recoveries.cancel(1, &id));
+		assert!(!recoveries.cancel(1, &id));
+		assert_eq!(recoveries.wrapping(&token, DELAY), Err(Error::NotFound));
+		assert!(recoveries.requests_for_contact(2, 1).is_empty());
+	}
+
+	#[test]
+	fn test_contact_recoveries_are_rate_limited() {
+		let mut recoveries = Recoveries::new();
+
+		recoveries.add_contact(1, 2, wrapped());
+
+		(0..MAX_STARTS).for_each(|_| {
+			assert!(recoveries
+				.recover_with_contact(1, 2, eph(), TTL, DELAY, 0)
+				.is_ok());
+		});
+
+		assert_eq!(
+			recoveries
+				.recover_with_contact(1, 2, eph(), TTL, DELAY, 1)
+				.err(),
+			Some(Error::Blocked)
+		);
+		assert!(recoveries
+			.recover_with_contact(1, 2, eph(), TTL, DELAY, START_WINDOW)
+			.is_ok());
+	}
+
+	#[test]
+	fn test_removed_contact_cant_approve() {
+		let mut recoveries = Recoveries::new();
+
+		recoveries.add_contact(1, 2, wrapped());
+
+		recoveries
+			.recover_with_contact(1, 2, eph(), TTL, DELAY, 0)
+			.unwrap();
+
+		let id = recoveries.requests_for_contact(2, 1)[0].id.clone();
+
+		assert!(recoveries.remove_contact(1, 2));
+		assert_eq!(
+			recoveries.approve(2, &id, wrapped(), 1),
+			Err(Error::NotFound)
+		);
+	}
+}
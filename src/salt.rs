@@ -17,6 +17,7 @@ pub struct Salt {
 impl Salt {
 	pub const SIZE: usize = SALT_SIZE;
 
+	#[allow(dead_code)]
 	pub fn generate() -> Self {
 		let mut bytes = [0u8; Self::SIZE];
 		OsRng.fill_bytes(&mut bytes);
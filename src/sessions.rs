@@ -1,18 +1,151 @@
-use crate::{purge::Purge, shares::Seed};
-use std::collections::HashMap;
+use crate::{
+	pairing::{self, Channel, Commitment, Joined},
+	purge::Purge,
+	rate_limit::RateLimit,
+	shares::Seed,
+};
+use serde::Deserialize;
+use std::{collections::HashMap, sync::Arc};
+use tokio::sync::Notify;
+
+// how long a locked session lives, seconds
+pub const TTL: u64 = 5 * 60;
+// unlocks and joins finding nothing, per caller and for all ids together: counted per id, a
+// guesser would just move on to the next one. long-polls count too, so each caller only ever holds
+// a few of the waiter slots
+pub const MAX_MISSES: usize = 20;
+// seconds
+pub const MISS_WINDOW: u64 = 60;
+// unlocks waiting for their counterpart at once
+pub const MAX_WAITERS: usize = 1000;
+// the longest an unlock may wait for its counterpart to lock, seconds
+pub const MAX_WAIT: u64 = 30;
+
+#[derive(PartialEq, Debug)]
+pub enum Error {
+	// too many misses or waiting unlocks; ids locked already can still be unlocked
+	Blocked,
+	// a channel with this id is already open
+	Taken,
+	Pairing(pairing::Error),
+}
+
+#[derive(Deserialize)]
+pub struct Wait {
+	// seconds to wait for the session to be locked, if it isn't yet
+	pub wait: Option<u64>,
+}
+
+pub struct Locked {
+	pub seed: Seed,
+	pub expires_at: u64,
+}
 
 pub struct Sessions {
 	// { token_id, token }
-	pub tokens: HashMap<String, Seed>,
+	pub tokens: HashMap<String, Locked>,
+	// by caller, see MAX_MISSES
+	misses: RateLimit,
+	// { token_id, unlocks waiting for this id to be locked }
+	waiters: HashMap<String, Arc<Notify>>,
+	// { channel_id, pairing channel }
+	channels: HashMap<String, Channel>,
 }
 
 impl Sessions {
-	pub fn add_token(&mut self, id: &str, token: Seed) {
-		self.tokens.insert(id.to_string(), token);
+	// `ttl` in seconds, same for open_channel
+	pub fn add_token(&mut self, id: &str, token: Seed, ttl: u64, now: u64) {
+		self.tokens.insert(
+			id.to_string(),
+			Locked {
+				seed: token,
+				expires_at: now + ttl,
+			},
+		);
+
+		// notify_one keeps a permit, so an unlock about to wait won't miss it
+		if let Some(waiter) = self.waiters.remove(id) {
+			waiter.notify_one();
+		}
 	}
 
-	pub fn consume_token_by_id(&mut self, id: &str) -> Option<Seed> {
-		self.tokens.remove(id)
+	pub fn consume_token_by_id(&mut self, id: &str, now: u64) -> Option<Seed> {
+		self.tokens
+			.remove(id)
+			.filter(|locked| now < locked.expires_at)
+			.map(|locked| locked.seed)
+	}
+
+	// an id looked up and not found by `caller`; fails once it has had too many lately
+	pub fn miss(&mut self, caller: u64, now: u64) -> Result<(), Error> {
+		if self.misses.check(caller, MAX_MISSES, MISS_WINDOW, now) {
+			Ok(())
+		} else {
+			Err(Error::Blocked)
+		}
+	}
+
+	// to be awaited (with a timeout) when nothing is locked under `id` yet
+	pub fn waiter(&mut self, id: &str) -> Result<Arc<Notify>, Error> {
+		if !self.waiters.contains_key(id) && self.waiters.len() >= MAX_WAITERS {
+			return Err(Error::Blocked);
+		}
+
+		Ok(self.waiters.entry(id.to_string()).or_default().clone())
+	}
+
+	pub fn open_channel(
+		&mut self,
+		id: &str,
+		owner_id: u64,
+		ttl: u64,
+		now: u64,
+	) -> Result<(), Error> {
+		if self.channels.contains_key(id) {
+			return Err(Error::Taken);
+		}
+
+		self.channels
+			.insert(id.to_string(), Channel::new(owner_id, now + ttl));
+
+		Ok(())
+	}
+
+	// unknown ids count as misses, same as unlocks
+	pub fn join_channel(
+		&mut self,
+		id: &str,
+		commitment: Commitment,
+		caller: u64,
+		now: u64,
+	) -> Result<Joined, Error> {
+		match self.channel(id, now) {
+			Some(channel) => channel.join(commitment).map_err(Error::Pairing),
+			None => {
+				self.miss(caller, now)?;
+
+				Err(Error::Pairing(pairing::Error::NotFound))
+			}
+		}
+	}
+
+	pub fn channel(&mut self, id: &str, now: u64) -> Option<&mut Channel> {
+		self.channels
+			.get_mut(id)
+			.filter(|channel| now < channel.expires_at)
+	}
+
+	pub fn close_channel(&mut self, id: &str) -> Option<Channel> {
+		self.channels.remove(id)
+	}
+
+	pub fn remove_expired(&mut self, now: u64) {
+		self.tokens.retain(|_, locked| now < locked.expires_at);
+		self.channels.retain(|_, channel| now < channel.expires_at);
+		self.misses.remove_expired(MISS_WINDOW, now);
+		// nobody's waiting on these anymore
+		self.waiters
+			.retain(|_, waiter| Arc::strong_count(waiter) > 1);
 	}
 }
 
@@ -20,6 +153,135 @@ impl Purge for Sessions {
 	fn new() -> Self {
 		Self {
 			tokens: HashMap::new(),
+			misses: RateLimit::new(),
+			waiters: HashMap::new(),
+			channels: HashMap::new(),
 		}
 	}
 }
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use std::time::Duration;
+
+	fn seed() -> Seed {
+		Seed { bytes: [1u8; 32] }
+	}
+
+	#[test]
+	fn test_consume_once() {
+		let mut sessions = Sessions::new();
+
+		sessions.add_token("a", seed(), TTL, 0);
+
+		assert_eq!(sessions.consume_token_by_id("a", 1), Some(seed()));
+		assert_eq!(sessions.consume_token_by_id("a", 1), None);
+	}
+
+	#[test]
+	fn test_expired_token() {
+		let mut sessions = Sessions::new();
+
+		sessions.add_token("a", seed(), TTL, 0);
+
+		assert_eq!(sessions.consume_token_by_id("a", TTL), None);
+	}
+
+	#[test]
+	fn test_blocked_after_misses() {
+		let mut sessions = Sessions::new();
+
+		sessions.add_token("a", seed(), TTL, 0);
+		(0..MAX_MISSES).for_each(|_| assert_eq!(sessions.miss(1, 0), Ok(())));
+
+		assert_eq!(sessions.miss(1, 1), Err(Error::Blocked));
+		// others keep their own budget
+		assert_eq!(sessions.miss(2, 1), Ok(()));
+		// whatever is locked can still be unlocked
+		assert_eq!(sessions.consume_token_by_id("a", 1), Some(seed()));
+		// and the block goes away along with the window
+		assert_eq!(sessions.miss(1, MISS_WINDOW), Ok(()));
+	}
+
+	#[test]
+	fn test_waiters_are_capped() {
+		let mut sessions = Sessions::new();
+		let waiters: Vec<_> = (0..MAX_WAITERS)
+			.map(|id| sessions.waiter(&id.to_string()).unwrap())
+			.collect();
+
+		assert_eq!(sessions.waiter("x").err(), Some(Error::Blocked));
+		// another unlock of an id waited on already is fine
+		assert!(sessions.waiter("0").is_ok());
+
+		drop(waiters);
+		sessions.remove_expired(0);
+
+		assert!(sessions.waiter("x").is_ok());
+	}
+
+	#[test]
+	fn test_remove_expired() {
+		let mut sessions = Sessions::new();
+
+		sessions.add_token("a", seed(), TTL, 0);
+		sessions.add_token("b", seed(), TTL, 10);
+		sessions.remove_expired(TTL);
+
+		assert!(!sessions.tokens.contains_key("a"));
+		assert!(sessions.tokens.contains_key("b"));
+	}
+
+	fn commitment() -> Commitment {
+		Commitment::new(&pairing::Hello {
+			eph_x448: crate::x448::PublicKeyX448::new([1u8; 56]),
+		})
+	}
+
+	#[test]
+	fn test_join_unknown_channel_counts_misses() {
+		let mut sessions = Sessions::new();
+
+		(0..MAX_MISSES).for_each(|id| {
+			assert_eq!(
+				sessions
+					.join_channel(&id.to_string(), commitment(), 1, 0)
+					.err(),
+				Some(Error::Pairing(pairing::Error::NotFound))
+			)
+		});
+
+		assert_eq!(
+			sessions.join_channel("a", commitment(), 1, 0).err(),
+			Some(Error::Blocked)
+		);
+		assert_eq!(sessions.open_channel("a", 1, TTL, 0), Ok(()));
+	}
+
+	#[test]
+	fn test_channel_expires() {
+		let mut sessions = Sessions::new();
+
+		sessions.open_channel("a", 1, TTL, 0).unwrap();
+
+		assert_eq!(sessions.open_channel("a", 1, TTL, 0), Err(Error::Taken));
+		assert!(sessions.join_channel("a", commitment(), 1, 1).is_ok());
+		assert!(sessions.channel("a", TTL).is_none());
+	}
+
+	#[tokio::test]
+	async fn test_waiter_is_notified_on_lock() {
+		let mut sessions = Sessions::new();
+		let waiter = sessions.waiter("a").unwrap();
+
+		// locking before the waiter gets to await must not be missed
+		sessions.add_token("a", seed(), TTL, 0);
+
+		assert!(
+			tokio::time::timeout(Duration::from_secs(1), waiter.notified())
+				.await
+				.is_ok()
+		);
+	}
+}
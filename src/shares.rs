@@ -1,14 +1,16 @@
-use std::collections::HashMap;
+use std::collections::{HashMap, HashSet};
 
 use crate::{
 	base64_blobs::{deserialize_array_base64, serialize_array_base64},
 	ed448, identity, lock,
-	nodes::LockedNode,
+	nodes::{LockedNode, Nodes},
 	purge::Purge,
 };
 use serde::{Deserialize, Serialize};
 
 const SEED_SIZE: usize = 32;
+// prepended to what a share's sig covers, so it can't be lifted from anywhere else
+const SHARE_CONTEXT: &[u8] = b"uploader share:";
 
 #[derive(Serialize, Deserialize, PartialEq, Debug, Clone, Hash)]
 pub struct Seed {
@@ -19,6 +21,31 @@ pub struct Seed {
 	pub(crate) bytes: [u8; SEED_SIZE],
 }
 
+// ordered: a higher role can do everything a lower one can
+#[derive(Serialize, Deserialize, Debug, PartialEq, Eq, PartialOrd, Ord, Clone, Copy, Default)]
+#[serde(rename_all = "lowercase")]
+pub enum Role {
+	// read only
+	// exports from clients that predate roles get the least one, hence the default
+	#[default]
+	Viewer,
+	// can add, upload, move and delete within the shared subtree
+	Editor,
+	// can do anything, including changing roles of others
+	Owner,
+}
+
+impl Role {
+	// same as serialized
+	pub fn as_str(&self) -> &'static str {
+		match self {
+			Role::Viewer => "viewer",
+			Role::Editor => "editor",
+			Role::Owner => "owner",
+		}
+	}
+}
+
 #[derive(Serialize, Deserialize, Debug, PartialEq, Clone)]
 pub struct Export {
 	// no sig is required here; validate LockedShare instead
@@ -26,6 +53,14 @@ pub struct Export {
 	// these are ids of the exported seeds
 	pub fs: Vec<u64>,
 	pub db: Vec<u64>,
+	// signed along with the rest, see LockedShare::message; owners may still override it later
+	#[serde(default)]
+	pub role: Role,
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct RoleChange {
+	pub role: Role,
 }
 
 #[derive(Serialize, Deserialize, PartialEq, Debug, Clone)]
@@ -36,10 +71,53 @@ pub struct LockedShare {
 	pub export: Export,
 	// encrypted content of the sahre
 	pub payload: identity::Encrypted,
-	// sign({ sender, exports })
+	// sign(message())
 	pub sig: ed448::Signature,
 }
 
+impl LockedShare {
+	// the context, sender id, receiver and role, then the fs and db ids, each list prefixed with
+	// its length; numbers are big endian u64s, the role is as serialized and prefixed with its length
+	pub fn message(&self) -> Vec<u8> {
+		let role = self.export.role.as_str().as_bytes();
+		let mut msg = SHARE_CONTEXT.to_vec();
+
+		msg.extend(self.sender.id().to_be_bytes());
+		msg.extend(self.export.receiver.to_be_bytes());
+		msg.push(role.len() as u8);
+		msg.extend(role);
+
+		for ids in [&self.export.fs, &self.export.db] {
+			msg.extend((ids.len() as u64).to_be_bytes());
+			ids.iter().for_each(|id| msg.extend(id.to_be_bytes()));
+		}
+
+		msg
+	}
+
+	// what clients signed before roles were: message() without the context and the role
+	pub fn legacy_message(&self) -> Vec<u8> {
+		let mut msg = Vec::new();
+
+		msg.extend(self.sender.id().to_be_bytes());
+		msg.extend(self.export.receiver.to_be_bytes());
+
+		for ids in [&self.export.fs, &self.export.db] {
+			msg.extend((ids.len() as u64).to_be_bytes());
+			ids.iter().for_each(|id| msg.extend(id.to_be_bytes()));
+		}
+
+		msg
+	}
+
+	// a legacy sig doesn't cover the role, so it only ever grants the default one
+	pub fn verify(&self) -> bool {
+		self.sender.ed448.verify(&self.message(), &self.sig)
+			|| (self.export.role == Role::default()
+				&& self.sender.ed448.verify(&self.legacy_message(), &self.sig))
+	}
+}
+
 #[derive(Serialize, Deserialize, PartialEq, Debug)]
 pub struct Invite {
 	pub(crate) user_id: u64,
@@ -64,6 +142,10 @@ pub struct Welcome {
 pub struct Shares {
 	pub shares: Vec<LockedShare>,
 	pub invites: HashMap<String, Invite>,
+	// { email, unix time the invite was sent }
+	invited_at: HashMap<String, u64>,
+	// { (receiver, node_id), role } set by owners; the signed exports keep the role they were made with
+	overrides: HashMap<(u64, u64), Role>,
 }
 
 impl Shares {
@@ -71,16 +153,132 @@ impl Shares {
 		self.shares.push(share);
 	}
 
-	pub fn all_shares_for_user(&self, user_id: u64) -> Vec<LockedShare> {
+	pub fn count(&self) -> usize {
+		self.shares.len()
+	}
+
+	// takes back shares added on trial, down to `count`
+	pub fn truncate(&mut self, count: usize) {
+		self.shares.truncate(count);
+		self.remove_stale_overrides();
+	}
+
+	// including the ones exported to any of `groups` the user is a member of
+	pub fn all_shares_for_user(&self, user_id: u64, groups: &[u64]) -> Vec<LockedShare> {
 		self.shares
 			.iter()
-			.filter(|&share| share.sender.id() == user_id || share.export.receiver == user_id)
+			.filter(|&share| {
+				share.sender.id() == user_id
+					|| share.export.receiver == user_id
+					|| groups.contains(&share.export.receiver)
+			})
 			.cloned()
 			.collect()
 	}
 
-	pub fn add_invite(&mut self, invite: Invite, email: &str) {
+	// everything sent or exported to the user, pending invites they sent included
+	pub fn remove_for_user(&mut self, user_id: u64) {
+		self.shares
+			.retain(|share| share.sender.id() != user_id && share.export.receiver != user_id);
+		self.invites
+			.retain(|_, invite| invite.sender.id() != user_id);
+		self.remove_stale_overrides();
+	}
+
+	pub fn remove_for_receiver(&mut self, receiver: u64) {
+		self.shares
+			.retain(|share| share.export.receiver != receiver);
+		self.remove_stale_overrides();
+	}
+
+	// shares and invites naming any of the `removed` nodes, whatever else they name: they are signed
+	// as a whole, and a node added later under a reused id must not be shared by them
+	pub fn remove_for_nodes(&mut self, removed: &HashSet<u64>) {
+		let names_removed = |export: &Export| export.fs.iter().any(|id| removed.contains(id));
+		let emails: Vec<String> = self
+			.invites
+			.iter()
+			.filter(|(_, invite)| names_removed(&invite.export))
+			.map(|(email, _)| email.clone())
+			.collect();
+
+		self.shares.retain(|share| !names_removed(&share.export));
+		emails.iter().for_each(|email| self.delete_invite(email));
+		self.remove_stale_overrides();
+	}
+
+	// so an override can't outlive the shares it was set on and apply to a later one
+	fn remove_stale_overrides(&mut self) {
+		let shares = &self.shares;
+
+		self.overrides.retain(|(receiver, node_id), _| {
+			shares.iter().any(|share| {
+				share.export.receiver == *receiver && share.export.fs.contains(node_id)
+			})
+		});
+	}
+
+	// Owner for whoever owns the node, otherwise the highest role exported to `user_id`
+	// (or any of its `groups`) for the node or any of its parents
+	pub fn role(&self, user_id: u64, groups: &[u64], nodes: &Nodes, node_id: u64) -> Option<Role> {
+		if nodes.owner_of(node_id) == Some(user_id) {
+			return Some(Role::Owner);
+		}
+
+		let chain = nodes.ancestors(node_id);
+
+		self.shares
+			.iter()
+			.filter(|share| {
+				share.export.receiver == user_id || groups.contains(&share.export.receiver)
+			})
+			.flat_map(|share| {
+				share
+					.export
+					.fs
+					.iter()
+					.filter(|id| chain.contains(id))
+					.map(|id| {
+						self.overrides
+							.get(&(share.export.receiver, *id))
+							.copied()
+							.unwrap_or(share.export.role)
+					})
+			})
+			.max()
+	}
+
+	// returns false if nothing has been exported to `receiver` for `node_id`
+	pub fn set_role(&mut self, receiver: u64, node_id: u64, role: Role) -> bool {
+		let found = self
+			.shares
+			.iter()
+			.any(|share| share.export.receiver == receiver && share.export.fs.contains(&node_id));
+
+		if found {
+			self.overrides.insert((receiver, node_id), role);
+		}
+
+		found
+	}
+
+	// false if someone other than `sender` has an invite pending for `email`
+	pub fn can_invite(&self, email: &str, sender: u64) -> bool {
+		self.invites
+			.get(email)
+			.is_none_or(|invite| invite.sender.id() == sender)
+	}
+
+	// returns false, keeping the pending one, if someone else has invited `email` already
+	pub fn add_invite(&mut self, invite: Invite, email: &str, now: u64) -> bool {
+		if !self.can_invite(email, invite.sender.id()) {
+			return false;
+		}
+
 		self.invites.insert(email.to_string(), invite);
+		self.invited_at.insert(email.to_string(), now);
+
+		true
 	}
 
 	pub fn invie_for_mail(&self, email: &str) -> Option<&Invite> {
@@ -89,6 +287,21 @@ impl Shares {
 
 	pub fn delete_invite(&mut self, email: &str) {
 		self.invites.remove(email);
+		self.invited_at.remove(email);
+	}
+
+	// invites older than `ttl` seconds; returns how many went
+	pub fn remove_stale_invites(&mut self, ttl: u64, now: u64) -> usize {
+		let stale: Vec<String> = self
+			.invited_at
+			.iter()
+			.filter(|(_, at)| **at + ttl <= now)
+			.map(|(email, _)| email.clone())
+			.collect();
+
+		stale.iter().for_each(|email| self.delete_invite(email));
+
+		stale.len()
 	}
 }
 
@@ -97,6 +310,8 @@ impl Purge for Shares {
 		Self {
 			shares: Vec::new(),
 			invites: HashMap::new(),
+			invited_at: HashMap::new(),
+			overrides: HashMap::new(),
 		}
 	}
 }
@@ -104,3 +319,231 @@ impl Purge for Shares {
 // get_nodes(locked_shares(user_id == share.receiver | user_id == 0 then node_id_root).export.fs.ids + children)
 // { user_id, share }
 // shares: HashMap<u64, LockedShare>,
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use crate::{encrypted::Encrypted, salt::Salt};
+
+	const NO_PARENT_ID: u64 = u64::MAX;
+
+	fn share(sender: u64, receiver: u64, fs: Vec<u64>, role: Role) -> LockedShare {
+		let x448 = base64::encode([0u8; 56]);
+		let ed448 = base64::encode([0u8; 57]);
+		let sig = base64::encode([0u8; 114]);
+
+		serde_json::from_value(serde_json::json!({
+			"sender": { "id": sender, "x448": x448, "ed448": ed448 },
+			"export": { "receiver": receiver, "fs": fs, "db": [], "role": role },
+			"payload": { "ct": "", "eph_x448": x448 },
+			"sig": { "bytes": sig },
+		}))
+		.unwrap()
+	}
+
+	fn node(id: u64, parent_id: u64) -> LockedNode {
+		LockedNode {
+			id,
+			parent_id,
+			content: Encrypted {
+				ct: vec![],
+				salt: Salt::generate(),
+			},
+			dirty: false,
+		}
+	}
+
+	// 0 (owned by 1)
+	//  2
+	//   3
+	//  4
+	fn tree() -> Nodes {
+		let mut nodes = Nodes::new();
+
+		nodes.add(node(0, NO_PARENT_ID));
+		nodes.add(node(2, 0));
+		nodes.add(node(3, 2));
+		nodes.add(node(4, 0));
+		nodes.set_owner(0, 1);
+
+		nodes
+	}
+
+	#[test]
+	fn test_remove_for_user() {
+		let mut shares = Shares::new();
+
+		shares.add_share(share(1, 5, vec![2], Role::Viewer));
+		shares.add_share(share(5, 6, vec![2], Role::Viewer));
+		shares.add_share(share(6, 1, vec![2], Role::Viewer));
+		shares.remove_for_user(1);
+
+		assert_eq!(shares.shares.len(), 1);
+		assert_eq!(shares.shares[0].sender.id(), 5);
+	}
+
+	#[test]
+	fn test_remove_for_nodes() {
+		let mut shares = Shares::new();
+
+		shares.add_share(share(1, 5, vec![2], Role::Viewer));
+		shares.add_share(share(1, 6, vec![2, 4], Role::Viewer));
+		shares.add_share(share(1, 7, vec![4], Role::Viewer));
+		shares.set_role(5, 2, Role::Editor);
+		shares.remove_for_nodes(&HashSet::from([2, 3]));
+
+		assert_eq!(shares.shares.len(), 1);
+		assert_eq!(shares.shares[0].export.receiver, 7);
+		assert!(shares.overrides.is_empty());
+	}
+
+	fn invite(sender: u64, email: &str) -> Invite {
+		let x448 = base64::encode([0u8; 56]);
+		let ed448 = base64::encode([0u8; 57]);
+
+		serde_json::from_value(serde_json::json!({
+			"user_id": 9,
+			"sender": { "id": sender, "x448": x448, "ed448": ed448 },
+			"email": email,
+			"payload": {
+				"ct": "",
+				"master_key": { "ct": "", "salt": { "bytes": base64::encode([0u8; 32]) } },
+			},
+			"export": { "receiver": 9, "fs": [], "db": [], "role": "viewer" },
+			"sig": { "bytes": base64::encode([0u8; 114]) },
+		}))
+		.unwrap()
+	}
+
+	#[test]
+	fn test_pending_invite_kept_from_others() {
+		let mut shares = Shares::new();
+
+		assert!(shares.add_invite(invite(1, "a@mail.com"), "a@mail.com", 0));
+		assert!(shares.add_invite(invite(1, "a@mail.com"), "a@mail.com", 1));
+		assert!(!shares.add_invite(invite(2, "a@mail.com"), "a@mail.com", 2));
+		assert_eq!(
+			shares
+				.invie_for_mail("a@mail.com")
+				.map(|invite| invite.sender.id()),
+			Some(1)
+		);
+		assert_eq!(shares.invited_at["a@mail.com"], 1);
+	}
+
+	#[test]
+	fn test_role_of_owner() {
+		let shares = Shares::new();
+		let nodes = tree();
+
+		assert_eq!(shares.role(1, &[], &nodes, 3), Some(Role::Owner));
+		assert_eq!(shares.role(5, &[], &nodes, 3), None);
+	}
+
+	#[test]
+	fn test_role_is_inherited_by_subtree_only() {
+		let mut shares = Shares::new();
+		let nodes = tree();
+
+		shares.add_share(share(1, 5, vec![2], Role::Viewer));
+
+		assert_eq!(shares.role(5, &[], &nodes, 2), Some(Role::Viewer));
+		assert_eq!(shares.role(5, &[], &nodes, 3), Some(Role::Viewer));
+		assert_eq!(shares.role(5, &[], &nodes, 4), None);
+		assert_eq!(shares.role(5, &[], &nodes, 0), None);
+	}
+
+	#[test]
+	fn test_highest_role_wins() {
+		let mut shares = Shares::new();
+		let nodes = tree();
+
+		shares.add_share(share(1, 5, vec![0], Role::Viewer));
+		shares.add_share(share(1, 5, vec![2], Role::Editor));
+
+		assert_eq!(shares.role(5, &[], &nodes, 3), Some(Role::Editor));
+		assert_eq!(shares.role(5, &[], &nodes, 4), Some(Role::Viewer));
+	}
+
+	#[test]
+	fn test_set_role() {
+		let mut shares = Shares::new();
+		let nodes = tree();
+
+		shares.add_share(share(1, 5, vec![2], Role::Editor));
+
+		assert!(shares.set_role(5, 2, Role::Viewer));
+		assert!(!shares.set_role(5, 4, Role::Viewer));
+		assert_eq!(shares.role(5, &[], &nodes, 3), Some(Role::Viewer));
+		// the signed export is left as it was
+		assert_eq!(
+			shares.all_shares_for_user(5, &[])[0].export.role,
+			Role::Editor
+		);
+	}
+
+	#[test]
+	fn test_override_goes_with_share() {
+		let mut shares = Shares::new();
+		let nodes = tree();
+
+		shares.add_share(share(1, 5, vec![2], Role::Viewer));
+		shares.set_role(5, 2, Role::Editor);
+		shares.remove_for_receiver(5);
+		shares.add_share(share(1, 5, vec![2], Role::Viewer));
+
+		assert_eq!(shares.role(5, &[], &nodes, 3), Some(Role::Viewer));
+	}
+
+	#[test]
+	fn test_sig_covers_role() {
+		let mut share = share(1, 5, vec![2], Role::Viewer);
+		let (ed448, sig) = ed448::sign(&[1; 57], &share.message());
+
+		share.sender.ed448 = ed448;
+		share.sig = sig;
+
+		assert!(share.verify());
+
+		share.export.role = Role::Editor;
+
+		assert!(!share.verify());
+	}
+
+	#[test]
+	fn test_legacy_sig_grants_viewer_only() {
+		let mut share = share(1, 5, vec![2], Role::Viewer);
+		let (ed448, sig) = ed448::sign(&[1; 57], &share.legacy_message());
+
+		share.sender.ed448 = ed448;
+		share.sig = sig;
+
+		assert!(share.verify());
+
+		share.export.role = Role::Editor;
+
+		assert!(!share.verify());
+	}
+
+	#[test]
+	fn test_role_defaults_to_viewer() {
+		let export: Export =
+			serde_json::from_value(serde_json::json!({ "receiver": 5, "fs": [2], "db": [] }))
+				.unwrap();
+
+		assert_eq!(export.role, Role::Viewer);
+	}
+
+	#[test]
+	fn test_role_through_group() {
+		let mut shares = Shares::new();
+		let nodes = tree();
+
+		shares.add_share(share(1, 100, vec![2], Role::Editor));
+
+		assert_eq!(shares.role(5, &[], &nodes, 3), None);
+		assert_eq!(shares.role(5, &[100], &nodes, 3), Some(Role::Editor));
+		assert_eq!(shares.all_shares_for_user(5, &[100]).len(), 1);
+		assert!(shares.all_shares_for_user(5, &[]).is_empty());
+	}
+}
@@ -0,0 +1,1311 @@
+use super::*;
+use admin::handlers::list_blobs;
+use axum::extract::FromRequest;
+use jobs::{handlers::run_job, Job};
+use tmp_dir::TmpDir;
+use users::Login;
+
+async fn body_of(err: Error) -> (StatusCode, ErrorBody) {
+	let response = err.into_response();
+	let status = response.status();
+	let bytes = axum::body::to_bytes(response.into_body(), usize::MAX)
+		.await
+		.unwrap();
+
+	(status, serde_json::from_slice(&bytes).unwrap())
+}
+
+#[tokio::test]
+async fn test_error_body() {
+	let (status, body) = body_of(Error::NoBlob(7)).await;
+
+	assert_eq!(status, StatusCode::NOT_FOUND);
+	assert_eq!(body.code, "no_blob");
+	assert_eq!(body.details, Some(ErrorDetails::Id { id: 7 }));
+
+	let (status, body) = body_of(Error::InvalidRange(Some(10))).await;
+
+	assert_eq!(status, StatusCode::RANGE_NOT_SATISFIABLE);
+	assert_eq!(body.details, Some(ErrorDetails::Range { length: 10 }));
+}
+
+#[tokio::test]
+async fn test_unauthenticated_is_not_forbidden() {
+	assert_eq!(
+		body_of(Error::Unauthenticated).await.0,
+		StatusCode::UNAUTHORIZED
+	);
+	assert_eq!(body_of(Error::Unauthorised).await.0, StatusCode::FORBIDDEN);
+}
+
+#[tokio::test]
+async fn test_bad_json() {
+	let request = Request::builder()
+		.header("Content-Type", "application/json")
+		.body(Body::from("{\"email\": 1}"))
+		.unwrap();
+	let err = JsonBody::<Login>::from_request(request, &())
+		.await
+		.err()
+		.unwrap();
+	let (status, body) = body_of(err).await;
+
+	assert_eq!(status, StatusCode::BAD_REQUEST);
+	assert_eq!(body.code, "bad_json");
+}
+
+#[tokio::test]
+async fn test_bad_params() {
+	use tower::ServiceExt;
+
+	let mut config = Config::default();
+
+	config.admin.token_sha256 = Some(admin::token_hash("secret"));
+
+	let (state, _dir) = test_state(config, Arc::new(jobs::SystemClock));
+	let router = router(state);
+
+	for (method, uri) in [
+		("GET", "/uploads/chunk/abc"),
+		("POST", "/admin/jobs/nope"),
+		("GET", "/events?after=never"),
+	] {
+		let request = Request::builder()
+			.method(method)
+			.uri(uri)
+			.header(admin::TOKEN_HEADER, "secret")
+			.body(Body::empty())
+			.unwrap();
+		let res = router.clone().oneshot(request).await.unwrap();
+
+		assert_eq!(res.status(), StatusCode::BAD_REQUEST, "{}", uri);
+
+		let bytes = axum::body::to_bytes(res.into_body(), usize::MAX)
+			.await
+			.unwrap();
+		let body: ErrorBody = serde_json::from_slice(&bytes).unwrap();
+
+		assert_eq!(body.code, "bad_request");
+	}
+}
+
+struct FailingMailer;
+
+#[async_trait::async_trait]
+impl Mailer for FailingMailer {
+	async fn send(&self, _: mailer::Mail) -> Result<(), mailer::Error> {
+		Err(mailer::Error::Transport("down".to_string()))
+	}
+}
+
+fn new_invite(sender: u64, email: &str) -> Invite {
+	let x448 = base64::encode([0u8; 56]);
+	let ed448 = base64::encode([0u8; 57]);
+
+	serde_json::from_value(serde_json::json!({
+		"user_id": 2,
+		"sender": { "id": sender, "x448": x448, "ed448": ed448 },
+		"email": email,
+		"payload": {
+			"ct": "",
+			"master_key": { "ct": "", "salt": { "bytes": base64::encode([0u8; 32]) } },
+		},
+		"export": { "receiver": 2, "fs": [], "db": [], "role": "viewer" },
+		"sig": { "bytes": base64::encode([0u8; 114]) },
+	}))
+	.unwrap()
+}
+
+#[tokio::test]
+async fn test_invite_not_kept_when_mail_fails() {
+	let state = State::new(Arc::new(FailingMailer), Config::default());
+	let headers = auth_headers(&state.tokens.lock().await.issue(1));
+	let res = super::invite(
+		extract::State(state.clone()),
+		headers,
+		JsonBody(new_invite(1, "alice@mail.com")),
+	)
+	.await;
+
+	assert!(matches!(res, Err(Error::Mail(_))));
+	assert!(state
+		.shares
+		.lock()
+		.await
+		.invie_for_mail("alice@mail.com")
+		.is_none());
+}
+
+#[tokio::test]
+async fn test_invite_only_as_oneself() {
+	let (state, _dir) = test_state(Config::default(), Arc::new(jobs::SystemClock));
+	let bob = auth_headers(&state.tokens.lock().await.issue(1));
+	let eve = auth_headers(&state.tokens.lock().await.issue(3));
+	let invite = |headers: HeaderMap, sender: u64, email: &str| {
+		super::invite(
+			extract::State(state.clone()),
+			headers,
+			JsonBody(new_invite(sender, email)),
+		)
+	};
+
+	assert!(matches!(
+		invite(HeaderMap::new(), 1, "alice@mail.com").await,
+		Err(Error::Unauthenticated)
+	));
+	assert!(matches!(
+		invite(eve.clone(), 1, "alice@mail.com").await,
+		Err(Error::Unauthorised)
+	));
+	assert_eq!(
+		invite(bob.clone(), 1, "alice@mail.com").await.unwrap(),
+		StatusCode::CREATED
+	);
+	// bob may resend, eve can't take it over
+	assert!(invite(bob.clone(), 1, "alice@mail.com").await.is_ok());
+	assert!(matches!(
+		invite(eve.clone(), 3, "alice@mail.com").await,
+		Err(Error::Conflict)
+	));
+	assert_eq!(
+		state
+			.shares
+			.lock()
+			.await
+			.invie_for_mail("alice@mail.com")
+			.map(|invite| invite.sender.id()),
+		Some(1)
+	);
+}
+
+#[tokio::test]
+async fn test_invites_are_limited_per_sender() {
+	let (state, _dir) = test_state(Config::default(), Arc::new(jobs::SystemClock));
+	let bob = auth_headers(&state.tokens.lock().await.issue(1));
+	let eve = auth_headers(&state.tokens.lock().await.issue(3));
+	let invite = |headers: HeaderMap, sender: u64, email: String| {
+		super::invite(
+			extract::State(state.clone()),
+			headers,
+			JsonBody(new_invite(sender, &email)),
+		)
+	};
+
+	for i in 0..INVITE_MAX {
+		assert!(invite(bob.clone(), 1, format!("{}@mail.com", i))
+			.await
+			.is_ok());
+	}
+
+	assert!(matches!(
+		invite(bob.clone(), 1, "one-more@mail.com".to_string()).await,
+		Err(Error::TooManyAttempts)
+	));
+	// others are counted on their own
+	assert!(invite(eve, 3, "one-more@mail.com".to_string())
+		.await
+		.is_ok());
+}
+
+// mail goes to `mail` in the dir, which lives as long as it's held on to
+fn test_state(config: Config, clock: Arc<dyn jobs::Clock>) -> (State, TmpDir) {
+	let dir = TmpDir::new("main");
+	let mailer = mailer::FileMailer::new(dir.join("mail"), "uploader@mail.com").unwrap();
+
+	std::fs::create_dir_all(dir.join("uploads")).unwrap();
+
+	let state = State::with_clock(
+		Arc::new(mailer),
+		Config {
+			uploads_dir: dir.join("uploads"),
+			..config
+		},
+		clock,
+	);
+
+	(state, dir)
+}
+
+async fn status_of(router: &Router, method: &str, uri: &str, token: Option<&str>) -> StatusCode {
+	let mut request = Request::builder().method(method).uri(uri);
+
+	if let Some(token) = token {
+		request = request.header(admin::TOKEN_HEADER, token);
+	}
+
+	status_for(router, request.body(Body::empty()).unwrap()).await
+}
+
+async fn status_for(router: &Router, request: Request<Body>) -> StatusCode {
+	use tower::ServiceExt;
+
+	router.clone().oneshot(request).await.unwrap().status()
+}
+
+#[tokio::test]
+async fn test_admin_routes() {
+	let mut config = Config::default();
+
+	config.admin.token_sha256 = Some(admin::token_hash("secret"));
+
+	let (state, _dir) = test_state(config.clone(), Arc::new(jobs::SystemClock));
+	let router = router(state.clone());
+
+	// no dev mode, no purge
+	assert_eq!(
+		status_of(&router, "POST", "/purge", Some("secret")).await,
+		StatusCode::NOT_FOUND
+	);
+	assert_eq!(
+		status_of(&router, "POST", "/admin/expired", None).await,
+		StatusCode::UNAUTHORIZED
+	);
+	// imports are up to an admin
+	assert_eq!(
+		status_of(&router, "POST", "/import", None).await,
+		StatusCode::NOT_FOUND
+	);
+	assert_eq!(
+		status_of(&router, "POST", "/admin/import", None).await,
+		StatusCode::UNAUTHORIZED
+	);
+	// metrics are off unless asked for
+	assert_eq!(
+		status_of(&router, "GET", "/metrics", Some("secret")).await,
+		StatusCode::NOT_FOUND
+	);
+	assert_eq!(
+		status_of(&router, "POST", "/admin/expired", Some("guess")).await,
+		StatusCode::UNAUTHORIZED
+	);
+	assert_eq!(
+		status_of(&router, "POST", "/admin/expired", Some("secret")).await,
+		StatusCode::NO_CONTENT
+	);
+	assert_eq!(
+		status_of(&router, "DELETE", "/admin/users/7", Some("secret")).await,
+		StatusCode::NOT_FOUND
+	);
+
+	let entries = state.audit.lock().await.by_admins();
+
+	assert_eq!(entries.len(), 1);
+	assert_eq!(entries[0].action, audit::Action::PurgeExpired);
+	assert_eq!(entries[0].admin.as_deref(), Some(admin::TOKEN_PRINCIPAL));
+
+	config.dev_mode = true;
+	config.features.metrics = true;
+
+	let (state, _dev_dir) = test_state(config, Arc::new(jobs::SystemClock));
+	let dev = super::router(state.clone());
+
+	assert_eq!(
+		status_of(&dev, "GET", "/metrics", None).await,
+		StatusCode::UNAUTHORIZED
+	);
+	assert_eq!(
+		status_of(&dev, "GET", "/metrics", Some("secret")).await,
+		StatusCode::OK
+	);
+
+	assert_eq!(
+		status_of(&dev, "POST", "/purge", None).await,
+		StatusCode::UNAUTHORIZED
+	);
+	assert_eq!(
+		status_of(&dev, "POST", "/purge", Some("secret")).await,
+		StatusCode::OK
+	);
+	assert_eq!(state.audit.lock().await.by_admins().len(), 1);
+}
+
+#[tokio::test]
+async fn test_abandoned_upload_shows_as_missing() {
+	let clock = Arc::new(jobs::tests::ManualClock::default());
+	let (state, _dir) = test_state(Config::default(), clock.clone());
+
+	state.nodes.lock().await.add(node(7, 100));
+	state.nodes.lock().await.set_owner(7, 1);
+	state.blobs.lock().await.expect(7, 20);
+	std::fs::write(state.path_for_file_id(7), [1u8; 10]).unwrap();
+	clock.advance(now() + admin::ORPHAN_GRACE + 1);
+
+	assert_eq!(run_job(&state, Job::Uploads).await.unwrap(), 1);
+	assert!(!state.path_for_file_id(7).exists());
+	assert_eq!(
+		fsck::check(
+			&*state.nodes.lock().await,
+			&list_blobs(&state).await.unwrap(),
+			state.blobs.lock().await.expected(),
+			state.jobs.lock().await.now(),
+		),
+		vec![admin::api::Issue::MissingBlob {
+			id: 7,
+			expected: 20
+		}]
+	);
+}
+
+#[tokio::test]
+async fn test_trigger_job() {
+	let mut config = Config::default();
+
+	config.admin.token_sha256 = Some(admin::token_hash("secret"));
+
+	let clock = Arc::new(jobs::tests::ManualClock::default());
+	let (state, _dir) = test_state(config, clock.clone());
+	let router = router(state.clone());
+
+	clock.advance(1000);
+
+	assert_eq!(
+		status_of(&router, "POST", "/admin/jobs/gc", None).await,
+		StatusCode::UNAUTHORIZED
+	);
+	assert_eq!(
+		status_of(&router, "POST", "/admin/jobs/nope", Some("secret")).await,
+		StatusCode::BAD_REQUEST
+	);
+	assert_eq!(
+		status_of(&router, "POST", "/admin/jobs/gc", Some("secret")).await,
+		StatusCode::NO_CONTENT
+	);
+	assert_eq!(
+		status_of(&router, "GET", "/admin/jobs", Some("secret")).await,
+		StatusCode::OK
+	);
+	assert_eq!(state.jobs.lock().await.last_run(Job::Gc), Some(1000));
+	assert_eq!(state.jobs.lock().await.last_run(Job::Expire), None);
+
+	let entries = state.audit.lock().await.by_admins();
+
+	assert_eq!(
+		entries[0].action,
+		audit::Action::RunJob {
+			job: "gc".to_string()
+		}
+	);
+	assert!(state
+		.metrics
+		.render()
+		.contains("uploader_job_runs_total{job=\"gc\",outcome=\"ok\"} 1"));
+}
+
+#[tokio::test]
+async fn test_pending_upload_is_the_uploaders() {
+	let (state, _dir) = test_state(Config::default(), Arc::new(jobs::SystemClock));
+	let router = router(state.clone());
+	let bob = state.tokens.lock().await.issue(1);
+	let eve = state.tokens.lock().await.issue(3);
+	let upload = |id: u64, token: &str, range: &str| {
+		Request::builder()
+			.method("POST")
+			.uri(format!("/uploads/chunk/{}", id))
+			.header("Content-Range", range)
+			.header(AUTH_HEADER, token)
+			.body(Body::from(vec![1u8; 10]))
+			.unwrap()
+	};
+	let download = |token: &str| {
+		Request::builder()
+			.uri("/uploads/chunk/7")
+			.header("Range", "bytes=0-9")
+			.header(AUTH_HEADER, token)
+			.body(Body::empty())
+			.unwrap()
+	};
+
+	assert_eq!(
+		status_for(&router, upload(7, &bob, "bytes 0-9/20")).await,
+		StatusCode::OK
+	);
+	assert_eq!(
+		status_for(&router, upload(7, &eve, "bytes 10-19/20")).await,
+		StatusCode::FORBIDDEN
+	);
+	assert_eq!(
+		status_for(&router, download(&eve)).await,
+		StatusCode::FORBIDDEN
+	);
+	assert_eq!(
+		status_for(&router, download(&bob)).await,
+		StatusCode::PARTIAL_CONTENT
+	);
+
+	// nobody is known to have started this one
+	tokio::fs::write(state.path_for_file_id(8), [1u8; 10])
+		.await
+		.unwrap();
+
+	assert_eq!(
+		status_for(&router, upload(8, &bob, "bytes 10-19/20")).await,
+		StatusCode::FORBIDDEN
+	);
+}
+
+#[tokio::test]
+async fn test_link_download_in_chunks() {
+	let (state, _dir) = test_state(Config::default(), Arc::new(jobs::SystemClock));
+	let router = router(state.clone());
+	let token = state.links.lock().await.add(
+		links::NewLink {
+			node_id: 7,
+			expires_at: now() + 60,
+			max_downloads: Some(1),
+			pass: None,
+		},
+		None,
+	);
+	let chunk = |range: &str, download_id: Option<&str>| {
+		let mut request = Request::builder()
+			.uri("/uploads/chunk/7")
+			.header("Range", range)
+			.header("x-uploader-link", &token);
+
+		if let Some(download_id) = download_id {
+			request = request.header("x-uploader-link-download", download_id);
+		}
+
+		request.body(Body::empty()).unwrap()
+	};
+
+	tokio::fs::write(state.path_for_file_id(7), [1u8; 30])
+		.await
+		.unwrap();
+
+	let first = {
+		use tower::ServiceExt;
+
+		router
+			.clone()
+			.oneshot(chunk("bytes=0-9", None))
+			.await
+			.unwrap()
+	};
+	let download_id = header(first.headers(), "x-uploader-link-download")
+		.unwrap()
+		.to_string();
+
+	assert_eq!(first.status(), StatusCode::PARTIAL_CONTENT);
+
+	for range in ["bytes=10-19", "bytes=20-29"] {
+		assert_eq!(
+			status_for(&router, chunk(range, Some(&download_id))).await,
+			StatusCode::PARTIAL_CONTENT
+		);
+	}
+
+	// a second download is one too many, wherever it starts
+	assert_eq!(
+		status_for(&router, chunk("bytes=0-9", None)).await,
+		StatusCode::GONE
+	);
+	assert_eq!(
+		status_for(&router, chunk("bytes=10-19", None)).await,
+		StatusCode::GONE
+	);
+	assert_eq!(
+		status_for(&router, chunk("bytes=10-19", Some("made up"))).await,
+		StatusCode::GONE
+	);
+}
+
+fn node(id: u64, parent_id: u64) -> LockedNode {
+	LockedNode {
+		id,
+		parent_id,
+		content: encrypted::Encrypted {
+			ct: vec![],
+			salt: salt::Salt::generate(),
+		},
+		dirty: false,
+	}
+}
+
+#[tokio::test]
+async fn test_nodes_listed_to_those_with_a_role() {
+	let (state, _dir) = test_state(Config::default(), Arc::new(jobs::SystemClock));
+	let token = state.tokens.lock().await.issue(7);
+	let mut headers = HeaderMap::new();
+
+	{
+		let mut nodes = state.nodes.lock().await;
+
+		nodes.add(node(1, 100));
+		nodes.add(node(2, 1));
+		nodes.add(node(3, 200));
+		nodes.set_owner(1, 7);
+	}
+
+	let anonymous = get_all(extract::State(state.clone()), headers.clone())
+		.await
+		.unwrap()
+		.1;
+
+	assert!(anonymous.0.is_empty());
+
+	headers.insert(AUTH_HEADER, token.parse().unwrap());
+
+	let owned = get_all(extract::State(state.clone()), headers)
+		.await
+		.unwrap()
+		.1;
+	let mut ids: Vec<u64> = owned.0.iter().map(|node| node.id).collect();
+
+	ids.sort();
+
+	assert_eq!(ids, vec![1, 2]);
+}
+
+#[tokio::test]
+async fn test_legacy_token() {
+	let (state, _dir) = test_state(
+		Config {
+			legacy_token: Some("aabb1122".into()),
+			..Default::default()
+		},
+		Arc::new(jobs::SystemClock),
+	);
+	let (off, _off_dir) = test_state(Config::default(), Arc::new(jobs::SystemClock));
+	let download = |token: &str| {
+		Request::builder()
+			.uri("/uploads/chunk/7")
+			.header("Range", "bytes=0-9")
+			.header(AUTH_HEADER, token)
+			.body(Body::empty())
+			.unwrap()
+	};
+
+	tokio::fs::write(state.path_for_file_id(7), [1u8; 30])
+		.await
+		.unwrap();
+
+	assert_eq!(
+		status_for(&router(state), download("aabb1122")).await,
+		StatusCode::PARTIAL_CONTENT
+	);
+	assert_eq!(
+		status_for(&router(off.clone()), download("guess")).await,
+		StatusCode::UNAUTHORIZED
+	);
+	// unless configured
+	assert_eq!(
+		status_for(&router(off), download("aabb1122")).await,
+		StatusCode::UNAUTHORIZED
+	);
+}
+
+// ed448 keys are derived from `secret`
+fn public(id: u64, secret: u8) -> identity::Public {
+	identity::Public {
+		id,
+		x448: x448::PublicKeyX448::new([0; 56]),
+		ed448: ed448::sign(&[secret; 57], b"").0,
+	}
+}
+
+// signed with the key of public(sender, sender)
+fn signed_share(sender: u64, receiver: u64, fs: Vec<u64>, role: Role) -> LockedShare {
+	let mut share: LockedShare = serde_json::from_value(serde_json::json!({
+		"sender": public(sender, sender as u8),
+		"export": { "receiver": receiver, "fs": fs, "db": [], "role": role },
+		"payload": { "ct": "", "eph_x448": base64::encode([0u8; 56]) },
+		"sig": { "bytes": base64::encode([0u8; 114]) },
+	}))
+	.unwrap();
+
+	share.sig = ed448::sign(&[sender as u8; 57], &share.message()).1;
+
+	share
+}
+
+fn new_user(id: u64, shares: Vec<LockedShare>, roots: Vec<LockedNode>) -> Signup {
+	Signup {
+		email: format!("{}@mail.com", id),
+		pass: "pass".into(),
+		user: LockedUser {
+			encrypted_priv: serde_json::from_value(serde_json::json!({
+				"ct": "",
+				"master_key": { "ct": "", "salt": { "bytes": base64::encode([0u8; 32]) } },
+			}))
+			.unwrap(),
+			_pub: public(id, id as u8),
+			shares,
+			roots,
+			groups: Vec::new(),
+		},
+	}
+}
+
+#[tokio::test]
+async fn test_signup_takes_only_what_was_granted() {
+	let (state, _dir) = test_state(Config::default(), Arc::new(jobs::SystemClock));
+	let signup = |shares, roots| {
+		super::signup(
+			extract::State(state.clone()),
+			JsonBody(new_user(2, shares, roots)),
+		)
+	};
+	let mut forged = signed_share(1, 2, vec![10], Role::Viewer);
+
+	forged.export.role = Role::Owner;
+	state.users.lock().await.add_pub(1, public(1, 1));
+
+	{
+		let mut nodes = state.nodes.lock().await;
+
+		nodes.add(node(10, 100));
+		nodes.add(node(11, 10));
+		nodes.set_owner(10, 1);
+	}
+
+	// a viewer can't overwrite what it was shared
+	assert!(matches!(
+		signup(
+			vec![signed_share(1, 2, vec![10], Role::Viewer)],
+			vec![node(11, 10)]
+		)
+		.await,
+		Err(Error::Unauthorised)
+	));
+	// nor raise the role it was signed
+	assert!(matches!(
+		signup(vec![forged], vec![]).await,
+		Err(Error::Unauthorised)
+	));
+	// nor share itself somebody else's tree
+	assert!(matches!(
+		signup(vec![signed_share(2, 2, vec![10], Role::Owner)], vec![]).await,
+		Err(Error::Unauthorised)
+	));
+	assert!(state
+		.shares
+		.lock()
+		.await
+		.all_shares_for_user(2, &[])
+		.is_empty());
+	assert!(signup(
+		vec![
+			signed_share(1, 2, vec![10], Role::Viewer),
+			signed_share(2, 2, vec![20], Role::Owner)
+		],
+		vec![node(20, 200)]
+	)
+	.await
+	.is_ok());
+
+	let nodes = state.nodes.lock().await;
+	let shares = state.shares.lock().await;
+
+	assert_eq!(shares.role(2, &[], &nodes, 11), Some(Role::Viewer));
+	assert_eq!(nodes.owner_of(20), Some(2));
+}
+
+#[tokio::test]
+async fn test_failed_import_leaves_no_blobs() {
+	let (state, _dir) = test_state(Config::default(), Arc::new(jobs::SystemClock));
+	let user = new_user(
+		2,
+		Vec::new(),
+		vec![node(20, 200), node(21, 201), node(22, 202)],
+	)
+	.user;
+	let mut archive = Vec::new();
+	let mut entry = |path: &str, data: &[u8]| {
+		archive.extend(archive::entry_header(path, data.len() as u64, 0).unwrap());
+		archive.extend(data);
+		archive.extend(archive::padding(data.len() as u64));
+	};
+
+	entry(
+		archive::MANIFEST,
+		&serde_json::to_vec(&Manifest {
+			version: archive::VERSION,
+			email: "2@mail.com".into(),
+			user,
+			owned: vec![20, 21, 22],
+		})
+		.unwrap(),
+	);
+
+	for id in [20, 21, 22] {
+		entry(&archive::blob_path(id), &[7; 10]);
+	}
+
+	archive.extend(archive::end());
+	// in the way of the last blob, whichever order they're moved in
+	std::fs::create_dir_all(state.path_for_file_id(22).join("x")).unwrap();
+
+	let res = import_user(
+		extract::State(state.clone()),
+		Admin("admin".into()),
+		Request::new(Body::from(archive)),
+	)
+	.await;
+
+	assert!(matches!(res, Err(Error::Io(_))));
+	assert!(state.users.lock().await.pub_for_id(2).is_none());
+	assert_eq!(
+		std::fs::read_dir(&state.config.uploads_dir)
+			.unwrap()
+			.map(|entry| entry.unwrap().file_name())
+			.collect::<Vec<_>>(),
+		vec!["22"]
+	);
+}
+
+#[tokio::test]
+async fn test_import_takes_only_what_holds() {
+	let (state, _dir) = test_state(Config::default(), Arc::new(jobs::SystemClock));
+	let mut forged = signed_share(1, 2, vec![10], Role::Viewer);
+	let mut user = new_user(2, Vec::new(), vec![node(10, 100), node(20, 200)]).user;
+	let mut archive = Vec::new();
+	let mut entry = |path: &str, data: &[u8]| {
+		archive.extend(archive::entry_header(path, data.len() as u64, 0).unwrap());
+		archive.extend(data);
+		archive.extend(archive::padding(data.len() as u64));
+	};
+
+	forged.export.role = Role::Owner;
+	user.shares = vec![
+		signed_share(1, 2, vec![10], Role::Viewer),
+		forged,
+		signed_share(2, 2, vec![20], Role::Owner),
+		signed_share(2, 2, vec![10], Role::Owner),
+	];
+	entry(
+		archive::MANIFEST,
+		&serde_json::to_vec(&Manifest {
+			version: archive::VERSION,
+			email: "2@mail.com".into(),
+			user,
+			owned: vec![20],
+		})
+		.unwrap(),
+	);
+	entry(&archive::blob_path(20), &[7; 10]);
+	archive.extend(archive::end());
+
+	state.users.lock().await.add_pub(1, public(1, 1));
+	state.nodes.lock().await.add(node(10, 100));
+	state.nodes.lock().await.set_owner(10, 1);
+
+	let (status, Json(imported)) = import_user(
+		extract::State(state.clone()),
+		Admin("admin".into()),
+		Request::new(Body::from(archive)),
+	)
+	.await
+	.unwrap();
+
+	assert_eq!(status, StatusCode::CREATED);
+	assert_eq!(imported.user_id, 2);
+	assert!(imported.renamed.is_empty());
+	assert_eq!(
+		state.shares.lock().await.all_shares_for_user(2, &[]).len(),
+		2
+	);
+
+	{
+		let nodes = state.nodes.lock().await;
+		let shares = state.shares.lock().await;
+
+		assert_eq!(shares.role(2, &[], &nodes, 10), Some(Role::Viewer));
+		assert_eq!(nodes.owner_of(20), Some(2));
+	}
+
+	// no password came along, and only the blob is left in the uploads
+	assert_eq!(state.users.lock().await.pass_hash_for_id(2), None);
+	assert_eq!(
+		std::fs::read_dir(&state.config.uploads_dir)
+			.unwrap()
+			.map(|entry| entry.unwrap().file_name())
+			.collect::<Vec<_>>(),
+		vec!["20"]
+	);
+	assert_eq!(
+		state.audit.lock().await.by_admins()[0].action,
+		audit::Action::Import
+	);
+}
+
+#[tokio::test]
+async fn test_login_lists_only_own_nodes() {
+	let (state, _dir) = test_state(Config::default(), Arc::new(jobs::SystemClock));
+
+	for (id, root) in [(1, 10), (2, 20)] {
+		super::signup(
+			extract::State(state.clone()),
+			JsonBody(new_user(
+				id,
+				vec![signed_share(id, id, vec![root], Role::Owner)],
+				vec![node(root, 100 + root)],
+			)),
+		)
+		.await
+		.unwrap();
+	}
+
+	let res = super::login(
+		extract::State(state.clone()),
+		JsonBody(Login {
+			email: "2@mail.com".into(),
+			pass: "pass".into(),
+			device: None,
+		}),
+	)
+	.await
+	.unwrap();
+	let bytes = axum::body::to_bytes(res.into_body(), usize::MAX)
+		.await
+		.unwrap();
+	let user: LockedUser = serde_json::from_slice(&bytes).unwrap();
+
+	assert_eq!(
+		user.roots.iter().map(|node| node.id).collect::<Vec<_>>(),
+		vec![20]
+	);
+}
+
+fn auth_headers(token: &str) -> HeaderMap {
+	let mut headers = HeaderMap::new();
+
+	headers.insert(AUTH_HEADER, token.parse().unwrap());
+
+	headers
+}
+
+#[tokio::test]
+async fn test_share_with_group() {
+	let (state, _dir) = test_state(Config::default(), Arc::new(jobs::SystemClock));
+	let owner = auth_headers(&state.tokens.lock().await.issue(1));
+	let leaving = auth_headers(&state.tokens.lock().await.issue(3));
+	let key = || {
+		serde_json::from_value::<identity::Encrypted>(serde_json::json!({
+			"ct": "",
+			"eph_x448": base64::encode([0u8; 56]),
+		}))
+		.unwrap()
+	};
+	let share = |role| {
+		share_with_group(
+			extract::State(state.clone()),
+			PathParam(100),
+			owner.clone(),
+			JsonBody(signed_share(1, 100, vec![10], role)),
+		)
+	};
+
+	state.users.lock().await.add_pub(1, public(1, 1));
+	state.nodes.lock().await.add(node(10, 200));
+	state.nodes.lock().await.set_owner(10, 1);
+
+	{
+		let mut groups = state.groups.lock().await;
+
+		groups.add(
+			groups::NewGroup {
+				_pub: public(100, 100),
+				key: key(),
+			},
+			1,
+		);
+		groups.add_member(100, 2, key(), 1).unwrap();
+		groups.add_member(100, 3, key(), 1).unwrap();
+	}
+
+	remove_group_member(extract::State(state.clone()), PathParam((100, 3)), leaving)
+		.await
+		.unwrap();
+
+	assert_eq!(
+		state.events.lock().await.since(1, 0)[0].event,
+		Event::GroupMemberLeft {
+			group_id: 100,
+			user_id: 3
+		}
+	);
+	// not until the key has been rotated
+	assert!(matches!(share(Role::Viewer).await, Err(Error::Conflict)));
+
+	rotate_group_key(
+		extract::State(state.clone()),
+		PathParam(100),
+		owner.clone(),
+		JsonBody(groups::Rotation {
+			_pub: public(100, 101),
+			members: HashMap::from([(1, key()), (2, key())]),
+			previous: key(),
+		}),
+	)
+	.await
+	.unwrap();
+
+	assert!(matches!(share(Role::Viewer).await, Ok(StatusCode::CREATED)));
+
+	let groups = state.groups_of(2).await;
+	let nodes = state.nodes.lock().await;
+
+	assert_eq!(
+		state.shares.lock().await.role(2, &groups, &nodes, 10),
+		Some(Role::Viewer)
+	);
+}
+
+#[tokio::test]
+async fn test_change_lock() {
+	let (state, _dir) = test_state(Config::default(), Arc::new(jobs::SystemClock));
+	let (_, [(_, token)]) = super::signup(
+		extract::State(state.clone()),
+		JsonBody(new_user(2, Vec::new(), Vec::new())),
+	)
+	.await
+	.unwrap();
+	let other = state.tokens.lock().await.issue(2);
+	let someone_else = state.tokens.lock().await.issue(3);
+	let change = |old_pass: &str| {
+		super::change_lock(
+			extract::State(state.clone()),
+			PathParam(2),
+			auth_headers(&token),
+			JsonBody(LockChange {
+				old_pass: old_pass.into(),
+				new_pass: "new pass".into(),
+				lock: new_user(2, Vec::new(), Vec::new()).user.encrypted_priv,
+			}),
+		)
+	};
+	let login = |pass: &str| {
+		super::login(
+			extract::State(state.clone()),
+			JsonBody(Login {
+				email: "2@mail.com".into(),
+				pass: pass.into(),
+				device: None,
+			}),
+		)
+	};
+
+	assert!(matches!(change("wrong").await, Err(Error::Unauthorised)));
+	assert_eq!(state.tokens.lock().await.user_for(&other), Some(2));
+	assert!(login("pass").await.is_ok());
+
+	assert!(matches!(change("pass").await, Ok(StatusCode::NO_CONTENT)));
+	// the caller stays logged in, its other sessions don't
+	assert_eq!(state.tokens.lock().await.user_for(&token), Some(2));
+	assert_eq!(state.tokens.lock().await.user_for(&other), None);
+	assert_eq!(state.tokens.lock().await.user_for(&someone_else), Some(3));
+	assert!(matches!(login("pass").await, Err(Error::Unauthenticated)));
+	assert!(login("new pass").await.is_ok());
+}
+
+#[tokio::test]
+async fn test_contact_recovery_goes_to_the_owner() {
+	let (state, dir) = test_state(Config::default(), Arc::new(jobs::SystemClock));
+	let (_, [(_, token)]) = super::signup(
+		extract::State(state.clone()),
+		JsonBody(new_user(2, Vec::new(), Vec::new())),
+	)
+	.await
+	.unwrap();
+	let contact = auth_headers(&state.tokens.lock().await.issue(3));
+
+	state.recoveries.lock().await.add_contact(
+		2,
+		3,
+		serde_json::from_value(serde_json::json!({
+			"ct": "",
+			"eph_x448": base64::encode([0u8; 56]),
+		}))
+		.unwrap(),
+	);
+
+	let started = recover_with_contact(
+		extract::State(state.clone()),
+		JsonBody(recovery::ContactRecovery {
+			email: "2@mail.com".into(),
+			contact_id: 3,
+			eph_x448: x448::PublicKeyX448::new([1; 56]),
+		}),
+	)
+	.await
+	.unwrap();
+
+	// the token is only in the mail
+	assert_eq!(started, StatusCode::ACCEPTED);
+
+	let mail = std::fs::read_dir(dir.join("mail"))
+		.unwrap()
+		.map(|entry| std::fs::read_to_string(entry.unwrap().path()).unwrap())
+		.collect::<String>();
+
+	assert!(mail.contains("/recover?token="));
+
+	let Event::RecoveryStarted {
+		request_id,
+		contact_id,
+	} = state.events.lock().await.since(2, 0)[0].event.clone()
+	else {
+		panic!("no recovery event");
+	};
+
+	assert_eq!(contact_id, 3);
+
+	let requests = |headers| get_recovery_requests(extract::State(state.clone()), headers);
+	let Json(pending) = requests(contact.clone()).await.unwrap();
+
+	assert_eq!(pending[0].id, request_id);
+
+	// only the owner cancels
+	assert!(cancel_recovery(
+		extract::State(state.clone()),
+		PathParam((2, request_id.clone())),
+		contact.clone(),
+	)
+	.await
+	.is_err());
+	assert!(matches!(
+		cancel_recovery(
+			extract::State(state.clone()),
+			PathParam((2, request_id)),
+			auth_headers(&token),
+		)
+		.await,
+		Ok(StatusCode::NO_CONTENT)
+	));
+	assert!(requests(contact).await.unwrap().0.is_empty());
+}
+
+#[tokio::test]
+async fn test_login_needs_device_proof() {
+	let (state, _dir) = test_state(Config::default(), Arc::new(jobs::SystemClock));
+	let (_, [(_, token)]) = super::signup(
+		extract::State(state.clone()),
+		JsonBody(new_user(2, Vec::new(), Vec::new())),
+	)
+	.await
+	.unwrap();
+	let other = state.tokens.lock().await.issue(2);
+	let x448 = x448::PublicKeyX448::new([9; 56]);
+	let (ed448, sig) = ed448::sign(&[9; 57], &devices::NewDevice::message(2, &x448));
+	let login = |device| {
+		super::login(
+			extract::State(state.clone()),
+			JsonBody(Login {
+				email: "2@mail.com".into(),
+				pass: "pass".into(),
+				device,
+			}),
+		)
+	};
+	let proof = |challenge: &auth::Challenge| devices::Proof {
+		sig: ed448::sign(&[9; 57], &challenge.device_message()).1,
+		challenge: challenge.id.clone(),
+	};
+
+	// a key has to come with proof it's held
+	let forged = devices::NewDevice {
+		name: "phone".into(),
+		x448: x448.clone(),
+		ed448: ed448.clone(),
+		sig: ed448::sign(&[8; 57], &devices::NewDevice::message(2, &x448)).1,
+	};
+
+	assert!(matches!(
+		add_device(
+			extract::State(state.clone()),
+			auth_headers(&token),
+			JsonBody(forged)
+		)
+		.await,
+		Err(Error::Unauthorised)
+	));
+	assert!(login(None).await.is_ok());
+
+	let (_, Json(device)) = add_device(
+		extract::State(state.clone()),
+		auth_headers(&token),
+		JsonBody(devices::NewDevice {
+			name: "phone".into(),
+			x448,
+			ed448,
+			sig,
+		}),
+	)
+	.await
+	.unwrap();
+
+	// the caller keeps its token, nobody else gets one without the device
+	assert_eq!(state.tokens.lock().await.user_for(&token), Some(2));
+	assert_eq!(state.tokens.lock().await.user_for(&other), None);
+	assert!(matches!(login(None).await, Err(Error::Unauthenticated)));
+
+	let (_, Json(challenge)) = device_challenge(
+		extract::State(state.clone()),
+		Caller(1),
+		JsonBody(auth::DeviceChallengeRequest {
+			device_id: device.id,
+		}),
+	)
+	.await
+	.unwrap();
+	let response = login(Some(proof(&challenge))).await.unwrap();
+	let bound = response.headers()[AUTH_HEADER].to_str().unwrap();
+
+	assert_eq!(state.tokens.lock().await.device_for(bound), Some(device.id));
+	// a proof is good for one login
+	assert!(matches!(
+		login(Some(proof(&challenge))).await,
+		Err(Error::Unauthenticated)
+	));
+}
+
+#[tokio::test]
+async fn test_pairing_keys_follow_commitment() {
+	let (state, _dir) = test_state(Config::default(), Arc::new(jobs::SystemClock));
+	let initiator = auth_headers(&state.tokens.lock().await.issue(1));
+	let hello = |b| pairing::Hello {
+		eph_x448: x448::PublicKeyX448::new([b; 56]),
+	};
+	let id = || PathParam("chan".to_string());
+	let no_wait = || QueryParam(sessions::Wait { wait: None });
+	let reveal = |headers: HeaderMap, b| {
+		post_pairing_key(
+			extract::State(state.clone()),
+			id(),
+			headers,
+			JsonBody(hello(b)),
+		)
+	};
+
+	open_pairing(extract::State(state.clone()), id(), initiator.clone())
+		.await
+		.unwrap();
+
+	let Json(joined) = join_pairing(
+		extract::State(state.clone()),
+		id(),
+		Caller(1),
+		JsonBody(pairing::Commitment::new(&hello(2))),
+	)
+	.await
+	.unwrap();
+	let mut joiner = HeaderMap::new();
+
+	joiner.insert(PAIRING_HEADER, joined.token.parse().unwrap());
+
+	// the joiner only gets to reveal after the initiator has
+	assert!(matches!(
+		reveal(joiner.clone(), 2).await,
+		Err(Error::Conflict)
+	));
+
+	let Json(commitment) = get_pairing_commitment(
+		extract::State(state.clone()),
+		id(),
+		initiator.clone(),
+		no_wait(),
+	)
+	.await
+	.unwrap();
+
+	assert_eq!(commitment, pairing::Commitment::new(&hello(2)));
+	assert!(reveal(initiator.clone(), 1).await.is_ok());
+
+	let Json(peer) = get_pairing_peer(
+		extract::State(state.clone()),
+		id(),
+		joiner.clone(),
+		no_wait(),
+	)
+	.await
+	.unwrap();
+
+	assert_eq!(peer, hello(1));
+	// a key other than the one committed to ends the channel
+	assert!(matches!(
+		reveal(joiner.clone(), 3).await,
+		Err(Error::Unauthorised)
+	));
+	assert!(matches!(reveal(joiner, 2).await, Err(Error::NoSession)));
+}
+
+#[tokio::test]
+async fn test_pending_challenges_are_capped() {
+	let (state, _dir) = test_state(Config::default(), Arc::new(jobs::SystemClock));
+	let (ed448, _) = ed448::sign(&[1; 57], b"");
+	let challenge = |user_id, caller| {
+		login_challenge(
+			extract::State(state.clone()),
+			Caller(caller),
+			JsonBody(auth::ChallengeRequest { user_id }),
+		)
+	};
+
+	state.users.lock().await.add_pub(
+		3,
+		identity::Public {
+			id: 3,
+			x448: x448::PublicKeyX448::new([0; 56]),
+			ed448,
+		},
+	);
+
+	for _ in 0..auth::MAX_CHALLENGES_PER_REQUESTER {
+		assert!(challenge(3, 1).await.is_ok());
+	}
+
+	assert!(matches!(challenge(3, 1).await, Err(Error::TooManyAttempts)));
+	// whoever filled their own cap doesn't lock the user out for others
+	assert!(challenge(3, 2).await.is_ok());
+	// nothing is kept for unknown users, so nothing runs out either
+	for _ in 0..=auth::MAX_CHALLENGES_PER_REQUESTER {
+		assert!(challenge(4, 1).await.is_ok());
+	}
+}
+
+#[tokio::test]
+async fn test_unlock_misses_count_per_caller() {
+	let (state, _dir) = test_state(Config::default(), Arc::new(jobs::SystemClock));
+	let unlock = |wait, caller| {
+		unlock_session(
+			extract::State(state.clone()),
+			PathParam("nope".to_string()),
+			QueryParam(sessions::Wait { wait }),
+			Caller(caller),
+		)
+	};
+
+	// a long-poll running out is a miss like any other
+	assert!(matches!(unlock(Some(1), 1).await, Err(Error::Unauthorised)));
+
+	for _ in 1..sessions::MAX_MISSES {
+		assert!(matches!(unlock(None, 1).await, Err(Error::Unauthorised)));
+	}
+
+	assert!(matches!(unlock(None, 1).await, Err(Error::TooManyAttempts)));
+	assert!(matches!(
+		unlock(Some(1), 1).await,
+		Err(Error::TooManyAttempts)
+	));
+	assert!(matches!(unlock(None, 2).await, Err(Error::Unauthorised)));
+}
+
+#[tokio::test]
+async fn test_readiness() {
+	let (state, _dir) = test_state(Config::default(), Arc::new(jobs::SystemClock));
+
+	assert!(check_readiness(&state).await.ready);
+
+	std::fs::remove_dir(&state.config.uploads_dir).unwrap();
+
+	assert_eq!(check_readiness(&state).await.failed, vec!["uploads_dir"]);
+
+	clear_uploads_dir(&state.config.uploads_dir).await;
+
+	assert!(check_readiness(&state).await.ready);
+
+	state.shutting_down.store(true, Ordering::SeqCst);
+
+	assert_eq!(check_readiness(&state).await.failed, vec!["shutting_down"]);
+}
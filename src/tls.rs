@@ -0,0 +1,241 @@
+use crate::config::{self, ClientAuth};
+use axum::{middleware::AddExtension, Extension};
+use axum_server::{
+	accept::Accept,
+	tls_rustls::{RustlsAcceptor, RustlsConfig},
+};
+use futures_util::future::BoxFuture;
+use rustls::{
+	server::{AllowAnyAnonymousOrAuthenticatedClient, AllowAnyAuthenticatedClient, NoClientAuth},
+	Certificate, PrivateKey, RootCertStore, ServerConfig,
+};
+use std::{fmt, fs, io, path::Path, sync::Arc};
+use tokio::io::{AsyncRead, AsyncWrite};
+use tokio_rustls::server::TlsStream;
+use tower::Layer;
+use tracing::{error, info};
+use x509_parser::prelude::{FromDer, X509Certificate};
+
+#[derive(Debug)]
+pub enum Error {
+	Io(String),
+	Invalid(String),
+}
+
+impl From<io::Error> for Error {
+	fn from(err: io::Error) -> Self {
+		Error::Io(format!("{}", err))
+	}
+}
+
+impl fmt::Display for Error {
+	fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+		match self {
+			Error::Io(err) | Error::Invalid(err) => write!(f, "{}", err),
+		}
+	}
+}
+
+// the client certificate of a tls connection, if it sent one; always verified against tls.client_ca
+#[derive(Clone, Debug, Default)]
+pub struct ClientCert {
+	// eg CN=backup, O=example
+	pub subject: Option<String>,
+}
+
+fn read_certs(path: &Path) -> Result<Vec<Certificate>, Error> {
+	let pem = fs::read(path)?;
+	let certs = rustls_pemfile::certs(&mut pem.as_slice())
+		.map(|cert| cert.map(|cert| Certificate(cert.to_vec())))
+		.collect::<Result<Vec<_>, _>>()?;
+
+	if certs.is_empty() {
+		return Err(Error::Invalid(format!(
+			"no certificates in {}",
+			path.display()
+		)));
+	}
+
+	Ok(certs)
+}
+
+fn read_key(path: &Path) -> Result<PrivateKey, Error> {
+	let pem = fs::read(path)?;
+
+	rustls_pemfile::private_key(&mut pem.as_slice())?
+		.map(|key| PrivateKey(key.secret_der().to_vec()))
+		.ok_or_else(|| Error::Invalid(format!("no private key in {}", path.display())))
+}
+
+// read from disk every time, so it doubles as a reload
+pub fn server_config(tls: &config::Tls) -> Result<ServerConfig, Error> {
+	let builder = ServerConfig::builder().with_safe_defaults();
+	let builder = match &tls.client_ca {
+		Some(path) => {
+			let mut roots = RootCertStore::empty();
+
+			for cert in read_certs(path)? {
+				roots
+					.add(&cert)
+					.map_err(|err| Error::Invalid(err.to_string()))?;
+			}
+
+			let verifier = match tls.client_auth {
+				ClientAuth::Optional => AllowAnyAnonymousOrAuthenticatedClient::new(roots).boxed(),
+				ClientAuth::Required => AllowAnyAuthenticatedClient::new(roots).boxed(),
+			};
+
+			builder.with_client_cert_verifier(verifier)
+		}
+		None => builder.with_client_cert_verifier(NoClientAuth::boxed()),
+	};
+	let mut config = builder
+		.with_single_cert(read_certs(&tls.cert)?, read_key(&tls.key)?)
+		.map_err(|err| Error::Invalid(err.to_string()))?;
+
+	config.alpn_protocols = vec![b"h2".to_vec(), b"http/1.1".to_vec()];
+
+	Ok(config)
+}
+
+pub fn subject(cert: &Certificate) -> Option<String> {
+	X509Certificate::from_der(&cert.0)
+		.ok()
+		.map(|(_, cert)| cert.subject().to_string())
+}
+
+// swaps in a freshly read cert, key and ca on every SIGHUP; a broken set keeps the old one serving
+pub async fn reload_on_sighup(rustls: RustlsConfig, tls: config::Tls) {
+	let mut hangups = match tokio::signal::unix::signal(tokio::signal::unix::SignalKind::hangup()) {
+		Ok(signal) => signal,
+		Err(err) => {
+			error!(%err, "can't listen for SIGHUP, tls won't reload");
+			return;
+		}
+	};
+
+	while hangups.recv().await.is_some() {
+		let loaded = {
+			let tls = tls.clone();
+
+			tokio::task::spawn_blocking(move || server_config(&tls)).await
+		};
+
+		match loaded {
+			Ok(Ok(config)) => {
+				rustls.reload_from_config(Arc::new(config));
+				info!("tls reloaded");
+			}
+			Ok(Err(err)) => error!(%err, "failed to reload tls, keeping the old cert"),
+			Err(err) => error!(%err, "failed to reload tls, keeping the old cert"),
+		}
+	}
+}
+
+// hands the client cert of each connection to its requests
+#[derive(Clone)]
+pub struct Acceptor {
+	inner: RustlsAcceptor,
+}
+
+impl Acceptor {
+	pub fn new(config: RustlsConfig) -> Self {
+		Self {
+			inner: RustlsAcceptor::new(config),
+		}
+	}
+}
+
+impl<I, S> Accept<I, S> for Acceptor
+where
+	I: AsyncRead + AsyncWrite + Unpin + Send + 'static,
+	S: Send + 'static,
+{
+	type Stream = TlsStream<I>;
+	type Service = AddExtension<S, ClientCert>;
+	type Future = BoxFuture<'static, io::Result<(Self::Stream, Self::Service)>>;
+
+	fn accept(&self, stream: I, service: S) -> Self::Future {
+		let acceptor = self.inner.clone();
+
+		Box::pin(async move {
+			let (stream, service) = acceptor.accept(stream, service).await?;
+			let client = ClientCert {
+				subject: stream
+					.get_ref()
+					.1
+					.peer_certificates()
+					.and_then(|certs| certs.first())
+					.and_then(subject),
+			};
+
+			Ok((stream, Extension(client).layer(service)))
+		})
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use crate::tmp_dir::TmpDir;
+	use rcgen::{BasicConstraints, DistinguishedName, DnType, IsCa};
+
+	fn cert(name: &str, ca: Option<&rcgen::Certificate>) -> rcgen::Certificate {
+		let mut params = rcgen::CertificateParams::new(vec!["localhost".to_string()]);
+		let mut dn = DistinguishedName::new();
+
+		dn.push(DnType::CommonName, name);
+		params.distinguished_name = dn;
+
+		if ca.is_none() {
+			params.is_ca = IsCa::Ca(BasicConstraints::Unconstrained);
+		}
+
+		rcgen::Certificate::from_params(params).unwrap()
+	}
+
+	#[test]
+	fn test_server_config() {
+		let dir = TmpDir::new("tls");
+		let ca = cert("ca", None);
+		let server = cert("server", Some(&ca));
+
+		fs::write(
+			dir.join("cert.pem"),
+			server.serialize_pem_with_signer(&ca).unwrap(),
+		)
+		.unwrap();
+		fs::write(dir.join("key.pem"), server.serialize_private_key_pem()).unwrap();
+		fs::write(dir.join("ca.pem"), ca.serialize_pem().unwrap()).unwrap();
+
+		let mut tls = config::Tls {
+			enabled: true,
+			cert: dir.join("cert.pem"),
+			key: dir.join("key.pem"),
+			client_ca: None,
+			client_auth: ClientAuth::Optional,
+		};
+
+		assert!(server_config(&tls).is_ok());
+
+		tls.client_ca = Some(dir.join("ca.pem"));
+		tls.client_auth = ClientAuth::Required;
+
+		assert!(server_config(&tls).is_ok());
+
+		// a key where the ca should be
+		tls.client_ca = Some(dir.join("key.pem"));
+
+		assert!(matches!(server_config(&tls), Err(Error::Invalid(_))));
+	}
+
+	#[test]
+	fn test_subject() {
+		let ca = cert("ca", None);
+		let client = cert("backup", Some(&ca));
+		let der = client.serialize_der_with_signer(&ca).unwrap();
+
+		assert_eq!(subject(&Certificate(der)), Some("CN=backup".to_string()));
+		assert_eq!(subject(&Certificate(vec![1, 2, 3])), None);
+	}
+}
@@ -0,0 +1,55 @@
+use std::{
+	env, fs,
+	ops::Deref,
+	path::{Path, PathBuf},
+};
+
+// a fresh dir under the system one for a test to write to; removed when dropped, panics included
+pub struct TmpDir(PathBuf);
+
+impl TmpDir {
+	// `name` tells apart what's left behind if a test gets killed before it's dropped
+	pub fn new(name: &str) -> Self {
+		let dir = env::temp_dir().join(format!("uploader-{}-{}", name, rand::random::<u64>()));
+
+		fs::create_dir_all(&dir).unwrap();
+
+		Self(dir)
+	}
+}
+
+impl Deref for TmpDir {
+	type Target = Path;
+
+	fn deref(&self) -> &Path {
+		&self.0
+	}
+}
+
+impl AsRef<Path> for TmpDir {
+	fn as_ref(&self) -> &Path {
+		&self.0
+	}
+}
+
+impl Drop for TmpDir {
+	fn drop(&mut self) {
+		_ = fs::remove_dir_all(&self.0);
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	#[test]
+	fn test_removed_on_drop() {
+		let dir = TmpDir::new("tmp");
+		let path = dir.to_path_buf();
+
+		fs::write(dir.join("a"), [1]).unwrap();
+		drop(dir);
+
+		assert!(!path.exists());
+	}
+}
@@ -0,0 +1,11 @@
+use rand::{rngs::OsRng, RngCore};
+
+const TOKEN_SIZE: usize = 32;
+
+// an unguessable, url-safe random token
+pub fn generate() -> String {
+	let mut bytes = [0u8; TOKEN_SIZE];
+	OsRng.fill_bytes(&mut bytes);
+
+	base64::encode_config(bytes, base64::URL_SAFE_NO_PAD)
+}
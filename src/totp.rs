@@ -0,0 +1,127 @@
+use hmac::{Hmac, Mac};
+use rand::{rngs::OsRng, RngCore};
+use sha1::Sha1;
+
+// rfc 6238 defaults, which is what authenticator apps expect
+pub const STEP: u64 = 30;
+pub const DIGITS: u32 = 6;
+// steps either side of now a code is still accepted at, for clocks that drift
+pub const SKEW: u64 = 1;
+
+const SECRET_SIZE: usize = 20;
+const BASE32: &[u8] = b"ABCDEFGHIJKLMNOPQRSTUVWXYZ234567";
+
+pub fn generate_secret() -> Vec<u8> {
+	let mut secret = vec![0u8; SECRET_SIZE];
+	OsRng.fill_bytes(&mut secret);
+
+	secret
+}
+
+// rfc 4648, no padding; the form authenticator apps take secrets in
+pub fn base32(bytes: &[u8]) -> String {
+	bytes
+		.chunks(5)
+		.flat_map(|chunk| {
+			let mut buf = [0u8; 5];
+			buf[..chunk.len()].copy_from_slice(chunk);
+
+			let bits = buf.iter().fold(0u64, |acc, b| acc << 8 | *b as u64);
+			let chars = (chunk.len() * 8).div_ceil(5);
+
+			(0..chars).map(move |i| BASE32[(bits >> (35 - i * 5)) as usize & 0x1f] as char)
+		})
+		.collect()
+}
+
+// rfc 4226
+pub fn hotp(secret: &[u8], counter: u64, digits: u32) -> u32 {
+	let mut mac = Hmac::<Sha1>::new_from_slice(secret).expect("hmac takes keys of any size");
+
+	mac.update(&counter.to_be_bytes());
+
+	let hash = mac.finalize().into_bytes();
+	let offset = (hash[hash.len() - 1] & 0xf) as usize;
+	let code = u32::from_be_bytes(hash[offset..offset + 4].try_into().unwrap()) & 0x7fff_ffff;
+
+	code % 10u32.pow(digits)
+}
+
+// what an authenticator app shows
+#[cfg(test)]
+pub fn code(secret: &[u8], now: u64) -> String {
+	format!(
+		"{:0width$}",
+		hotp(secret, now / STEP, DIGITS),
+		width = DIGITS as usize
+	)
+}
+
+// returns the step the code belongs to, so it can't be replayed within it
+pub fn verify(secret: &[u8], code: &str, now: u64) -> Option<u64> {
+	let step = now / STEP;
+
+	(step.saturating_sub(SKEW)..=step + SKEW).find(|step| {
+		format!(
+			"{:0width$}",
+			hotp(secret, *step, DIGITS),
+			width = DIGITS as usize
+		) == code
+	})
+}
+
+pub fn uri(secret: &[u8], account: &str) -> String {
+	format!(
+		"otpauth://totp/uploader:{}?secret={}&issuer=uploader&digits={}&period={}",
+		urlencoding::encode(account),
+		base32(secret),
+		DIGITS,
+		STEP
+	)
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	// rfc 6238, appendix b, sha1
+	const SECRET: &[u8] = b"12345678901234567890";
+
+	#[test]
+	fn test_rfc_vectors() {
+		[
+			(59, 94287082),
+			(1111111109, 7081804),
+			(1111111111, 14050471),
+			(1234567890, 89005924),
+			(2000000000, 69279037),
+			(20000000000, 65353130),
+		]
+		.iter()
+		.for_each(|(time, expected)| assert_eq!(hotp(SECRET, time / STEP, 8), *expected));
+	}
+
+	#[test]
+	fn test_verify_with_skew() {
+		let now = 1111111109;
+		let code = code(SECRET, now);
+
+		assert_eq!(verify(SECRET, &code, now), Some(now / STEP));
+		assert_eq!(verify(SECRET, &code, now + STEP), Some(now / STEP));
+		assert_eq!(verify(SECRET, &code, now - STEP), Some(now / STEP));
+		assert_eq!(verify(SECRET, &code, now + 2 * STEP), None);
+		assert_eq!(verify(SECRET, "000000", now), None);
+	}
+
+	#[test]
+	fn test_base32() {
+		// rfc 4648, section 10
+		assert_eq!(base32(b""), "");
+		assert_eq!(base32(b"f"), "MY");
+		assert_eq!(base32(b"fo"), "MZXQ");
+		assert_eq!(base32(b"foo"), "MZXW6");
+		assert_eq!(base32(b"foob"), "MZXW6YQ");
+		assert_eq!(base32(b"fooba"), "MZXW6YTB");
+		assert_eq!(base32(b"foobar"), "MZXW6YTBOI");
+	}
+}
@@ -1,7 +1,19 @@
 use std::collections::HashMap;
 
-use crate::{encrypted, identity, lock, nodes::LockedNode, purge::Purge, shares::LockedShare};
+use crate::{
+	devices, encrypted, groups::LockedGroup, identity, lock, nodes::LockedNode, purge::Purge,
+	shares::LockedShare, token, totp,
+};
 use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+
+// handed out on totp enrollment, each good for a single login
+pub const BACKUP_CODES: usize = 10;
+const BACKUP_CODE_SIZE: usize = 10;
+// wrong second factors in a row, across logins, after which an account takes none for a while
+pub const TOTP_MAX_FAILURES: u32 = 10;
+// seconds
+pub const TOTP_LOCKOUT: u64 = 15 * 60;
 
 #[derive(Serialize, Deserialize, PartialEq, Debug)]
 pub struct LockedUser {
@@ -15,6 +27,9 @@ pub struct LockedUser {
 	// get_nodes(locked_shares(user_id == share.receiver | user_id == 0 then node_id_root).export.fs.ids + children)
 	// TODO: include a hash of the hierarchy for later checks
 	pub roots: Vec<LockedNode>,
+	// groups the user is a member of; shares exported to any of them are in `shares` as well
+	#[serde(default)]
+	pub groups: Vec<LockedGroup>,
 }
 
 #[derive(Serialize, Deserialize)]
@@ -28,16 +43,81 @@ pub struct Signup {
 pub struct Login {
 	pub email: String,
 	pub pass: String,
+	// required once the user has registered devices
+	#[serde(default)]
+	pub device: Option<devices::Proof>,
+}
+
+// a new password means a new master key wrap; the identity itself stays the same
+#[derive(Serialize, Deserialize)]
+pub struct LockChange {
+	// proves the caller knows the current password, not just holds a token
+	pub old_pass: String,
+	pub new_pass: String,
+	pub lock: lock::Lock,
+}
+
+// sets a new password once the master key has been recovered some other way
+#[derive(Serialize, Deserialize)]
+pub struct Reset {
+	pub new_pass: String,
+	pub lock: lock::Lock,
+}
+
+// deleting an account has to be re-confirmed with the password (and second factor, if any)
+#[derive(Serialize, Deserialize)]
+pub struct Deletion {
+	pub pass: String,
+	pub code: Option<String>,
+	// owned trees this user has been shared are handed over to them, the rest is deleted
+	pub transfer_to: Option<u64>,
+}
+
+// shown once: to be put into an authenticator app and stored somewhere safe
+#[derive(Serialize, Deserialize)]
+pub struct TotpEnrollment {
+	// base32
+	pub secret: String,
+	pub uri: String,
+	pub backup_codes: Vec<String>,
+}
+
+// either a totp code or one of the backup codes
+#[derive(Serialize, Deserialize)]
+pub struct TotpCode {
+	pub code: String,
+}
+
+struct Totp {
+	secret: Vec<u8>,
+	// not enforced until a first code has been entered correctly
+	confirmed: bool,
+	// the step of the last accepted code, so it can't be used twice
+	last_step: Option<u64>,
+	// sha256 of the unused backup codes; they are random enough to do without a slow hash
+	backup_codes: Vec<[u8; 32]>,
+	// wrong codes since the last right one; a fresh login doesn't start this over
+	failures: u32,
+	locked_until: u64,
+}
+
+fn backup_hash(code: &str) -> [u8; 32] {
+	Sha256::digest(code.as_bytes()).into()
 }
 
 pub struct Users {
-	// no pass is needed here, since it's just a playground
 	// { email, user_id }
 	pub credentials: HashMap<String, u64>,
+	// { user_id, password::hash(pass) }
+	pub passwords: HashMap<u64, String>,
 	// { user_id, Public }
 	pub public_keys: HashMap<u64, identity::Public>,
 	// { user_id, Lock }
 	pub private_keys: HashMap<u64, lock::Lock>,
+	// { user_id, second factor }
+	totp: HashMap<u64, Totp>,
+	// { user_id, deleted at }; ids of deleted accounts can't be signed up with again
+	tombstones: HashMap<u64, u64>,
 }
 
 impl Users {
@@ -68,14 +148,189 @@ impl Users {
 	pub fn id_for_email(&self, email: &str) -> Option<u64> {
 		self.credentials.get(email).cloned()
 	}
+
+	pub fn email_for_id(&self, id: u64) -> Option<&String> {
+		self.credentials
+			.iter()
+			.find(|(_, user_id)| **user_id == id)
+			.map(|(email, _)| email)
+	}
+
+	// hashes are made and checked by the caller, see password::hash_async
+	pub fn pass_hash_for_id(&self, id: u64) -> Option<&String> {
+		self.passwords.get(&id)
+	}
+
+	pub fn add_pass_hash(&mut self, id: u64, hash: &str) {
+		self.passwords.insert(id, hash.to_string());
+	}
+
+	// `checked` is the hash the old password was verified against; if the password changed
+	// meanwhile, nothing changes
+	// `checked` is the hash the old password matched, none if there was no password yet
+	pub fn change_lock(
+		&mut self,
+		id: u64,
+		checked: Option<&str>,
+		hash: &str,
+		lock: lock::Lock,
+	) -> bool {
+		if !self.private_keys.contains_key(&id)
+			|| self.pass_hash_for_id(id).map(String::as_str) != checked
+		{
+			return false;
+		}
+
+		self.add_pass_hash(id, hash);
+		self.add_priv(id, lock);
+
+		true
+	}
+
+	pub fn reset_lock(&mut self, id: u64, hash: &str, lock: lock::Lock) -> bool {
+		if !self.private_keys.contains_key(&id) {
+			return false;
+		}
+
+		self.add_pass_hash(id, hash);
+		self.add_priv(id, lock);
+
+		true
+	}
+
+	// replaces a pending enrollment; an active one has to be disabled first
+	pub fn enroll_totp(&mut self, id: u64) -> Option<TotpEnrollment> {
+		if self.has_totp(id) {
+			return None;
+		}
+
+		let secret = totp::generate_secret();
+		let backup_codes: Vec<String> = (0..BACKUP_CODES)
+			.map(|_| token::generate()[..BACKUP_CODE_SIZE].to_string())
+			.collect();
+		let enrollment = TotpEnrollment {
+			secret: totp::base32(&secret),
+			uri: totp::uri(&secret, &id.to_string()),
+			backup_codes: backup_codes.clone(),
+		};
+
+		self.totp.insert(
+			id,
+			Totp {
+				secret,
+				confirmed: false,
+				last_step: None,
+				backup_codes: backup_codes.iter().map(|c| backup_hash(c)).collect(),
+				failures: 0,
+				locked_until: 0,
+			},
+		);
+
+		Some(enrollment)
+	}
+
+	pub fn confirm_totp(&mut self, id: u64, code: &str, now: u64) -> bool {
+		let Some(totp) = self.totp.get_mut(&id) else {
+			return false;
+		};
+
+		match totp::verify(&totp.secret, code, now) {
+			Some(step) => {
+				totp.confirmed = true;
+				totp.last_step = Some(step);
+
+				true
+			}
+			None => false,
+		}
+	}
+
+	pub fn has_totp(&self, id: u64) -> bool {
+		self.totp.get(&id).is_some_and(|totp| totp.confirmed)
+	}
+
+	pub fn is_totp_locked(&self, id: u64, now: u64) -> bool {
+		self.totp
+			.get(&id)
+			.is_some_and(|totp| now < totp.locked_until)
+	}
+
+	// a current totp code not used before, or an unused backup code, which is used up;
+	// nothing is taken while locked out, see TOTP_MAX_FAILURES
+	pub fn check_second_factor(&mut self, id: u64, code: &str, now: u64) -> bool {
+		let Some(totp) = self.totp.get_mut(&id).filter(|totp| totp.confirmed) else {
+			return false;
+		};
+
+		if now < totp.locked_until {
+			return false;
+		}
+
+		let ok = match totp::verify(&totp.secret, code, now) {
+			Some(step) if totp.last_step.is_some_and(|last| step <= last) => false,
+			Some(step) => {
+				totp.last_step = Some(step);
+
+				true
+			}
+			None => {
+				let hash = backup_hash(code);
+				let before = totp.backup_codes.len();
+
+				totp.backup_codes.retain(|c| *c != hash);
+
+				totp.backup_codes.len() < before
+			}
+		};
+
+		if ok {
+			totp.failures = 0;
+		} else {
+			totp.failures += 1;
+
+			if totp.failures >= TOTP_MAX_FAILURES {
+				totp.failures = 0;
+				totp.locked_until = now + TOTP_LOCKOUT;
+			}
+		}
+
+		ok
+	}
+
+	// forgets everything about the user but the fact it existed. the email is let go on purpose, so
+	// its owner can sign up again: nothing but invites is looked up by email, and those go to
+	// whoever reads the mailbox anyway
+	pub fn remove(&mut self, id: u64, now: u64) {
+		self.credentials.retain(|_, user_id| *user_id != id);
+		self.passwords.remove(&id);
+		self.public_keys.remove(&id);
+		self.private_keys.remove(&id);
+		self.totp.remove(&id);
+		self.tombstones.insert(id, now);
+	}
+
+	pub fn is_tombstoned(&self, id: u64) -> bool {
+		self.tombstones.contains_key(&id)
+	}
+
+	pub fn disable_totp(&mut self, id: u64, code: &str, now: u64) -> bool {
+		if !self.check_second_factor(id, code, now) {
+			return false;
+		}
+
+		self.totp.remove(&id).is_some()
+	}
 }
 
 impl Purge for Users {
 	fn new() -> Self {
 		Self {
 			credentials: HashMap::new(),
+			passwords: HashMap::new(),
 			public_keys: HashMap::new(),
 			private_keys: HashMap::new(),
+			totp: HashMap::new(),
+			tombstones: HashMap::new(),
 		}
 	}
 }
@@ -83,3 +338,137 @@ impl Purge for Users {
 // users:
 // 	priv
 //  pub
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	fn enrolled(users: &mut Users, now: u64) -> TotpEnrollment {
+		let enrollment = users.enroll_totp(1).unwrap();
+		let secret = users.totp[&1].secret.clone();
+
+		assert!(!users.has_totp(1));
+		assert!(users.confirm_totp(1, &totp::code(&secret, now), now));
+		assert!(users.has_totp(1));
+
+		enrollment
+	}
+
+	#[test]
+	fn test_enroll_once() {
+		let mut users = Users::new();
+
+		enrolled(&mut users, 1000);
+
+		assert!(users.enroll_totp(1).is_none());
+	}
+
+	#[test]
+	fn test_unconfirmed_is_not_enforced() {
+		let mut users = Users::new();
+		let enrollment = users.enroll_totp(1).unwrap();
+
+		assert!(!users.check_second_factor(1, &enrollment.backup_codes[0], 1000));
+	}
+
+	#[test]
+	fn test_code_is_not_replayed() {
+		let mut users = Users::new();
+
+		enrolled(&mut users, 1000);
+
+		let secret = users.totp[&1].secret.clone();
+		let next = 1000 + totp::STEP;
+
+		assert!(!users.check_second_factor(1, &totp::code(&secret, 1000), 1000));
+		assert!(users.check_second_factor(1, &totp::code(&secret, next), next));
+		assert!(!users.check_second_factor(1, &totp::code(&secret, next), next));
+	}
+
+	#[test]
+	fn test_backup_codes_are_used_up() {
+		let mut users = Users::new();
+		let enrollment = enrolled(&mut users, 1000);
+		let code = &enrollment.backup_codes[3];
+
+		assert_eq!(enrollment.backup_codes.len(), BACKUP_CODES);
+		assert!(users.check_second_factor(1, code, 1000));
+		assert!(!users.check_second_factor(1, code, 1000));
+	}
+
+	#[test]
+	fn test_remove_leaves_tombstone() {
+		let mut users = Users::new();
+
+		users.add_credentials("a@mail.com", 1);
+		users.add_pass_hash(1, "hash");
+		users.remove(1, 1000);
+
+		assert_eq!(users.id_for_email("a@mail.com"), None);
+		assert_eq!(users.pass_hash_for_id(1), None);
+		assert!(users.is_tombstoned(1));
+		assert!(!users.is_tombstoned(2));
+	}
+
+	fn lock(b: u8) -> lock::Lock {
+		serde_json::from_value(serde_json::json!({
+			"ct": base64::encode([b]),
+			"master_key": { "ct": "", "salt": { "bytes": base64::encode([0u8; 32]) } },
+		}))
+		.unwrap()
+	}
+
+	#[test]
+	fn test_change_lock() {
+		let mut users = Users::new();
+
+		// nobody to change it for
+		assert!(!users.change_lock(1, Some("old"), "new", lock(1)));
+
+		// imported, so no password yet
+		users.add_priv(1, lock(0));
+
+		assert!(!users.change_lock(1, Some("old"), "old", lock(0)));
+		assert!(users.change_lock(1, None, "old", lock(0)));
+		assert!(!users.change_lock(1, None, "new", lock(1)));
+		assert!(!users.change_lock(1, Some("wrong"), "new", lock(1)));
+		assert_eq!(users.priv_for_id(1), Some(&lock(0)));
+		assert!(users.change_lock(1, Some("old"), "new", lock(1)));
+		assert_eq!(users.pass_hash_for_id(1).map(String::as_str), Some("new"));
+		assert_eq!(users.priv_for_id(1), Some(&lock(1)));
+		// checked against a hash that has changed since
+		assert!(!users.change_lock(1, Some("old"), "newer", lock(2)));
+		assert_eq!(users.priv_for_id(1), Some(&lock(1)));
+	}
+
+	#[test]
+	fn test_failures_lock_out_the_account() {
+		let mut users = Users::new();
+
+		enrolled(&mut users, 1000);
+
+		let secret = users.totp[&1].secret.clone();
+		let next = 1000 + totp::STEP;
+
+		(0..TOTP_MAX_FAILURES).for_each(|_| assert!(!users.check_second_factor(1, "nope", next)));
+
+		assert!(users.is_totp_locked(1, next));
+		// not even the right code
+		assert!(!users.check_second_factor(1, &totp::code(&secret, next), next));
+
+		let later = next + TOTP_LOCKOUT;
+
+		assert!(!users.is_totp_locked(1, later));
+		assert!(users.check_second_factor(1, &totp::code(&secret, later), later));
+	}
+
+	#[test]
+	fn test_disable() {
+		let mut users = Users::new();
+		let enrollment = enrolled(&mut users, 1000);
+
+		assert!(!users.disable_totp(1, "nope", 1000));
+		assert!(users.disable_totp(1, &enrollment.backup_codes[0], 1000));
+		assert!(!users.has_totp(1));
+	}
+}